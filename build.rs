@@ -1,27 +1,163 @@
-use std::process::Command;
-
-fn main() -> Result<(), ()> {
-    println!("cargo:rerun-if-changed=generate.sql");
-
-    let output = Command::new("sqlite3")
-        .arg("-bail")
-        .arg("example.sqlite3")
-        .arg(".read generate.sql")
-        .arg(".exit")
-        .output()
-        .expect("sqlite3");
-
-    if !output.status.success() {
-        eprintln!(
-            "stdout: {}",
-            std::str::from_utf8(&output.stdout).expect("stdout")
-        );
-        eprintln!(
-            "stderr: {}",
-            std::str::from_utf8(&output.stderr).expect("stderr")
-        );
-        return Err(());
+use std::path::Path;
+
+// the same encoder the server compresses pages with
+#[path = "src/gzip.rs"]
+mod gzip;
+
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+
+    create_example_database();
+    embed_assets();
+    embed_themes();
+}
+
+/// Run the migrations into a fresh example.sqlite3 for `query!` to check queries against. Starts
+/// from an empty database so changes to migrations that were already applied take effect too.
+fn create_example_database() {
+    for stale in [
+        "example.sqlite3",
+        "example.sqlite3-wal",
+        "example.sqlite3-shm",
+    ] {
+        let _ = std::fs::remove_file(stale);
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime");
+
+    runtime.block_on(async {
+        use sqlx::{
+            Connection,
+            migrate::Migrator,
+            sqlite::{SqliteConnectOptions, SqliteConnection},
+        };
+
+        let migrator = Migrator::new(Path::new("migrations"))
+            .await
+            .expect("read migrations");
+        let options = SqliteConnectOptions::new()
+            .filename("example.sqlite3")
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options)
+            .await
+            .expect("example.sqlite3");
+        migrator.run(&mut conn).await.expect("run migrations");
+        conn.close().await.expect("close example.sqlite3");
+    });
+}
+
+/// Build frontend/assets into the binary for release builds to serve, as a table of
+/// `EmbeddedAsset`s in OUT_DIR. Text files also get a gzipped version.
+fn embed_assets() {
+    println!("cargo:rerun-if-changed=frontend/assets");
+
+    let table = asset_table(Path::new("frontend/assets"), "");
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR");
+    std::fs::write(Path::new(&out_dir).join("assets.rs"), table).expect("write asset table");
+}
+
+/// Each directory in frontend/themes as an `EmbeddedTheme`, with its templates and its assets
+/// embedded like frontend/assets are
+fn embed_themes() {
+    println!("cargo:rerun-if-changed=frontend/themes");
+
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR");
+    let mut themes = std::fs::read_dir("frontend/themes")
+        .map(|entries| {
+            entries
+                .map(|entry| entry.expect("readable themes dir").path())
+                .filter(|path| path.is_dir())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    themes.sort();
+
+    let mut table = String::from("&[\n");
+    for theme in themes.iter() {
+        println!("cargo:rerun-if-changed={}", theme.display());
+        let name = theme
+            .file_name()
+            .expect("theme name")
+            .to_string_lossy()
+            .into_owned();
+
+        let mut templates = glob::glob(&format!("{}/*.tera", theme.display()))
+            .expect("valid glob")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("readable theme dir");
+        templates.sort();
+
+        table += &format!("    EmbeddedTheme {{\n        name: {name:?},\n        templates: &[\n");
+        for template in templates.iter() {
+            println!("cargo:rerun-if-changed={}", template.display());
+            let template_name = template
+                .file_name()
+                .expect("template name")
+                .to_string_lossy();
+            let absolute = Path::new(&manifest_dir).join(template);
+            let contents = format!("include_str!({:?})", absolute.display().to_string());
+            table += &format!("            ({template_name:?}, {contents}),\n");
+        }
+        table += "        ],\n        assets: ";
+        table += &asset_table(&theme.join("assets"), &format!("{name}_"));
+        table += "    },\n";
+    }
+    table += "]\n";
+
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR");
+    std::fs::write(Path::new(&out_dir).join("themes.rs"), table).expect("write theme table");
+}
+
+/// The files under `dir` as an `EmbeddedAsset` table, with compressed versions in OUT_DIR named
+/// after `prefix` and their path
+fn asset_table(dir: &Path, prefix: &str) -> String {
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR");
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR");
+    let root = Path::new(&manifest_dir).join(dir);
+
+    let mut assets = glob::glob(&format!("{}/**/*", dir.display()))
+        .expect("valid glob")
+        .collect::<Result<Vec<_>, _>>()
+        .expect("readable assets dir");
+    assets.retain(|asset| asset.is_file());
+    assets.sort();
+
+    let mut table = String::from("&[\n");
+
+    for asset in assets.iter() {
+        println!("cargo:rerun-if-changed={}", asset.display());
+
+        let absolute = Path::new(&manifest_dir).join(asset);
+        let name = absolute
+            .strip_prefix(&root)
+            .expect("asset under assets dir")
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let compressible = asset
+            .extension()
+            .is_some_and(|extension| ["css", "js", "svg"].iter().any(|text| extension == *text));
+        let gzip = match compressible {
+            true => {
+                let flattened = prefix.to_string() + &name.replace('/', "_");
+                let path = Path::new(&out_dir).join(format!("{flattened}.gz"));
+                let contents = std::fs::read(asset).expect("readable asset");
+                std::fs::write(&path, gzip::compress(&contents)).expect("write compressed asset");
+                format!("Some(include_bytes!({:?}))", path.display().to_string())
+            }
+            false => String::from("None"),
+        };
+        let contents = format!("include_bytes!({:?})", absolute.display().to_string());
+        table +=
+            &format!("    EmbeddedAsset {{ path: {name:?}, contents: {contents}, gzip: {gzip} }},");
+        table += "\n";
     }
 
-    Ok(())
+    table += "]\n";
+    table
 }
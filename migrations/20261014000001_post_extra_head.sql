@@ -0,0 +1,2 @@
+-- style and link tags for the post's head, already sanitized
+alter table post add column extra_head text;
@@ -0,0 +1,2 @@
+-- markdown or html, which is how the content gets rendered. posts from before it are markdown
+alter table post add column content_type text not null default 'markdown';
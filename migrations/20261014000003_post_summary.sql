@@ -0,0 +1,2 @@
+-- for meta descriptions, instead of the subtitle or the start of the content
+alter table post add column summary text;
@@ -0,0 +1,4 @@
+-- when the post last changed. posts already there are taken to be unchanged since they were
+-- published, and the default is only there because added columns need one
+alter table post add column updated datetime not null default '1970-01-01T00:00:00+00:00';
+update post set updated = published;
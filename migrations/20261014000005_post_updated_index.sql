@@ -0,0 +1 @@
+create index post_updated on post (julianday(updated));
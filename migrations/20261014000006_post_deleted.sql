@@ -0,0 +1,2 @@
+-- when the post was deleted, which leaves it in place to serve 410 Gone instead of the post
+alter table post add column deleted datetime;
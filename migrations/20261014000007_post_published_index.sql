@@ -0,0 +1 @@
+create index post_published on post (published);
@@ -0,0 +1,8 @@
+create table tag (
+    post_id blob not null,
+    tag text not null,
+    primary key (post_id, tag),
+    foreign key (post_id) references post (id)
+);
+
+create index tag_tag on tag (tag);
@@ -0,0 +1,15 @@
+-- old revisions, which used to be kept as json in old. those get copied in here on startup
+create table revision (
+    id blob not null,
+    revision integer not null,
+    title text not null,
+    subtitle text,
+    published datetime not null,
+    content text not null,
+    content_type text not null default 'markdown',
+    created_at datetime not null,
+    -- where in old this was copied from, if it was
+    old_rowid integer unique,
+    primary key (id, revision),
+    foreign key (id) references post (id)
+);
@@ -0,0 +1,8 @@
+-- uploaded files, which live in media_dir under filename
+create table media (
+    id blob unique not null primary key,
+    filename text unique not null,
+    content_type text not null,
+    size integer not null,
+    uploaded_at datetime not null
+);
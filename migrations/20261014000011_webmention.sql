@@ -0,0 +1,14 @@
+-- outgoing webmentions, the latest attempt for each link from each post
+create table webmention (
+    post_id blob not null,
+    source text not null,
+    target text not null,
+    endpoint text,
+    -- sent, failed, or no endpoint
+    status text not null,
+    detail text,
+    attempts integer not null,
+    updated_at datetime not null,
+    primary key (post_id, target),
+    foreign key (post_id) references post (id)
+);
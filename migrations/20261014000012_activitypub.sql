@@ -0,0 +1,13 @@
+-- the activitypub actor's key, when it isn't in private_key_file
+create table activitypub_key (
+    id integer primary key check (id = 0),
+    private_key text not null
+);
+
+-- activitypub actors following the blog, who new posts get delivered to
+create table follower (
+    actor text primary key not null,
+    inbox text not null,
+    shared_inbox text,
+    followed_at datetime not null
+);
@@ -0,0 +1,16 @@
+-- comments from visitors, which only show once they're approved
+create table comment (
+    id blob unique not null primary key,
+    post_id blob not null,
+    author text not null,
+    url text,
+    email text,
+    body text not null,
+    created_at datetime not null,
+    -- pending, approved, or rejected
+    status text not null,
+    moderated_at datetime,
+    foreign key (post_id) references post (id)
+);
+
+create index comment_post_id on comment (post_id, status);
@@ -0,0 +1,8 @@
+-- views of each post by anonymous visitors, a row per post per day
+create table view (
+    post_id blob not null,
+    date date not null,
+    count integer not null,
+    primary key (post_id, date),
+    foreign key (post_id) references post (id)
+);
@@ -0,0 +1,2 @@
+-- url of the picture for link previews
+alter table post add column image text;
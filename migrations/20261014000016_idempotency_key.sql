@@ -0,0 +1,9 @@
+-- a publish's Idempotency-Key and the post it made, so a retry gets that post instead of another
+create table idempotency_key (
+    key text primary key not null,
+    -- of the request body, to tell a retry from a different request reusing the key
+    body_sha256 blob not null,
+    post_id blob not null,
+    created_at datetime not null,
+    foreign key (post_id) references post (id)
+);
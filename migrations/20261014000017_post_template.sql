@@ -0,0 +1,2 @@
+-- a template to render the post with instead of post.html.tera, for posts that need their own layout
+alter table post add column template text;
@@ -0,0 +1,680 @@
+//! Just enough ActivityPub to be followed from Mastodon and the like: a service actor,
+//! WebFinger to find it by, an outbox of posts, and an inbox that takes follows. New posts are
+//! delivered to followers' inboxes, signed with the actor's key.
+
+use crate::{App, CanonicalPost, Config, FeedDate, FetchBody, Post, RateScope, fetch, sha256};
+use anyhow::Result;
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use regex::Regex;
+use rsa::{
+    RsaPrivateKey, RsaPublicKey,
+    pkcs1::DecodeRsaPublicKey,
+    pkcs1v15::{Signature, SigningKey, VerifyingKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    signature::{SignatureEncoding, Signer, Verifier},
+};
+use serde_json::{Value, json};
+use std::{
+    path::PathBuf,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use uuid::Uuid;
+
+const ACTIVITY_JSON: &str = "application/activity+json";
+const ACTIVITY_STREAMS: &str = "https://www.w3.org/ns/activitystreams";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+/// How far the Date of a signed request can be from now
+const MAX_CLOCK_SKEW: chrono::TimeDelta = chrono::TimeDelta::hours(12);
+/// Delivery is tried this many times, waiting twice as long before each retry
+const DELIVERY_ATTEMPTS: u32 = 3;
+const DELIVERY_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct ActivityPubConfig {
+    /// The name in `@name@host`
+    #[serde(default = "default_username")]
+    pub username: String,
+    /// PKCS#8 PEM private key to sign with. One gets made and kept in the database without it.
+    #[serde(default)]
+    pub private_key_file: Option<PathBuf>,
+    /// Mastodon shows an `Article` as its title and a link, and a `Note` in full
+    #[serde(default)]
+    pub object_type: ObjectType,
+}
+
+fn default_username() -> String {
+    String::from("blog")
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ObjectType {
+    #[default]
+    Article,
+    Note,
+}
+
+/// The actor's key pair
+pub struct Keys {
+    private: RsaPrivateKey,
+    public_pem: String,
+}
+
+impl Keys {
+    pub async fn load(config: &ActivityPubConfig, pool: &sqlx::SqlitePool) -> Result<Keys> {
+        let pem = match config.private_key_file.as_ref() {
+            Some(path) => match tokio::fs::read_to_string(path).await {
+                Ok(pem) => pem,
+                Err(err) => fatal!("couldn't read private_key_file {}: {}", path.display(), err),
+            },
+
+            None => {
+                let stored = sqlx::query_scalar!("select private_key from activitypub_key")
+                    .fetch_optional(pool)
+                    .await?;
+                match stored {
+                    Some(pem) => pem,
+                    None => {
+                        tracing::info!("generating a key for activitypub");
+                        let key = tokio::task::spawn_blocking(|| {
+                            RsaPrivateKey::new(&mut rand::thread_rng(), 2048)
+                        })
+                        .await??;
+                        let pem = key.to_pkcs8_pem(LineEnding::LF)?.to_string();
+                        sqlx::query!(
+                            "insert into activitypub_key (id, private_key) values (0, $1)",
+                            pem
+                        )
+                        .execute(pool)
+                        .await?;
+                        pem
+                    }
+                }
+            }
+        };
+
+        let private = match RsaPrivateKey::from_pkcs8_pem(&pem) {
+            Ok(private) => private,
+            Err(err) => fatal!("activitypub key isn't a PKCS#8 RSA key: {}", err),
+        };
+        let public_pem = private.to_public_key().to_public_key_pem(LineEnding::LF)?;
+        Ok(Keys {
+            private,
+            public_pem,
+        })
+    }
+
+    fn sign(&self, data: &str) -> String {
+        let key = SigningKey::<sha2::Sha256>::new(self.private.clone());
+        STANDARD.encode(key.sign(data.as_bytes()).to_bytes())
+    }
+}
+
+/// The routes, which only exist when `activitypub` is configured
+pub fn router<S>(app: Arc<App>) -> Router<S> {
+    let config = app.config();
+    Router::new()
+        .route(&config.route_dot("/actor"), get(actor_handler))
+        .route(&config.route_dot("/outbox"), get(outbox_handler))
+        .route(&config.route_dot("/followers"), get(followers_handler))
+        .route(&config.route_dot("/inbox"), post(inbox_handler))
+        .route("/.well-known/webfinger", get(webfinger_handler))
+        .layer(axum::middleware::from_fn_with_state(
+            (app.clone(), RateScope::Public),
+            crate::rate_limit_layer,
+        ))
+        .with_state(app.clone())
+}
+
+fn actor_id(config: &Config) -> String {
+    config.absolute_dot("/actor")
+}
+
+fn key_id(config: &Config) -> String {
+    actor_id(config) + "#main-key"
+}
+
+/// The host part of `@name@host`, which is wherever `base_url` points
+fn webfinger_host(config: &Config) -> Option<String> {
+    let base_url = url::Url::parse(config.base_url.as_deref()?).ok()?;
+    let host = base_url.host_str()?;
+    Some(match base_url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    })
+}
+
+fn activity_json(value: Value) -> Response {
+    ([("Content-Type", ACTIVITY_JSON)], Json(value)).into_response()
+}
+
+async fn actor_handler(State(app): State<Arc<App>>) -> Response {
+    let config = app.config();
+    let (Some(activitypub), Some(keys)) = (&config.activitypub, &app.activitypub_keys) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let actor = actor_id(&config);
+    activity_json(json!({
+        "@context": [ACTIVITY_STREAMS, "https://w3id.org/security/v1"],
+        "id": actor,
+        "type": "Service",
+        "preferredUsername": activitypub.username,
+        "name": config.title,
+        "url": config.absolute("/"),
+        "inbox": config.absolute_dot("/inbox"),
+        "outbox": config.absolute_dot("/outbox"),
+        "followers": config.absolute_dot("/followers"),
+        "publicKey": {
+            "id": key_id(&config),
+            "owner": actor,
+            "publicKeyPem": keys.public_pem,
+        },
+    }))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct WebfingerQuery {
+    resource: String,
+}
+
+#[tracing::instrument(skip(app))]
+async fn webfinger_handler(
+    State(app): State<Arc<App>>,
+    Query(query): Query<WebfingerQuery>,
+) -> Response {
+    let config = app.config();
+    let (Some(activitypub), Some(host)) = (&config.activitypub, webfinger_host(&config)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let subject = format!("acct:{}@{}", activitypub.username, host);
+    let actor = actor_id(&config);
+    if !query.resource.eq_ignore_ascii_case(&subject) && query.resource != actor {
+        tracing::debug!("not us");
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    (
+        [("Content-Type", "application/jrd+json")],
+        Json(json!({
+            "subject": subject,
+            "aliases": [actor],
+            "links": [
+                { "rel": "self", "type": ACTIVITY_JSON, "href": actor },
+                { "rel": "http://webfinger.net/rel/profile-page", "href": config.absolute("/") },
+            ],
+        })),
+    )
+        .into_response()
+}
+
+/// A post the way other servers see it
+fn object(config: &Config, object_type: ObjectType, post: &Post, slug: &str) -> Value {
+    let url = config.absolute(&format!("/{slug}"));
+    let content = config.render_content(post.content_type, &post.content);
+    let mut object = json!({
+        "id": url,
+        "type": object_type,
+        "attributedTo": actor_id(config),
+        "url": url,
+        "published": post.published.to_rfc3339(),
+        "updated": post.updated.to_rfc3339(),
+        "to": [PUBLIC],
+        "cc": [config.absolute_dot("/followers")],
+    });
+
+    match object_type {
+        ObjectType::Article => {
+            object["name"] = json!(post.title);
+            object["content"] = json!(content);
+            if let Some(summary) = post.summary.as_deref() {
+                object["summary"] = json!(summary);
+            }
+        }
+        // notes don't have titles, so it goes at the top
+        ObjectType::Note => {
+            let title = tera::escape_html(&post.title);
+            object["content"] = json!(format!("<p><a href=\"{url}\">{title}</a></p>{content}"));
+        }
+    }
+    object
+}
+
+fn create_activity(config: &Config, object_type: ObjectType, post: &Post, slug: &str) -> Value {
+    let object = object(config, object_type, post, slug);
+    json!({
+        "@context": ACTIVITY_STREAMS,
+        "id": format!("{}#create", object["id"].as_str().unwrap_or_default()),
+        "type": "Create",
+        "actor": actor_id(config),
+        "published": object["published"],
+        "to": object["to"],
+        "cc": object["cc"],
+        "object": object,
+    })
+}
+
+#[tracing::instrument(skip(app))]
+async fn outbox_handler(State(app): State<Arc<App>>) -> Response {
+    let config = app.config();
+    let Some(activitypub) = &config.activitypub else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, outbox_connection),
+    };
+    let posts = match app.feed_posts(&mut conn, FeedDate::Published).await {
+        Ok(posts) => posts,
+        Err(err) => return_500!(err, outbox_posts),
+    };
+
+    let items = posts
+        .iter()
+        .map(|CanonicalPost { post, slug }| {
+            create_activity(&config, activitypub.object_type, post, slug)
+        })
+        .collect::<Vec<_>>();
+    activity_json(json!({
+        "@context": ACTIVITY_STREAMS,
+        "id": config.absolute_dot("/outbox"),
+        "type": "OrderedCollection",
+        "totalItems": items.len(),
+        "orderedItems": items,
+    }))
+}
+
+/// Just how many there are, who they are isn't anyone else's business
+#[tracing::instrument(skip(app))]
+async fn followers_handler(State(app): State<Arc<App>>) -> Response {
+    let config = app.config();
+    if config.activitypub.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let count = match sqlx::query_scalar!("select count(*) from follower")
+        .fetch_one(&app.pool)
+        .await
+    {
+        Ok(count) => count,
+        Err(err) => return_500!(err, count_followers),
+    };
+    activity_json(json!({
+        "@context": ACTIVITY_STREAMS,
+        "id": config.absolute_dot("/followers"),
+        "type": "OrderedCollection",
+        "totalItems": count,
+    }))
+}
+
+#[tracing::instrument(skip_all)]
+async fn inbox_handler(
+    State(app): State<Arc<App>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Response {
+    let config = app.config();
+    if config.activitypub.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let Ok(activity) = serde_json::from_slice::<Value>(&body) else {
+        return (StatusCode::BAD_REQUEST, "Not JSON").into_response();
+    };
+    let kind = activity["type"].as_str().unwrap_or_default();
+    let actor = activity["actor"].as_str().unwrap_or_default();
+    tracing::debug!(kind, actor);
+
+    // servers announce deleted accounts to everyone they know of, and the key to check the
+    // signature with is gone along with the account
+    if kind == "Delete" && activity["object"].as_str() == Some(actor) {
+        return StatusCode::ACCEPTED.into_response();
+    }
+
+    let signer = match verify_signature(&uri, &headers, &body, actor).await {
+        Ok(signer) => signer,
+        Err(err) => {
+            tracing::debug!(bad_signature = %err);
+            return (StatusCode::UNAUTHORIZED, "Bad signature").into_response();
+        }
+    };
+    if signer["id"].as_str() != Some(actor) {
+        tracing::debug!(signer = ?signer["id"], "signed by someone else");
+        return (
+            StatusCode::UNAUTHORIZED,
+            "Signed by someone other than the actor",
+        )
+            .into_response();
+    }
+
+    match kind {
+        "Follow" if activity["object"].as_str() == Some(&actor_id(&config)) => {
+            // everything gets delivered to these later, so they can't point anywhere else
+            let on_actor_server = |inbox: &&str| same_server(inbox, actor);
+            let Some(inbox) = signer["inbox"].as_str().filter(on_actor_server) else {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "Follower has no inbox on their server",
+                )
+                    .into_response();
+            };
+            let shared_inbox = signer["endpoints"]["sharedInbox"]
+                .as_str()
+                .filter(on_actor_server);
+            let now = config.now();
+            let inserted = sqlx::query!(
+                "insert into follower (actor, inbox, shared_inbox, followed_at) values ($1, $2, $3, $4)
+                on conflict (actor) do update set inbox = excluded.inbox, shared_inbox = excluded.shared_inbox",
+                actor,
+                inbox,
+                shared_inbox,
+                now,
+            )
+            .execute(&app.pool)
+            .await;
+            if let Err(err) = inserted {
+                return_500!(err, insert_follower);
+            }
+            tracing::info!(new_follower = actor);
+
+            let accept = json!({
+                "@context": ACTIVITY_STREAMS,
+                "id": format!("{}#accept-{}", actor_id(&config), Uuid::new_v4()),
+                "type": "Accept",
+                "actor": actor_id(&config),
+                "object": activity,
+            });
+            spawn_delivery(&app, vec![inbox.to_string()], accept);
+        }
+
+        "Undo" if activity["object"]["type"].as_str() == Some("Follow") => {
+            let deleted = sqlx::query!("delete from follower where actor = $1", actor)
+                .execute(&app.pool)
+                .await;
+            if let Err(err) = deleted {
+                return_500!(err, delete_follower);
+            }
+            tracing::info!(unfollowed = actor);
+        }
+
+        _ => tracing::debug!("ignored"),
+    }
+
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// Check a draft-cavage HTTP signature, giving back the actor whose key made it
+async fn verify_signature(
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &[u8],
+    actor: &str,
+) -> Result<Value> {
+    static PARAM: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"([a-zA-Z]+)="([^"]*)""#).expect("valid regex"));
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| anyhow::anyhow!("no {name} header"))
+    };
+
+    let signature = header("signature")?;
+    let param = |name: &str| {
+        PARAM
+            .captures_iter(signature)
+            .find(|param| &param[1] == name)
+            .map(|param| param[2].to_string())
+    };
+    let key_id = param("keyId").ok_or_else(|| anyhow::anyhow!("no keyId"))?;
+    let signed_headers = param("headers").unwrap_or_else(|| String::from("date"));
+    let signed = STANDARD.decode(param("signature").unwrap_or_default())?;
+
+    // without these signatures could be replayed, or moved to a different body
+    for required in ["(request-target)", "host", "date", "digest"] {
+        if !signed_headers.split(' ').any(|signed| signed == required) {
+            anyhow::bail!("{required} isn't signed");
+        }
+    }
+
+    let date = chrono::DateTime::from(httpdate::parse_http_date(header("date")?)?);
+    if (chrono::Utc::now() - date).abs() > MAX_CLOCK_SKEW {
+        anyhow::bail!("date {date} is too far off");
+    }
+
+    let digest = format!("SHA-256={}", STANDARD.encode(sha256(body)));
+    if !header("digest")?
+        .split(',')
+        .any(|sent| sent.trim() == digest)
+    {
+        anyhow::bail!("digest doesn't match the body");
+    }
+
+    let signing = signed_headers
+        .split(' ')
+        .map(|name| match name {
+            "(request-target)" => {
+                let target = uri
+                    .path_and_query()
+                    .map(|target| target.as_str())
+                    .unwrap_or("/");
+                Ok(format!("(request-target): post {target}"))
+            }
+            name => Ok(format!("{name}: {}", header(name)?)),
+        })
+        .collect::<Result<Vec<_>>>()?
+        .join("\n");
+
+    // keys are usually part of the actor, but could be on their own and point at it. Either
+    // way they're only looked for on the actor's server, so whoever sent this doesn't get to pick
+    // what gets fetched.
+    if !same_server(&key_id, actor) {
+        anyhow::bail!("{key_id} isn't on the same server as {actor}");
+    }
+    let mut document = fetch_object(&key_id).await?;
+    if document.get("publicKey").is_none()
+        && let Some(owner) = document["owner"].as_str()
+    {
+        if !same_server(owner, actor) {
+            anyhow::bail!("{owner} isn't on the same server as {actor}");
+        }
+        document = fetch_object(owner).await?;
+    }
+    let key = &document["publicKey"];
+    if key["id"].as_str() != Some(&key_id) {
+        anyhow::bail!("{key_id} isn't the actor's key");
+    }
+
+    let pem = key["publicKeyPem"].as_str().unwrap_or_default();
+    let key =
+        RsaPublicKey::from_public_key_pem(pem).or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))?;
+    let signature = Signature::try_from(&signed[..])?;
+    VerifyingKey::<sha2::Sha256>::new(key).verify(signing.as_bytes(), &signature)?;
+    Ok(document)
+}
+
+/// Parse the id of something on another server, which is only ever fetched over https
+fn remote_url(id: &str) -> Result<url::Url> {
+    let url = url::Url::parse(id)?;
+    if url.scheme() != "https" {
+        anyhow::bail!("{id} isn't https");
+    }
+    Ok(url)
+}
+
+/// Whether `id` is on the same server as `actor`, which is all an actor gets to speak for
+fn same_server(id: &str, actor: &str) -> bool {
+    match (remote_url(id), remote_url(actor)) {
+        (Ok(id), Ok(actor)) => {
+            id.host() == actor.host() && id.port_or_known_default() == actor.port_or_known_default()
+        }
+        _ => false,
+    }
+}
+
+async fn fetch_object(id: &str) -> Result<Value> {
+    let url = remote_url(id)?;
+    let response = fetch(&url, &[("Accept", ACTIVITY_JSON)], FetchBody::None).await?;
+    if !(200..300).contains(&response.status) {
+        anyhow::bail!("HTTP {} from {}", response.status, url);
+    }
+    Ok(serde_json::from_slice(&response.body)?)
+}
+
+/// Send a new post to everyone following, in the background
+pub fn spawn_post_delivery(app: &Arc<App>, id: Uuid) {
+    if app.config().activitypub.is_none() {
+        return;
+    }
+
+    let app = app.clone();
+    tokio::spawn(async move {
+        if let Err(err) = deliver_post(&app, id).await {
+            tracing::error!(activitypub_delivery = %err);
+        }
+    });
+}
+
+#[tracing::instrument(skip(app))]
+async fn deliver_post(app: &Arc<App>, id: Uuid) -> Result<()> {
+    let config = app.config();
+    let Some(activitypub) = &config.activitypub else {
+        return Ok(());
+    };
+
+    let mut conn = app.pool.acquire().await?;
+    let Some(post) = app.find_post_uuid(&mut conn, id).await? else {
+        return Ok(());
+    };
+    if post.draft || post.deleted.is_some() {
+        tracing::debug!("not public");
+        return Ok(());
+    }
+    let slug = app
+        .slug_history(&mut conn, id)
+        .await?
+        .into_iter()
+        .find(|slug| slug.canonical);
+    let Some(slug) = slug else {
+        return Ok(());
+    };
+
+    // one delivery per server when they have a shared inbox
+    let inboxes = sqlx::query_scalar!(
+        r#"select distinct coalesce(shared_inbox, inbox) as "inbox!: String" from follower"#
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    drop(conn);
+
+    let activity = create_activity(&config, activitypub.object_type, &post, &slug.slug);
+    deliver(app, inboxes, &activity).await;
+    Ok(())
+}
+
+fn spawn_delivery(app: &Arc<App>, inboxes: Vec<String>, activity: Value) {
+    let app = app.clone();
+    tokio::spawn(async move { deliver(&app, inboxes, &activity).await });
+}
+
+/// Inboxes that didn't take it get tried again together, so one slow server doesn't hold up
+/// the rest
+async fn deliver(app: &App, mut inboxes: Vec<String>, activity: &Value) {
+    let Some(keys) = &app.activitypub_keys else {
+        return;
+    };
+    let body = activity.to_string();
+
+    let mut retry = DELIVERY_RETRY;
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let mut failed = Vec::new();
+        for inbox in inboxes {
+            match post_signed(&app.config(), keys, &inbox, body.as_bytes()).await {
+                Ok(()) => tracing::debug!(delivered = %inbox),
+                Err(err) => {
+                    tracing::warn!(%inbox, attempt, undelivered = %err);
+                    failed.push(inbox);
+                }
+            }
+        }
+
+        if failed.is_empty() || attempt == DELIVERY_ATTEMPTS {
+            break;
+        }
+        tokio::time::sleep(retry).await;
+        retry *= 2;
+        inboxes = failed;
+    }
+}
+
+async fn post_signed(config: &Config, keys: &Keys, inbox: &str, body: &[u8]) -> Result<()> {
+    let url = remote_url(inbox)?;
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => anyhow::bail!("inbox {inbox} has no host"),
+    };
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let date = httpdate::fmt_http_date(std::time::SystemTime::now());
+    let digest = format!("SHA-256={}", STANDARD.encode(sha256(body)));
+
+    let signing =
+        format!("(request-target): post {target}\nhost: {host}\ndate: {date}\ndigest: {digest}");
+    let signature = format!(
+        r#"keyId="{}",algorithm="rsa-sha256",headers="(request-target) host date digest",signature="{}""#,
+        key_id(config),
+        keys.sign(&signing),
+    );
+
+    let headers = [
+        ("Content-Type", ACTIVITY_JSON),
+        ("Date", date.as_str()),
+        ("Digest", digest.as_str()),
+        ("Signature", signature.as_str()),
+    ];
+    let response = fetch(&url, &headers, FetchBody::Raw(body)).await?;
+    if !(200..300).contains(&response.status) {
+        anyhow::bail!("HTTP {}", response.status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actors_only_speak_for_their_own_server() {
+        let actor = "https://example.com/users/someone";
+        assert!(same_server(
+            "https://example.com/users/someone#main-key",
+            actor
+        ));
+        assert!(same_server("https://EXAMPLE.com:443/inbox", actor));
+        assert!(!same_server("https://example.com:8443/inbox", actor));
+        assert!(!same_server("https://example.com.evil.test/key", actor));
+        assert!(!same_server("https://127.0.0.1/key", actor));
+        assert!(!same_server(
+            "http://example.com/users/someone#main-key",
+            actor
+        ));
+        assert!(!same_server(
+            "https://example.com/key",
+            "http://example.com/users/someone"
+        ));
+        assert!(!same_server("not a url", actor));
+    }
+}
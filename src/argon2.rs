@@ -0,0 +1,529 @@
+//! Argon2id (RFC 9106) for checking password hashes, along with the Blake2b it's built on.
+//! Only the single-threaded version, lanes are filled one after another.
+
+use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
+
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: usize = 4;
+const VERSION: u32 = 0x13;
+/// The type number Argon2id hashes in, 0 and 1 being Argon2d and Argon2i
+const ARGON2ID: u32 = 2;
+
+/// Parameters `hash` uses for new hashes, the OWASP recommendation for Argon2id
+const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_PASSES: u32 = 2;
+const DEFAULT_LANES: u32 = 1;
+const SALT_BYTES: usize = 16;
+const TAG_BYTES: usize = 32;
+
+/// A parsed `$argon2id$v=19$m=...,t=...,p=...$salt$tag` string
+#[derive(Clone)]
+pub struct PasswordHash {
+    memory_kib: u32,
+    passes: u32,
+    lanes: u32,
+    salt: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+impl PasswordHash {
+    pub fn parse(phc: &str) -> Option<PasswordHash> {
+        let mut parts = phc.strip_prefix('$')?.split('$');
+        if parts.next()? != "argon2id" || parts.next()? != format!("v={VERSION}") {
+            return None;
+        }
+
+        let (mut memory_kib, mut passes, mut lanes) = (None, None, None);
+        for param in parts.next()?.split(',') {
+            let (name, value) = param.split_once('=')?;
+            let value = value.parse().ok()?;
+            match name {
+                "m" => memory_kib = Some(value),
+                "t" => passes = Some(value),
+                "p" => lanes = Some(value),
+                _ => return None,
+            }
+        }
+
+        let hash = PasswordHash {
+            memory_kib: memory_kib?,
+            passes: passes?,
+            lanes: lanes?,
+            salt: STANDARD_NO_PAD.decode(parts.next()?).ok()?,
+            tag: STANDARD_NO_PAD.decode(parts.next()?).ok()?,
+        };
+
+        let sane = (1..=0xff_ffff).contains(&hash.lanes)
+            && hash.memory_kib >= 8 * hash.lanes
+            && hash.passes >= 1
+            && hash.salt.len() >= 8
+            && hash.tag.len() >= 4;
+        (sane && parts.next().is_none()).then_some(hash)
+    }
+
+    /// In constant time, as far as the password is concerned
+    pub fn verify(&self, password: &[u8]) -> bool {
+        use subtle::ConstantTimeEq;
+
+        let tag = argon2id(
+            [password, &self.salt, &[], &[]],
+            self.memory_kib,
+            self.passes,
+            self.lanes,
+            self.tag.len(),
+        );
+        tag.ct_eq(&self.tag).into()
+    }
+}
+
+/// A PHC string for `password` with a fresh random salt
+pub fn hash(password: &[u8]) -> Result<String, getrandom::Error> {
+    let mut salt = [0; SALT_BYTES];
+    getrandom::fill(&mut salt)?;
+
+    let tag = argon2id(
+        [password, &salt, &[], &[]],
+        DEFAULT_MEMORY_KIB,
+        DEFAULT_PASSES,
+        DEFAULT_LANES,
+        TAG_BYTES,
+    );
+    Ok(format!(
+        "$argon2id$v={VERSION}$m={DEFAULT_MEMORY_KIB},t={DEFAULT_PASSES},p={DEFAULT_LANES}${}${}",
+        STANDARD_NO_PAD.encode(salt),
+        STANDARD_NO_PAD.encode(tag),
+    ))
+}
+
+type Block = [u64; BLOCK_WORDS];
+
+/// `inputs` are the password, salt, secret key, and associated data, the order H0 hashes them in
+fn argon2id(
+    inputs: [&[u8]; 4],
+    memory_kib: u32,
+    passes: u32,
+    lanes: u32,
+    tag_bytes: usize,
+) -> Vec<u8> {
+    let mut h0 = Blake2b::new(64);
+    for value in [
+        lanes,
+        tag_bytes as u32,
+        memory_kib,
+        passes,
+        VERSION,
+        ARGON2ID,
+    ] {
+        h0.update(&value.to_le_bytes());
+    }
+    for input in inputs {
+        h0.update(&(input.len() as u32).to_le_bytes());
+        h0.update(input);
+    }
+    let h0 = h0.finalize();
+
+    let lanes = lanes as usize;
+    let segment_length = memory_kib as usize / (SYNC_POINTS * lanes);
+    let lane_length = segment_length * SYNC_POINTS;
+    let mut memory = vec![[0u64; BLOCK_WORDS]; lane_length * lanes];
+
+    for lane in 0..lanes {
+        for column in 0..2 {
+            let mut input = h0.clone();
+            input.extend((column as u32).to_le_bytes());
+            input.extend((lane as u32).to_le_bytes());
+            memory[lane * lane_length + column] = block_from_bytes(&long_hash(&input, 1024));
+        }
+    }
+
+    let memory_blocks = (lane_length * lanes) as u64;
+    for pass in 0..passes as usize {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                let position = Position {
+                    pass,
+                    slice,
+                    lane,
+                    lanes,
+                    segment_length,
+                    lane_length,
+                };
+                // the first half of the first pass is Argon2i, so it can't leak the password
+                // through which blocks it reads
+                let data_independent = pass == 0 && slice < SYNC_POINTS / 2;
+                fill_segment(
+                    &mut memory,
+                    &position,
+                    data_independent.then_some([memory_blocks, passes as u64]),
+                );
+            }
+        }
+    }
+
+    let mut last = memory[lane_length - 1];
+    for lane in 1..lanes {
+        xor_into(&mut last, &memory[lane * lane_length + lane_length - 1]);
+    }
+
+    let bytes = last
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+    long_hash(&bytes, tag_bytes)
+}
+
+struct Position {
+    pass: usize,
+    slice: usize,
+    lane: usize,
+    lanes: usize,
+    segment_length: usize,
+    lane_length: usize,
+}
+
+/// `addressing` holds the total block and pass counts when reference blocks are picked from
+/// address blocks rather than from the previous block's contents
+fn fill_segment(memory: &mut [Block], position: &Position, addressing: Option<[u64; 2]>) {
+    let Position {
+        pass,
+        slice,
+        lane,
+        lanes,
+        segment_length,
+        lane_length,
+    } = *position;
+
+    let zero = [0u64; BLOCK_WORDS];
+    let mut input = [0u64; BLOCK_WORDS];
+    let mut addresses = [0u64; BLOCK_WORDS];
+    if let Some([memory_blocks, passes]) = addressing {
+        input[..6].copy_from_slice(&[
+            pass as u64,
+            lane as u64,
+            slice as u64,
+            memory_blocks,
+            passes,
+            ARGON2ID as u64,
+        ]);
+    }
+    let next_addresses = |input: &mut Block, addresses: &mut Block| {
+        input[6] += 1;
+        *addresses = compress(&zero, input, None);
+        *addresses = compress(&zero, addresses, None);
+    };
+
+    // the first two blocks of each lane were filled in from the password
+    let start = if pass == 0 && slice == 0 { 2 } else { 0 };
+    if addressing.is_some() && start == 2 {
+        next_addresses(&mut input, &mut addresses);
+    }
+
+    for index in start..segment_length {
+        let current = lane * lane_length + slice * segment_length + index;
+        let previous = if current % lane_length == 0 {
+            current + lane_length - 1
+        } else {
+            current - 1
+        };
+
+        let pseudo_random = if addressing.is_some() {
+            if index % BLOCK_WORDS == 0 {
+                next_addresses(&mut input, &mut addresses);
+            }
+            addresses[index % BLOCK_WORDS]
+        } else {
+            memory[previous][0]
+        };
+
+        let reference_lane = if pass == 0 && slice == 0 {
+            lane
+        } else {
+            (pseudo_random >> 32) as usize % lanes
+        };
+        let same_lane = reference_lane == lane;
+
+        // which blocks are finished and allowed to be referenced
+        let area = if pass == 0 {
+            if slice == 0 || same_lane {
+                slice * segment_length + index - 1
+            } else {
+                slice * segment_length - (index == 0) as usize
+            }
+        } else if same_lane {
+            lane_length - segment_length + index - 1
+        } else {
+            lane_length - segment_length - (index == 0) as usize
+        } as u64;
+
+        let j1 = pseudo_random & 0xffff_ffff;
+        let relative = area - 1 - ((area * ((j1 * j1) >> 32)) >> 32);
+        let start_position = if pass == 0 || slice == SYNC_POINTS - 1 {
+            0
+        } else {
+            (slice + 1) * segment_length
+        };
+        let reference =
+            reference_lane * lane_length + (start_position + relative as usize) % lane_length;
+
+        // later passes mix into what's there instead of overwriting it
+        let overwritten = (pass > 0).then(|| memory[current]);
+        memory[current] = compress(&memory[previous], &memory[reference], overwritten.as_ref());
+    }
+}
+
+/// The compression function G, xoring the result into `with` if there is one
+fn compress(x: &Block, y: &Block, with: Option<&Block>) -> Block {
+    let mut r = *x;
+    xor_into(&mut r, y);
+
+    let mut z = r;
+    // the block is an 8x8 matrix of 16 byte registers, mixed by row then by column
+    for row in 0..8 {
+        let mut words = [0; 16];
+        words.copy_from_slice(&z[row * 16..row * 16 + 16]);
+        permute(&mut words);
+        z[row * 16..row * 16 + 16].copy_from_slice(&words);
+    }
+    for column in 0..8 {
+        let indices: [usize; 16] = std::array::from_fn(|i| (i / 2) * 16 + column * 2 + i % 2);
+        let mut words = indices.map(|i| z[i]);
+        permute(&mut words);
+        for (i, word) in indices.into_iter().zip(words) {
+            z[i] = word;
+        }
+    }
+
+    xor_into(&mut z, &r);
+    if let Some(with) = with {
+        xor_into(&mut z, with);
+    }
+    z
+}
+
+/// Blake2b's round with its additions replaced by ones that also multiply
+fn permute(v: &mut [u64; 16]) {
+    fn mix(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize) {
+        let multiply = |x: u64, y: u64| {
+            2u64.wrapping_mul(x & 0xffff_ffff)
+                .wrapping_mul(y & 0xffff_ffff)
+        };
+
+        v[a] = v[a].wrapping_add(v[b]).wrapping_add(multiply(v[a], v[b]));
+        v[d] = (v[d] ^ v[a]).rotate_right(32);
+        v[c] = v[c].wrapping_add(v[d]).wrapping_add(multiply(v[c], v[d]));
+        v[b] = (v[b] ^ v[c]).rotate_right(24);
+        v[a] = v[a].wrapping_add(v[b]).wrapping_add(multiply(v[a], v[b]));
+        v[d] = (v[d] ^ v[a]).rotate_right(16);
+        v[c] = v[c].wrapping_add(v[d]).wrapping_add(multiply(v[c], v[d]));
+        v[b] = (v[b] ^ v[c]).rotate_right(63);
+    }
+
+    mix(v, 0, 4, 8, 12);
+    mix(v, 1, 5, 9, 13);
+    mix(v, 2, 6, 10, 14);
+    mix(v, 3, 7, 11, 15);
+    mix(v, 0, 5, 10, 15);
+    mix(v, 1, 6, 11, 12);
+    mix(v, 2, 7, 8, 13);
+    mix(v, 3, 4, 9, 14);
+}
+
+fn xor_into(block: &mut Block, other: &Block) {
+    for (word, other) in block.iter_mut().zip(other) {
+        *word ^= other;
+    }
+}
+
+fn block_from_bytes(bytes: &[u8]) -> Block {
+    std::array::from_fn(|i| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap()))
+}
+
+/// H', Blake2b stretched to any length by chaining 64 byte hashes and keeping half of each
+fn long_hash(input: &[u8], length: usize) -> Vec<u8> {
+    let hash = |length: usize, parts: &[&[u8]]| {
+        let mut hasher = Blake2b::new(length);
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize()
+    };
+
+    let length_prefix = (length as u32).to_le_bytes();
+    if length <= 64 {
+        return hash(length, &[&length_prefix, input]);
+    }
+
+    let mut out = Vec::with_capacity(length);
+    let mut v = hash(64, &[&length_prefix, input]);
+    out.extend(&v[..32]);
+    while length - out.len() > 64 {
+        v = hash(64, &[&v]);
+        out.extend(&v[..32]);
+    }
+    // the last one is only as long as what's left
+    out.extend(hash(length - out.len(), &[&v]));
+    out
+}
+
+const BLAKE2B_IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const BLAKE2B_SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+/// Unkeyed Blake2b with an output of up to 64 bytes
+struct Blake2b {
+    h: [u64; 8],
+    buffer: Vec<u8>,
+    counter: u128,
+    length: usize,
+}
+
+impl Blake2b {
+    fn new(length: usize) -> Blake2b {
+        let mut h = BLAKE2B_IV;
+        h[0] ^= 0x0101_0000 ^ length as u64;
+        Blake2b {
+            h,
+            buffer: Vec::with_capacity(128),
+            counter: 0,
+            length,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // the last block gets compressed differently, so hold on to a full one until more
+            // data shows up
+            if self.buffer.len() == 128 {
+                self.counter += 128;
+                let block = std::mem::take(&mut self.buffer);
+                self.compress(&block, false);
+            }
+
+            let taken = data.len().min(128 - self.buffer.len());
+            self.buffer.extend(&data[..taken]);
+            data = &data[taken..];
+        }
+    }
+
+    fn finalize(mut self) -> Vec<u8> {
+        self.counter += self.buffer.len() as u128;
+        let mut block = std::mem::take(&mut self.buffer);
+        block.resize(128, 0);
+        self.compress(&block, true);
+
+        let mut out = self
+            .h
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<_>>();
+        out.truncate(self.length);
+        out
+    }
+
+    fn compress(&mut self, block: &[u8], last: bool) {
+        let m: [u64; 16] = std::array::from_fn(|i| {
+            u64::from_le_bytes(block[i * 8..i * 8 + 8].try_into().unwrap())
+        });
+
+        let mut v = [0; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&BLAKE2B_IV);
+        v[12] ^= self.counter as u64;
+        v[13] ^= (self.counter >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+
+        fn mix(v: &mut [u64; 16], [a, b, c, d]: [usize; 4], x: u64, y: u64) {
+            v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+            v[d] = (v[d] ^ v[a]).rotate_right(32);
+            v[c] = v[c].wrapping_add(v[d]);
+            v[b] = (v[b] ^ v[c]).rotate_right(24);
+            v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+            v[d] = (v[d] ^ v[a]).rotate_right(16);
+            v[c] = v[c].wrapping_add(v[d]);
+            v[b] = (v[b] ^ v[c]).rotate_right(63);
+        }
+
+        for round in 0..12 {
+            let s = &BLAKE2B_SIGMA[round % 10];
+            for (i, indices) in [
+                [0, 4, 8, 12],
+                [1, 5, 9, 13],
+                [2, 6, 10, 14],
+                [3, 7, 11, 15],
+                [0, 5, 10, 15],
+                [1, 6, 11, 12],
+                [2, 7, 8, 13],
+                [3, 4, 9, 14],
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                mix(&mut v, indices, m[s[2 * i]], m[s[2 * i + 1]]);
+            }
+        }
+
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn blake2b_rfc_7693() {
+        // appendix A
+        let mut hasher = Blake2b::new(64);
+        hasher.update(b"abc");
+        assert_eq!(
+            hex(&hasher.finalize()),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923",
+        );
+    }
+
+    #[test]
+    fn argon2id_rfc_9106() {
+        // section 5.3
+        let tag = argon2id([&[1; 32], &[2; 16], &[3; 8], &[4; 12]], 32, 3, 4, 32);
+        assert_eq!(
+            hex(&tag),
+            "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659",
+        );
+    }
+
+    #[test]
+    fn hashes_verify() {
+        let hash = PasswordHash::parse(&hash(b"hunter2").unwrap()).unwrap();
+        assert!(hash.verify(b"hunter2"));
+        assert!(!hash.verify(b"hunter3"));
+    }
+}
@@ -0,0 +1,111 @@
+//! Copies of the live database made with `VACUUM INTO`, which reads one consistent snapshot
+//! through an ordinary connection, so the server doesn't need stopping and the WAL doesn't need
+//! copying along with the file. `blog3 backup <config> [dest]` makes one from the command line,
+//! and `POST /.blog3/backup` from the server.
+
+use crate::Config;
+use anyhow::Result;
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "usage: blog3 backup <config> [file or directory, backup_dir if unset]";
+
+/// Backups made into `backup_dir` are named this plus a timestamp, which is also how pruning
+/// tells them apart from anything else in there
+const PREFIX: &str = "blog3-";
+const EXTENSION: &str = "sqlite3";
+
+pub async fn run() -> Result<()> {
+    let mut args = std::env::args().skip(2);
+    let Some(config_path) = args.next() else {
+        fatal!("{}", USAGE);
+    };
+    let config = Config::load(Path::new(&config_path)).await?;
+
+    let (dest, prune_dir) = match args.next().map(PathBuf::from) {
+        Some(dest)
+            if tokio::fs::metadata(&dest)
+                .await
+                .is_ok_and(|dest| dest.is_dir()) =>
+        {
+            (timestamped(&dest), None)
+        }
+        Some(dest) => (dest, None),
+        None => match config.backup_dir.as_ref() {
+            Some(backup_dir) => {
+                tokio::fs::create_dir_all(backup_dir).await?;
+                (timestamped(backup_dir), Some(backup_dir))
+            }
+            None => fatal!("{}", USAGE),
+        },
+    };
+
+    let pool = crate::pool_options(&config)
+        .max_connections(1)
+        .connect_with(crate::connect_options(&config))
+        .await?;
+    let size = backup(&pool, &dest).await?;
+    println!("{} ({size} bytes)", dest.display());
+
+    if let (Some(backup_dir), Some(days)) = (prune_dir, config.backup_retention_days) {
+        prune(backup_dir, days).await?;
+    }
+    Ok(())
+}
+
+/// Where in `dir` a backup made now goes
+pub fn timestamped(dir: &Path) -> PathBuf {
+    let now = chrono::Utc::now().format("%Y%m%dT%H%M%S%3fZ");
+    dir.join(format!("{PREFIX}{now}.{EXTENSION}"))
+}
+
+/// Write the database to `dest`, which can't exist yet, returning its size. Doesn't leave
+/// anything at `dest` if it fails partway.
+pub async fn backup(pool: &SqlitePool, dest: &Path) -> Result<u64> {
+    let Some(dest_str) = dest.to_str() else {
+        anyhow::bail!("backup path {} isn't utf-8", dest.display());
+    };
+
+    if tokio::fs::try_exists(dest).await? {
+        anyhow::bail!("{} already exists", dest.display());
+    }
+
+    if let Err(err) = sqlx::query("vacuum into $1")
+        .bind(dest_str)
+        .execute(pool)
+        .await
+    {
+        // so a full disk doesn't leave something that looks like a backup
+        let _ = tokio::fs::remove_file(dest).await;
+        return Err(err.into());
+    }
+
+    let size = tokio::fs::metadata(dest).await?.len();
+    tracing::info!(backup = %dest.display(), size);
+    Ok(size)
+}
+
+/// Delete backups in `dir` older than `days`
+pub async fn prune(dir: &Path, days: u32) -> Result<()> {
+    let age = std::time::Duration::from_secs(u64::from(days) * 24 * 60 * 60);
+    let cutoff = std::time::SystemTime::now() - age;
+
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let ours = entry.file_name().to_string_lossy().starts_with(PREFIX)
+            && path
+                .extension()
+                .is_some_and(|extension| extension == EXTENSION);
+        if !ours {
+            continue;
+        }
+
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() && metadata.modified()? < cutoff {
+            tokio::fs::remove_file(&path).await?;
+            tracing::info!(pruned_backup = %path.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! `blog3 export <config> <file.json>`: every post with its tags, slugs, and revisions as one
+//! JSON document, for backups and moving somewhere else. Posts are written one at a time in a
+//! fixed order, so exports of the same blog come out the same and diff line by line.
+
+use crate::{Config, ContentType, Post};
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset};
+use sqlx::SqliteConnection;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// What `format` says, so an import can tell it's been handed the right kind of file
+const FORMAT: &str = "blog3-export";
+
+/// Goes up when something in the document changes meaning, not when fields are added
+const VERSION: u32 = 1;
+
+#[derive(Debug, serde::Serialize)]
+struct ExportedSlug {
+    slug: String,
+    /// The canonical slug, which the canonical one has as either itself or nothing
+    newslug: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, sqlx::FromRow)]
+struct ExportedRevision {
+    revision: i64,
+    title: String,
+    subtitle: Option<String>,
+    published: DateTime<FixedOffset>,
+    content: String,
+    content_type: ContentType,
+    created_at: DateTime<FixedOffset>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ExportedPost<'a> {
+    #[serde(flatten)]
+    post: &'a Post,
+    tags: Vec<String>,
+    /// Oldest first
+    slugs: Vec<ExportedSlug>,
+    /// Oldest first
+    revisions: Vec<ExportedRevision>,
+}
+
+pub async fn run() -> Result<()> {
+    let mut args = std::env::args().skip(2);
+    let (Some(config_path), Some(out_path)) = (args.next(), args.next()) else {
+        fatal!("usage: blog3 export <config> <file.json>");
+    };
+    let config = Config::load(&PathBuf::from(config_path)).await?;
+    let pool = crate::pool_options(&config)
+        .connect_with(crate::connect_options(&config))
+        .await?;
+
+    // written next to where it's going and moved there at the end, so a failed export doesn't
+    // replace a good one with half a file
+    let out_path = PathBuf::from(out_path);
+    let partial_path = out_path.with_extension("json.partial");
+    let mut out = tokio::io::BufWriter::new(tokio::fs::File::create(&partial_path).await?);
+
+    // one read transaction for everything, so posts can't change partway through
+    let mut tx = pool.begin().await?;
+    let count = export(&mut tx, &mut out).await?;
+    tx.rollback().await?;
+
+    out.flush().await?;
+    out.into_inner().sync_all().await?;
+    tokio::fs::rename(&partial_path, &out_path).await?;
+
+    tracing::info!(exported = count, to = %out_path.display());
+    Ok(())
+}
+
+/// Write the document, returning how many posts it has
+async fn export(
+    conn: &mut SqliteConnection,
+    out: &mut (impl AsyncWriteExt + Unpin),
+) -> Result<usize> {
+    let ids = sqlx::query_scalar!(r#"select id as "id: Uuid" from post order by published, id"#)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    out.write_all(format!("{{\"format\":{FORMAT:?},\"version\":{VERSION},\"posts\":[").as_bytes())
+        .await?;
+
+    for (i, id) in ids.iter().enumerate() {
+        let post = sqlx::query_as::<_, Post>("select * from post where id = $1")
+            .bind(id)
+            .fetch_one(&mut *conn)
+            .await?;
+
+        let tags = sqlx::query_scalar!("select tag from tag where post_id = $1 order by tag", id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+        let slugs = sqlx::query_as!(
+            ExportedSlug,
+            "select slug, newslug from slug where id = $1 order by rowid",
+            id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let revisions = sqlx::query_as::<_, ExportedRevision>(
+            r#"
+                select revision, title, subtitle, published, content, content_type, created_at
+                from revision
+                where id = $1
+                order by revision
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let exported = ExportedPost {
+            post: &post,
+            tags,
+            slugs,
+            revisions,
+        };
+        let mut entry = if i == 0 {
+            b"\n".to_vec()
+        } else {
+            b",\n".to_vec()
+        };
+        entry.extend(serde_json::to_vec_pretty(&exported)?);
+        out.write_all(&entry).await?;
+    }
+
+    out.write_all(b"\n]}\n").await?;
+    Ok(ids.len())
+}
@@ -0,0 +1,273 @@
+//! Just enough gzip to compress pages: LZ77 over a 32k window, written out with the fixed
+//! Huffman codes from RFC 1951. Compresses less than zlib does, but text still shrinks a lot.
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash to try before settling for the best match yet
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// A gzip member holding `data`
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    static CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+    // no name or mtime, so the same input always compresses the same
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend(deflate(data));
+    out.extend(CRC.checksum(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Bits go into bytes starting from the least significant
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are defined most significant bit first, unlike everything else
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn write_literal_length(writer: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE.partition_point(|&base| base as usize <= length) - 1;
+    write_literal_length(writer, 257 + code as u16);
+    writer.write(
+        (length - LENGTH_BASE[code] as usize) as u32,
+        LENGTH_EXTRA[code] as u32,
+    );
+
+    let code = DISTANCE_BASE.partition_point(|&base| base as usize <= distance) - 1;
+    writer.write_code(code as u32, 5);
+    writer.write(
+        (distance - DISTANCE_BASE[code] as usize) as u32,
+        DISTANCE_EXTRA[code] as u32,
+    );
+}
+
+fn hash(data: &[u8]) -> usize {
+    let joined = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (joined.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn insert(data: &[u8], head: &mut [usize], prev: &mut [usize], at: usize) {
+    if at + MIN_MATCH <= data.len() {
+        let hash = hash(&data[at..]);
+        prev[at] = head[hash];
+        head[hash] = at;
+    }
+}
+
+/// A single final block with fixed codes
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::with_capacity(data.len() / 2),
+        bits: 0,
+        count: 0,
+    };
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    // most recent position for each hash, and the one before each position with the same hash
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+
+    let mut at = 0;
+    while at < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+
+        if at + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - at);
+            let mut candidate = head[hash(&data[at..])];
+            let mut chain = 0;
+
+            while candidate != usize::MAX && at - candidate <= WINDOW && chain < MAX_CHAIN {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(&data[at..at + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    (best_length, best_distance) = (length, at - candidate);
+                    if length == max_length {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            write_match(&mut writer, best_length, best_distance);
+            for position in at..at + best_length {
+                insert(data, &mut head, &mut prev, position);
+            }
+            at += best_length;
+        } else {
+            write_literal_length(&mut writer, data[at] as u16);
+            insert(data, &mut head, &mut prev, at);
+            at += 1;
+        }
+    }
+
+    write_literal_length(&mut writer, 256);
+    writer.finish()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// What `compress` compressed, as far as the fixed codes it writes go, checking the
+    /// trailer along the way
+    pub fn decompress(gzip: &[u8]) -> Vec<u8> {
+        static CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+        assert_eq!(gzip[..3], [0x1f, 0x8b, 8]);
+        let mut reader = BitReader {
+            data: &gzip[10..],
+            at: 0,
+        };
+
+        let mut out = Vec::new();
+        loop {
+            let last = reader.bits(1) == 1;
+            assert_eq!(reader.bits(2), 1, "only fixed codes");
+            loop {
+                let symbol = reader.literal_length();
+                if symbol < 256 {
+                    out.push(symbol as u8);
+                    continue;
+                } else if symbol == 256 {
+                    break;
+                }
+
+                let code = symbol as usize - 257;
+                let length = LENGTH_BASE[code] as usize + reader.bits(LENGTH_EXTRA[code]) as usize;
+                let code = reader.code(5) as usize;
+                let distance =
+                    DISTANCE_BASE[code] as usize + reader.bits(DISTANCE_EXTRA[code]) as usize;
+                for _ in 0..length {
+                    out.push(out[out.len() - distance]);
+                }
+            }
+            if last {
+                break;
+            }
+        }
+
+        let trailer = &reader.data[reader.at.div_ceil(8)..];
+        assert_eq!(trailer[..4], CRC.checksum(&out).to_le_bytes());
+        assert_eq!(trailer[4..], (out.len() as u32).to_le_bytes());
+        out
+    }
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        /// In bits
+        at: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: u8) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let bit = (self.data[self.at / 8] >> (self.at % 8)) & 1;
+                value |= (bit as u32) << i;
+                self.at += 1;
+            }
+            value
+        }
+
+        fn code(&mut self, count: u8) -> u32 {
+            (0..count).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        fn literal_length(&mut self) -> u16 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return 256 + code as u16;
+            }
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xbf => (code - 0x30) as u16,
+                0xc0..=0xc7 => (280 + code - 0xc0) as u16,
+                _ => (144 + (code << 1 | self.bits(1)) - 0x190) as u16,
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = "<p>Some text that repeats, like pages do.</p>\n".repeat(2000);
+        // far enough apart that matches have to stay inside the window
+        let mut noise = Vec::new();
+        let mut state = 1u32;
+        for _ in 0..100_000 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            noise.push((state >> 16) as u8 % 8);
+        }
+        let every_byte = (0..=255).cycle().take(1000).collect::<Vec<u8>>();
+
+        for data in [
+            &b""[..],
+            b"a",
+            b"aaaaaaaaaa",
+            text.as_bytes(),
+            &noise,
+            &every_byte,
+        ] {
+            assert_eq!(decompress(&compress(data)), data);
+        }
+        assert!(compress(text.as_bytes()).len() < text.len() / 10);
+    }
+}
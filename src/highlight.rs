@@ -0,0 +1,539 @@
+//! Just enough syntax highlighting for code blocks in posts: one small lexer driven by a table of
+//! keywords, comments, and quotes for each language, wrapping what it finds in `<span>`s with
+//! classes. The colors come from `stylesheet`, served as the `highlight.css` asset, so changing
+//! the theme doesn't change any rendered post.
+
+/// Asset path the stylesheet is served under
+pub const STYLESHEET: &str = "highlight.css";
+
+/// Blocks bigger than this stay plain, so a huge paste can't hold up rendering
+const MAX_CODE_BYTES: usize = 256 * 1024;
+
+struct Language {
+    names: &'static [&'static str],
+    keywords: &'static [&'static str],
+    literals: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    /// Whether capitalized names are types, like they are by convention in Rust or Java
+    capitalized_types: bool,
+    case_insensitive: bool,
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "auto",
+    "break",
+    "case",
+    "char",
+    "class",
+    "const",
+    "constexpr",
+    "continue",
+    "default",
+    "delete",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "include",
+    "inline",
+    "int",
+    "long",
+    "namespace",
+    "new",
+    "private",
+    "protected",
+    "public",
+    "register",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "template",
+    "this",
+    "typedef",
+    "typename",
+    "union",
+    "unsigned",
+    "using",
+    "virtual",
+    "void",
+    "volatile",
+    "while",
+];
+
+const JS_KEYWORDS: &[&str] = &[
+    "as",
+    "async",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "finally",
+    "for",
+    "from",
+    "function",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "instanceof",
+    "interface",
+    "let",
+    "new",
+    "of",
+    "private",
+    "protected",
+    "public",
+    "readonly",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "try",
+    "type",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "yield",
+];
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        names: &["rust", "rs"],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+            "mut", "pub", "ref", "return", "static", "struct", "super", "trait", "type", "unsafe",
+            "use", "where", "while",
+        ],
+        literals: &["true", "false", "self", "Self"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"'],
+        capitalized_types: true,
+        case_insensitive: false,
+    },
+    Language {
+        names: &["c", "h", "cpp", "c++", "cc", "hpp", "cxx"],
+        keywords: C_KEYWORDS,
+        literals: &["true", "false", "NULL", "nullptr"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+        capitalized_types: false,
+        case_insensitive: false,
+    },
+    Language {
+        names: &["javascript", "js", "jsx", "typescript", "ts", "tsx", "mjs"],
+        keywords: JS_KEYWORDS,
+        literals: &["true", "false", "null", "undefined", "NaN"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\'', '`'],
+        capitalized_types: true,
+        case_insensitive: false,
+    },
+    Language {
+        names: &["python", "py"],
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in",
+            "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+            "with", "yield",
+        ],
+        literals: &["True", "False", "None", "self"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        capitalized_types: true,
+        case_insensitive: false,
+    },
+    Language {
+        names: &["go", "golang"],
+        keywords: &[
+            "break",
+            "case",
+            "chan",
+            "const",
+            "continue",
+            "default",
+            "defer",
+            "else",
+            "fallthrough",
+            "for",
+            "func",
+            "go",
+            "goto",
+            "if",
+            "import",
+            "interface",
+            "map",
+            "package",
+            "range",
+            "return",
+            "select",
+            "struct",
+            "switch",
+            "type",
+            "var",
+        ],
+        literals: &["true", "false", "nil", "iota"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\'', '`'],
+        capitalized_types: false,
+        case_insensitive: false,
+    },
+    Language {
+        names: &["java", "kotlin", "kt", "cs", "csharp"],
+        keywords: &[
+            "abstract",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "continue",
+            "default",
+            "do",
+            "else",
+            "enum",
+            "extends",
+            "final",
+            "finally",
+            "for",
+            "fun",
+            "if",
+            "implements",
+            "import",
+            "instanceof",
+            "interface",
+            "new",
+            "override",
+            "package",
+            "private",
+            "protected",
+            "public",
+            "return",
+            "static",
+            "super",
+            "switch",
+            "this",
+            "throw",
+            "throws",
+            "try",
+            "val",
+            "var",
+            "void",
+            "when",
+            "while",
+        ],
+        literals: &["true", "false", "null"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"', '\''],
+        capitalized_types: true,
+        case_insensitive: false,
+    },
+    Language {
+        names: &["sh", "bash", "shell", "zsh", "console"],
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+            "in", "local", "return", "then", "until", "while",
+        ],
+        literals: &["true", "false"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        capitalized_types: false,
+        case_insensitive: false,
+    },
+    Language {
+        names: &["sql", "sqlite"],
+        keywords: &[
+            "add",
+            "alter",
+            "and",
+            "as",
+            "asc",
+            "begin",
+            "by",
+            "case",
+            "commit",
+            "create",
+            "default",
+            "delete",
+            "desc",
+            "distinct",
+            "drop",
+            "else",
+            "end",
+            "exists",
+            "from",
+            "group",
+            "having",
+            "if",
+            "in",
+            "index",
+            "insert",
+            "into",
+            "is",
+            "join",
+            "key",
+            "left",
+            "limit",
+            "not",
+            "offset",
+            "on",
+            "or",
+            "order",
+            "primary",
+            "references",
+            "select",
+            "set",
+            "table",
+            "then",
+            "union",
+            "unique",
+            "update",
+            "values",
+            "when",
+            "where",
+            "with",
+        ],
+        literals: &["true", "false", "null"],
+        line_comments: &["--"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['\''],
+        capitalized_types: false,
+        case_insensitive: true,
+    },
+    Language {
+        names: &["toml", "yaml", "yml", "ini"],
+        keywords: &[],
+        literals: &["true", "false", "null"],
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        capitalized_types: false,
+        case_insensitive: false,
+    },
+    Language {
+        names: &["json", "jsonc"],
+        keywords: &[],
+        literals: &["true", "false", "null"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        quotes: &['"'],
+        capitalized_types: false,
+        case_insensitive: false,
+    },
+];
+
+/// Color for each class, in the order of `CLASSES`
+type Palette = [&'static str; 7];
+
+const CLASSES: [&str; 7] = [
+    "keyword", "type", "fn", "string", "number", "comment", "literal",
+];
+
+const LIGHT: Palette = [
+    "#a626a4", "#c18401", "#4078f2", "#50a14f", "#986801", "#a0a1a7", "#0184bc",
+];
+const DARK: Palette = [
+    "#c678dd", "#e5c07b", "#61afef", "#98c379", "#d19a66", "#7f848e", "#56b6c2",
+];
+const SOLARIZED_LIGHT: Palette = [
+    "#859900", "#b58900", "#268bd2", "#2aa198", "#d33682", "#93a1a1", "#cb4b16",
+];
+const SOLARIZED_DARK: Palette = [
+    "#859900", "#b58900", "#268bd2", "#2aa198", "#d33682", "#586e75", "#cb4b16",
+];
+
+/// Names `highlight_theme` can be. `auto` follows the reader's light or dark preference.
+pub const THEMES: &[&str] = &["auto", "light", "dark", "solarized-light", "solarized-dark"];
+
+/// The stylesheet for a theme in `THEMES`
+pub fn stylesheet(theme: &str) -> Option<String> {
+    let rules = |palette: &Palette| {
+        let mut css = String::new();
+        for (class, color) in CLASSES.iter().zip(palette) {
+            css += &format!(".hl-{class} {{ color: {color}; }}\n");
+        }
+        css
+    };
+
+    let mut css = String::from(".hl-comment { font-style: italic; }\n");
+    match theme {
+        "auto" => {
+            css += &rules(&LIGHT);
+            css += "@media (prefers-color-scheme: dark) {\n";
+            css += &rules(&DARK);
+            css += "}\n";
+        }
+        "light" => css += &rules(&LIGHT),
+        "dark" => css += &rules(&DARK),
+        "solarized-light" => css += &rules(&SOLARIZED_LIGHT),
+        "solarized-dark" => css += &rules(&SOLARIZED_DARK),
+        _ => return None,
+    }
+    Some(css)
+}
+
+/// Rendered HTML with the contents of every `<pre><code class="language-...">` highlighted, for
+/// the languages there are lexers for
+pub fn highlight_html(html: &str) -> String {
+    const OPEN: &str = r#"<pre><code class="language-"#;
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(OPEN) {
+        let after_open = &rest[start + OPEN.len()..];
+        let block = after_open.split_once(r#"">"#).and_then(|(name, after)| {
+            let (code, after) = after.split_once("</code></pre>")?;
+            Some((name, code, after))
+        });
+        let Some((name, code, after)) = block else {
+            break;
+        };
+
+        // info strings like `rust,ignore` only have the language first
+        let language = name
+            .split([',', ' '])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let highlighted = LANGUAGES
+            .iter()
+            .find(|candidate| candidate.names.contains(&language.as_str()))
+            .filter(|_| code.len() <= MAX_CODE_BYTES && !name.contains('"'))
+            .map(|language| highlight(&unescape(code), language));
+
+        out += &rest[..start];
+        out += &format!(r#"<pre><code class="language-{name}">"#);
+        out += highlighted.as_deref().unwrap_or(code);
+        out += "</code></pre>";
+        rest = after;
+    }
+
+    out + rest
+}
+
+fn highlight(code: &str, language: &Language) -> String {
+    let mut out = String::with_capacity(code.len() * 2);
+    let span = |out: &mut String, class: &str, text: &str| {
+        *out += &format!(r#"<span class="hl-{class}">{}</span>"#, escape(text));
+    };
+
+    let mut i = 0;
+    while i < code.len() {
+        let rest = &code[i..];
+        let c = rest.chars().next().expect("not at the end");
+
+        if language
+            .line_comments
+            .iter()
+            .any(|marker| rest.starts_with(marker))
+        {
+            let end = rest.find('\n').unwrap_or(rest.len());
+            span(&mut out, "comment", &rest[..end]);
+            i += end;
+        } else if let Some((open, close)) = language.block_comment
+            && rest.starts_with(open)
+        {
+            let end = rest[open.len()..]
+                .find(close)
+                .map_or(rest.len(), |end| open.len() + end + close.len());
+            span(&mut out, "comment", &rest[..end]);
+            i += end;
+        } else if language.quotes.contains(&c) {
+            let mut end = c.len_utf8();
+            let mut escaped = false;
+            for next in rest[end..].chars() {
+                end += next.len_utf8();
+                match next {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    _ if next == c => break,
+                    _ => {}
+                }
+            }
+            span(&mut out, "string", &rest[..end]);
+            i += end;
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            span(&mut out, "number", &rest[..end]);
+            i += end;
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            let is = |words: &[&str]| match language.case_insensitive {
+                true => words.iter().any(|w| w.eq_ignore_ascii_case(word)),
+                false => words.contains(&word),
+            };
+            let called = rest[end..].trim_start().starts_with(['(', '!']);
+
+            if is(language.keywords) {
+                span(&mut out, "keyword", word);
+            } else if is(language.literals) {
+                span(&mut out, "literal", word);
+            } else if called {
+                span(&mut out, "fn", word);
+            } else if language.capitalized_types && c.is_uppercase() {
+                span(&mut out, "type", word);
+            } else {
+                out += word;
+            }
+            i += end;
+        } else {
+            out += &escape(&rest[..c.len_utf8()]);
+            i += c.len_utf8();
+        }
+    }
+
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// What the Markdown renderer escaped in code blocks, back how it was written
+fn unescape(html: &str) -> String {
+    html.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#x27;", "'")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
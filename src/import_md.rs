@@ -0,0 +1,339 @@
+//! `blog3 import-md <config> <dir> [--dry-run]`: a post for each Markdown file in a directory,
+//! with its title, date, slug, tags, and template from YAML (`---`) or TOML (`+++`) front
+//! matter. YAML is only understood as far as front matter tends to go: `key: value` lines, and
+//! lists written either `[a, b]` or as `- item` lines under their key.
+
+use crate::{App, Config, ContentType, Publish};
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+use sqlx::{Acquire, Sqlite, Transaction};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const USAGE: &str = "usage: blog3 import-md <config> <dir> [--dry-run]";
+
+enum Value {
+    Text(String),
+    List(Vec<String>),
+}
+
+type Fields = HashMap<String, Value>;
+
+enum Outcome {
+    Imported {
+        slug: String,
+        published: DateTime<FixedOffset>,
+    },
+    Skipped(&'static str),
+}
+
+pub async fn run() -> Result<()> {
+    let args = std::env::args().skip(2).collect::<Vec<_>>();
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let mut paths = args.iter().filter(|arg| *arg != "--dry-run");
+    let (Some(config_path), Some(dir)) = (paths.next(), paths.next()) else {
+        fatal!("{}", USAGE);
+    };
+
+    let app = Arc::new(crate::load_app(Path::new(config_path)).await?);
+    // registering the routes is what reserves their names, so slugs get checked against them
+    let _ = crate::router(&app);
+
+    let mut files = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    files.retain(|path| {
+        path.is_file()
+            && path
+                .extension()
+                .is_some_and(|extension| extension == "md" || extension == "markdown")
+    });
+    files.sort();
+
+    // a dry run puts everything in one transaction that gets rolled back, so files still see the
+    // slugs taken by the ones before them
+    let mut dry_run_tx = match dry_run {
+        true => Some(app.begin_write().await?),
+        false => None,
+    };
+
+    let (mut imported, mut skipped, mut errors) = (0, 0, 0);
+    for path in &files {
+        let tx = match dry_run_tx.as_mut() {
+            Some(dry_run_tx) => dry_run_tx.begin().await?,
+            None => app.begin_write().await?,
+        };
+
+        match import_file(&app, tx, path).await {
+            Ok(Outcome::Imported { slug, published }) => {
+                imported += 1;
+                let action = if dry_run { "would import" } else { "imported" };
+                println!(
+                    "{action} {} as {slug}, published {published}",
+                    path.display()
+                );
+            }
+            Ok(Outcome::Skipped(reason)) => {
+                skipped += 1;
+                println!("skipped {}: {reason}", path.display());
+            }
+            Err(err) => {
+                errors += 1;
+                println!("couldn't import {}: {err:#}", path.display());
+            }
+        }
+    }
+
+    let action = if dry_run { "would import" } else { "imported" };
+    println!("{action} {imported}, skipped {skipped}, {errors} errors");
+    Ok(())
+}
+
+/// Everything about one file happens in `tx`, so a bad file doesn't leave half a post behind
+async fn import_file(app: &App, mut tx: Transaction<'_, Sqlite>, path: &Path) -> Result<Outcome> {
+    let source = std::fs::read_to_string(path)?;
+    let (fields, body) = split_front_matter(&source)?;
+
+    let (title, content) = match text(&fields, "title") {
+        Some(title) => (title, String::from(body)),
+        None => match take_heading(body) {
+            Some((title, content)) => (title, content),
+            None => (title_from_filename(path), String::from(body)),
+        },
+    };
+
+    let config = app.config();
+    let published = match text(&fields, "date") {
+        Some(date) => {
+            parse_date(&date, &config).ok_or_else(|| anyhow!("can't read the date {date:?}"))?
+        }
+        None => config.local(DateTime::<Utc>::from(std::fs::metadata(path)?.modified()?).into()),
+    };
+
+    let to_publish = Publish {
+        title,
+        subtitle: text(&fields, "subtitle"),
+        summary: text(&fields, "summary").or_else(|| text(&fields, "description")),
+        image: text(&fields, "image"),
+        slug: text(&fields, "slug"),
+        published: None,
+        content: content.trim_start().to_string(),
+        content_type: Some(ContentType::Markdown),
+        draft: text(&fields, "draft").is_some_and(|draft| draft == "true"),
+        extra_head: None,
+        template: text(&fields, "template"),
+        tags: Some(list(&fields, "tags")),
+    };
+    if let Err(errors) = to_publish.validate(&config) {
+        let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+        bail!("{}", errors.join(", "));
+    }
+    let custom_slug = to_publish
+        .custom_slug(app)
+        .map_err(|error| anyhow!("{error}"))?;
+    to_publish
+        .check_template(app)
+        .await
+        .map_err(|error| anyhow!("{error}"))?;
+
+    // so a second run over the same files picks up where the first stopped
+    let already_imported = sqlx::query_scalar!(
+        r#"select count(*) as "count: i64" from post where title = $1 and published = $2"#,
+        to_publish.title,
+        published
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if already_imported > 0 {
+        return Ok(Outcome::Skipped(
+            "a post with that title and date already exists",
+        ));
+    }
+
+    let post = to_publish.new_post(published);
+    let slug = custom_slug.unwrap_or_else(|| post.slug(&config));
+    let slug = app
+        .create_post(&mut tx, &post, &to_publish.tags.unwrap_or_default(), &slug)
+        .await?;
+    tx.commit().await?;
+
+    Ok(Outcome::Imported { slug, published })
+}
+
+/// The front matter's fields and what's after it
+fn split_front_matter(source: &str) -> Result<(Fields, &str)> {
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    let Some((fence, first)) = source.split_once('\n') else {
+        return Ok((Fields::new(), source));
+    };
+    let fence = fence.trim_end();
+    if fence != "---" && fence != "+++" {
+        return Ok((Fields::new(), source));
+    }
+
+    let mut front_len = 0;
+    let mut rest = None;
+    for line in first.split_inclusive('\n') {
+        if line.trim_end() == fence || (fence == "---" && line.trim_end() == "...") {
+            rest = Some(&first[front_len + line.len()..]);
+            break;
+        }
+        front_len += line.len();
+    }
+    let Some(rest) = rest else {
+        bail!("front matter starting with {fence} never ends");
+    };
+
+    let front = &first[..front_len];
+    let fields = match fence {
+        "+++" => toml_fields(front)?,
+        _ => yaml_fields(front)?,
+    };
+    Ok((fields, rest))
+}
+
+fn toml_fields(front: &str) -> Result<Fields> {
+    let table = toml::from_str::<toml::Table>(front)?;
+    let to_text = |value: &toml::Value| match value {
+        toml::Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+
+    Ok(table
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::Array(items) => Value::List(items.iter().map(to_text).collect()),
+                other => Value::Text(to_text(other)),
+            };
+            (key.clone(), value)
+        })
+        .collect())
+}
+
+fn yaml_fields(front: &str) -> Result<Fields> {
+    let mut fields = Fields::new();
+    // the key a `- item` line adds to
+    let mut list_key = None::<String>;
+
+    for line in front.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if let Some(item) = trimmed.strip_prefix('-') {
+            match list_key.as_ref().and_then(|key| fields.get_mut(key)) {
+                Some(Value::List(items)) => items.push(unquote(item)),
+                _ => bail!("list item outside of a list: {line:?}"),
+            }
+            continue;
+        }
+
+        let Some((key, value)) = trimmed.split_once(':') else {
+            bail!("front matter line isn't `key: value`: {line:?}");
+        };
+        let (key, value) = (String::from(key.trim()), value.trim());
+
+        list_key = None;
+        let inline_list = value
+            .strip_prefix('[')
+            .and_then(|inner| inner.strip_suffix(']'));
+        if value.is_empty() {
+            list_key = Some(key.clone());
+            fields.insert(key, Value::List(Vec::new()));
+        } else if let Some(items) = inline_list {
+            let items = items
+                .split(',')
+                .map(unquote)
+                .filter(|item| !item.is_empty());
+            fields.insert(key, Value::List(items.collect()));
+        } else {
+            fields.insert(key, Value::Text(unquote(value)));
+        }
+    }
+
+    Ok(fields)
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        let unquoted = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote));
+        if let Some(unquoted) = unquoted {
+            return String::from(unquoted);
+        }
+    }
+    String::from(value)
+}
+
+fn text(fields: &Fields, key: &str) -> Option<String> {
+    match fields.get(key)? {
+        Value::Text(text) if !text.trim().is_empty() => Some(text.clone()),
+        _ => None,
+    }
+}
+
+/// A list, or a comma separated string like the composer's tags field
+fn list(fields: &Fields, key: &str) -> Vec<String> {
+    match fields.get(key) {
+        Some(Value::List(items)) => items.clone(),
+        Some(Value::Text(text)) => text
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// The first `# heading` outside of a code block, and the content without it
+fn take_heading(body: &str) -> Option<(String, String)> {
+    let mut in_code = false;
+    let mut offset = 0;
+    for line in body.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+        } else if !in_code && let Some(heading) = trimmed.strip_prefix("# ") {
+            let content = String::from(&body[..offset]) + &body[offset + line.len()..];
+            return Some((
+                heading.trim().trim_end_matches('#').trim().to_string(),
+                content,
+            ));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+fn title_from_filename(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().replace(['-', '_'], " "))
+        .unwrap_or_default()
+}
+
+/// RFC 3339, or a date with or without a time in the blog's time zone
+fn parse_date(date: &str, config: &Config) -> Option<DateTime<FixedOffset>> {
+    let local = |naive: NaiveDateTime| config.localize(naive);
+
+    DateTime::parse_from_rfc3339(date)
+        .ok()
+        .or_else(|| DateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S %z").ok())
+        .or_else(|| {
+            ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+                .and_then(local)
+        })
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .and_then(local)
+        })
+}
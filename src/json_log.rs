@@ -0,0 +1,147 @@
+//! Logs as one JSON object per line, for `log_format = "json"`. Events look like
+//! `{"timestamp":..,"level":"INFO","target":"blog3","message":..,<fields>,"span":{<fields of
+//! the spans it's in>},"spans":["request",..]}`.
+
+use std::fmt::{self, Write};
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+    span,
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, format::Writer},
+    registry::LookupSpan,
+};
+
+/// Writes fields as `"name":value` separated by commas, the inside of an object
+struct Visitor<'a> {
+    out: &'a mut String,
+}
+
+impl Visitor<'_> {
+    fn entry(&mut self, field: &Field, value: serde_json::Value) {
+        if !self.out.is_empty() {
+            self.out.push(',');
+        }
+        let name = serde_json::Value::from(field.name());
+        let _ = write!(self.out, "{name}:{value}");
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.entry(field, format!("{value:?}").into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.entry(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.entry(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.entry(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.entry(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.entry(field, value.into());
+    }
+}
+
+fn fields(record: impl RecordFields) -> String {
+    let mut out = String::new();
+    record.record(&mut Visitor { out: &mut out });
+    out
+}
+
+/// How span fields get stored, so events can put them in their objects as they are
+pub struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        record: R,
+    ) -> fmt::Result {
+        writer.write_str(&fields(record))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        record: &span::Record<'_>,
+    ) -> fmt::Result {
+        let added = fields(record);
+        if !added.is_empty() {
+            if !current.fields.is_empty() {
+                current.fields.push(',');
+            }
+            current.fields.push_str(&added);
+        }
+        Ok(())
+    }
+}
+
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+        write!(
+            writer,
+            "{{\"timestamp\":\"{}\",\"level\":\"{}\",\"target\":{}",
+            timestamp,
+            metadata.level(),
+            serde_json::Value::from(metadata.target()),
+        )?;
+
+        let fields = fields(event);
+        if !fields.is_empty() {
+            write!(writer, ",{fields}")?;
+        }
+
+        // outer spans first, so when two have the same field the inner one wins
+        let mut names = Vec::new();
+        let mut span_fields = String::new();
+        for span in ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            names.push(span.name());
+            if let Some(formatted) = span.extensions().get::<FormattedFields<N>>()
+                && !formatted.fields.is_empty()
+            {
+                if !span_fields.is_empty() {
+                    span_fields.push(',');
+                }
+                span_fields.push_str(&formatted.fields);
+            }
+        }
+        if !span_fields.is_empty() {
+            write!(writer, ",\"span\":{{{span_fields}}}")?;
+        }
+        if !names.is_empty() {
+            write!(writer, ",\"spans\":{}", serde_json::Value::from(names))?;
+        }
+
+        writeln!(writer, "}}")
+    }
+}
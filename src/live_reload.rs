@@ -0,0 +1,150 @@
+//! Debug builds only: pages reload themselves when a template or stylesheet changes. A task
+//! polls the directories they come from, and every page gets a script listening for changes on
+//! an event stream at `PATH`.
+
+use crate::App;
+use axum::{
+    body::Bytes,
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Under the dot dir
+pub const PATH: &str = "/live-reload";
+
+const POLL: Duration = Duration::from_millis(250);
+
+/// How long files have to stop changing before pages reload, since editors often save with a
+/// few writes and renames
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// So a stream whose page went away gets noticed even if nothing changes
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Watch for changes for as long as the server runs
+pub async fn watch(app: Arc<App>) {
+    let mut seen = None;
+    let mut changed_at = None::<Instant>;
+    let mut interval = tokio::time::interval(POLL);
+
+    loop {
+        interval.tick().await;
+
+        let dirs = dirs(&app.config());
+        let Ok(current) = tokio::task::spawn_blocking(move || scan(&dirs)).await else {
+            continue;
+        };
+        if seen.as_ref() != Some(&current) {
+            // the first scan is only something to compare to
+            if seen.is_some() {
+                changed_at = Some(Instant::now());
+            }
+            seen = Some(current);
+            continue;
+        }
+
+        if changed_at.is_some_and(|changed_at| changed_at.elapsed() >= DEBOUNCE) {
+            changed_at = None;
+            tracing::debug!("live reload");
+            app.live_reload.send_modify(|changes| *changes += 1);
+        }
+    }
+}
+
+/// Where templates and stylesheets come from, which can change when the config is reloaded
+fn dirs(config: &crate::Config) -> Vec<PathBuf> {
+    let theme_dir = match &config.theme_files {
+        crate::theme::Theme::Dir(dir) => Some(dir.clone()),
+        _ => None,
+    };
+    std::iter::once(PathBuf::from("frontend"))
+        .chain(crate::template_dir(config))
+        .chain(crate::assets_dir(config))
+        .chain(theme_dir)
+        .collect()
+}
+
+/// When each template and stylesheet under `dirs` was last modified
+fn scan(dirs: &[PathBuf]) -> HashMap<PathBuf, SystemTime> {
+    fn walk(dir: &Path, files: &mut HashMap<PathBuf, SystemTime>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                walk(&path, files);
+                continue;
+            }
+
+            let watched = path
+                .extension()
+                .is_some_and(|extension| extension == "tera" || extension == "css");
+            if watched && let Ok(modified) = metadata.modified() {
+                files.insert(path, modified);
+            }
+        }
+    }
+
+    let mut files = HashMap::new();
+    for dir in dirs {
+        walk(dir, &mut files);
+    }
+    files
+}
+
+/// A `text/event-stream` that sends `reload` whenever something changes
+pub async fn handler(State(app): State<Arc<App>>) -> Response {
+    let mut changes = app.live_reload.subscribe();
+    let (mut sender, body) = http_body_util::Channel::<Bytes>::new(1);
+
+    tokio::spawn(async move {
+        loop {
+            let message = tokio::select! {
+                changed = changes.changed() => match changed {
+                    Ok(()) => "data: reload\n\n",
+                    Err(_) => return,
+                },
+                _ = tokio::time::sleep(KEEPALIVE) => ": keepalive\n\n",
+            };
+            // the page was closed or reloaded
+            if sender
+                .send_data(Bytes::from_static(message.as_bytes()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+
+    let headers = [
+        ("Content-Type", "text/event-stream"),
+        ("Cache-Control", "no-store"),
+    ];
+    (headers, axum::body::Body::new(body)).into_response()
+}
+
+/// `page` with the script that reloads it, listening at `url`. It also reloads when the stream
+/// comes back after losing the server, since that's a restart with whatever changed in it.
+pub fn inject(mut page: String, url: &str) -> String {
+    let script = format!(
+        "<script>(() => {{ let lost = false; const events = new EventSource({url:?}); \
+         events.onmessage = () => location.reload(); events.onerror = () => lost = true; \
+         events.onopen = () => lost && location.reload(); }})();</script>\n"
+    );
+    match page.rfind("</body>") {
+        Some(end) => page.insert_str(end, &script),
+        None => page.push_str(&script),
+    }
+    page
+}
@@ -1,25 +1,46 @@
+#![allow(clippy::explicit_auto_deref)]
+
 use anyhow::Result;
 use axum::{
     Json, Router, ServiceExt,
-    extract::{Path, State},
-    http::{Request, StatusCode, uri::Builder},
+    extract::{Path, Query, State},
+    http::{HeaderMap, Request, StatusCode, uri::Builder},
     response::{Html, IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post},
 };
-use axum_extra::{
-    TypedHeader,
-    headers::{Authorization, authorization::Basic},
+use axum_extra::headers::{
+    Authorization, Cookie, HeaderMapExt,
+    authorization::{Basic, Bearer},
 };
-use chrono::{DateTime, Datelike, FixedOffset, Local};
+use chrono::{DateTime, Datelike, FixedOffset, Local, Utc};
+use regex::Regex;
 use serde_json::json;
-use sqlx::{SqliteConnection, SqlitePool};
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use sqlx::{
+    SqliteConnection, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 use tera::{Context, Tera};
 use tokio::{net::TcpListener, sync::RwLock};
 use tower::Layer;
 use tracing::info;
 use uuid::Uuid;
 
+mod argon2;
+mod gzip;
+mod highlight;
+mod json_log;
+#[cfg(debug_assertions)]
+mod live_reload;
+mod theme;
+mod tls;
+
 macro_rules! fatal {
     ($($arg:tt)*) => {{
         ::tracing::error!($($arg)*);
@@ -29,802 +50,9902 @@ macro_rules! fatal {
 
 macro_rules! return_500 {
     ($err:expr, $errname:ident) => {{
-        ::tracing::error!($errname = ?$err);
-        return (::axum::http::StatusCode::INTERNAL_SERVER_ERROR, $err.to_string()).into_response()
+        let error = $crate::AppError::new(&$err);
+        ::tracing::error!(error_id = %error.id, $errname = ?$err);
+        return ::axum::response::IntoResponse::into_response(error)
     }};
 }
 
+#[cfg(feature = "activitypub")]
+mod activitypub;
+mod backup;
+mod export;
+mod import_md;
+
+/// A 500 with an id to find it in the logs by. Responses carry the error until
+/// `error_page_layer` decides whether the client gets to see it.
+#[derive(Debug, Clone)]
+struct AppError {
+    id: String,
+    message: String,
+}
+
+impl AppError {
+    fn new(err: &dyn std::fmt::Display) -> AppError {
+        AppError {
+            id: Uuid::new_v4().simple().to_string()[..12].to_string(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let body = self.message.clone();
+        let mut response = (StatusCode::INTERNAL_SERVER_ERROR, body).into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Why a post can't have a slug, when `slug_collision` is `reject`
+#[derive(Debug)]
+enum SlugUnavailable {
+    /// Another post's
+    Taken(String),
+    /// One of the blog's own routes
+    Reserved(String),
+}
+
+impl SlugUnavailable {
+    fn status(&self) -> StatusCode {
+        match self {
+            SlugUnavailable::Taken(_) => StatusCode::CONFLICT,
+            SlugUnavailable::Reserved(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+impl std::fmt::Display for SlugUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlugUnavailable::Taken(slug) => write!(f, "slug {slug:?} is already another post's"),
+            SlugUnavailable::Reserved(slug) => {
+                write!(f, "slug {slug:?} is taken by one of the blog's own pages")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SlugUnavailable {}
+
 #[derive(Debug, serde::Serialize, sqlx::FromRow)]
 struct Post {
     id: Uuid,
     title: String,
     subtitle: Option<String>,
+    summary: Option<String>,
+    /// Shown in link previews, either a full url or a path on this site
+    image: Option<String>,
     published: DateTime<FixedOffset>,
+    updated: DateTime<FixedOffset>,
     content: String,
+    content_type: ContentType,
     draft: bool,
+    extra_head: Option<String>,
+    /// Rendered with instead of `POST_TEMPLATE`
+    template: Option<String>,
+    /// Deleted posts keep their row and slugs so their urls can say they're gone
+    deleted: Option<DateTime<FixedOffset>>,
 }
 
-impl Post {
-    fn slug(&self) -> String {
-        let short = if self.title.len() > 26 {
-            &self.title[..26]
-        } else {
-            &self.title
-        };
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize, sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+enum ContentType {
+    #[default]
+    Markdown,
+    Html,
+}
 
-        slug::slugify(short)
-            + &format!(
-                "-{:04}-{:02}-{:02}",
-                self.published.year(),
-                self.published.month(),
-                self.published.day()
-            )
+/// GFM, letting HTML in posts through since only authors can write them
+fn markdown_options() -> markdown::Options {
+    let mut options = markdown::Options::gfm();
+    options.compile.allow_dangerous_html = true;
+    options
+}
+
+impl ContentType {
+    /// HTML for some content, with its code blocks highlighted
+    fn render(self, content: &str) -> String {
+        match self {
+            ContentType::Markdown => {
+                let html = markdown::to_html_with_options(content, &markdown_options())
+                    .expect("valid markdown");
+                highlight::highlight_html(&html)
+            }
+            ContentType::Html => highlight::highlight_html(content),
+        }
     }
 }
 
-const DOT_DIR: &str = ".blog3";
+/// Length of the excerpt used to describe posts without a summary or subtitle
+const DESCRIPTION_CHARS: usize = 200;
 
-#[derive(Debug, serde::Deserialize)]
-struct Config {
-    page_root: String,
-    bind: SocketAddr,
-    database: PathBuf,
-    title: String,
-    #[serde(default)]
-    basic_auth: Option<BasicAuthConfig>,
-}
+/// Longest slug a post can ask for instead of the one from its title
+const MAX_CUSTOM_SLUG_CHARS: usize = 100;
 
-#[derive(Debug, serde::Deserialize)]
-struct BasicAuthConfig {
-    user: String,
-    password: String,
-    realm: Option<String>,
-}
+/// Limits on what gets published, so a mistake can't make a post nobody could read
+const MAX_SUBTITLE_CHARS: usize = 300;
+const MAX_SUMMARY_CHARS: usize = 1000;
+const MAX_CONTENT_BYTES: usize = 1024 * 1024;
+const MAX_EXTRA_HEAD_BYTES: usize = 16 * 1024;
+const MAX_TAGS: usize = 50;
+const MAX_TAG_CHARS: usize = 100;
 
-impl Config {
-    fn route(&self, child: &str) -> String {
-        if self.page_root == "/" {
-            String::from(child)
-        } else {
-            self.page_root.clone() + child
+/// How far ahead of the server's clock a post's date can be without `future_published`, for
+/// clients whose clocks are a little off
+const FUTURE_SLACK_MINUTES: i64 = 5;
+
+/// How `published_local` and `updated_local` are written in templates
+const LOCAL_DATETIME_FORMAT: &str = "%B %-d, %Y at %-I:%M %p";
+
+impl Post {
+    /// `published`, `draft`, or `deleted`
+    fn status(&self) -> &'static str {
+        match (self.deleted, self.draft) {
+            (Some(_), _) => "deleted",
+            (None, true) => "draft",
+            (None, false) => "published",
         }
     }
 
-    fn route_dot(&self, child: &str) -> String {
-        if self.page_root == "/" {
-            String::from("/") + DOT_DIR + child
+    /// Changes every time the post does, so a client can tell whether its copy is still current.
+    /// Sent as the ETag too.
+    fn version(&self) -> String {
+        format!(
+            "{:x}",
+            self.updated.timestamp_nanos_opt().unwrap_or_default()
+        )
+    }
+
+    /// When the post was published and last updated as readers are shown them
+    fn local_times(&self, config: &Config) -> (String, String) {
+        let format = |date| config.local(date).format(LOCAL_DATETIME_FORMAT).to_string();
+        (format(self.published), format(self.updated))
+    }
+
+    /// What to describe the post with in meta tags and feeds: the summary, then the subtitle, and
+    /// finally an excerpt of the content.
+    fn description(&self) -> String {
+        describe(
+            &self.summary,
+            &self.subtitle,
+            &self.content,
+            self.content_type,
+        )
+    }
+
+    /// The title's words up to `slug_max_length`, and the date it was published
+    fn slug(&self, config: &Config) -> String {
+        // shortened after slugifying since that transliterates, so the cut can't land inside a
+        // character and the length is what ends up in the url
+        let slug = slug::slugify(&self.title);
+        let max = config.slug_max_length;
+        let short = if slug.chars().count() <= max {
+            slug
         } else {
-            self.page_root.clone() + "/" + DOT_DIR + child
-        }
+            // one past the limit, in case the word right at it ends there
+            let cut = slug.chars().take(max + 1).collect::<String>();
+            match cut.rfind('-') {
+                Some(word_end) if word_end > 0 => String::from(&cut[..word_end]),
+                // one long word gets cut wherever it has to be
+                _ => cut.chars().take(max).collect(),
+            }
+        };
+        let short = short.trim_end_matches('-');
+
+        // the day it was where the blog is, whatever offset it was saved with
+        let published = config.local(self.published);
+        String::from(short)
+            + &format!(
+                "-{:04}-{:02}-{:02}",
+                published.year(),
+                published.month(),
+                published.day()
+            )
     }
 }
 
-struct App {
-    config: Config,
-    pool: SqlitePool,
-    tera: RwLock<Tera>,
+/// The summary, then the subtitle, and finally an excerpt of the content, so there's always
+/// something to put in a meta description.
+fn describe(
+    summary: &Option<String>,
+    subtitle: &Option<String>,
+    content: &str,
+    content_type: ContentType,
+) -> String {
+    summary
+        .clone()
+        .filter(|summary| !summary.trim().is_empty())
+        .or_else(|| subtitle.clone())
+        .unwrap_or_else(|| excerpt(content, content_type, DESCRIPTION_CHARS))
 }
 
-impl App {
-    #[tracing::instrument(skip(self, context))]
-    async fn render(&self, template_name: &str, context: &Context) -> Result<String> {
-        #[cfg(debug_assertions)]
-        {
-            tracing::debug!("reloading");
-            self.tera.write().await.full_reload()?;
+/// The visible text of some content, without markup, inline HTML, or code blocks, with
+/// whitespace collapsed.
+fn plain_text(content: &str, content_type: ContentType) -> String {
+    use markdown::mdast::Node;
+
+    if content_type == ContentType::Html {
+        static INVISIBLE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"(?is)<(script|style|pre)\b.*?</(script|style|pre)\s*>|<[^>]*>")
+                .expect("valid regex")
+        });
+        let text = INVISIBLE
+            .replace_all(content, " ")
+            .replace("&nbsp;", " ")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&");
+        return text.split_whitespace().collect::<Vec<_>>().join(" ");
+    }
+
+    fn walk(node: &Node, out: &mut String) {
+        match node {
+            Node::Text(text) => out.push_str(&text.value),
+            Node::InlineCode(code) => out.push_str(&code.value),
+            Node::Code(_) | Node::Html(_) | Node::Yaml(_) | Node::Toml(_) | Node::Definition(_) => {
+            }
+            Node::Break(_) => out.push(' '),
+            _ => {
+                for child in node.children().into_iter().flatten() {
+                    walk(child, out);
+                }
+            }
         }
 
-        tracing::trace!("rendering");
-        Ok(self.tera.read().await.render(template_name, context)?)
+        // keep words in adjacent blocks apart
+        if matches!(
+            node,
+            Node::Paragraph(_) | Node::Heading(_) | Node::ListItem(_) | Node::TableCell(_)
+        ) {
+            out.push(' ');
+        }
     }
+
+    let root = markdown::to_mdast(content, &markdown::ParseOptions::gfm()).expect("valid markdown");
+    let mut out = String::new();
+    walk(&root, &mut out);
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
-    run().await.unwrap();
+/// How many words are in some content's plain text, and the minutes they take to read, at
+/// least one so an empty post doesn't claim to take no time at all
+fn reading_time(
+    content: &str,
+    content_type: ContentType,
+    words_per_minute: usize,
+) -> (usize, usize) {
+    let words = plain_text(content, content_type).split_whitespace().count();
+    (words, words.div_ceil(words_per_minute).max(1))
 }
 
-const POST_TEMPLATE: &str = "post.html.tera";
-const INDEX_TEMPLATE: &str = "index.html.tera";
-const EDIT_TEMPLATE: &str = "edit.html.tera";
+/// Where a post's author wants excerpts to stop
+const MORE_MARKER: &str = "<!--more-->";
 
-async fn run() -> Result<()> {
-    let Some(config) = std::env::args().nth(1) else {
-        fatal!("missing config path filename");
-    };
-    let config = tokio::fs::read_to_string(config).await?;
-    let mut config: Config = match toml::from_str(&config) {
-        Ok(config) => config,
-        Err(err) => fatal!("{}", err),
+/// At most `max_chars` characters of the plain text of some content, or of what comes before
+/// `<!--more-->` if it has one, cut at a word boundary where possible, with an ellipsis if
+/// anything was cut.
+fn excerpt(content: &str, content_type: ContentType, max_chars: usize) -> String {
+    let (content, more) = match content.split_once(MORE_MARKER) {
+        Some((before, after)) => (before, !after.trim().is_empty()),
+        None => (content, false),
     };
-    config.page_root = String::from("/") + config.page_root.trim_matches('/');
-
-    info!("{:#?}", config);
+    let text = plain_text(content, content_type);
 
-    let app = App {
-        pool: SqlitePool::connect(&format!("sqlite:{}", config.database.display())).await?,
-        tera: if cfg!(debug_assertions) {
-            RwLock::new(
-                Tera::new("frontend/*.tera")
-                    .inspect_err(|err| println!("{}", err))
-                    .expect("valid templates"),
-            )
+    let Some((end, next)) = text.char_indices().nth(max_chars) else {
+        return if more && !text.is_empty() {
+            format!("{text}…")
         } else {
-            RwLock::new(Tera::default())
-        },
-        config,
+            text
+        };
     };
 
-    if !cfg!(debug_assertions) {
-        app.tera
-            .write()
-            .await
-            .add_raw_template("macros.html.tera", include_str!("../frontend/macros.html.tera"))?;
-        app.tera
-            .write()
-            .await
-            .add_raw_template(POST_TEMPLATE, include_str!("../frontend/post.html.tera"))?;
-        app.tera
-            .write()
-            .await
-            .add_raw_template(INDEX_TEMPLATE, include_str!("../frontend/index.html.tera"))?;
-        app.tera
-            .write()
-            .await
-            .add_raw_template(EDIT_TEMPLATE, include_str!("../frontend/edit.html.tera"))?;
-    }
-
-    let bind = app.config.bind.clone();
-    let app = Arc::new(app);
-
-    let authed_router = Router::new()
-        .route(&app.config.route_dot("/publish"), post(publish_handler))
-        .route(
-            &app.config.route_dot("/publish/{update}"),
-            post(update_handler),
-        )
-        .route(&app.config.route("/drafts"), get(drafts_handler))
-        .route(&app.config.route("/edit"), get(edit_handler))
-        .route(&app.config.route("/edit/{page}"), get(edit_handler))
-        .route(&app.config.route("/{page}/edit"), get(edit_handler))
-        .layer(axum::middleware::from_fn_with_state(
-            app.clone(),
-            basic_auth_layer,
-        ))
-        .with_state(app.clone());
-
-    let unauthed_router = Router::new()
-        .route(&app.config.route_dot("/assets/{item}"), get(assets_handler))
-        .route(&app.config.page_root, get(index_handler))
-        .route(&app.config.route("/{slug}"), get(post_handler))
-        .with_state(app.clone());
+    let cut = &text[..end];
+    let cut = match cut.rfind(' ') {
+        // a single enormous word can't be cut on a boundary
+        Some(space) if next != ' ' => &cut[..space],
+        _ => cut,
+    };
 
-    let router = Router::new()
-        .merge(authed_router)
-        .merge(unauthed_router)
-        .fallback(fallback_handler);
-
-    let listener = TcpListener::bind(bind).await?;
-    axum::serve(
-        listener,
-        tower::util::MapRequestLayer::new(strip_trailing_slash)
-            .layer(router)
-            .into_make_service(),
-    )
-    .await?;
+    format!("{}…", cut.trim_end())
+}
 
-    Ok(())
+/// A heading in a post's table of contents, with the ones under it
+#[derive(Debug, serde::Serialize)]
+struct TocEntry {
+    level: u8,
+    id: String,
+    text: String,
+    children: Vec<TocEntry>,
 }
 
-fn strip_trailing_slash<B>(mut req: Request<B>) -> Request<B> {
-    if let Some(pandq) = req.uri().path_and_query() {
-        let trimmed = pandq.path().trim_end_matches("/");
-        if trimmed == pandq.path() || trimmed == "" {
-            return req;
+/// Rendered content with an `id` on every heading so they can be linked to, and its `<h2>`
+/// through `<h{depth}>` headings nested as a table of contents. Headings that already have an
+/// id keep it, and the rest get one from their text, numbered when it's been used already.
+fn anchor_headings(html: &str, depth: u8) -> (String, Vec<TocEntry>) {
+    static HEADING: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?is)<h([1-6])(\s[^>]*)?>(.*?)</h[1-6]\s*>").expect("valid regex")
+    });
+    static ID: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"(?i)\sid\s*=\s*(?:"([^"]*)"|'([^']*)')"#).expect("valid regex")
+    });
+
+    fn nest(entries: &mut Vec<TocEntry>, entry: TocEntry) {
+        match entries.last_mut() {
+            Some(last) if last.level < entry.level => nest(&mut last.children, entry),
+            _ => entries.push(entry),
         }
+    }
 
-        let mut new_pandq = String::from(trimmed);
+    let mut used = std::collections::HashSet::new();
+    let mut toc = Vec::new();
+    let anchored = HEADING.replace_all(html, |heading: &regex::Captures| {
+        let level = heading[1].parse::<u8>().expect("one digit");
+        let attributes = heading.get(2).map_or("", |attributes| attributes.as_str());
+        let inner = &heading[3];
+        let text = plain_text(inner, ContentType::Html);
 
-        if let Some(query) = pandq.query() {
-            new_pandq += "?";
-            new_pandq += query;
+        let existing = ID
+            .captures(attributes)
+            .and_then(|id| id.get(1).or(id.get(2)));
+        let id = match existing {
+            Some(id) => String::from(id.as_str()),
+            None => {
+                let base = match slug::slugify(&text) {
+                    base if base.is_empty() => String::from("section"),
+                    base => base,
+                };
+                let mut id = base.clone();
+                let mut n = 0;
+                while used.contains(&id) {
+                    n += 1;
+                    id = format!("{base}-{n}");
+                }
+                id
+            }
+        };
+        used.insert(id.clone());
+
+        if (2..=depth).contains(&level) {
+            let children = Vec::new();
+            nest(
+                &mut toc,
+                TocEntry {
+                    level,
+                    id: id.clone(),
+                    text,
+                    children,
+                },
+            );
         }
 
-        if let Ok(new_uri) = Builder::from(req.uri().clone())
-            .path_and_query(new_pandq)
-            .build()
-        {
-            tracing::trace!(from = %req.uri(), to = %new_uri, "rewriting");
-            *req.uri_mut() = new_uri;
+        match existing {
+            Some(_) => String::from(&heading[0]),
+            None => format!(r#"<h{level} id="{id}"{attributes}>{inner}</h{level}>"#),
         }
-    }
+    });
 
-    req
+    (anchored.into_owned(), toc)
 }
 
-#[tracing::instrument(skip_all)]
-async fn basic_auth_layer(
-    State(app): State<Arc<App>>,
-    basic_auth: Option<TypedHeader<Authorization<Basic>>>,
-    request: axum::extract::Request,
-    next: axum::middleware::Next,
-) -> Response {
-    match (app.config.basic_auth.as_ref(), basic_auth) {
-        (Some(BasicAuthConfig { user, password, .. }), Some(TypedHeader(header))) => {
-            if header.username() == user && header.password() == password {
-                tracing::trace!(successful_basic = ?user);
-                next.run(request).await
-            } else {
-                tracing::debug!(unsuccessful_basic = ?user);
-                (StatusCode::UNAUTHORIZED, "Incorrect username/password").into_response()
-            }
-        }
+/// Whether a url from an attribute is relative or has one of `schemes`, the way a browser
+/// would read it: character references decoded, with or without their semicolons, and the
+/// tabs and newlines it skips taken out. A reference that can't be decoded before the path
+/// could be hiding a colon, so that isn't safe either.
+fn is_safe_url<S: AsRef<str>>(url: &str, schemes: &[S]) -> bool {
+    static REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"&(?:#([0-9]+)|#[xX]([0-9a-fA-F]+)|([a-zA-Z][a-zA-Z0-9]*));?")
+            .expect("valid regex")
+    });
 
-        (Some(BasicAuthConfig { realm, .. }), None) => (
-            StatusCode::UNAUTHORIZED,
-            [(
-                axum::http::header::WWW_AUTHENTICATE,
-                &format!(
-                    "Basic realm=\"{}\"",
-                    realm.as_deref().unwrap_or("mycoolblog")
-                ),
-            )],
-            "Need auth",
+    let decoded = REFERENCE.replace_all(url, |reference: &regex::Captures| {
+        let code = match (reference.get(1), reference.get(2), reference.get(3)) {
+            (Some(decimal), _, _) => decimal.as_str().parse().ok(),
+            (_, Some(hex), _) => u32::from_str_radix(hex.as_str(), 16).ok(),
+            (_, _, Some(name)) => match name.as_str() {
+                "colon" => Some(u32::from(':')),
+                "Tab" => Some(u32::from('\t')),
+                "NewLine" => Some(u32::from('\n')),
+                "sol" => Some(u32::from('/')),
+                "quest" => Some(u32::from('?')),
+                "num" => Some(u32::from('#')),
+                "amp" | "AMP" => Some(u32::from('&')),
+                // left alone, and caught below if it's before the path
+                _ => return String::from(&reference[0]),
+            },
+            _ => None,
+        };
+        String::from(
+            code.and_then(char::from_u32)
+                .unwrap_or(char::REPLACEMENT_CHARACTER),
         )
-            .into_response(),
+    });
+    let url = decoded
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>();
 
-        (None, _) => next.run(request).await,
+    let before_path = &url[..url.find(['/', '?', '#']).unwrap_or(url.len())];
+    match before_path.split_once(':') {
+        Some((scheme, _)) => schemes
+            .iter()
+            .any(|allowed| allowed.as_ref().eq_ignore_ascii_case(scheme)),
+        None => !before_path.contains('&'),
     }
 }
 
-#[tracing::instrument]
-async fn assets_handler(Path(item): Path<String>) -> Response {
-    // 1 year by default
-    macro_rules! response {
-        ($name:literal => $content_type:literal $file:literal) => {
-            response!($name => $content_type $file "max-age=31536000, immutable")
-        };
-
-        ($name:literal => $content_type:literal $file:literal $cache:literal) => {
-            if item == $name {
-                ::tracing::trace!(content_type = %$content_type, cache = %$cache);
+/// Only `<style>` blocks and `<link>` tags (with a small set of attributes, and an http or
+/// https `href`) survive, so a post's extra head content can't run scripts.
+fn sanitize_extra_head(raw: &str) -> Option<String> {
+    // browsers end a style at `</style` followed by a space, a slash, or `>`
+    static ELEMENT: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?is)<style(?:\s[^>]*)?>(.*?)</style(?:[\s/][^>]*)?>|<link(\s[^>]*)?/?>")
+            .expect("valid regex")
+    });
+    static ATTR: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"(?i)([a-z-]+)\s*=\s*("[^"]*"|'[^']*')"#).expect("valid regex")
+    });
+    const LINK_ATTRS: &[&str] = &["rel", "href", "type", "media", "crossorigin", "integrity"];
 
-                if cfg!(debug_assertions) {
-                    ::tracing::debug!("reading");
-                    return (
-                        [("Content-Type", $content_type)],
-                        ::tokio::fs::read(format!("frontend/{}", $file)).await.expect($file),
-                    )
-                        .into_response();
+    let elements = ELEMENT
+        .captures_iter(raw)
+        .filter_map(|element| {
+            if let Some(style) = element.get(1) {
+                // anything that looks like the end of it spells trouble
+                if style.as_str().to_ascii_lowercase().contains("</style") {
+                    return None;
                 }
-
-                return (
-                    [
-                        ("Content-Type", $content_type),
-                        ("Cache-Control", $cache),
-                    ],
-                    include_bytes!($file),
-                )
-                    .into_response();
+                Some(format!("<style>{}</style>", style.as_str()))
+            } else {
+                let attrs = element
+                    .get(2)
+                    .map(|attrs| attrs.as_str())
+                    .unwrap_or_default();
+                let attrs = ATTR
+                    .captures_iter(attrs)
+                    .filter(|attr| LINK_ATTRS.contains(&attr[1].to_lowercase().as_str()))
+                    .collect::<Vec<_>>();
+                let unsafe_href = attrs.iter().any(|attr| {
+                    let value = &attr[2][1..attr[2].len() - 1];
+                    attr[1].eq_ignore_ascii_case("href") && !is_safe_url(value, &["http", "https"])
+                });
+                if unsafe_href {
+                    return None;
+                }
+                let attrs = attrs
+                    .iter()
+                    .map(|attr| format!(" {}={}", attr[1].to_lowercase(), &attr[2]))
+                    .collect::<String>();
+                Some(format!("<link{attrs} />"))
             }
-        };
+        })
+        .collect::<Vec<_>>();
+
+    if elements.is_empty() {
+        None
+    } else {
+        Some(elements.join("\n"))
     }
+}
 
-    response!("post.css" => "text/css" "../frontend/post.css" "max-age=3600, must-revalidate");
-    response!("index.css" => "text/css" "../frontend/index.css" "max-age=3600, must-revalidate");
+/// Rendered content with only the allowed elements, attributes, and url schemes, for when
+/// content comes from people who shouldn't be able to run scripts on the blog. Elements that
+/// aren't allowed lose their tags but keep what's in them, except scripts and styles which go
+/// entirely.
+fn sanitize_html(html: &str, allowed: &SanitizeConfig) -> String {
+    static INVISIBLE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?is)<(script|style)\b.*?</(script|style)(?:[\s/][^>]*)?>|<!--.*?-->")
+            .expect("valid regex")
+    });
+    static TAG: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"<(/?)([a-zA-Z][a-zA-Z0-9-]*)((?:[^>"']|"[^"]*"|'[^']*')*)>"#)
+            .expect("valid regex")
+    });
+    static ATTR: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"([^\s"'>/=]+)(?:\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+)))?"#)
+            .expect("valid regex")
+    });
+    let escape_text = |text: &str| text.replace('<', "&lt;").replace('>', "&gt;");
+    let is = |list: &[String], name: &str| list.iter().any(|item| item.eq_ignore_ascii_case(name));
 
-    response!("apple-touch-icon.png" => "image/png" "../frontend/assets/apple-touch-icon.png");
-    response!("favicon-96x96.png" => "image/png" "../frontend/assets/favicon-96x96.png");
-    response!("favicon.ico" => "image/x-icon" "../frontend/assets/favicon.ico");
-    response!("favicon.svg" => "image/svg+xml" "../frontend/assets/favicon.svg");
-    response!("web-app-manifest-192x192.png" => "image/png" "../frontend/assets/web-app-manifest-192x192.png");
-    response!("web-app-manifest-512x512.png" => "image/png" "../frontend/assets/web-app-manifest-512x512.png");
+    let html = INVISIBLE.replace_all(html, "");
+    let mut out = String::with_capacity(html.len());
+    let mut last = 0;
+    for tag in TAG.captures_iter(&html) {
+        let whole = tag.get(0).expect("whole match");
+        out += &escape_text(&html[last..whole.start()]);
+        last = whole.end();
 
-    tracing::debug!("not found");
-    StatusCode::NOT_FOUND.into_response()
+        let name = tag[2].to_ascii_lowercase();
+        if !is(&allowed.tags, &name) {
+            continue;
+        }
+        if &tag[1] == "/" {
+            out += &format!("</{name}>");
+            continue;
+        }
+
+        out += &format!("<{name}");
+        for attr in ATTR.captures_iter(&tag[3]) {
+            let attr_name = attr[1].to_ascii_lowercase();
+            if attr_name.starts_with("on") || !is(&allowed.attributes, &attr_name) {
+                continue;
+            }
+            let value = attr
+                .get(2)
+                .or(attr.get(3))
+                .or(attr.get(4))
+                .map(|value| value.as_str());
+            let is_url = matches!(attr_name.as_str(), "href" | "src");
+            match value {
+                Some(value) if is_url && !is_safe_url(value, &allowed.url_schemes) => {}
+                Some(value) => {
+                    out += &format!(r#" {attr_name}="{}""#, value.replace('"', "&quot;"));
+                }
+                None => out += &format!(" {attr_name}"),
+            }
+        }
+        out += ">";
+    }
+    out += &escape_text(&html[last..]);
+    out
 }
 
+const DOT_DIR: &str = ".blog3";
+
 #[derive(Debug, serde::Deserialize)]
-struct Publish {
+struct Config {
+    page_root: String,
+    /// An address like `127.0.0.1:8000`, a socket path like `unix:/run/blog3.sock`, or a list of
+    /// either to listen on all of them
+    #[serde(deserialize_with = "deserialize_binds")]
+    bind: Vec<Bind>,
+    /// Permissions for sockets when binding to a path
+    #[serde(default = "default_socket_mode")]
+    socket_mode: u32,
+    /// Serve https on the addresses in `bind` instead of plain http. Socket paths stay plain,
+    /// since whatever's in front of them does TLS.
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    /// A path, or a `[database]` table with the path and connection settings
+    #[serde(deserialize_with = "deserialize_database")]
+    database: DatabaseConfig,
+    /// Create `database` when it doesn't exist, instead of stopping in case the path is a typo
+    #[serde(default = "default_create_database")]
+    create_database: bool,
     title: String,
     #[serde(default)]
-    subtitle: Option<String>,
-    content: String,
+    basic_auth: Option<BasicAuthConfig>,
+    /// Tokens API clients can send as `Authorization: Bearer <token>`, alongside or instead of
+    /// basic auth
     #[serde(default)]
-    draft: bool,
+    api_tokens: Vec<ApiToken>,
+    /// Log usernames in full rather than hashed
+    #[serde(default)]
+    log_usernames: bool,
+    /// `json` for one object per line instead of text
+    #[serde(default)]
+    log_format: LogFormat,
+    #[serde(default)]
+    access_log: AccessLogConfig,
+    /// `Cache-Control` for assets, media, pages, and feeds
+    #[serde(default)]
+    cache: CacheConfig,
+    /// Send the error itself in 500 responses instead of a page with only its id. On by
+    /// default in debug builds.
+    #[serde(default = "default_verbose_errors")]
+    verbose_errors: bool,
+    /// Tag each request with the `X-Request-Id` it came with, or a new one, in the logs and
+    /// the response
+    #[serde(default)]
+    request_ids: bool,
+    /// Largest request body the admin routes accept, in bytes
+    #[serde(default = "default_max_body_size")]
+    max_body_size: usize,
+    /// Send webmentions to the sites posts link to when they're published or updated. Needs
+    /// `base_url` to say where the posts are, and curl to make the requests. Only public
+    /// addresses are sent to.
+    #[serde(default)]
+    send_webmentions: bool,
+    /// Let visitors comment on posts, which show up once they're approved
+    #[serde(default)]
+    comments: bool,
+    /// Lets the blog be followed from the fediverse, when built with the `activitypub` feature.
+    /// Needs `base_url`, and curl to talk to other servers.
+    #[cfg(feature = "activitypub")]
+    #[serde(default)]
+    activitypub: Option<activitypub::ActivityPubConfig>,
+    /// Directory to keep uploaded files in, uploads are turned off without it
+    #[serde(default)]
+    media_dir: Option<PathBuf>,
+    /// Extensions uploaded files can have, which decide the type they're served as
+    #[serde(default = "default_media_extensions")]
+    media_extensions: Vec<String>,
+    /// Largest upload accepted, in bytes
+    #[serde(default = "default_max_upload_size")]
+    max_upload_size: usize,
+    /// Requests each client can make per minute
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    /// Addresses or CIDR ranges of proxies whose `X-Forwarded-For` says who the client is.
+    /// Connections over a Unix socket count as coming from one when this isn't empty.
+    #[serde(default)]
+    trusted_proxies: Vec<IpRange>,
+    /// Path segment the admin and asset routes live under
+    #[serde(default = "default_admin_prefix")]
+    admin_prefix: String,
+    /// `database.busy_timeout_ms`, from before there was a `[database]` table
+    #[serde(default)]
+    busy_timeout_ms: Option<u64>,
+    /// Gzip pages and feeds, which isn't needed behind a proxy that compresses them itself
+    #[serde(default = "default_compress_responses")]
+    compress_responses: bool,
+    /// Posts on each page of the index
+    #[serde(default = "default_posts_per_page")]
+    posts_per_page: usize,
+    /// Clean up post content before readers get it, in case someone who can publish shouldn't
+    /// be trusted with scripts
+    #[serde(default)]
+    sanitize_html: SanitizeConfig,
+    /// Colors for highlighted code blocks, `auto` to follow the reader's light or dark preference.
+    /// Also `light`, `dark`, `solarized-light`, or `solarized-dark`.
+    #[serde(default = "default_highlight_theme")]
+    highlight_theme: String,
+    /// Deepest heading level that goes in a post's table of contents, which starts at `<h2>`
+    #[serde(default = "default_toc_depth")]
+    toc_depth: u8,
+    /// Headings a post needs before it gets a table of contents
+    #[serde(default = "default_toc_min_headings")]
+    toc_min_headings: usize,
+    /// Posts linked as related at the bottom of each post page, 0 for none
+    #[serde(default = "default_related_posts")]
+    related_posts: usize,
+    /// Reading speed behind the `reading_time_minutes` templates get for each post
+    #[serde(default = "default_words_per_minute")]
+    words_per_minute: usize,
+    /// Length of the excerpt shown under each post on the index, 0 for none
+    #[serde(default)]
+    index_excerpt_chars: usize,
+    /// Rendered post pages to keep in memory for anonymous visitors, 0 to turn the cache off.
+    /// Always off in debug builds so template edits show up.
+    #[serde(default = "default_render_cache_entries")]
+    render_cache_entries: usize,
+    /// Directory to keep pre-rendered post pages in
+    #[serde(default)]
+    snapshot_dir: Option<PathBuf>,
+    /// Directory `POST /.blog3/backup` writes copies of the database to
+    #[serde(default)]
+    backup_dir: Option<PathBuf>,
+    /// Delete backups in `backup_dir` older than this many days after each new one
+    #[serde(default)]
+    backup_retention_days: Option<u32>,
+    /// Slugs a post keeps, including the canonical one, before its oldest redirects are deleted
+    #[serde(default)]
+    max_slugs_per_post: Option<usize>,
+    /// IANA name of the time zone posts are dated in, like `America/Chicago`. The server's own if
+    /// unset.
+    #[serde(default, deserialize_with = "deserialize_timezone")]
+    timezone: Option<chrono_tz::Tz>,
+    /// strftime format the `blogdate` template filter writes dates with
+    #[serde(default = "default_date_format")]
+    date_format: String,
+    /// Anything else for templates to use as `extra`, like footer text or social links
+    #[serde(default, deserialize_with = "deserialize_extra")]
+    extra: serde_json::Map<String, serde_json::Value>,
+    /// Let posts be published with dates in the future. They show up right away regardless.
+    #[serde(default)]
+    future_published: bool,
+    /// Characters of the title that go in a post's slug before the date, cut between words
+    #[serde(default = "default_slug_max_length")]
+    slug_max_length: usize,
+    /// Longest title a post can have, in characters
+    #[serde(default = "default_title_max_length")]
+    title_max_length: usize,
+    /// What a post gets when its slug is already another post's
+    #[serde(default)]
+    slug_collision: SlugCollision,
+    /// Who feeds name as the author, the blog title if unset
+    #[serde(default)]
+    author: Option<AuthorConfig>,
+    /// Contents of robots.txt, served as they are instead of the default that keeps crawlers out
+    /// of the admin pages
+    #[serde(default)]
+    robots: Option<String>,
+    /// File to read `robots` from instead
+    #[serde(default)]
+    robots_file: Option<PathBuf>,
+    /// Where the blog is reachable from outside, e.g. `https://example.com`, for feeds and
+    /// anything else that needs absolute urls. Doesn't include `page_root`.
+    #[serde(default)]
+    base_url: Option<String>,
+    /// Directory to serve assets from instead of the copy of frontend/assets built in, which
+    /// debug builds always read from disk
+    #[serde(default)]
+    assets: Option<PathBuf>,
+    /// Directory of templates to use over the built-in ones, and for posts to pick from with
+    /// `template`, which also lets SIGHUP reload them
+    #[serde(default)]
+    template_dir: Option<PathBuf>,
+    /// A directory in `themes_dir` whose templates and assets replace the default ones
+    #[serde(default = "default_theme")]
+    theme: String,
+    /// Where to find themes instead of the ones built in, which debug builds always read from
+    /// frontend/themes
+    #[serde(default)]
+    themes_dir: Option<PathBuf>,
+    /// Found from `theme` when the config is loaded
+    #[serde(skip)]
+    theme_files: theme::Theme,
+    /// Made from `robots` or `robots_file` when the config is loaded
+    #[serde(skip)]
+    robots_rendered: String,
+    /// When the config was loaded, since pages can look different after a reload even if their
+    /// post didn't change
+    #[serde(skip, default = "Utc::now")]
+    loaded: DateTime<Utc>,
 }
 
-#[tracing::instrument(skip_all)]
-async fn publish_handler(State(app): State<Arc<App>>, Json(to_publish): Json<Publish>) -> Response {
-    let post = Post {
-        id: Uuid::new_v4(),
-        title: to_publish.title,
-        subtitle: to_publish.subtitle,
-        published: Local::now().fixed_offset(),
-        content: to_publish.content,
-        draft: to_publish.draft,
-    };
+fn default_verbose_errors() -> bool {
+    cfg!(debug_assertions)
+}
 
-    tracing::debug!(new_post = ?post);
+fn default_theme() -> String {
+    String::from(theme::DEFAULT)
+}
 
-    let mut tx = match app.pool.begin().await {
-        Ok(tx) => tx,
-        Err(err) => return_500!(err, new_post_transaction),
-    };
+fn default_highlight_theme() -> String {
+    String::from("auto")
+}
 
-    if let Err(err) = app.insert_post(&mut *tx, &post).await {
-        return_500!(err, insert_post);
-    }
+fn default_toc_depth() -> u8 {
+    3
+}
 
-    // insert a slug
-    let slug = post.slug();
-    let posts_with_slug = match app.count_ids_with_similar_slugs(&mut *tx, &slug).await {
-        Ok(slug) => slug,
-        Err(err) => return_500!(err, new_post_slug),
-    };
+fn default_toc_min_headings() -> usize {
+    3
+}
 
-    let slug = if posts_with_slug > 0 {
-        format!("{slug}-{posts_with_slug}")
-    } else {
-        slug
-    };
+fn default_related_posts() -> usize {
+    3
+}
 
-    if let Err(err) = app.insert_slug(&mut *tx, &slug, post.id).await {
-        return_500!(err, insert_slug);
-    }
+fn default_words_per_minute() -> usize {
+    200
+}
 
-    if let Err(err) = tx.commit().await {
-        return_500!(err, new_post_transaction_commit);
-    }
+fn default_date_format() -> String {
+    String::from("%B %-d, %Y")
+}
 
-    Json(json!({ "id": post.id, "slug": slug })).into_response()
+fn default_slug_max_length() -> usize {
+    26
 }
 
-#[tracing::instrument(skip_all)]
+fn default_title_max_length() -> usize {
+    200
+}
+
+fn default_create_database() -> bool {
+    true
+}
+
+fn default_admin_prefix() -> String {
+    String::from(DOT_DIR)
+}
+
+fn default_posts_per_page() -> usize {
+    50
+}
+
+fn default_compress_responses() -> bool {
+    true
+}
+
+fn default_render_cache_entries() -> usize {
+    256
+}
+
+fn default_socket_mode() -> u32 {
+    0o660
+}
+
+fn default_max_body_size() -> usize {
+    4 * 1024 * 1024
+}
+
+/// Not svg, since scripts in one would run as the blog
+fn default_media_extensions() -> Vec<String> {
+    [
+        "png", "jpg", "jpeg", "gif", "webp", "avif", "mp4", "webm", "mp3", "ogg", "pdf",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_max_upload_size() -> usize {
+    32 * 1024 * 1024
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+fn deserialize_binds<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Bind>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Binds {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let binds = match <Binds as serde::Deserialize>::deserialize(deserializer)? {
+        Binds::One(bind) => vec![bind],
+        Binds::Many(binds) => binds,
+    };
+    if binds.is_empty() {
+        return Err(serde::de::Error::custom("bind needs at least one address"));
+    }
+
+    binds
+        .into_iter()
+        .map(|bind| {
+            Bind::try_from(bind.clone())
+                .map_err(|err| serde::de::Error::custom(format!("bind {bind:?}: {err}")))
+        })
+        .collect()
+}
+
+impl TryFrom<String> for Bind {
+    type Error = std::net::AddrParseError;
+
+    fn try_from(bind: String) -> Result<Bind, Self::Error> {
+        match bind.strip_prefix("unix:") {
+            Some(path) => Ok(Bind::Unix(PathBuf::from(path))),
+            None => Ok(Bind::Tcp(bind.parse()?)),
+        }
+    }
+}
+
+/// PEM files, read again on SIGHUP so renewed certificates get used without a restart
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+struct TlsConfig {
+    /// The certificate chain, the server's own first, like certbot's fullchain.pem
+    cert: PathBuf,
+    /// The RSA or P-256 key for it
+    key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+struct DatabaseConfig {
+    path: PathBuf,
+    /// How long a connection waits for another to finish writing before giving up with
+    /// `database is locked`, 5 seconds if unset
+    #[serde(default)]
+    busy_timeout_ms: Option<u64>,
+    #[serde(default)]
+    journal_mode: JournalMode,
+    #[serde(default)]
+    synchronous: Synchronous,
+    #[serde(default = "default_foreign_keys")]
+    foreign_keys: bool,
+    /// Connections the pool keeps open at most
+    #[serde(default = "default_max_connections")]
+    max_connections: u32,
+    /// How long a query waits for one of them to be free
+    #[serde(default = "default_acquire_timeout_ms")]
+    acquire_timeout_ms: u64,
+}
+
+const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+
+fn default_foreign_keys() -> bool {
+    true
+}
+
+fn default_max_connections() -> u32 {
+    10
+}
+
+fn default_acquire_timeout_ms() -> u64 {
+    30_000
+}
+
+impl DatabaseConfig {
+    fn new(path: PathBuf) -> DatabaseConfig {
+        DatabaseConfig {
+            path,
+            busy_timeout_ms: None,
+            journal_mode: JournalMode::default(),
+            synchronous: Synchronous::default(),
+            foreign_keys: default_foreign_keys(),
+            max_connections: default_max_connections(),
+            acquire_timeout_ms: default_acquire_timeout_ms(),
+        }
+    }
+}
+
+fn deserialize_timezone<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<chrono_tz::Tz>, D::Error> {
+    let Some(name) = <Option<String> as serde::Deserialize>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    match name.parse() {
+        Ok(timezone) => Ok(Some(timezone)),
+        Err(_) => Err(serde::de::Error::custom(format!(
+            "unknown timezone {name:?}, expected an IANA name like \"America/Chicago\""
+        ))),
+    }
+}
+
+fn deserialize_extra<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<serde_json::Map<String, serde_json::Value>, D::Error> {
+    // going through toml's own types, since its dates would come out as a table otherwise
+    fn to_json(value: toml::Value) -> serde_json::Value {
+        match value {
+            toml::Value::String(text) => serde_json::Value::String(text),
+            toml::Value::Integer(int) => serde_json::Value::from(int),
+            toml::Value::Float(float) => serde_json::Value::from(float),
+            toml::Value::Boolean(boolean) => serde_json::Value::Bool(boolean),
+            toml::Value::Datetime(datetime) => serde_json::Value::String(datetime.to_string()),
+            toml::Value::Array(items) => items.into_iter().map(to_json).collect(),
+            toml::Value::Table(table) => serde_json::Value::Object(
+                table
+                    .into_iter()
+                    .map(|(key, value)| (key, to_json(value)))
+                    .collect(),
+            ),
+        }
+    }
+
+    let table = <toml::Table as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(table
+        .into_iter()
+        .map(|(key, value)| (key, to_json(value)))
+        .collect())
+}
+
+fn deserialize_database<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<DatabaseConfig, D::Error> {
+    // by hand rather than an untagged enum, so mistakes in the table get their own errors
+    struct DatabaseVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for DatabaseVisitor {
+        type Value = DatabaseConfig;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a path, or a table with one")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, path: &str) -> Result<DatabaseConfig, E> {
+            Ok(DatabaseConfig::new(PathBuf::from(path)))
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(
+            self,
+            map: A,
+        ) -> Result<DatabaseConfig, A::Error> {
+            serde::Deserialize::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+        }
+    }
+
+    deserializer.deserialize_any(DatabaseVisitor)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    #[default]
+    Wal,
+    Off,
+}
+
+/// `normal` is the usual choice with WAL, where a power cut can lose the latest commits but won't
+/// corrupt anything
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Synchronous {
+    Off,
+    #[default]
+    Normal,
+    Full,
+    Extra,
+}
+
+/// What's left of post content when it's sanitized, which happens as it's rendered so the stored
+/// post stays as it was written and changes here apply to every post
+#[derive(Debug, serde::Deserialize)]
+struct SanitizeConfig {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "default_sanitize_tags")]
+    tags: Vec<String>,
+    /// Allowed on any allowed element. Event handlers like `onclick` never are.
+    #[serde(default = "default_sanitize_attributes")]
+    attributes: Vec<String>,
+    /// Schemes `href` and `src` can have, relative urls are always fine
+    #[serde(default = "default_sanitize_url_schemes")]
+    url_schemes: Vec<String>,
+}
+
+fn default_sanitize_tags() -> Vec<String> {
+    [
+        "a",
+        "abbr",
+        "b",
+        "blockquote",
+        "br",
+        "caption",
+        "cite",
+        "code",
+        "col",
+        "colgroup",
+        "dd",
+        "del",
+        "details",
+        "div",
+        "dl",
+        "dt",
+        "em",
+        "figcaption",
+        "figure",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "hr",
+        "i",
+        "img",
+        "ins",
+        "kbd",
+        "li",
+        "mark",
+        "ol",
+        "p",
+        "pre",
+        "q",
+        "s",
+        "samp",
+        "small",
+        "span",
+        "strong",
+        "sub",
+        "summary",
+        "sup",
+        "table",
+        "tbody",
+        "td",
+        "tfoot",
+        "th",
+        "thead",
+        "time",
+        "tr",
+        "u",
+        "ul",
+        "var",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_sanitize_attributes() -> Vec<String> {
+    [
+        "href", "src", "alt", "title", "width", "height", "class", "id", "colspan", "rowspan",
+        "datetime", "cite", "lang", "start", "open", "loading",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_sanitize_url_schemes() -> Vec<String> {
+    ["http", "https", "mailto"].map(String::from).to_vec()
+}
+
+impl Default for SanitizeConfig {
+    fn default() -> SanitizeConfig {
+        SanitizeConfig {
+            enabled: false,
+            tags: default_sanitize_tags(),
+            attributes: default_sanitize_attributes(),
+            url_schemes: default_sanitize_url_schemes(),
+        }
+    }
+}
+
+/// How long browsers can keep each kind of response. Pages and feeds don't get `Cache-Control`
+/// unless it's set here, so browsers check them with `ETag` or `Last-Modified` every time.
+/// Assets linked with their hash in the url are always kept for a year, since a change to one
+/// gives it a new url.
+#[derive(Debug, serde::Deserialize)]
+struct CacheConfig {
+    /// Stylesheets, which change along with the templates. Also used for the web app manifest,
+    /// and assets asked for with a hash they don't have anymore, which both change while their
+    /// url stays the same.
+    #[serde(default = "default_css_cache")]
+    css: CachePolicy,
+    /// Images in the assets and media directories
+    #[serde(default = "default_file_cache")]
+    images: CachePolicy,
+    /// Every other asset and media file, like scripts and fonts
+    #[serde(default = "default_file_cache")]
+    assets: CachePolicy,
+    /// The index, post, archive, and tag pages
+    #[serde(default)]
+    html_pages: Option<CachePolicy>,
+    /// RSS, Atom, and JSON feeds
+    #[serde(default)]
+    feeds: Option<CachePolicy>,
+}
+
+/// One `Cache-Control`, like `{ max_age = 3600, must_revalidate = true }`
+#[derive(Debug, Clone, serde::Deserialize)]
+struct CachePolicy {
+    /// Seconds
+    max_age: u32,
+    #[serde(default)]
+    immutable: bool,
+    #[serde(default)]
+    must_revalidate: bool,
+}
+
+fn default_css_cache() -> CachePolicy {
+    CachePolicy {
+        max_age: 60 * 60,
+        immutable: false,
+        must_revalidate: true,
+    }
+}
+
+fn default_file_cache() -> CachePolicy {
+    CachePolicy {
+        max_age: 365 * 24 * 60 * 60,
+        immutable: true,
+        must_revalidate: false,
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            css: default_css_cache(),
+            images: default_file_cache(),
+            assets: default_file_cache(),
+            html_pages: None,
+            feeds: None,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// The policy for an asset or media file of some content type
+    fn file(&self, content_type: &str) -> &CachePolicy {
+        if content_type == "text/css" {
+            &self.css
+        } else if content_type.starts_with("image/") {
+            &self.images
+        } else {
+            &self.assets
+        }
+    }
+
+    /// `Cache-Control` for a page, if it gets one. Pages for authors don't, since they change
+    /// as posts get edited.
+    fn html_page(&self, is_authenticated: bool) -> Option<[(&'static str, String); 1]> {
+        let policy = self.html_pages.as_ref().filter(|_| !is_authenticated)?;
+        Some([("Cache-Control", policy.header())])
+    }
+
+    /// `Cache-Control` for a feed, if it gets one
+    fn feed(&self) -> Option<[(&'static str, String); 1]> {
+        self.feeds
+            .as_ref()
+            .map(|policy| [("Cache-Control", policy.header())])
+    }
+}
+
+impl CachePolicy {
+    fn header(&self) -> String {
+        let mut header = format!("max-age={}", self.max_age);
+        if self.immutable {
+            header += ", immutable";
+        }
+        if self.must_revalidate {
+            header += ", must-revalidate";
+        }
+        header
+    }
+}
+
+/// A line for every request, logged as `blog3::access` so `RUST_LOG` can pick them out
+#[derive(Debug, serde::Deserialize)]
+struct AccessLogConfig {
+    #[serde(default = "default_access_log_enabled")]
+    enabled: bool,
+    /// Leave out health checks, which monitoring makes a lot of
+    #[serde(default)]
+    skip_healthz: bool,
+    /// Leave out assets and media, which come along with every page
+    #[serde(default)]
+    skip_assets: bool,
+}
+
+fn default_access_log_enabled() -> bool {
+    true
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> AccessLogConfig {
+        AccessLogConfig {
+            enabled: default_access_log_enabled(),
+            skip_healthz: false,
+            skip_assets: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// `suffix-number` for `slug-1`, `slug-2` and so on, `suffix-shortid` for `slug-` and the start
+/// of the post's id, or `reject` to refuse the post with a 409
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SlugCollision {
+    #[default]
+    SuffixNumber,
+    SuffixShortid,
+    Reject,
+}
+
+/// Limits are per client IP, and up to a minute's worth of requests can come all at once.
+/// Unset or 0 for no limit, except for comments.
+#[derive(Debug, serde::Deserialize)]
+struct RateLimitConfig {
+    #[serde(default)]
+    public: u32,
+    /// Everything behind auth, counted before credentials are checked
+    #[serde(default)]
+    admin: u32,
+    /// Comments submitted, on top of `public`
+    #[serde(default = "default_comment_rate_limit")]
+    comments: u32,
+}
+
+fn default_comment_rate_limit() -> u32 {
+    5
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            public: 0,
+            admin: 0,
+            comments: default_comment_rate_limit(),
+        }
+    }
+}
+
+/// An address with a prefix length, like `10.0.0.0/8` or `fd00::/8`. A bare address is a range
+/// of one.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(range: String) -> Result<IpRange, Self::Error> {
+        let (addr, prefix) = match range.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (range.as_str(), None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("{range:?}: {err}"))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.map(str::parse) {
+            None => bits,
+            Some(Ok(prefix)) if prefix <= bits => prefix,
+            Some(_) => return Err(format!("{range:?}: prefix isn't 0 to {bits}")),
+        };
+        Ok(IpRange { addr, prefix })
+    }
+}
+
+impl IpRange {
+    fn contains(&self, addr: IpAddr) -> bool {
+        let mask = |bits: u32| u128::MAX.checked_shl(bits - self.prefix).unwrap_or(0);
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => {
+                let mask = mask(32) as u32;
+                u32::from(range) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(addr)) => {
+                let mask = mask(128);
+                u128::from(range) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct BasicAuthConfig {
+    /// A single user, from before there could be several. Gets moved into `users`.
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    users: Vec<BasicAuthUser>,
+    realm: Option<String>,
+    /// Ranges the admin pages can be reached from at all, with any credentials. Empty for
+    /// anywhere.
+    #[serde(default)]
+    allow_ips: Vec<IpRange>,
+}
+
+#[derive(serde::Deserialize)]
+struct BasicAuthUser {
+    user: String,
+    #[serde(default)]
+    password: Option<String>,
+    /// An argon2id PHC string, as printed by `blog3 hash-password`
+    #[serde(default)]
+    password_hash: Option<String>,
+    /// Parsed from `password_hash` when the config is loaded
+    #[serde(skip)]
+    hash: Option<argon2::PasswordHash>,
+}
+
+/// Checked against when nobody has the username that was sent, so unknown users take as long
+/// to turn away as wrong passwords do
+static DUMMY_PASSWORD_HASH: LazyLock<argon2::PasswordHash> = LazyLock::new(|| {
+    argon2::PasswordHash::parse(
+        "$argon2id$v=19$m=19456,t=2,p=1$AAAAAAAAAAAAAAAAAAAAAA\
+         $AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+    )
+    .expect("valid placeholder hash")
+});
+
+/// How many users' credentials to remember having checked against their hashes
+const VERIFIED_PASSWORDS: usize = 64;
+
+#[derive(serde::Deserialize)]
+struct ApiToken {
+    /// Logged instead of the token
+    name: String,
+    #[serde(default)]
+    token: Option<String>,
+    /// Hex SHA-256 of the token, to keep the token itself out of the config
+    #[serde(default)]
+    token_sha256: Option<String>,
+    /// Filled in from whichever of the two is set when the config is loaded
+    #[serde(skip)]
+    digest: [u8; 32],
+}
+
+impl std::fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("name", &self.name)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("token_sha256", &self.token_sha256)
+            .finish()
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    use sha2::Digest;
+    sha2::Sha256::digest(data).into()
+}
+
+/// Who a request came from, going by its Authorization header. Authenticated requests carry it
+/// in their extensions.
+#[derive(Clone)]
+enum Caller {
+    Anonymous,
+    /// Sent credentials that didn't match, with what to tell them
+    Rejected(&'static str),
+    User(String),
+    Token(String),
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AuthorConfig {
+    name: String,
+    email: Option<String>,
+    uri: Option<String>,
+}
+
+impl std::fmt::Debug for BasicAuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuthConfig")
+            .field("users", &self.users)
+            .field("realm", &self.realm)
+            .field("allow_ips", &self.allow_ips)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for BasicAuthUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuthUser")
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("password_hash", &self.password_hash)
+            .finish()
+    }
+}
+
+impl Config {
+    /// Content as readers get it, sanitized if `sanitize_html` is on
+    fn render_content(&self, content_type: ContentType, content: &str) -> String {
+        let html = content_type.render(content);
+        match self.sanitize_html.enabled {
+            true => sanitize_html(&html, &self.sanitize_html),
+            false => html,
+        }
+    }
+
+    /// The stylesheet for `highlight_theme`
+    fn highlight_stylesheet(&self) -> String {
+        highlight::stylesheet(&self.highlight_theme).unwrap_or_default()
+    }
+
+    /// Now, in the blog's time zone
+    fn now(&self) -> DateTime<FixedOffset> {
+        self.local(Utc::now().fixed_offset())
+    }
+
+    /// The same instant in the blog's time zone
+    fn local(&self, date: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        match self.timezone {
+            Some(timezone) => date.with_timezone(&timezone).fixed_offset(),
+            None => date.with_timezone(&Local).fixed_offset(),
+        }
+    }
+
+    /// A date and time without an offset, taken to be in the blog's time zone. The earlier one
+    /// when it happens twice, and nothing when it's skipped over.
+    fn localize(&self, naive: chrono::NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+        use chrono::TimeZone;
+        match self.timezone {
+            Some(timezone) => timezone
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|date| date.fixed_offset()),
+            None => Local
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|date| date.fixed_offset()),
+        }
+    }
+
+    /// Read and check a config file, failing on anything that would stop the server starting
+    async fn load(path: &std::path::Path) -> Result<Config> {
+        let config = match tokio::fs::read_to_string(path).await {
+            Ok(config) => config,
+            Err(err) => fatal!("couldn't read config {}: {}", path.display(), err),
+        };
+        let mut config: Config = match toml::from_str(&config) {
+            Ok(config) => config,
+            Err(err) => fatal!("{}", err),
+        };
+        config.page_root = String::from("/") + config.page_root.trim_matches('/');
+        config.database.busy_timeout_ms = Some(
+            config
+                .database
+                .busy_timeout_ms
+                .or(config.busy_timeout_ms)
+                .unwrap_or(DEFAULT_BUSY_TIMEOUT_MS),
+        );
+
+        // page_root goes on the end of it, so it can't have a path of its own
+        if let Some(base_url) = config.base_url.as_deref() {
+            let parsed = url::Url::parse(base_url);
+            if !parsed
+                .as_ref()
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.host().is_some())
+            {
+                fatal!(
+                    "base_url {:?} must be an absolute http or https url",
+                    base_url
+                );
+            }
+            if base_url.ends_with('/') || parsed.is_ok_and(|url| url.path() != "/") {
+                fatal!(
+                    "base_url {:?} can't have a path or a trailing slash",
+                    base_url
+                );
+            }
+        }
+
+        if chrono::format::StrftimeItems::new(&config.date_format)
+            .parse()
+            .is_err()
+        {
+            fatal!(
+                "date_format {:?} isn't a strftime format",
+                config.date_format
+            );
+        }
+
+        if !highlight::THEMES.contains(&config.highlight_theme.as_str()) {
+            fatal!(
+                "highlight_theme {:?} isn't one of {}",
+                config.highlight_theme,
+                highlight::THEMES.join(", ")
+            );
+        }
+
+        let themes_dir = match config.themes_dir.clone() {
+            Some(themes_dir) => Some(themes_dir),
+            None if cfg!(debug_assertions) => Some(PathBuf::from("frontend/themes")),
+            None => None,
+        };
+        config.theme_files = match theme::Theme::find(&config.theme, themes_dir.as_deref()) {
+            Ok(theme_files) => theme_files,
+            Err(err) => fatal!("{}", err),
+        };
+
+        if !(2..=6).contains(&config.toc_depth) {
+            fatal!("toc_depth must be between 2 and 6");
+        }
+
+        if config.words_per_minute == 0 {
+            fatal!("words_per_minute must be at least 1");
+        }
+
+        if config.slug_max_length == 0 {
+            fatal!("slug_max_length must be at least 1");
+        }
+
+        if config.admin_prefix.is_empty()
+            || config.admin_prefix == "."
+            || config.admin_prefix == ".."
+            || !config
+                .admin_prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        {
+            fatal!(
+                "admin_prefix {:?} must be a single path segment of letters, digits, '.', '-', or '_'",
+                config.admin_prefix
+            );
+        }
+
+        let robots = match config.robots_file.as_ref() {
+            Some(robots_file) => match tokio::fs::read_to_string(robots_file).await {
+                Ok(robots) => Some(robots),
+                Err(err) => fatal!(
+                    "couldn't read robots_file {}: {}",
+                    robots_file.display(),
+                    err
+                ),
+            },
+            None => config.robots.clone(),
+        };
+        config.robots_rendered = config.robots_txt(robots.as_deref());
+
+        if let Some(basic_auth) = config.basic_auth.as_mut() {
+            match (
+                basic_auth.user.take(),
+                basic_auth.password.take(),
+                basic_auth.password_hash.take(),
+            ) {
+                (Some(user), password, password_hash)
+                    if password.is_some() || password_hash.is_some() =>
+                {
+                    let user = BasicAuthUser {
+                        user,
+                        password,
+                        password_hash,
+                        hash: None,
+                    };
+                    basic_auth.users.insert(0, user)
+                }
+                (None, None, None) => {}
+                _ => fatal!("basic_auth needs both user and password or password_hash"),
+            }
+
+            if basic_auth.users.is_empty() {
+                fatal!("basic_auth needs at least one user");
+            }
+            for (i, user) in basic_auth.users.iter().enumerate() {
+                if basic_auth.users[..i]
+                    .iter()
+                    .any(|other| other.user == user.user)
+                {
+                    fatal!("basic_auth user {:?} is listed twice", user.user);
+                }
+            }
+
+            for user in basic_auth.users.iter_mut() {
+                match (&user.password, &user.password_hash) {
+                    (Some(_), None) => tracing::warn!(
+                        user = %user.user,
+                        "plaintext basic_auth password, `blog3 hash-password` makes a \
+                         password_hash to use instead"
+                    ),
+                    (None, Some(password_hash)) => {
+                        user.hash = match argon2::PasswordHash::parse(password_hash) {
+                            Some(hash) => Some(hash),
+                            None => fatal!("password_hash of {:?} isn't argon2id", user.user),
+                        }
+                    }
+                    _ => fatal!(
+                        "basic_auth user {:?} needs one of password or password_hash",
+                        user.user
+                    ),
+                }
+            }
+        }
+
+        for api_token in config.api_tokens.iter_mut() {
+            api_token.digest = match (&api_token.token, &api_token.token_sha256) {
+                (Some(token), None) => sha256(token.as_bytes()),
+                (None, Some(hex)) => {
+                    let digest = (0..hex.len())
+                        .step_by(2)
+                        .map(|i| hex.get(i..i + 2))
+                        .map(|byte| byte.and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+                        .collect::<Option<Vec<u8>>>()
+                        .and_then(|digest| <[u8; 32]>::try_from(digest).ok());
+                    match digest {
+                        Some(digest) => digest,
+                        None => fatal!("token_sha256 of {:?} isn't 64 hex digits", api_token.name),
+                    }
+                }
+                _ => fatal!(
+                    "api token {:?} needs one of token or token_sha256",
+                    api_token.name
+                ),
+            };
+        }
+
+        if config.send_webmentions && !curl_installed() {
+            fatal!("send_webmentions needs curl installed");
+        }
+
+        #[cfg(feature = "activitypub")]
+        if config.activitypub.is_some() && config.base_url.is_none() {
+            fatal!("activitypub needs base_url");
+        }
+        #[cfg(feature = "activitypub")]
+        if config.activitypub.is_some() && !curl_installed() {
+            fatal!("activitypub needs curl installed");
+        }
+
+        Ok(config)
+    }
+
+    /// Undo changes to settings that are only read at startup, rather than apply them halfway
+    fn keep_startup_settings(&mut self, current: &Config) {
+        macro_rules! keep {
+            ($($field:ident),*) => {$(
+                if self.$field != current.$field {
+                    tracing::warn!(setting = stringify!($field), "changing this requires restart");
+                    self.$field = current.$field.clone();
+                }
+            )*};
+        }
+
+        keep!(
+            bind,
+            socket_mode,
+            tls,
+            database,
+            page_root,
+            admin_prefix,
+            snapshot_dir,
+            template_dir,
+            log_format
+        );
+        #[cfg(feature = "activitypub")]
+        keep!(activitypub);
+    }
+
+    /// Usernames are only logged as a short hash unless `log_usernames` is set, which is still
+    /// enough to tell users apart.
+    fn loggable_user(&self, user: &str) -> String {
+        use std::hash::{DefaultHasher, Hash, Hasher};
+
+        if self.log_usernames {
+            return String::from(user);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        user.hash(&mut hasher);
+        format!("user#{:08x}", hasher.finish() as u32)
+    }
+
+    fn route(&self, child: &str) -> String {
+        if self.page_root == "/" {
+            String::from(child)
+        } else {
+            self.page_root.clone() + child
+        }
+    }
+
+    /// The route as an absolute URL if `base_url` is set, otherwise the same as `route`
+    fn absolute(&self, child: &str) -> String {
+        match self.base_url.as_deref() {
+            Some(base_url) => String::from(base_url) + &self.route(child),
+            None => self.route(child),
+        }
+    }
+
+    /// `absolute` for `route_dot`
+    fn absolute_dot(&self, child: &str) -> String {
+        match self.base_url.as_deref() {
+            Some(base_url) => String::from(base_url) + &self.route_dot(child),
+            None => self.route_dot(child),
+        }
+    }
+
+    /// A url that might be a path on this site, like the ones media uploads get without a base
+    /// url, made absolute if possible. Paths already have the page root in them.
+    fn absolute_url(&self, url: &str) -> String {
+        match self.base_url.as_deref() {
+            Some(base_url) if url.starts_with('/') && !url.starts_with("//") => {
+                String::from(base_url) + url
+            }
+            _ => String::from(url),
+        }
+    }
+
+    /// `custom` as it is, or by default everything but the admin pages allowed, pointing at the
+    /// sitemap
+    fn robots_txt(&self, custom: Option<&str>) -> String {
+        if let Some(custom) = custom {
+            return String::from(custom);
+        }
+
+        let mut robots = String::from("User-agent: *\n");
+        for admin in [
+            self.route_dot("/publish"),
+            self.route_dot("/preview"),
+            self.route_dot("/delete"),
+            self.route_dot("/unpublish"),
+            self.route_dot("/republish"),
+            self.route_dot("/revisions"),
+            self.route_dot("/api/"),
+            self.route_dot("/stats"),
+            self.route_dot("/posts"),
+            self.route("/drafts"),
+            self.route("/new"),
+            self.route("/edit"),
+            self.route("/*/edit"),
+        ] {
+            robots += &format!("Disallow: {admin}\n");
+        }
+        robots += "Allow: /\n";
+        // has to be an absolute url
+        if self.base_url.is_some() {
+            robots += &format!("\nSitemap: {}\n", self.absolute("/sitemap.xml"));
+        }
+        robots
+    }
+
+    fn route_dot(&self, child: &str) -> String {
+        if self.page_root == "/" {
+            String::from("/") + &self.admin_prefix + child
+        } else {
+            self.page_root.clone() + "/" + &self.admin_prefix + child
+        }
+    }
+}
+
+/// Paths under page_root as routes get registered, keeping their first segments so posts can't
+/// have them as slugs
+struct RouteNames<'a> {
+    config: &'a Config,
+    names: std::cell::RefCell<std::collections::HashSet<String>>,
+}
+
+impl RouteNames<'_> {
+    fn new(config: &Config) -> RouteNames<'_> {
+        RouteNames {
+            config,
+            names: Default::default(),
+        }
+    }
+
+    /// `Config::route`, remembering `child`'s first segment unless it's a path parameter
+    fn route(&self, child: &str) -> String {
+        if let Some(name) = child.trim_start_matches('/').split('/').next()
+            && !name.is_empty()
+            && !name.starts_with('{')
+        {
+            self.names.borrow_mut().insert(String::from(name));
+        }
+        self.config.route(child)
+    }
+
+    /// `Config::route_dot`, remembering the admin prefix
+    fn route_dot(&self, child: &str) -> String {
+        self.names
+            .borrow_mut()
+            .insert(self.config.admin_prefix.clone());
+        self.config.route_dot(child)
+    }
+}
+
+struct App {
+    /// Swapped out whole when SIGHUP reloads the config file
+    config: std::sync::RwLock<Arc<Config>>,
+    pool: SqlitePool,
+    tera: RwLock<Tera>,
+    render_cache: std::sync::Mutex<RenderCache>,
+    asset_hashes: std::sync::RwLock<Arc<AssetHashes>>,
+    rate_limiter: std::sync::Mutex<RateLimiter>,
+    /// Digests of credentials that matched a password_hash, since hashing is slow on purpose
+    verified_passwords: std::sync::Mutex<std::collections::HashSet<[u8; 32]>>,
+    /// Signs CSRF tokens, so they last until the server restarts
+    csrf_key: [u8; 32],
+    /// For the uptime in the health check
+    started: std::time::Instant,
+    /// Post views by day that haven't been written to the database yet
+    views: std::sync::Mutex<HashMap<(Uuid, chrono::NaiveDate), i64>>,
+    /// Names of the routes under page_root, filled in once they're registered
+    reserved_slugs: std::sync::OnceLock<std::collections::HashSet<String>>,
+    /// Held while a backup runs, so there's only ever one
+    backing_up: tokio::sync::Mutex<()>,
+    /// The certificate for `tls`, swapped on SIGHUP so new connections get the renewed one
+    tls: Option<Arc<std::sync::RwLock<Arc<tls::Identity>>>>,
+    #[cfg(feature = "activitypub")]
+    activitypub_keys: Option<activitypub::Keys>,
+    /// Counts changes to templates and stylesheets, for pages to reload on
+    #[cfg(debug_assertions)]
+    live_reload: tokio::sync::watch::Sender<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RateScope {
+    Public,
+    Admin,
+    Comment,
+}
+
+/// A token bucket for each client in each scope, which fill back up over a minute
+struct RateLimiter {
+    buckets: HashMap<(RateScope, Option<IpAddr>), Bucket>,
+    swept: std::time::Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: std::time::Instant,
+}
+
+/// Every bucket is full again after this long, which is the same as not having one
+const RATE_LIMIT_REFILL: Duration = Duration::from_secs(60);
+
+impl RateLimiter {
+    fn new() -> RateLimiter {
+        RateLimiter {
+            buckets: HashMap::new(),
+            swept: std::time::Instant::now(),
+        }
+    }
+
+    /// Take a request out of the client's bucket, or say how long until one is available
+    fn take(
+        &mut self,
+        scope: RateScope,
+        client: Option<IpAddr>,
+        per_minute: u32,
+    ) -> std::result::Result<(), Duration> {
+        let now = std::time::Instant::now();
+        if now - self.swept >= RATE_LIMIT_REFILL {
+            self.buckets
+                .retain(|_, bucket| now - bucket.updated < RATE_LIMIT_REFILL);
+            self.swept = now;
+        }
+
+        let capacity = per_minute as f64;
+        let per_second = capacity / RATE_LIMIT_REFILL.as_secs_f64();
+        let bucket = self.buckets.entry((scope, client)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        bucket.tokens += (now - bucket.updated).as_secs_f64() * per_second;
+        bucket.tokens = bucket.tokens.min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// Where a connection came from, before looking at `X-Forwarded-For`. Unix socket peers don't
+/// have an address.
+#[derive(Clone, Copy)]
+struct Peer(Option<IpAddr>);
+
+impl axum::extract::connect_info::Connected<axum::serve::IncomingStream<'_, TcpListener>> for Peer {
+    fn connect_info(stream: axum::serve::IncomingStream<'_, TcpListener>) -> Peer {
+        Peer(Some(stream.remote_addr().ip().to_canonical()))
+    }
+}
+
+impl axum::extract::connect_info::Connected<axum::serve::IncomingStream<'_, tls::Listener>>
+    for Peer
+{
+    fn connect_info(stream: axum::serve::IncomingStream<'_, tls::Listener>) -> Peer {
+        Peer(Some(stream.remote_addr().ip().to_canonical()))
+    }
+}
+
+impl
+    axum::extract::connect_info::Connected<
+        axum::serve::IncomingStream<'_, tokio::net::UnixListener>,
+    > for Peer
+{
+    fn connect_info(_: axum::serve::IncomingStream<'_, tokio::net::UnixListener>) -> Peer {
+        Peer(None)
+    }
+}
+
+/// `render_cache_entries`, except debug builds never cache
+fn render_cache_entries(config: &Config) -> usize {
+    if cfg!(debug_assertions) {
+        0
+    } else {
+        config.render_cache_entries
+    }
+}
+
+/// A post page as anonymous visitors see it
+#[derive(Clone)]
+struct CachedPage {
+    id: Uuid,
+    html: axum::body::Bytes,
+    etag: String,
+    last_modified: DateTime<Utc>,
+}
+
+/// Rendered post pages by canonical slug, so repeat views skip the database and templates. When
+/// full, the page that went longest without a view makes room.
+struct RenderCache {
+    capacity: usize,
+    pages: HashMap<String, (CachedPage, u64)>,
+    /// Counts lookups, to know which page was used least recently
+    clock: u64,
+    /// Bumped whenever pages are dropped, so a render of what a post used to be doesn't get
+    /// cached after the post changed
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl RenderCache {
+    fn new(capacity: usize) -> RenderCache {
+        RenderCache {
+            capacity,
+            pages: HashMap::new(),
+            clock: 0,
+            generation: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, slug: &str) -> Option<CachedPage> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        self.clock += 1;
+        let page = self.pages.get_mut(slug).map(|(page, last_used)| {
+            *last_used = self.clock;
+            page.clone()
+        });
+
+        if page.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        tracing::debug!(
+            render_cache_hit = page.is_some(),
+            hits = self.hits,
+            misses = self.misses
+        );
+
+        page
+    }
+
+    /// Keep a page rendered from what the database held at `generation`
+    fn insert(&mut self, slug: &str, page: CachedPage, generation: u64) {
+        if self.capacity == 0 || generation != self.generation {
+            return;
+        }
+
+        if self.pages.len() >= self.capacity && !self.pages.contains_key(slug) {
+            let oldest = self
+                .pages
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(slug, _)| slug.clone());
+            if let Some(oldest) = oldest {
+                tracing::trace!(render_cache_evict = %oldest);
+                self.pages.remove(&oldest);
+            }
+        }
+
+        self.pages.insert(String::from(slug), (page, self.clock));
+    }
+
+    /// Start over with room for `capacity` pages
+    fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.generation += 1;
+        self.pages.clear();
+    }
+
+    /// Drop every page, since a change to one post can show up on the pages of the posts around it
+    fn invalidate(&mut self) {
+        self.generation += 1;
+        self.pages.clear();
+    }
+}
+
+impl App {
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Re-read the config file, and the templates if they come from a directory. Either one
+    /// failing to load leaves everything as it was.
+    async fn reload(&self, path: &std::path::Path) {
+        // tls is only set up at startup, so this is the same cert and key, maybe renewed
+        if let (Some(tls), Some(identity)) = (self.config().tls.as_ref(), self.tls.as_ref()) {
+            match load_tls(tls).await {
+                Ok(reloaded) => {
+                    *identity.write().unwrap() = Arc::new(reloaded);
+                    info!("reloaded {}", tls.cert.display());
+                }
+                Err(err) => tracing::error!(reload_tls = %err, "keeping the current certificate"),
+            }
+        }
+
+        let mut config = match Config::load(path).await {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!(reload_config = %err, "keeping the current config");
+                return;
+            }
+        };
+        config.keep_startup_settings(&self.config());
+
+        // the theme can change too, so templates get loaded again even if they're built in
+        let mut tera = match load_templates(&config) {
+            Ok(tera) => tera,
+            Err(err) => {
+                tracing::error!(reload_templates = ?err, "keeping the current config");
+                return;
+            }
+        };
+
+        // after the swap, so nothing rendered with the old config gets cached
+        let render_cache_entries = render_cache_entries(&config);
+        let asset_hashes = hash_assets(&config);
+        let config = Arc::new(config);
+        register_filters(&mut tera, config.clone());
+        *self.tera.write().await = tera;
+        *self.asset_hashes.write().unwrap() = Arc::new(asset_hashes);
+        *self.config.write().unwrap() = config;
+        self.render_cache
+            .lock()
+            .unwrap()
+            .resize(render_cache_entries);
+
+        info!("reloaded {}", path.display());
+    }
+
+    /// Who a request is from. When the connection is from a trusted proxy that's the last
+    /// address in `X-Forwarded-For` that isn't one too.
+    fn client_ip(&self, request: &axum::extract::Request) -> Option<IpAddr> {
+        let config = self.config();
+        let peer = request
+            .extensions()
+            .get::<axum::extract::ConnectInfo<Peer>>()
+            .and_then(|info| info.0.0);
+
+        let trusted = |addr: IpAddr| {
+            config
+                .trusted_proxies
+                .iter()
+                .any(|range| range.contains(addr))
+        };
+        if config.trusted_proxies.is_empty() || peer.is_some_and(|peer| !trusted(peer)) {
+            return peer;
+        }
+
+        // proxies append to the end, so everything before the first untrusted one from the
+        // right could have been made up by the client
+        let mut client = peer;
+        for forwarded in request.headers().get_all("x-forwarded-for").iter().rev() {
+            let Ok(forwarded) = forwarded.to_str() else {
+                return client;
+            };
+            for hop in forwarded.rsplit(',') {
+                let Ok(addr) = hop.trim().parse::<IpAddr>() else {
+                    return client;
+                };
+                client = Some(addr.to_canonical());
+                if !trusted(addr) {
+                    return client;
+                }
+            }
+        }
+        client
+    }
+
+    /// Check credentials without challenging. Always false when no auth is configured, so
+    /// anonymous visitors never get admin links. Never hashes a password either, since public
+    /// pages would make that free for anyone to ask for, so someone with a `password_hash` is
+    /// only recognized once the admin pages have checked their password.
+    async fn is_authenticated(&self, headers: &HeaderMap) -> bool {
+        matches!(
+            self.identify(headers, false).await,
+            Caller::User(_) | Caller::Token(_)
+        )
+    }
+
+    async fn caller(&self, headers: &HeaderMap) -> Caller {
+        self.identify(headers, true).await
+    }
+
+    /// Who sent `headers`, hashing the password if `may_hash` and it hasn't been verified yet
+    async fn identify(&self, headers: &HeaderMap, may_hash: bool) -> Caller {
+        use subtle::ConstantTimeEq;
+
+        let config = self.config();
+
+        if let Some(basic) = headers.typed_get::<Authorization<Basic>>() {
+            let users = config
+                .basic_auth
+                .as_ref()
+                .map(|basic_auth| basic_auth.users.as_slice())
+                .unwrap_or_default();
+
+            let username = sha256(basic.username().as_bytes());
+            let user = users.iter().fold(None, |found, user| {
+                let equal = bool::from(sha256(user.user.as_bytes()).ct_eq(&username));
+                found.or(equal.then_some(user))
+            });
+
+            let matched = match user {
+                Some(user) => self
+                    .check_password(user, basic.password(), may_hash)
+                    .await
+                    .then_some(user),
+                None => {
+                    if may_hash && users.iter().any(|user| user.hash.is_some()) {
+                        self.check_hash(&DUMMY_PASSWORD_HASH, basic.password())
+                            .await;
+                    }
+                    None
+                }
+            };
+
+            return match matched {
+                Some(user) => Caller::User(user.user.clone()),
+                None => {
+                    tracing::debug!(unsuccessful_basic = %config.loggable_user(basic.username()));
+                    Caller::Rejected("Incorrect username/password")
+                }
+            };
+        }
+
+        if let Some(bearer) = headers.typed_get::<Authorization<Bearer>>() {
+            // compare digests so every comparison takes as long, whatever the token's length
+            let digest = sha256(bearer.token().as_bytes());
+            let matched = config.api_tokens.iter().fold(None, |matched, api_token| {
+                let equal = bool::from(api_token.digest.ct_eq(&digest));
+                matched.or(equal.then_some(api_token))
+            });
+
+            return match matched {
+                Some(api_token) => Caller::Token(api_token.name.clone()),
+                None => {
+                    // the same token logs the same way, so repeated attempts are recognizable
+                    let short = digest[..4]
+                        .iter()
+                        .map(|b| format!("{b:02x}"))
+                        .collect::<String>();
+                    tracing::debug!(unsuccessful_token = %format!("token#{short}"));
+                    Caller::Rejected("Incorrect token")
+                }
+            };
+        }
+
+        Caller::Anonymous
+    }
+
+    /// What the admin pages have to send back with forms, which is the same for every page a
+    /// user loads in one browser session
+    fn csrf_token(&self, caller: Option<&Caller>, session: &str) -> String {
+        use hmac::Mac;
+
+        let identity = match caller {
+            Some(Caller::User(user)) => user.as_str(),
+            _ => "",
+        };
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.csrf_key)
+            .expect("hmac takes any key length");
+        mac.update(identity.as_bytes());
+        mac.update(&[0]);
+        mac.update(session.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// The browser session csrf tokens are tied to, from the cookie if one was sent. Otherwise
+    /// a new one, along with the `Set-Cookie` header that starts it.
+    fn csrf_session(&self, headers: &HeaderMap) -> (String, Option<String>) {
+        if let Some(session) = csrf_session_cookie(headers) {
+            return (session, None);
+        }
+
+        let config = self.config();
+        let session = Uuid::new_v4().simple().to_string();
+        let secure = match config.base_url.as_deref() {
+            Some(base_url) if base_url.starts_with("https:") => "; Secure",
+            _ => "",
+        };
+        let cookie = format!(
+            "{CSRF_COOKIE}={session}; Path={}; HttpOnly; SameSite=Strict{secure}",
+            config.page_root,
+        );
+        (session, Some(cookie))
+    }
+
+    async fn check_password(&self, user: &BasicAuthUser, password: &str, may_hash: bool) -> bool {
+        use subtle::ConstantTimeEq;
+
+        match (&user.hash, &user.password) {
+            (Some(hash), _) => {
+                let memo = sha256(
+                    [
+                        user.user.as_bytes(),
+                        password.as_bytes(),
+                        user.password_hash.as_deref().unwrap_or_default().as_bytes(),
+                    ]
+                    .join(&0)
+                    .as_slice(),
+                );
+                if self.verified_passwords.lock().unwrap().contains(&memo) {
+                    return true;
+                } else if !may_hash {
+                    return false;
+                }
+
+                let verified = self.check_hash(hash, password).await;
+                if verified {
+                    let mut verified_passwords = self.verified_passwords.lock().unwrap();
+                    if verified_passwords.len() >= VERIFIED_PASSWORDS {
+                        verified_passwords.clear();
+                    }
+                    verified_passwords.insert(memo);
+                }
+                verified
+            }
+            (None, Some(expected)) => sha256(password.as_bytes())
+                .ct_eq(&sha256(expected.as_bytes()))
+                .into(),
+            (None, None) => false,
+        }
+    }
+
+    /// A transaction that takes the write lock up front. One that reads before writing would
+    /// fail with `database is locked` instead of waiting out the busy timeout if another
+    /// connection wrote in between, since what it read could be stale by then.
+    async fn begin_write(&self) -> sqlx::Result<sqlx::Transaction<'static, sqlx::Sqlite>> {
+        self.pool.begin_with("begin immediate").await
+    }
+
+    /// Hashing takes a while, so it happens on the blocking pool instead of the async workers
+    async fn check_hash(&self, hash: &argon2::PasswordHash, password: &str) -> bool {
+        let hash = hash.clone();
+        let password = password.to_owned();
+        tokio::task::spawn_blocking(move || hash.verify(password.as_bytes()))
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn render_post(&self, post: Post, slug: &str, is_authenticated: bool) -> Result<String> {
+        let mut conn = self.pool.acquire().await?;
+        let tags = self.post_tags(&mut conn, post.id).await?;
+        let comments = self.approved_comments(&mut conn, post.id).await?;
+        let links = self.post_links(&mut conn, &post).await?;
+        drop(conn);
+        self.render_post_page(post, slug, &tags, &comments, &links, is_authenticated)
+            .await
+    }
+
+    /// render_post without going to the database, for posts that might not be in it
+    async fn render_post_page(
+        &self,
+        post: Post,
+        slug: &str,
+        tags: &[String],
+        comments: &[Comment],
+        links: &PostLinks,
+        is_authenticated: bool,
+    ) -> Result<String> {
+        let description = post.description();
+        let image = post
+            .image
+            .as_deref()
+            .map(|image| self.config().absolute_url(image));
+
+        // big posts take a moment to render
+        let (content_type, content_raw) = (post.content_type, post.content.clone());
+        let config = self.config();
+        let ((content_html, mut toc), (word_count, reading_time_minutes)) =
+            tokio::task::spawn_blocking(move || {
+                let rendered = config.render_content(content_type, &content_raw);
+                (
+                    anchor_headings(&rendered, config.toc_depth),
+                    reading_time(&content_raw, content_type, config.words_per_minute),
+                )
+            })
+            .await?;
+
+        fn count(entries: &[TocEntry]) -> usize {
+            entries.iter().map(|entry| 1 + count(&entry.children)).sum()
+        }
+        if count(&toc) < self.config().toc_min_headings {
+            toc.clear();
+        }
+
+        let mut context = self.context();
+        context.insert("description", &description);
+        context.insert("image", &image);
+        context.insert("content_raw", &post.content);
+        context.insert("content_html", &content_html);
+        context.insert("toc", &toc);
+        let (published_local, updated_local) = post.local_times(&self.config());
+        context.insert("post", &post);
+        context.insert("published_local", &published_local);
+        context.insert("updated_local", &updated_local);
+        context.insert("word_count", &word_count);
+        context.insert("reading_time_minutes", &reading_time_minutes);
+        context.insert("tags", tags);
+        context.insert("comments", comments);
+        context.insert("related_posts", &links.related);
+        context.insert("prev_post", &links.prev);
+        context.insert("next_post", &links.next);
+        context.insert("comments_enabled", &self.config().comments);
+        context.insert("slug", slug);
+        context.insert("is_authenticated", &is_authenticated);
+
+        // a template that was there when the post was published can be gone after a reload
+        let template = match post.template.as_deref() {
+            Some(template) if self.has_template(template).await => template,
+            Some(template) => {
+                tracing::warn!(missing_template = template, post = %post.id);
+                POST_TEMPLATE
+            }
+            None => POST_TEMPLATE,
+        };
+        self.render(template, &context).await
+    }
+
+    /// Identifies how a post page looks, from what it was rendered from
+    fn post_etag(
+        &self,
+        post: &Post,
+        comments: &CommentSummary,
+        links_updated: Option<DateTime<FixedOffset>>,
+        is_authenticated: bool,
+    ) -> String {
+        use std::hash::{DefaultHasher, Hash, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        post.id.hash(&mut hasher);
+        post.updated.hash(&mut hasher);
+        comments.hash(&mut hasher);
+        links_updated.hash(&mut hasher);
+        is_authenticated.hash(&mut hasher);
+        self.config().loaded.hash(&mut hasher);
+
+        // templates get reloaded on every render in debug builds, so check them too
+        #[cfg(debug_assertions)]
+        {
+            let config = self.config();
+            let theme_dir = match &config.theme_files {
+                theme::Theme::Dir(dir) => Some(dir.clone()),
+                _ => None,
+            };
+            for dir in template_dir(&config).into_iter().chain(theme_dir) {
+                let Ok(entries) = std::fs::read_dir(dir) else {
+                    continue;
+                };
+                let mut templates = entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| {
+                        path.extension()
+                            .is_some_and(|extension| extension == "tera")
+                    })
+                    .collect::<Vec<_>>();
+                templates.sort();
+                for template in templates {
+                    std::fs::read(&template)
+                        .unwrap_or_default()
+                        .hash(&mut hasher);
+                }
+            }
+        }
+
+        format!("\"{:016x}\"", hasher.finish())
+    }
+
+    fn snapshot_path(&self, slug: &str) -> Option<PathBuf> {
+        self.config()
+            .snapshot_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{slug}.html")))
+    }
+
+    /// The snapshot of a post, unless it's missing or older than the post itself or anything it
+    /// links to
+    async fn read_snapshot(
+        &self,
+        post: &Post,
+        comments: &CommentSummary,
+        links_updated: Option<DateTime<FixedOffset>>,
+        slug: &str,
+    ) -> Option<String> {
+        let path = self.snapshot_path(slug)?;
+
+        let modified: DateTime<Utc> = tokio::fs::metadata(&path)
+            .await
+            .ok()?
+            .modified()
+            .ok()?
+            .into();
+        let older = |date: Option<DateTime<FixedOffset>>| date.is_some_and(|date| modified < date);
+        if modified < post.updated || older(comments.latest) || older(links_updated) {
+            tracing::debug!(stale_snapshot = %path.display());
+            return None;
+        }
+
+        tokio::fs::read_to_string(&path).await.ok()
+    }
+
+    /// Bring a post's snapshot and cached pages in line with what was just committed. Failures
+    /// are only logged, since post_handler renders live when the snapshot is missing or stale.
+    #[tracing::instrument(skip(self, post), fields(post = %post.id))]
+    async fn refresh_snapshot(&self, post: Post, slug: &str) {
+        self.render_cache.lock().unwrap().invalidate();
+
+        let Some(path) = self.snapshot_path(slug) else {
+            return;
+        };
+
+        if post.draft || post.deleted.is_some() {
+            tracing::debug!(remove_snapshot = %path.display());
+            if let Err(err) = tokio::fs::remove_file(&path).await
+                && err.kind() != std::io::ErrorKind::NotFound
+            {
+                tracing::error!(remove_snapshot = ?err);
+            }
+            return;
+        }
+
+        let rendered = match self.render_post(post, slug, false).await {
+            Ok(rendered) => rendered,
+            Err(err) => {
+                tracing::error!(render_snapshot = ?err);
+                return;
+            }
+        };
+
+        // write then rename so a reader never sees half a page
+        let partial = path.with_extension("html.partial");
+        if let Err(err) = tokio::fs::write(&partial, rendered).await {
+            tracing::error!(write_snapshot = ?err);
+        } else if let Err(err) = tokio::fs::rename(&partial, &path).await {
+            tracing::error!(rename_snapshot = ?err);
+        } else {
+            tracing::trace!(wrote_snapshot = %path.display());
+        }
+    }
+
+    /// What every page's context starts with
+    fn context(&self) -> Context {
+        let config = self.config();
+        let mut context = Context::new();
+        context.insert("blog_title", &config.title);
+        context.insert("page_root", &config.page_root);
+        context.insert("admin_prefix", &config.admin_prefix);
+        context.insert("extra", &config.extra);
+        context
+    }
+
+    #[tracing::instrument(skip(self, context))]
+    async fn render(&self, template_name: &str, context: &Context) -> Result<String> {
+        #[cfg(debug_assertions)]
+        {
+            tracing::debug!("reloading");
+            self.reload_templates().await?;
+        }
+
+        let mut context = context.clone();
+        context.insert("assets", &self.asset_urls());
+        context.insert("base_url", &self.config().base_url);
+
+        tracing::trace!("rendering");
+        let page = self.tera.read().await.render(template_name, &context)?;
+        #[cfg(debug_assertions)]
+        let page = live_reload::inject(page, &self.config().route_dot(live_reload::PATH));
+        Ok(page)
+    }
+
+    /// Read the templates from disk again, which debug builds do before every render
+    #[cfg(debug_assertions)]
+    async fn reload_templates(&self) -> tera::Result<()> {
+        let mut tera = load_templates(&self.config())?;
+        register_filters(&mut tera, self.config());
+        *self.tera.write().await = tera;
+        Ok(())
+    }
+
+    /// Whether there's a template by that name to render a post with
+    async fn has_template(&self, name: &str) -> bool {
+        #[cfg(debug_assertions)]
+        if let Err(err) = self.reload_templates().await {
+            tracing::warn!(reload_templates = ?err);
+        }
+
+        self.tera.read().await.get_template(name).is_ok()
+    }
+
+    /// The 404 page for a path, or just text if it doesn't render
+    async fn not_found(&self, path: &str) -> Response {
+        let mut context = self.context();
+        context.insert("path", path);
+
+        let headers = [("Cache-Control", "public, max-age=60")];
+        match self.render(NOT_FOUND_TEMPLATE, &context).await {
+            Ok(page) => (StatusCode::NOT_FOUND, headers, Html(page)).into_response(),
+            Err(err) => {
+                tracing::warn!(render_not_found = ?err);
+                (StatusCode::NOT_FOUND, headers, "not found").into_response()
+            }
+        }
+    }
+
+    /// Hashes of the assets as of startup or the last reload, or as they are now in debug builds
+    /// so edits show up
+    fn asset_hashes(&self) -> Arc<AssetHashes> {
+        if cfg!(debug_assertions) {
+            return Arc::new(hash_assets(&self.config()));
+        }
+        self.asset_hashes.read().unwrap().clone()
+    }
+
+    /// Where templates should link each asset, like `post.css` at
+    /// `/.blog3/assets/post.1a2b3c4d.css`
+    fn asset_urls(&self) -> HashMap<String, String> {
+        let config = self.config();
+        self.asset_hashes()
+            .iter()
+            .map(|(path, hash)| {
+                let url = config.route_dot(&format!("/assets/{}", hashed_asset_path(path, hash)));
+                (path.clone(), url)
+            })
+            .collect()
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    init_logging();
+    match std::env::args().nth(1).as_deref() {
+        Some("hash-password") => hash_password().unwrap(),
+        Some("backup") => backup::run().await.unwrap(),
+        Some("export") => export::run().await.unwrap(),
+        Some("import-md") => import_md::run().await.unwrap(),
+        _ => run().await.unwrap(),
+    }
+}
+
+/// Commands that come before the config path instead of it
+const SUBCOMMANDS: &[&str] = &["hash-password", "backup", "export", "import-md"];
+
+/// The config path is the first argument, or the second after a subcommand
+fn config_path_arg() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    match args.next() {
+        Some(arg) if SUBCOMMANDS.contains(&arg.as_str()) => args.next().map(PathBuf::from),
+        arg => arg.map(PathBuf::from),
+    }
+}
+
+/// These are per-connection settings, so every connection a pool opens gets them
+fn connect_options(config: &Config) -> SqliteConnectOptions {
+    let database = &config.database;
+    let busy_timeout_ms = database.busy_timeout_ms.unwrap_or(DEFAULT_BUSY_TIMEOUT_MS);
+
+    SqliteConnectOptions::new()
+        .filename(&database.path)
+        .journal_mode(match database.journal_mode {
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+            JournalMode::Memory => SqliteJournalMode::Memory,
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Off => SqliteJournalMode::Off,
+        })
+        .synchronous(match database.synchronous {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        })
+        .foreign_keys(database.foreign_keys)
+        .busy_timeout(Duration::from_millis(busy_timeout_ms))
+}
+
+fn pool_options(config: &Config) -> SqlitePoolOptions {
+    SqlitePoolOptions::new()
+        .max_connections(config.database.max_connections)
+        .acquire_timeout(Duration::from_millis(config.database.acquire_timeout_ms))
+}
+
+/// Connect to `database`, creating it first if it's missing and `create_database` allows it. The
+/// migrations then give a new one its schema.
+async fn connect_or_create(config: &Config) -> Result<SqlitePool> {
+    let path = &config.database.path;
+    let missing = !tokio::fs::try_exists(path).await?;
+    if missing {
+        if !config.create_database {
+            fatal!(
+                "{} doesn't exist, and create_database is off",
+                path.display()
+            );
+        }
+
+        let parent = match path.parent() {
+            Some(parent) if parent != std::path::Path::new("") => parent,
+            _ => std::path::Path::new("."),
+        };
+        if !tokio::fs::metadata(parent)
+            .await
+            .is_ok_and(|parent| parent.is_dir())
+        {
+            fatal!(
+                "can't create the database at {}, {} isn't a directory",
+                path.display(),
+                parent.display()
+            );
+        }
+    }
+
+    let options = connect_options(config).create_if_missing(missing);
+    match pool_options(config).connect_with(options).await {
+        Ok(pool) => {
+            if missing {
+                info!("created new database at {}", path.display());
+            }
+            Ok(pool)
+        }
+        Err(err) if missing => fatal!(
+            "couldn't create the database at {}: {}",
+            path.display(),
+            err
+        ),
+        Err(err) => Err(err.into()),
+    }
+}
+
+static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!();
+
+/// Bring the schema up to date with migrations/. A migration that changed after it was applied
+/// stops startup, since the database then doesn't have the schema the migration says it does.
+async fn migrate(pool: &SqlitePool, config: &Config) -> Result<()> {
+    use sqlx::migrate::{Migrate, MigrateError};
+
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect::<Vec<_>>();
+    drop(conn);
+
+    match MIGRATOR.run(pool).await {
+        Ok(()) => {}
+        Err(MigrateError::VersionMismatch(version)) => fatal!(
+            "migration {} has changed since it was applied to {}",
+            version,
+            config.database.path.display()
+        ),
+        Err(err) => fatal!(
+            "couldn't migrate {}: {}",
+            config.database.path.display(),
+            err
+        ),
+    }
+
+    for migration in MIGRATOR.iter() {
+        if !applied.contains(&migration.version) {
+            info!(migration = migration.version, description = %migration.description, "applied");
+        }
+    }
+    Ok(())
+}
+
+/// Log to stderr, filtered by `RUST_LOG` like `blog3=debug,info`. The config is only fully
+/// loaded once logging has started, so errors in it get logged, which means peeking at
+/// `log_format` here. A config that doesn't parse logs as text.
+fn init_logging() {
+    use tracing_subscriber::{
+        filter::{LevelFilter, Targets},
+        layer::SubscriberExt,
+        util::SubscriberInitExt,
+    };
+
+    #[derive(serde::Deserialize)]
+    struct LogConfig {
+        #[serde(default)]
+        log_format: LogFormat,
+    }
+
+    let log_format = config_path_arg()
+        .and_then(|config_path| std::fs::read_to_string(config_path).ok())
+        .and_then(|config| toml::from_str::<LogConfig>(&config).ok())
+        .map(|config| config.log_format)
+        .unwrap_or_default();
+
+    let targets = match std::env::var("RUST_LOG") {
+        Ok(rust_log) => rust_log.parse::<Targets>().unwrap_or_else(|err| {
+            eprintln!("ignoring RUST_LOG={rust_log:?}: {err}");
+            Targets::new().with_default(LevelFilter::INFO)
+        }),
+        Err(_) => Targets::new().with_default(LevelFilter::INFO),
+    };
+
+    let builder = tracing_subscriber::fmt().with_max_level(LevelFilter::TRACE);
+    match log_format {
+        LogFormat::Text => builder.finish().with(targets).init(),
+        LogFormat::Json => builder
+            .fmt_fields(json_log::JsonFields)
+            .event_format(json_log::JsonFormat)
+            .finish()
+            .with(targets)
+            .init(),
+    }
+}
+
+/// `blog3 hash-password`: read a password from the first line of stdin and print a
+/// password_hash for it
+fn hash_password() -> Result<()> {
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.strip_suffix('\n').unwrap_or(&password);
+    let password = password.strip_suffix('\r').unwrap_or(password);
+    if password.is_empty() {
+        fatal!("no password on stdin");
+    }
+
+    println!("{}", argon2::hash(password.as_bytes())?);
+    Ok(())
+}
+
+const POST_TEMPLATE: &str = "post.html.tera";
+const INDEX_TEMPLATE: &str = "index.html.tera";
+const EDIT_TEMPLATE: &str = "edit.html.tera";
+const NEW_TEMPLATE: &str = "new.html.tera";
+const ARCHIVE_TEMPLATE: &str = "archive.html.tera";
+const DIFF_TEMPLATE: &str = "diff.html.tera";
+const NOT_FOUND_TEMPLATE: &str = "404.html.tera";
+const ERROR_TEMPLATE: &str = "error.html.tera";
+
+async fn run() -> Result<()> {
+    let Some(config_path) = config_path_arg() else {
+        fatal!("missing config path filename");
+    };
+    let app = Arc::new(load_app(&config_path).await?);
+    let binds = app.config().bind.clone();
+
+    tokio::spawn(reload_on_sighup(app.clone(), config_path));
+    tokio::spawn(flush_views_periodically(app.clone()));
+    #[cfg(debug_assertions)]
+    tokio::spawn(live_reload::watch(app.clone()));
+
+    let service = tower::util::MapRequestLayer::new(strip_trailing_slash)
+        .layer(router(&app))
+        .into_make_service_with_connect_info::<Peer>();
+
+    enum Listener {
+        Tcp(TcpListener),
+        Tls(tls::Listener),
+        Unix(tokio::net::UnixListener, PathBuf),
+    }
+
+    // everything gets bound before anything gets served, so a bad address stops startup
+    let mut listeners = Vec::new();
+    for bind in binds {
+        listeners.push(match bind {
+            Bind::Tcp(addr) => match (TcpListener::bind(addr).await, &app.tls) {
+                (Ok(listener), None) => Listener::Tcp(listener),
+                (Ok(listener), Some(identity)) => {
+                    Listener::Tls(tls::Listener::new(listener, identity.clone())?)
+                }
+                (Err(err), _) => fatal!("couldn't bind to {}: {}", addr, err),
+            },
+            Bind::Unix(path) => {
+                Listener::Unix(bind_unix(&path, app.config().socket_mode).await?, path)
+            }
+        });
+    }
+
+    let (shutdown, _) = tokio::sync::watch::channel(false);
+    let mut servers = tokio::task::JoinSet::new();
+    for listener in listeners {
+        let service = service.clone();
+        let mut shutdown = shutdown.subscribe();
+        let stopped = async move {
+            let _ = shutdown.wait_for(|&stop| stop).await;
+        };
+
+        match listener {
+            Listener::Tcp(listener) => {
+                info!("serving on {}", listener.local_addr()?);
+                servers.spawn(async move {
+                    axum::serve(listener, service)
+                        .with_graceful_shutdown(stopped)
+                        .await
+                });
+            }
+
+            Listener::Tls(listener) => {
+                info!(
+                    "serving https on {}",
+                    axum::serve::Listener::local_addr(&listener)?
+                );
+                servers.spawn(async move {
+                    axum::serve(listener, service)
+                        .with_graceful_shutdown(stopped)
+                        .await
+                });
+            }
+
+            Listener::Unix(listener, path) => {
+                info!("serving on unix:{}", path.display());
+                servers.spawn(async move {
+                    let served = axum::serve(listener, service)
+                        .with_graceful_shutdown(stopped)
+                        .await;
+
+                    tracing::debug!(removing_socket = %path.display());
+                    if let Err(err) = tokio::fs::remove_file(&path).await {
+                        tracing::error!(remove_socket = %err);
+                    }
+                    served
+                });
+            }
+        }
+    }
+
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown.send(true);
+    });
+
+    while let Some(served) = servers.join_next().await {
+        served??;
+    }
+    app.flush_views().await;
+
+    Ok(())
+}
+
+/// The certificate and key `tls` points to
+async fn load_tls(tls: &TlsConfig) -> Result<tls::Identity> {
+    let cert = match tokio::fs::read_to_string(&tls.cert).await {
+        Ok(cert) => cert,
+        Err(err) => fatal!("couldn't read tls cert {}: {}", tls.cert.display(), err),
+    };
+    let key = match tokio::fs::read_to_string(&tls.key).await {
+        Ok(key) => key,
+        Err(err) => fatal!("couldn't read tls key {}: {}", tls.key.display(), err),
+    };
+    match tls::Identity::from_pem(&cert, &key) {
+        Ok(identity) => Ok(identity),
+        Err(err) => fatal!(
+            "couldn't use tls cert {} with key {}: {:#}",
+            tls.cert.display(),
+            tls.key.display(),
+            err
+        ),
+    }
+}
+
+/// Everything the server needs besides its sockets, with the database ready to use
+async fn load_app(config_path: &std::path::Path) -> Result<App> {
+    let config = Config::load(config_path).await?;
+
+    info!("{:#?}", config);
+
+    if let Some(snapshot_dir) = config.snapshot_dir.as_ref() {
+        tokio::fs::create_dir_all(snapshot_dir).await?;
+    }
+
+    let pool = connect_or_create(&config).await?;
+    migrate(&pool, &config).await?;
+    #[cfg(feature = "activitypub")]
+    let activitypub_keys = match config.activitypub.as_ref() {
+        Some(activitypub) => Some(activitypub::Keys::load(activitypub, &pool).await?),
+        None => None,
+    };
+    let tls = match config.tls.as_ref() {
+        Some(tls) => Some(Arc::new(std::sync::RwLock::new(Arc::new(
+            load_tls(tls).await?,
+        )))),
+        None => None,
+    };
+
+    let app = App {
+        render_cache: std::sync::Mutex::new(RenderCache::new(render_cache_entries(&config))),
+        asset_hashes: std::sync::RwLock::new(Arc::new(hash_assets(&config))),
+        rate_limiter: std::sync::Mutex::new(RateLimiter::new()),
+        verified_passwords: Default::default(),
+        views: Default::default(),
+        reserved_slugs: Default::default(),
+        backing_up: Default::default(),
+        tls,
+        started: std::time::Instant::now(),
+        csrf_key: [Uuid::new_v4().into_bytes(), Uuid::new_v4().into_bytes()]
+            .concat()
+            .try_into()
+            .expect("two uuids are 32 bytes"),
+        #[cfg(feature = "activitypub")]
+        activitypub_keys,
+        #[cfg(debug_assertions)]
+        live_reload: tokio::sync::watch::channel(0).0,
+        pool,
+        tera: match load_templates(&config) {
+            Ok(tera) => RwLock::new(tera),
+            Err(err) => fatal!("couldn't load templates: {:?}", err),
+        },
+        config: std::sync::RwLock::new(Arc::new(config)),
+    };
+
+    register_filters(&mut *app.tera.write().await, app.config());
+
+    app.migrate_old_revisions().await?;
+
+    Ok(app)
+}
+
+/// Every route, which also decides which slugs are reserved
+fn router(app: &Arc<App>) -> Router {
+    // everything behind auth gets these, with a bigger body limit for uploads
+    let admin_layers = |router: Router<Arc<App>>, body_limit: BodyLimit| {
+        router
+            .layer(axum::middleware::from_fn_with_state(
+                app.clone(),
+                csrf_layer,
+            ))
+            // body_limit_layer applies its own limit, instead of the extractors' 2MB
+            .layer(axum::extract::DefaultBodyLimit::disable())
+            .layer(axum::middleware::from_fn_with_state(
+                (app.clone(), body_limit),
+                body_limit_layer,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                app.clone(),
+                basic_auth_layer,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                app.clone(),
+                allow_ips_layer,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                (app.clone(), RateScope::Admin),
+                rate_limit_layer,
+            ))
+            .with_state(app.clone())
+    };
+
+    // everything registered under page_root, so posts can be kept from having the same names
+    let config = app.config();
+    let routes = RouteNames::new(&config);
+
+    let authed_router = Router::new()
+        .route(&routes.route_dot("/publish"), post(publish_handler))
+        .route(&routes.route_dot("/preview"), post(preview_handler))
+        .route(
+            &routes.route_dot("/publish/{update}"),
+            post(update_handler).patch(patch_handler),
+        )
+        .route(&routes.route_dot("/delete/{id}"), post(delete_handler))
+        .route(
+            &routes.route_dot("/unpublish/{id}"),
+            post(unpublish_handler),
+        )
+        .route(
+            &routes.route_dot("/republish/{id}"),
+            post(republish_handler),
+        )
+        .route(&routes.route_dot("/revisions/{id}"), get(revisions_handler))
+        .route(
+            &routes.route_dot("/revisions/{id}/diff"),
+            get(revision_diff_handler),
+        )
+        .route(
+            &routes.route_dot("/revisions/{id}/restore/{revision}"),
+            post(restore_handler),
+        )
+        .route(&routes.route_dot("/posts"), get(posts_handler))
+        .route(&routes.route_dot("/posts/{id}"), get(post_json_handler))
+        .route(&routes.route_dot("/api/slugs"), get(slugs_handler))
+        .route(
+            &routes.route_dot("/redirects"),
+            get(redirects_handler).post(create_redirect_handler),
+        )
+        .route(
+            &routes.route_dot("/redirects/{from}"),
+            delete(delete_redirect_handler),
+        )
+        .route(&routes.route_dot("/api/changes"), get(api_changes_handler))
+        .route(
+            &routes.route_dot("/api/webmentions"),
+            get(webmentions_handler),
+        )
+        .route(&routes.route_dot("/api/comments"), get(comments_handler))
+        .route(&routes.route_dot("/stats"), get(stats_handler))
+        .route(&routes.route_dot("/backup"), post(backup_handler))
+        .route(
+            &routes.route_dot("/api/comments/{id}"),
+            delete(delete_comment_handler),
+        )
+        .route(
+            &routes.route_dot("/api/comments/{id}/approve"),
+            post(approve_comment_handler),
+        )
+        .route(
+            &routes.route_dot("/api/comments/{id}/reject"),
+            post(reject_comment_handler),
+        )
+        .route(
+            &routes.route_dot("/api/slug-history/{id}"),
+            get(slug_history_handler),
+        )
+        .route(&routes.route("/drafts"), get(drafts_handler))
+        .route(&routes.route("/new"), get(new_handler))
+        .route(&routes.route("/edit"), get(edit_handler))
+        .route(&routes.route("/edit/{page}"), get(edit_handler))
+        .route(&routes.route("/{page}/edit"), get(edit_handler));
+    let authed_router = admin_layers(authed_router, BodyLimit::Body);
+
+    let upload_router =
+        Router::new().route(&routes.route_dot("/media"), post(media_upload_handler));
+    let upload_router = admin_layers(upload_router, BodyLimit::Upload);
+
+    let mut unauthed_router = Router::new()
+        .route(&routes.route_dot("/assets/{*path}"), get(assets_handler))
+        .route(&routes.route_dot("/media/{name}"), get(media_handler))
+        .route(&app.config().page_root, get(index_handler))
+        .route(&routes.route_dot("/feed.xml"), get(feed_handler))
+        .route(&routes.route_dot("/atom.xml"), get(atom_handler))
+        .route(&routes.route_dot("/feed.json"), get(json_feed_handler))
+        .route(&routes.route("/updated.xml"), get(updated_feed_handler))
+        .route(&routes.route("/sitemap.xml"), get(sitemap_handler))
+        .route(&routes.route("/{year}/{month}"), get(archive_month_handler))
+        .route(&routes.route("/tag/{tag}"), get(tag_handler))
+        .route("/robots.txt", get(robots_handler))
+        .route(
+            &routes.route("/{slug}"),
+            get(post_handler).merge(post(comment_handler).layer(
+                axum::middleware::from_fn_with_state(
+                    (app.clone(), RateScope::Comment),
+                    rate_limit_layer,
+                ),
+            )),
+        )
+        .route(&routes.route(MANIFEST), get(manifest_handler));
+    for icon in ROOT_ICONS {
+        let route = routes.route(&format!("/{icon}"));
+        unauthed_router = unauthed_router.route(&route, get(root_icon_handler));
+    }
+    if app.config().page_root != "/" {
+        for icon in DOMAIN_ROOT_ICONS {
+            unauthed_router = unauthed_router.route(&format!("/{icon}"), get(root_icon_handler));
+        }
+    }
+    let unauthed_router = unauthed_router
+        .layer(axum::middleware::from_fn_with_state(
+            (app.clone(), RateScope::Public),
+            rate_limit_layer,
+        ))
+        .with_state(app.clone());
+
+    // kept out of rate limiting so monitoring can't get locked out
+    let health_router = Router::new()
+        .route(&routes.route_dot("/healthz"), get(healthz_handler))
+        .with_state(app.clone());
+
+    #[cfg(debug_assertions)]
+    let live_reload_router = Router::new()
+        .route(
+            &routes.route_dot(live_reload::PATH),
+            get(live_reload::handler),
+        )
+        .with_state(app.clone());
+
+    app.reserved_slugs
+        .set(routes.names.into_inner())
+        .expect("routes only get registered once");
+
+    #[allow(unused_mut)]
+    let mut router = Router::new()
+        .merge(authed_router)
+        .merge(upload_router)
+        .merge(unauthed_router)
+        .merge(health_router);
+    #[cfg(feature = "activitypub")]
+    if app.config().activitypub.is_some() {
+        router = router.merge(activitypub::router(app.clone()));
+    }
+    #[cfg(debug_assertions)]
+    {
+        router = router.merge(live_reload_router);
+    }
+
+    router
+        .fallback(fallback_handler)
+        .with_state(app.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            app.clone(),
+            error_page_layer,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            app.clone(),
+            compression_layer,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            app.clone(),
+            access_log_layer,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            app.clone(),
+            request_id_layer,
+        ))
+}
+
+/// `{{ date | blogdate }}` in templates, which writes a date in the blog's time zone with
+/// `date_format`, or `blogdate(format="...")`. Anything that isn't a date is left as it is, so one
+/// odd value doesn't take the whole page down.
+struct BlogDate(Arc<Config>);
+
+impl tera::Filter for BlogDate {
+    fn filter(
+        &self,
+        value: &tera::Value,
+        args: &HashMap<String, tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        let Some(date) = value
+            .as_str()
+            .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+        else {
+            return Ok(value.clone());
+        };
+        let format = args
+            .get("format")
+            .and_then(|format| format.as_str())
+            .unwrap_or(&self.0.date_format);
+
+        // a bad format from a template fails when it's written rather than when it's parsed
+        use std::fmt::Write;
+        let mut formatted = String::new();
+        if write!(formatted, "{}", self.0.local(date).format(format)).is_err() {
+            formatted = date.to_rfc3339();
+        }
+        Ok(tera::Value::String(formatted))
+    }
+}
+
+/// `{{ text | markdown }}` in templates, which renders the text like a Markdown post's content.
+/// Nothing comes out for a missing value, and anything other than text is left as it is.
+struct MarkdownFilter(Arc<Config>);
+
+impl tera::Filter for MarkdownFilter {
+    fn filter(
+        &self,
+        value: &tera::Value,
+        _: &HashMap<String, tera::Value>,
+    ) -> tera::Result<tera::Value> {
+        match value {
+            tera::Value::Null => Ok(tera::Value::String(String::new())),
+            tera::Value::String(text) if text.trim().is_empty() => {
+                Ok(tera::Value::String(String::new()))
+            }
+            tera::Value::String(text) => Ok(tera::Value::String(
+                self.0.render_content(ContentType::Markdown, text),
+            )),
+            other => Ok(other.clone()),
+        }
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// Filters that depend on the config, registered again whenever it changes, including whenever
+/// debug builds load the templates again
+fn register_filters(tera: &mut Tera, config: Arc<Config>) {
+    tera.register_filter("blogdate", BlogDate(config.clone()));
+    tera.register_filter("markdown", MarkdownFilter(config));
+}
+
+/// The templates in frontend, as they were when the binary was built
+fn builtin_templates() -> tera::Result<Tera> {
+    let mut tera = Tera::default();
+    // the default only covers names ending in .html
+    tera.autoescape_on(vec![".html.tera"]);
+    tera.add_raw_templates([
+        (
+            "macros.html.tera",
+            include_str!("../frontend/macros.html.tera"),
+        ),
+        (POST_TEMPLATE, include_str!("../frontend/post.html.tera")),
+        (INDEX_TEMPLATE, include_str!("../frontend/index.html.tera")),
+        (EDIT_TEMPLATE, include_str!("../frontend/edit.html.tera")),
+        (NEW_TEMPLATE, include_str!("../frontend/new.html.tera")),
+        (
+            ARCHIVE_TEMPLATE,
+            include_str!("../frontend/archive.html.tera"),
+        ),
+        (DIFF_TEMPLATE, include_str!("../frontend/diff.html.tera")),
+        (
+            NOT_FOUND_TEMPLATE,
+            include_str!("../frontend/404.html.tera"),
+        ),
+        (ERROR_TEMPLATE, include_str!("../frontend/error.html.tera")),
+    ])?;
+    Ok(tera)
+}
+
+/// The directory to read the default theme's templates from, or None for the ones built in.
+/// Debug builds always read them from disk so edits show up without restarting.
+fn template_dir(config: &Config) -> Option<PathBuf> {
+    match config.template_dir.clone() {
+        Some(dir) => Some(dir),
+        None if cfg!(debug_assertions) => Some(PathBuf::from("frontend")),
+        None => None,
+    }
+}
+
+/// Every template `config` renders with. The theme's come first, then the ones in
+/// `template_dir`, then the built-in ones, so each only needs the templates it changes or adds.
+fn load_templates(config: &Config) -> tera::Result<Tera> {
+    let mut tera = builtin_templates()?;
+    if let Some(template_dir) = template_dir(config) {
+        tera = templates_over(&template_dir, &tera)?;
+    }
+    match &config.theme_files {
+        theme::Theme::Default => {}
+        theme::Theme::Dir(dir) => tera = templates_over(dir, &tera)?,
+        theme::Theme::Embedded(theme) => tera.add_raw_templates(theme.templates.iter().copied())?,
+    }
+    tera.check_macro_files()?;
+    Ok(tera)
+}
+
+/// The templates in `dir`, with the ones in `base` filling in for any it doesn't have
+fn templates_over(dir: &std::path::Path, base: &Tera) -> tera::Result<Tera> {
+    let mut tera = Tera::parse(&format!("{}/*.tera", dir.display()))?;
+    tera.autoescape_on(vec![".html.tera"]);
+    // also builds the inheritance chains, which parse doesn't
+    tera.extend(base)?;
+    Ok(tera)
+}
+
+async fn reload_on_sighup(app: Arc<App>, config_path: PathBuf) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            tracing::error!(sighup_handler = %err);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("got SIGHUP, reloading");
+        app.reload(&config_path).await;
+    }
+}
+
+async fn flush_views_periodically(app: Arc<App>) {
+    let mut interval = tokio::time::interval(VIEW_FLUSH);
+    loop {
+        interval.tick().await;
+        app.flush_views().await;
+    }
+}
+
+/// Listen on a socket path, replacing the file left behind by a server that didn't shut down
+/// cleanly, but not one that's still running
+async fn bind_unix(path: &std::path::Path, mode: u32) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if tokio::net::UnixStream::connect(path).await.is_ok() {
+        fatal!("something is already listening on {}", path.display());
+    }
+
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => tracing::debug!(removed_stale_socket = %path.display()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => fatal!("couldn't remove stale socket {}: {}", path.display(), err),
+    }
+
+    let listener = match tokio::net::UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => fatal!("couldn't bind to {}: {}", path.display(), err),
+    };
+
+    let permissions = std::fs::Permissions::from_mode(mode);
+    if let Err(err) = tokio::fs::set_permissions(path, permissions).await {
+        fatal!("couldn't set permissions on {}: {}", path.display(), err);
+    }
+
+    Ok(listener)
+}
+
+/// Ctrl-C or SIGTERM, whichever comes first
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::error!(sigterm_handler = %err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("shutting down");
+}
+
+fn strip_trailing_slash<B>(mut req: Request<B>) -> Request<B> {
+    if let Some(pandq) = req.uri().path_and_query() {
+        let trimmed = pandq.path().trim_end_matches("/");
+        if trimmed == pandq.path() || trimmed.is_empty() {
+            return req;
+        }
+
+        let mut new_pandq = String::from(trimmed);
+
+        if let Some(query) = pandq.query() {
+            new_pandq += "?";
+            new_pandq += query;
+        }
+
+        if let Ok(new_uri) = Builder::from(req.uri().clone())
+            .path_and_query(new_pandq)
+            .build()
+        {
+            tracing::trace!(from = %req.uri(), to = %new_uri, "rewriting");
+            *req.uri_mut() = new_uri;
+        }
+    }
+
+    req
+}
+
+async fn rate_limit_layer(
+    State((app, scope)): State<(Arc<App>, RateScope)>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let per_minute = match scope {
+        RateScope::Public => app.config().rate_limit.public,
+        RateScope::Admin => app.config().rate_limit.admin,
+        RateScope::Comment => app.config().rate_limit.comments,
+    };
+    if per_minute == 0 {
+        return next.run(request).await;
+    }
+
+    let client = app.client_ip(&request);
+    let taken = app
+        .rate_limiter
+        .lock()
+        .unwrap()
+        .take(scope, client, per_minute);
+    match taken {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let client = client.map(|client| client.to_string());
+            tracing::debug!(
+                rate_limited = client.as_deref().unwrap_or("unix socket"),
+                ?scope
+            );
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(axum::http::header::RETRY_AFTER, retry_after.to_string())],
+                "Too many requests",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Holds the browser session that csrf tokens are tied to
+const CSRF_COOKIE: &str = "blog3_csrf";
+
+fn csrf_session_cookie(headers: &HeaderMap) -> Option<String> {
+    let cookie = headers.typed_get::<Cookie>()?;
+    cookie
+        .get(CSRF_COOKIE)
+        .filter(|session| !session.is_empty())
+        .map(String::from)
+}
+
+/// Turn away POSTs a browser was tricked into making from another site. They have to come from
+/// the blog's own origin, and unless they're JSON, which another site can't send without a
+/// preflight, carry the csrf token from `App::csrf_token` for their session cookie as an
+/// `X-CSRF-Token` header or a `csrf_token` form field. API token callers are left alone since browsers don't send those
+/// by themselves.
+async fn csrf_layer(
+    State(app): State<Arc<App>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    use subtle::ConstantTimeEq;
+
+    fn header<'a>(request: &'a axum::extract::Request, name: &str) -> Option<&'a str> {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    if request.method().is_safe() {
+        return next.run(request).await;
+    }
+
+    let forbidden = |reason: &'static str| {
+        tracing::warn!(csrf = reason, path = request.uri().path());
+        (StatusCode::FORBIDDEN, reason).into_response()
+    };
+
+    let site = header(&request, "sec-fetch-site");
+    if site.is_some_and(|site| site != "same-origin" && site != "none") {
+        return forbidden("Cross-site request");
+    }
+
+    if let Some(origin) = header(&request, "origin") {
+        let host = origin.split_once("://").map(|(_, host)| host);
+        let same_host = host.is_some() && host == header(&request, "host");
+        let same_base = app
+            .config()
+            .base_url
+            .as_deref()
+            .is_some_and(|base_url| base_url == origin);
+        if !same_host && !same_base {
+            return forbidden("Cross-origin request");
+        }
+    }
+
+    let caller = request.extensions().get::<Caller>().cloned();
+    if let Some(Caller::Token(_)) = caller {
+        return next.run(request).await;
+    }
+
+    let content_type = header(&request, "content-type").unwrap_or_default();
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+    if content_type.eq_ignore_ascii_case("application/json") {
+        return next.run(request).await;
+    }
+
+    // a token only counts from the browser session it was made for, so one that leaked
+    // somewhere can't be used from anywhere else
+    let session = csrf_session_cookie(request.headers()).unwrap_or_default();
+    let expected = app.csrf_token(caller.as_ref(), &session);
+    let (request, sent) = if let Some(sent) = header(&request, "x-csrf-token") {
+        let sent = sent.to_string();
+        (request, Some(sent))
+    } else if content_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        #[derive(serde::Deserialize)]
+        struct CsrfField {
+            csrf_token: Option<String>,
+        }
+
+        // the handler still needs the body, so it goes back in once the field is read. It's
+        // already been read to check its size, so this doesn't wait on the client.
+        let (parts, body) = request.into_parts();
+        let body = match axum::body::to_bytes(body, usize::MAX).await {
+            Ok(body) => body,
+            Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        };
+        let sent = serde_urlencoded::from_bytes::<CsrfField>(&body)
+            .ok()
+            .and_then(|field| field.csrf_token);
+        (axum::extract::Request::from_parts(parts, body.into()), sent)
+    } else {
+        (request, None)
+    };
+
+    match sent {
+        Some(sent)
+            if !session.is_empty() && bool::from(sent.as_bytes().ct_eq(expected.as_bytes())) =>
+        {
+            next.run(request).await
+        }
+        _ => {
+            tracing::warn!(
+                csrf = "missing or incorrect token",
+                path = request.uri().path()
+            );
+            (
+                StatusCode::FORBIDDEN,
+                "Missing or outdated CSRF token, try reloading the page",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BodyLimit {
+    /// `max_body_size`
+    Body,
+    /// `max_upload_size`
+    Upload,
+}
+
+/// Read the whole body before the handler sees it, stopping at the limit
+async fn body_limit_layer(
+    State((app, limit)): State<(Arc<App>, BodyLimit)>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let (setting, limit) = match limit {
+        BodyLimit::Body => ("max_body_size", app.config().max_body_size),
+        BodyLimit::Upload => ("max_upload_size", app.config().max_upload_size),
+    };
+    let too_large = || {
+        let error = format!("Request body is larger than the limit of {limit} bytes");
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Json(json!({ "error": error, setting: limit })),
+        )
+            .into_response()
+    };
+
+    let content_length = request
+        .headers()
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > limit) {
+        return too_large();
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, limit).await {
+        Ok(body) => body,
+        Err(err) => {
+            let source = std::error::Error::source(&err);
+            if source.is_some_and(|source| source.is::<http_body_util::LengthLimitError>()) {
+                return too_large();
+            }
+            return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+        }
+    };
+
+    next.run(axum::extract::Request::from_parts(parts, body.into()))
+        .await
+}
+
+async fn allow_ips_layer(
+    State(app): State<Arc<App>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let config = app.config();
+    let Some(basic_auth) = config.basic_auth.as_ref() else {
+        return next.run(request).await;
+    };
+    if basic_auth.allow_ips.is_empty() {
+        return next.run(request).await;
+    }
+
+    let client = app.client_ip(&request);
+    if client.is_some_and(|client| {
+        basic_auth
+            .allow_ips
+            .iter()
+            .any(|range| range.contains(client))
+    }) {
+        return next.run(request).await;
+    }
+
+    let client = client.map(|client| client.to_string());
+    tracing::warn!(
+        not_allowed = client.as_deref().unwrap_or("unix socket"),
+        path = request.uri().path()
+    );
+    (StatusCode::FORBIDDEN, "Forbidden").into_response()
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(user = tracing::field::Empty, token = tracing::field::Empty)
+)]
+async fn basic_auth_layer(
+    State(app): State<Arc<App>>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let config = app.config();
+    if config.basic_auth.is_none() && config.api_tokens.is_empty() {
+        return next.run(request).await;
+    }
+
+    // recorded on the span so everything the handler logs says who did it
+    let caller = app.caller(request.headers()).await;
+    match &caller {
+        Caller::User(user) => {
+            tracing::Span::current().record("user", config.loggable_user(user));
+            tracing::trace!("successful_basic");
+            request.extensions_mut().insert(caller);
+            next.run(request).await
+        }
+
+        Caller::Token(name) => {
+            tracing::Span::current().record("token", name.as_str());
+            tracing::trace!("successful_token");
+            request.extensions_mut().insert(caller);
+            next.run(request).await
+        }
+
+        Caller::Rejected(message) => (StatusCode::UNAUTHORIZED, *message).into_response(),
+
+        Caller::Anonymous => {
+            let realm = config
+                .basic_auth
+                .as_ref()
+                .and_then(|basic_auth| basic_auth.realm.as_deref())
+                .unwrap_or("mycoolblog");
+
+            let mut response = (StatusCode::UNAUTHORIZED, "Need auth").into_response();
+            for (scheme, configured) in [
+                ("Basic", config.basic_auth.is_some()),
+                ("Bearer", !config.api_tokens.is_empty()),
+            ] {
+                if configured && let Ok(challenge) = format!("{scheme} realm=\"{realm}\"").parse() {
+                    response
+                        .headers_mut()
+                        .append(axum::http::header::WWW_AUTHENTICATE, challenge);
+                }
+            }
+            response
+        }
+    }
+}
+
+/// Responses smaller than this go out as they are, since gzip would barely help
+const MIN_COMPRESS_BYTES: usize = 512;
+
+/// Text that's worth compressing, which leaves out images since their formats already are
+fn is_compressible(content_type: &str) -> bool {
+    // compressing waits for the whole body, which event streams don't have
+    (content_type.starts_with("text/") && !content_type.starts_with("text/event-stream"))
+        || content_type.contains("json")
+        || content_type.contains("xml")
+        || content_type.contains("javascript")
+}
+
+/// Put everything logged while handling a request in a span with its id, when `request_ids` is
+/// set. Ids from the client are kept if they look like ids, so they match the proxy's logs.
+async fn request_id_layer(
+    State(app): State<Arc<App>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    use tracing::Instrument;
+
+    const REQUEST_ID: &str = "x-request-id";
+
+    if !app.config().request_ids {
+        return next.run(request).await;
+    }
+
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|request_id| request_id.to_str().ok())
+        .filter(|request_id| {
+            (1..=128).contains(&request_id.len())
+                && request_id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+        })
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    // error level so the span exists whenever anything inside it gets logged
+    let span = tracing::error_span!("request", request_id = %request_id);
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(value) = axum::http::HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+/// Swap the details of 500s for a page with just the error id, unless `verbose_errors` is set
+async fn error_page_layer(
+    State(app): State<Arc<App>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let wants_html = request
+        .headers()
+        .get(axum::http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+
+    let response = next.run(request).await;
+    if app.config().verbose_errors {
+        return response;
+    }
+    let Some(error) = response.extensions().get::<AppError>().cloned() else {
+        return response;
+    };
+
+    let status = response.status();
+    let text = format!("Something went wrong. The error id is {}.", error.id);
+    if !wants_html {
+        return (status, text).into_response();
+    }
+
+    let mut context = app.context();
+    context.insert("error_id", &error.id);
+    match app.render(ERROR_TEMPLATE, &context).await {
+        Ok(page) => (status, Html(page)).into_response(),
+        Err(err) => {
+            tracing::warn!(render_error_page = ?err, error_id = %error.id);
+            (status, text).into_response()
+        }
+    }
+}
+
+/// Log each request once it's been answered, at error level if it failed
+async fn access_log_layer(
+    State(app): State<Arc<App>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    use axum::body::HttpBody;
+
+    let config = app.config();
+    if !config.access_log.enabled {
+        return next.run(request).await;
+    }
+
+    // routes rather than paths, so assets are skipped whatever their names
+    let route = request
+        .extensions()
+        .get::<axum::extract::MatchedPath>()
+        .map(|route| route.as_str().to_string());
+    let skip = route.as_deref().is_some_and(|route| {
+        (config.access_log.skip_healthz && route == config.route_dot("/healthz"))
+            || (config.access_log.skip_assets
+                && (route == config.route_dot("/assets/{*path}")
+                    || route == config.route_dot("/media/{name}")))
+    });
+    if skip {
+        return next.run(request).await;
+    }
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let client = app.client_ip(&request).map(|client| client.to_string());
+    let started = std::time::Instant::now();
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16();
+    let bytes = response.body().size_hint().exact();
+    let latency_us = started.elapsed().as_micros() as u64;
+    macro_rules! access {
+        ($level:ident) => {
+            tracing::$level!(
+                target: "blog3::access",
+                %method,
+                route = route.as_deref().unwrap_or("-"),
+                path,
+                status,
+                bytes,
+                latency_us,
+                client = client.as_deref().unwrap_or("unix socket"),
+            )
+        };
+    }
+    if response.status().is_server_error() {
+        access!(error);
+    } else {
+        access!(info);
+    }
+    response
+}
+
+/// Gzip text responses for clients that take it, unless they're encoded already like the
+/// precompressed stylesheets
+#[tracing::instrument(skip_all)]
+async fn compression_layer(
+    State(app): State<Arc<App>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    use axum::http::{HeaderValue, header};
+
+    if !app.config().compress_responses {
+        return next.run(request).await;
+    }
+
+    let accepts_gzip = request
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|accept_encoding| accept_encoding.to_str().ok())
+        .is_some_and(|accept_encoding| accepts_encoding(accept_encoding, "gzip"));
+
+    let mut response = next.run(request).await;
+
+    let compressible = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(is_compressible);
+    if !compressible || response.headers().contains_key(header::CONTENT_ENCODING) {
+        return response;
+    }
+
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if !accepts_gzip || response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => return_500!(err, compress_read_body),
+    };
+
+    if body.len() < MIN_COMPRESS_BYTES {
+        return Response::from_parts(parts, body.into());
+    }
+
+    let compressed = match tokio::task::spawn_blocking(move || gzip::compress(&body)).await {
+        Ok(compressed) => compressed,
+        Err(err) => return_500!(err, compress),
+    };
+
+    parts
+        .headers
+        .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    // the bytes are different now, but they mean the same thing
+    if let Some(etag) = parts.headers.get(header::ETAG)
+        && !etag.as_bytes().starts_with(b"W/")
+        && let Ok(weak) = HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat())
+    {
+        parts.headers.insert(header::ETAG, weak);
+    }
+
+    Response::from_parts(parts, compressed.into())
+}
+
+/// Whether an Accept-Encoding header allows an encoding, ignoring anything with q=0
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|candidate| {
+        let mut parts = candidate.split(';').map(str::trim);
+        parts
+            .next()
+            .is_some_and(|name| name.eq_ignore_ascii_case(encoding))
+            && !parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            })
+    })
+}
+
+/// A file from frontend/assets or a theme's assets, and its precompressed versions if there are
+/// any
+struct EmbeddedAsset {
+    path: &'static str,
+    contents: &'static [u8],
+    gzip: Option<&'static [u8]>,
+}
+
+/// Generated by build.rs
+static EMBEDDED_ASSETS: &[EmbeddedAsset] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+/// Short content hashes by asset path, for URLs that change whenever the asset does
+type AssetHashes = HashMap<String, String>;
+
+/// Hex digits in an asset hash
+const ASSET_HASH_LEN: usize = 8;
+
+/// The directory to serve assets from, or None for the ones built in
+fn assets_dir(config: &Config) -> Option<PathBuf> {
+    match config.assets.clone() {
+        Some(dir) => Some(dir),
+        None if cfg!(debug_assertions) => Some(PathBuf::from("frontend/assets")),
+        None => None,
+    }
+}
+
+/// Somewhere assets are served from
+enum AssetSource {
+    Dir(PathBuf),
+    Embedded(&'static [EmbeddedAsset]),
+}
+
+/// Where `config`'s assets come from, in the order to look for them, so the theme's replace the
+/// default ones with the same path
+fn asset_sources(config: &Config) -> Vec<AssetSource> {
+    let mut sources = Vec::new();
+    match &config.theme_files {
+        theme::Theme::Default => {}
+        theme::Theme::Dir(dir) if dir.join("assets").is_dir() => {
+            sources.push(AssetSource::Dir(dir.join("assets")));
+        }
+        theme::Theme::Dir(_) => {}
+        theme::Theme::Embedded(theme) => sources.push(AssetSource::Embedded(theme.assets)),
+    }
+    sources.push(match assets_dir(config) {
+        Some(dir) => AssetSource::Dir(dir),
+        None => AssetSource::Embedded(EMBEDDED_ASSETS),
+    });
+    sources
+}
+
+fn hash_asset(contents: &[u8]) -> String {
+    static CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    format!(
+        "{:0width$x}",
+        CRC.checksum(contents),
+        width = ASSET_HASH_LEN
+    )
+}
+
+/// Hash every asset `config` serves, including the generated ones
+fn hash_assets(config: &Config) -> AssetHashes {
+    let mut hashes = AssetHashes::new();
+    for source in asset_sources(config).iter().rev() {
+        hashes.extend(hash_asset_files(source));
+    }
+    let stylesheet = config.highlight_stylesheet();
+    hashes.insert(
+        String::from(highlight::STYLESHEET),
+        hash_asset(stylesheet.as_bytes()),
+    );
+    hashes
+}
+
+/// Hash every asset in `source`
+fn hash_asset_files(source: &AssetSource) -> AssetHashes {
+    let dir = match source {
+        AssetSource::Dir(dir) => dir,
+        AssetSource::Embedded(assets) => {
+            return assets
+                .iter()
+                .map(|asset| (String::from(asset.path), hash_asset(asset.contents)))
+                .collect();
+        }
+    };
+
+    let mut hashes = AssetHashes::new();
+    let mut dirs = vec![(dir.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::error!(hash_assets = %err, dir = %dir.display());
+                continue;
+            }
+        };
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = prefix.clone() + &name;
+            if !is_safe_asset_path(&path) {
+                continue;
+            }
+
+            if entry.path().is_dir() {
+                dirs.push((entry.path(), path + "/"));
+            } else if let Ok(contents) = std::fs::read(entry.path()) {
+                hashes.insert(path, hash_asset(&contents));
+            }
+        }
+    }
+
+    hashes
+}
+
+/// `post.css` becomes `post.<hash>.css`
+fn hashed_asset_path(path: &str, hash: &str) -> String {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (&path[..dir.len() + 1], name),
+        None => ("", path),
+    };
+
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{dir}{stem}.{hash}.{extension}"),
+        _ => format!("{path}.{hash}"),
+    }
+}
+
+/// The other way around, giving the path and the hash if the name has one
+fn unhashed_asset_path(path: &str) -> Option<(String, &str)> {
+    let is_hash =
+        |hash: &str| hash.len() == ASSET_HASH_LEN && hash.bytes().all(|b| b.is_ascii_hexdigit());
+
+    let (rest, last) = path.rsplit_once('.')?;
+    if is_hash(last) {
+        return Some((String::from(rest), last));
+    }
+
+    let (stem, hash) = rest.rsplit_once('.')?;
+    is_hash(hash).then(|| (format!("{stem}.{last}"), hash))
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension)
+        .unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "html" | "htm" => "text/html",
+        "txt" => "text/plain",
+        "json" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Relative, with nothing that could climb out of the assets directory or name a hidden file
+fn is_safe_asset_path(path: &str) -> bool {
+    !path.is_empty()
+        && path
+            .split('/')
+            .all(|part| !part.is_empty() && !part.starts_with('.') && !part.contains('\\'))
+}
+
+/// A file under `dir`, as long as it really is under it after following symlinks
+async fn read_asset(dir: &std::path::Path, path: &str) -> std::io::Result<Vec<u8>> {
+    let dir = tokio::fs::canonicalize(dir).await?;
+    let file = tokio::fs::canonicalize(dir.join(path)).await?;
+    if !file.starts_with(&dir) {
+        return Err(std::io::ErrorKind::NotFound.into());
+    }
+    tokio::fs::read(&file).await
+}
+
+#[tracing::instrument(skip(app, uri, headers))]
+async fn assets_handler(
+    State(app): State<Arc<App>>,
+    Path(path): Path<String>,
+    uri: axum::http::Uri,
+    headers: HeaderMap,
+) -> Response {
+    // the path is decoded already, so check for slashes that were encoded to sneak past routing
+    let raw = uri.path().to_ascii_lowercase();
+    if !is_safe_asset_path(&path) || raw.contains("%2f") || raw.contains("%5c") {
+        tracing::debug!("rejected");
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    // links from templates have the hash in them, but the file is stored without it
+    let hashes = app.asset_hashes();
+    let (path, hash) = match unhashed_asset_path(&path) {
+        Some((unhashed, hash)) if !hashes.contains_key(&path) && hashes.contains_key(&unhashed) => {
+            let current = hashes[&unhashed] == hash;
+            (unhashed, Some(current))
+        }
+        _ => (path, None),
+    };
+
+    let content_type = content_type(&path);
+    let cache = match hash {
+        _ if cfg!(debug_assertions) => String::from("no-cache"),
+        Some(true) => default_file_cache().header(),
+        // old pages link to old hashes, those get what there is now but shouldn't keep it
+        Some(false) => app.config().cache.css.header(),
+        None => app.config().cache.file(content_type).header(),
+    };
+    let cache = cache.as_str();
+    tracing::trace!(%path, content_type = %content_type, cache = %cache);
+
+    if path == highlight::STYLESHEET {
+        let stylesheet = app.config().highlight_stylesheet();
+        return (
+            [("Content-Type", content_type), ("Cache-Control", cache)],
+            stylesheet,
+        )
+            .into_response();
+    }
+
+    asset_response(&app, &path, &headers, content_type, cache).await
+}
+
+/// The asset at `path` from wherever the config says assets come from
+async fn asset_response(
+    app: &App,
+    path: &str,
+    headers: &HeaderMap,
+    content_type: &str,
+    cache: &str,
+) -> Response {
+    let accept_encoding = headers
+        .get(axum::http::header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    for source in asset_sources(&app.config()) {
+        match source {
+            AssetSource::Dir(dir) => {
+                tracing::debug!(reading = %dir.display());
+                match read_asset(&dir, path).await {
+                    Ok(contents) => {
+                        let headers = [("Content-Type", content_type), ("Cache-Control", cache)];
+                        return (headers, contents).into_response();
+                    }
+                    Err(err) => tracing::debug!(not_found = %err),
+                }
+            }
+            AssetSource::Embedded(assets) => {
+                if let Some(asset) = assets.iter().find(|asset| asset.path == path) {
+                    return embedded_asset_response(asset, accept_encoding, content_type, cache);
+                }
+            }
+        }
+    }
+
+    tracing::debug!("not found");
+    StatusCode::NOT_FOUND.into_response()
+}
+
+/// An asset that's built in, precompressed if it can be
+fn embedded_asset_response(
+    asset: &EmbeddedAsset,
+    accept_encoding: &str,
+    content_type: &str,
+    cache: &str,
+) -> Response {
+    if let Some(contents) = asset.gzip
+        && accepts_encoding(accept_encoding, "gzip")
+    {
+        tracing::trace!(encoding = "gzip");
+        return (
+            [
+                ("Content-Type", content_type),
+                ("Cache-Control", cache),
+                ("Content-Encoding", "gzip"),
+                ("Vary", "Accept-Encoding"),
+            ],
+            contents,
+        )
+            .into_response();
+    }
+
+    let asset_headers = [("Content-Type", content_type), ("Cache-Control", cache)];
+    let mut response = (asset_headers, asset.contents).into_response();
+    if asset.gzip.is_some() {
+        response.headers_mut().insert(
+            axum::http::header::VARY,
+            axum::http::HeaderValue::from_static("Accept-Encoding"),
+        );
+    }
+    response
+}
+
+/// Icons browsers look for at fixed paths instead of following a link, served from the page
+/// root as well as with the other assets
+const ROOT_ICONS: &[&str] = &["favicon.ico", "favicon.svg", "apple-touch-icon.png"];
+
+/// The ones in `ROOT_ICONS` that get asked for at the root of the domain even when the blog
+/// isn't there
+const DOMAIN_ROOT_ICONS: &[&str] = &["favicon.ico", "apple-touch-icon.png"];
+
+const MANIFEST: &str = "/site.webmanifest";
+
+#[tracing::instrument(skip_all, fields(path = %uri.path()))]
+async fn root_icon_handler(
+    State(app): State<Arc<App>>,
+    uri: axum::http::Uri,
+    headers: HeaderMap,
+) -> Response {
+    let Some(icon) = ROOT_ICONS.iter().find(|icon| uri.path().ends_with(*icon)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let content_type = content_type(icon);
+    let cache = match cfg!(debug_assertions) {
+        true => String::from("no-cache"),
+        false => app.config().cache.file(content_type).header(),
+    };
+    asset_response(&app, icon, &headers, content_type, &cache).await
+}
+
+/// The web app manifest, which links the icons at their hashed urls
+#[tracing::instrument(skip_all)]
+async fn manifest_handler(State(app): State<Arc<App>>) -> Response {
+    let config = app.config();
+    let urls = app.asset_urls();
+    let icons = ["192x192", "512x512"]
+        .iter()
+        .filter_map(|size| {
+            let url = urls.get(&format!("web-app-manifest-{size}.png"))?;
+            Some(json!({
+                "src": config.absolute_url(url),
+                "sizes": size,
+                "type": "image/png",
+            }))
+        })
+        .collect::<Vec<_>>();
+
+    let manifest = json!({
+        "name": config.title,
+        "short_name": config.title,
+        "start_url": config.absolute("/"),
+        "display": "minimal-ui",
+        "icons": icons,
+    });
+
+    // the icon urls change along with them, so this can't be kept like they are
+    let cache = match cfg!(debug_assertions) {
+        true => String::from("no-cache"),
+        false => config.cache.css.header(),
+    };
+    let headers = [
+        ("Content-Type", "application/manifest+json"),
+        ("Cache-Control", &cache),
+    ];
+    (headers, manifest.to_string()).into_response()
+}
+
+/// The first file in a multipart/form-data body, and its filename
+fn multipart_file<'a>(content_type: &str, body: &'a [u8]) -> Option<(String, &'a [u8])> {
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
+
+    let boundary = content_type
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))?
+        .trim_matches('"');
+    let delimiter = format!("\r\n--{boundary}");
+    let delimiter = delimiter.as_bytes();
+
+    // the first delimiter doesn't need a newline before it, anything before it is ignored
+    let start = if body.starts_with(&delimiter[2..]) {
+        delimiter.len() - 2
+    } else {
+        find(body, delimiter)? + delimiter.len()
+    };
+    let mut rest = &body[start..];
+
+    // after each delimiter is either -- for the end or a newline and then a part
+    while let Some(part) = rest.strip_prefix(b"\r\n") {
+        let end = find(part, delimiter)?;
+        rest = &part[end + delimiter.len()..];
+        let part = &part[..end];
+
+        let headers_end = find(part, b"\r\n\r\n")?;
+        let headers = std::str::from_utf8(&part[..headers_end]).ok()?;
+        let filename = headers
+            .split("\r\n")
+            .filter_map(|header| header.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("content-disposition"))
+            .flat_map(|(_, value)| value.split(';'))
+            .find_map(|param| param.trim().strip_prefix("filename="));
+        if let Some(filename) = filename {
+            return Some((
+                filename.trim_matches('"').to_string(),
+                &part[headers_end + 4..],
+            ));
+        }
+    }
+
+    None
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct UploadQuery {
+    /// For raw bodies, where the extension comes from if it's there instead of Content-Type
+    #[serde(default)]
+    filename: Option<String>,
+}
+
+/// Store an image or other file for posts to link to, from the first file of a multipart
+/// form or the whole body. Files are named after their contents, so uploading the same thing
+/// again gives the same url.
+#[tracing::instrument(skip(app, headers, body))]
+async fn media_upload_handler(
+    State(app): State<Arc<App>>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Response {
+    let config = app.config();
+    let Some(media_dir) = config.media_dir.as_ref() else {
+        return (StatusCode::NOT_FOUND, "Uploads need media_dir to be set").into_response();
+    };
+
+    let request_type = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+    let essence = request_type.split(';').next().unwrap_or_default().trim();
+
+    let (filename, contents) = if essence.eq_ignore_ascii_case("multipart/form-data") {
+        match multipart_file(request_type, &body) {
+            Some((filename, contents)) => (Some(filename), contents),
+            None => {
+                let error = "Multipart body without a file in it";
+                return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response();
+            }
+        }
+    } else {
+        (query.filename, &body[..])
+    };
+
+    let extension = match filename
+        .as_deref()
+        .and_then(|filename| filename.rsplit_once('.'))
+    {
+        Some((_, extension)) => Some(extension.to_ascii_lowercase()),
+        None => config
+            .media_extensions
+            .iter()
+            .find(|extension| content_type(&format!(".{extension}")) == essence)
+            .cloned(),
+    };
+    let Some(extension) = extension.filter(|extension| {
+        config
+            .media_extensions
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(extension))
+    }) else {
+        let error = "Uploads need an allowed extension or Content-Type";
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(json!({ "error": error, "allowed": config.media_extensions })),
+        )
+            .into_response();
+    };
+
+    let digest = sha256(contents);
+    let hash = digest[..16]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    let name = format!("{hash}.{extension}");
+    let content_type = content_type(&name);
+    tracing::debug!(?filename, %name, size = contents.len());
+
+    // written beside the real name first so a half-written file is never served
+    let path = media_dir.join(&name);
+    if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        let partial = media_dir.join(format!(".{name}.{}", Uuid::new_v4()));
+        let written = async {
+            tokio::fs::create_dir_all(media_dir).await?;
+            tokio::fs::write(&partial, contents).await?;
+            tokio::fs::rename(&partial, &path).await
+        };
+        if let Err(err) = written.await {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return_500!(err, write_media);
+        }
+    }
+
+    let id = Uuid::new_v4();
+    let size = contents.len() as i64;
+    let now = app.config().now();
+    let inserted = sqlx::query!(
+        "insert into media (id, filename, content_type, size, uploaded_at) values ($1, $2, $3, $4, $5)
+        on conflict (filename) do nothing",
+        id,
+        name,
+        content_type,
+        size,
+        now,
+    )
+    .execute(&app.pool)
+    .await;
+    if let Err(err) = inserted {
+        return_500!(err, insert_media);
+    }
+
+    let id = sqlx::query_scalar!(
+        r#"select id as "id: Uuid" from media where filename = $1"#,
+        name
+    )
+    .fetch_one(&app.pool)
+    .await;
+    let id = match id {
+        Ok(id) => id,
+        Err(err) => return_500!(err, media_id),
+    };
+
+    let url = config.absolute_dot(&format!("/media/{name}"));
+    Json(json!({ "id": id, "filename": name, "url": url })).into_response()
+}
+
+#[tracing::instrument(skip(app))]
+async fn media_handler(State(app): State<Arc<App>>, Path(name): Path<String>) -> Response {
+    let Some(media_dir) = app.config().media_dir.clone() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // only names made by media_upload_handler, which are never hidden or in a subdirectory
+    if !is_safe_asset_path(&name) || name.contains('/') {
+        tracing::debug!("rejected");
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let content_type = content_type(&name);
+    match read_asset(&media_dir, &name).await {
+        Ok(contents) => (
+            [
+                ("Content-Type", content_type),
+                (
+                    "Cache-Control",
+                    &app.config().cache.file(content_type).header(),
+                ),
+                ("X-Content-Type-Options", "nosniff"),
+            ],
+            contents,
+        )
+            .into_response(),
+        Err(err) => {
+            tracing::debug!(not_found = %err);
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
+
+/// How long after a post is saved before sending webmentions for it, so the page is there
+/// when receivers come to check
+const WEBMENTION_DELAY: Duration = Duration::from_secs(10);
+/// Sending is tried this many times, waiting twice as long before each retry
+const WEBMENTION_ATTEMPTS: u32 = 3;
+const WEBMENTION_RETRY: Duration = Duration::from_secs(60);
+const MAX_REDIRECTS: usize = 5;
+const USER_AGENT: &str = concat!("blog3/", env!("CARGO_PKG_VERSION"));
+
+/// A response from `fetch`
+struct Fetched {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Fetched {
+    fn header<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// What `fetch` sends, which makes it a POST unless it's `None`
+enum FetchBody<'a> {
+    None,
+    Form(&'a [(&'a str, &'a str)]),
+    #[cfg_attr(not(feature = "activitypub"), allow(dead_code))]
+    Raw(&'a [u8]),
+}
+
+/// Addresses `fetch` won't send requests to: this machine, its networks, and everything else
+/// that isn't somewhere on the internet
+static NON_PUBLIC: LazyLock<Vec<IpRange>> = LazyLock::new(|| {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.0.2.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "198.51.100.0/24",
+        "203.0.113.0/24",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/96",
+        "64:ff9b::/96",
+        "64:ff9b:1::/48",
+        "100::/64",
+        "2001:db8::/32",
+        "fc00::/7",
+        "fe80::/10",
+        "fec0::/10",
+        "ff00::/8",
+    ]
+    .map(|range| IpRange::try_from(String::from(range)).expect("valid range"))
+    .to_vec()
+});
+
+/// Where a URL's host resolves to, as long as every address is public. Whoever picks the URL
+/// shouldn't get to make requests to anything that's only reachable from here.
+async fn public_addresses(url: &url::Url) -> Result<Vec<SocketAddr>> {
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("{url} isn't http or https");
+    }
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow::anyhow!("{url} has no port"))?;
+    let addresses = match url.host() {
+        Some(url::Host::Domain(domain)) => tokio::net::lookup_host((domain, port)).await?.collect(),
+        Some(url::Host::Ipv4(addr)) => vec![SocketAddr::new(addr.into(), port)],
+        Some(url::Host::Ipv6(addr)) => vec![SocketAddr::new(addr.into(), port)],
+        None => anyhow::bail!("{url} has no host"),
+    };
+    if addresses.is_empty() {
+        anyhow::bail!("{url} doesn't resolve to anything");
+    }
+    if let Some(addr) = addresses
+        .iter()
+        .find(|addr| NON_PUBLIC.iter().any(|range| range.contains(addr.ip())))
+    {
+        anyhow::bail!("{url} resolves to {}, which isn't public", addr.ip());
+    }
+    Ok(addresses)
+}
+
+/// Whether curl can be run, for the settings that need it
+fn curl_installed() -> bool {
+    std::process::Command::new("curl")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Make a request with curl, which needs to be installed for anything that talks to other
+/// sites. Redirects aren't followed, so every hop comes back through here to be checked.
+async fn fetch(url: &url::Url, headers: &[(&str, &str)], body: FetchBody<'_>) -> Result<Fetched> {
+    use tokio::io::AsyncWriteExt;
+
+    let addresses = public_addresses(url).await?;
+
+    let mut command = tokio::process::Command::new("curl");
+    command
+        .args([
+            "--silent",
+            "--show-error",
+            "--include",
+            "--proto",
+            "=http,https",
+        ])
+        .args(["--max-time", "10", "--max-filesize", "2000000"])
+        .args(["--user-agent", USER_AGENT])
+        // a proxy would look the host up again itself
+        .args(["--noproxy", "*"]);
+    // connect to the addresses that were checked, rather than let curl look them up again and
+    // maybe get something else
+    if let Some(url::Host::Domain(domain)) = url.host() {
+        let port = addresses[0].port();
+        let addresses = addresses
+            .iter()
+            .map(|addr| match addr {
+                SocketAddr::V4(addr) => addr.ip().to_string(),
+                SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        command
+            .arg("--resolve")
+            .arg(format!("{domain}:{port}:{addresses}"));
+    }
+    for (name, value) in headers {
+        command.arg("--header").arg(format!("{name}: {value}"));
+    }
+    match body {
+        FetchBody::None => {}
+        FetchBody::Form(form) => {
+            for (name, value) in form {
+                command
+                    .arg("--data-urlencode")
+                    .arg(format!("{name}={value}"));
+            }
+        }
+        FetchBody::Raw(_) => {
+            command.args(["--data-binary", "@-"]);
+        }
+    }
+
+    let mut child = command
+        .arg("--url")
+        .arg(url.as_str())
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take()
+        && let FetchBody::Raw(body) = body
+    {
+        stdin.write_all(body).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    // with --include the headers come first, after any 100 Continue
+    let mut rest = &output.stdout[..];
+    loop {
+        let end = rest
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(|| anyhow::anyhow!("response without headers"))?;
+        let head = String::from_utf8_lossy(&rest[..end]).into_owned();
+        rest = &rest[end + 4..];
+
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| anyhow::anyhow!("response without a status"))?;
+        if (100..200).contains(&status) {
+            continue;
+        }
+
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        return Ok(Fetched {
+            status,
+            headers,
+            body: rest.to_vec(),
+        });
+    }
+}
+
+/// The value of an attribute in the inside of an HTML tag, quoted or not
+fn html_attr(attrs: &str, name: &str) -> Option<String> {
+    static ATTR: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"(?i)([a-z-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#)
+            .expect("valid regex")
+    });
+
+    ATTR.captures_iter(attrs)
+        .find(|attr| attr[1].eq_ignore_ascii_case(name))
+        .and_then(|attr| attr.get(2).or(attr.get(3)).or(attr.get(4)))
+        .map(|value| value.as_str().replace("&amp;", "&"))
+}
+
+/// Links out of a post's HTML to other sites, once each in the order they appear
+fn external_links(html: &str, own_host: Option<&str>) -> Vec<String> {
+    static ANCHOR: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?is)<a\s([^>]*)>").expect("valid regex"));
+
+    let mut links = Vec::new();
+    for anchor in ANCHOR.captures_iter(html) {
+        let Some(href) = html_attr(&anchor[1], "href") else {
+            continue;
+        };
+        let Ok(url) = url::Url::parse(&href) else {
+            continue;
+        };
+        let external = matches!(url.scheme(), "http" | "https") && url.host_str() != own_host;
+        if external && !links.contains(&href) {
+            links.push(href);
+        }
+    }
+    links
+}
+
+/// Where a page takes webmentions, from its first `Link` header or `<link>`/`<a>` with
+/// `rel=webmention`, following redirects to get to it
+async fn webmention_endpoint(target: &str) -> Result<Option<url::Url>> {
+    static ELEMENT: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?is)<(?:link|a)\s([^>]*)>").expect("valid regex"));
+    let is_webmention = |rel: &str| rel.split_whitespace().any(|rel| rel == "webmention");
+
+    let mut url = url::Url::parse(target)?;
+    for _ in 0..=MAX_REDIRECTS {
+        let response = fetch(&url, &[], FetchBody::None).await?;
+        if (300..400).contains(&response.status)
+            && let Some(location) = response.header("location").next()
+        {
+            url = url.join(location)?;
+            continue;
+        }
+        if !(200..300).contains(&response.status) {
+            anyhow::bail!("HTTP {} from {}", response.status, url);
+        }
+
+        let from_header = response
+            .header("link")
+            .flat_map(|links| links.split(','))
+            .find_map(|link| {
+                let (target, params) = link.trim().strip_prefix('<')?.split_once('>')?;
+                let rel = params
+                    .split(';')
+                    .filter_map(|param| param.trim().strip_prefix("rel="))
+                    .next()?;
+                is_webmention(rel.trim_matches('"')).then(|| target.to_string())
+            });
+
+        let body = String::from_utf8_lossy(&response.body);
+        let endpoint = from_header.or_else(|| {
+            ELEMENT.captures_iter(&body).find_map(|element| {
+                let rel = html_attr(&element[1], "rel")?;
+                // an empty href means the page itself
+                is_webmention(&rel).then(|| html_attr(&element[1], "href"))?
+            })
+        });
+
+        return Ok(endpoint.map(|endpoint| url.join(&endpoint)).transpose()?);
+    }
+
+    anyhow::bail!("more than {MAX_REDIRECTS} redirects from {target}")
+}
+
+#[derive(Debug, serde::Serialize, sqlx::FromRow)]
+struct Webmention {
+    post_id: Uuid,
+    source: String,
+    target: String,
+    endpoint: Option<String>,
+    status: String,
+    detail: Option<String>,
+    attempts: i64,
+    updated_at: DateTime<FixedOffset>,
+}
+
+impl App {
+    /// Let every site the post links to know about it in the background, along with any it
+    /// used to link to so they can notice the link is gone
+    fn spawn_webmentions(self: &Arc<App>, id: Uuid) {
+        let config = self.config();
+        if !config.send_webmentions {
+            return;
+        }
+        if config.base_url.is_none() {
+            tracing::debug!("not sending webmentions without a base_url");
+            return;
+        }
+
+        let app = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(WEBMENTION_DELAY).await;
+            if let Err(err) = app.send_webmentions(id).await {
+                tracing::error!(send_webmentions = %err);
+            }
+        });
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn send_webmentions(&self, id: Uuid) -> Result<()> {
+        let config = self.config();
+        let own_host = config
+            .base_url
+            .as_deref()
+            .and_then(|base_url| url::Url::parse(base_url).ok())
+            .and_then(|base_url| base_url.host_str().map(String::from));
+
+        let mut conn = self.pool.acquire().await?;
+        // it might have changed again while waiting
+        let Some(post) = self.find_post_uuid(&mut conn, id).await? else {
+            return Ok(());
+        };
+        if post.draft || post.deleted.is_some() {
+            tracing::debug!("not public");
+            return Ok(());
+        }
+
+        let slug = self
+            .slug_history(&mut conn, id)
+            .await?
+            .into_iter()
+            .find(|slug| slug.canonical);
+        let Some(slug) = slug else {
+            return Ok(());
+        };
+        let source = config.absolute(&format!("/{}", slug.slug));
+
+        let html = post.content_type.render(&post.content);
+        let mut targets = external_links(&html, own_host.as_deref());
+        let previous = sqlx::query_scalar!("select target from webmention where post_id = $1", id)
+            .fetch_all(&mut *conn)
+            .await?;
+        for target in previous {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+        drop(conn);
+
+        // the ones that failed get tried again together, so one slow site doesn't hold up the
+        // rest
+        let mut retry = WEBMENTION_RETRY;
+        for attempt in 1..=WEBMENTION_ATTEMPTS {
+            let mut failed = Vec::new();
+            for target in targets {
+                if self.send_webmention(id, &source, &target, attempt).await? {
+                    failed.push(target);
+                }
+            }
+
+            if failed.is_empty() || attempt == WEBMENTION_ATTEMPTS {
+                break;
+            }
+            tokio::time::sleep(retry).await;
+            retry *= 2;
+            targets = failed;
+        }
+        Ok(())
+    }
+
+    /// Record how it went, and say whether it's worth trying again
+    async fn send_webmention(
+        &self,
+        id: Uuid,
+        source: &str,
+        target: &str,
+        attempt: u32,
+    ) -> Result<bool> {
+        let (status, endpoint, detail, retryable) = match webmention_endpoint(target).await {
+            Ok(None) => ("no endpoint", None, None, false),
+            Ok(Some(endpoint)) => {
+                let form = [("source", source), ("target", target)];
+                let detail = match fetch(&endpoint, &[], FetchBody::Form(&form)).await {
+                    Ok(response) if (200..300).contains(&response.status) => {
+                        Ok(format!("HTTP {}", response.status))
+                    }
+                    // only worth trying again if the receiver might be doing better later
+                    Ok(response) => Err((
+                        format!("HTTP {}", response.status),
+                        response.status >= 500 || response.status == 429,
+                    )),
+                    Err(err) => Err((err.to_string(), true)),
+                };
+                match detail {
+                    Ok(detail) => ("sent", Some(endpoint), Some(detail), false),
+                    Err((detail, retryable)) => ("failed", Some(endpoint), Some(detail), retryable),
+                }
+            }
+            Err(err) => ("failed", None, Some(err.to_string()), true),
+        };
+        tracing::debug!(%target, status, ?detail);
+
+        let endpoint = endpoint.map(String::from);
+        let attempts = attempt as i64;
+        let now = self.config().now();
+        sqlx::query!(
+            "insert into webmention (post_id, source, target, endpoint, status, detail, attempts, updated_at)
+            values ($1, $2, $3, $4, $5, $6, $7, $8)
+            on conflict (post_id, target) do update set
+                source = excluded.source,
+                endpoint = excluded.endpoint,
+                status = excluded.status,
+                detail = excluded.detail,
+                attempts = excluded.attempts,
+                updated_at = excluded.updated_at",
+            id,
+            source,
+            target,
+            endpoint,
+            status,
+            detail,
+            attempts,
+            now,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(retryable)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct WebmentionsQuery {
+    #[serde(default)]
+    post: Option<Uuid>,
+}
+
+/// What happened with each webmention that was sent, for one post or all
+#[tracing::instrument(skip(app))]
+async fn webmentions_handler(
+    State(app): State<Arc<App>>,
+    Query(query): Query<WebmentionsQuery>,
+) -> Response {
+    let webmentions = sqlx::query_as::<_, Webmention>(
+        "select * from webmention where $1 is null or post_id = $1 order by updated_at desc",
+    )
+    .bind(query.post)
+    .fetch_all(&app.pool)
+    .await;
+
+    match webmentions {
+        Ok(webmentions) => Json(json!({ "webmentions": webmentions })).into_response(),
+        Err(err) => return_500!(err, select_webmentions),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+enum CommentStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Debug, serde::Serialize, sqlx::FromRow)]
+struct Comment {
+    id: Uuid,
+    post_id: Uuid,
+    author: String,
+    url: Option<String>,
+    email: Option<String>,
+    body: String,
+    created_at: DateTime<FixedOffset>,
+    status: CommentStatus,
+    moderated_at: Option<DateTime<FixedOffset>>,
+}
+
+/// Enough about a post's approved comments to tell when they change, without loading them
+#[derive(Hash)]
+struct CommentSummary {
+    count: i64,
+    latest: Option<DateTime<FixedOffset>>,
+}
+
+const MAX_COMMENT_AUTHOR: usize = 100;
+const MAX_COMMENT_URL: usize = 500;
+const MAX_COMMENT_EMAIL: usize = 254;
+const MAX_COMMENT_BODY: usize = 5000;
+
+impl App {
+    /// Comments to show under a post, oldest first
+    async fn approved_comments(
+        &self,
+        conn: &mut SqliteConnection,
+        id: Uuid,
+    ) -> Result<Vec<Comment>> {
+        tracing::trace!(approved_comments = %id);
+
+        let mut comments = sqlx::query_as::<_, Comment>(
+            "select * from comment where post_id = $1 and status = 'approved' order by created_at",
+        )
+        .bind(id)
+        .fetch_all(conn)
+        .await?;
+
+        // only the author gets to see addresses
+        for comment in comments.iter_mut() {
+            comment.email = None;
+        }
+        Ok(comments)
+    }
+
+    async fn comment_summary(
+        &self,
+        conn: &mut SqliteConnection,
+        id: Uuid,
+    ) -> Result<CommentSummary> {
+        let summary = sqlx::query!(
+            r#"
+                select count(*) as count, max(moderated_at) as "latest: DateTime<FixedOffset>"
+                from comment
+                where post_id = $1 and status = 'approved'
+            "#,
+            id
+        )
+        .fetch_one(conn)
+        .await?;
+
+        Ok(CommentSummary {
+            count: summary.count,
+            latest: summary.latest,
+        })
+    }
+
+    /// The other posts a post's page links to
+    async fn post_links(&self, conn: &mut SqliteConnection, post: &Post) -> Result<PostLinks> {
+        // ties on published go by id, so every post has exactly one on each side
+        let neighbors = sqlx::query_as::<_, Neighbor>(
+            r#"
+                select * from (
+                    select 'prev' as side, post.title, slug.slug, post.published
+                    from post
+                    join slug on post.id = slug.id
+                        and (slug.newslug is null or slug.newslug = slug.slug)
+                    where draft is false
+                        and deleted is null
+                        and (julianday(post.published), post.id) < (julianday($2), $1)
+                    order by julianday(post.published) desc, post.id desc
+                    limit 1
+                )
+                union all
+                select * from (
+                    select 'next' as side, post.title, slug.slug, post.published
+                    from post
+                    join slug on post.id = slug.id
+                        and (slug.newslug is null or slug.newslug = slug.slug)
+                    where draft is false
+                        and deleted is null
+                        and (julianday(post.published), post.id) > (julianday($2), $1)
+                    order by julianday(post.published), post.id
+                    limit 1
+                )
+            "#,
+        )
+        .bind(post.id)
+        .bind(post.published)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut links = PostLinks::default();
+        for Neighbor { side, post } in neighbors {
+            match side.as_str() {
+                "prev" => links.prev = Some(post),
+                _ => links.next = Some(post),
+            }
+        }
+
+        let limit = self.config().related_posts as i64;
+        if limit == 0 {
+            return Ok(links);
+        }
+
+        // shared tags first, then closeness in time. a post with tags only gets ones sharing
+        // some, one without gets whatever's nearest
+        let related = sqlx::query_as::<_, LinkedPost>(
+            r#"
+                select post.title, slug.slug, post.published
+                from post
+                join slug on post.id = slug.id
+                    and (slug.newslug is null or slug.newslug = slug.slug)
+                left join tag on tag.post_id = post.id
+                    and tag.tag in (select tag from tag where post_id = $1)
+                where post.id != $1
+                    and draft is false
+                    and deleted is null
+                group by post.id
+                having count(tag.tag) > 0 or not exists (select 1 from tag where post_id = $1)
+                order by
+                    count(tag.tag) desc,
+                    abs(julianday(post.published) - julianday($2)),
+                    post.id
+                limit $3
+            "#,
+        )
+        .bind(post.id)
+        .bind(post.published)
+        .bind(limit)
+        .fetch_all(conn)
+        .await?;
+
+        links.related = related;
+        Ok(links)
+    }
+
+    /// When anything another post's page could link to last changed
+    async fn links_updated(
+        &self,
+        conn: &mut SqliteConnection,
+    ) -> Result<Option<DateTime<FixedOffset>>> {
+        // by julianday since the text doesn't sort right across offsets
+        Ok(sqlx::query_scalar!(
+            r#"
+                select coalesce(deleted, updated) as "updated!: DateTime<FixedOffset>"
+                from post
+                order by julianday(coalesce(deleted, updated)) desc
+                limit 1
+            "#
+        )
+        .fetch_optional(conn)
+        .await?)
+    }
+}
+
+/// Another post, as a post page links to it
+#[derive(Debug, serde::Serialize, sqlx::FromRow)]
+struct LinkedPost {
+    title: String,
+    slug: String,
+    published: DateTime<FixedOffset>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Neighbor {
+    side: String,
+    #[sqlx(flatten)]
+    post: LinkedPost,
+}
+
+#[derive(Debug, Default)]
+struct PostLinks {
+    related: Vec<LinkedPost>,
+    /// Published just before
+    prev: Option<LinkedPost>,
+    /// Published just after
+    next: Option<LinkedPost>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CommentForm {
+    author: String,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    body: String,
+}
+
+impl CommentForm {
+    /// Trimmed, with empty optional fields dropped, or what's wrong with it
+    fn validate(self) -> std::result::Result<CommentForm, String> {
+        let optional = |field: Option<String>| {
+            field
+                .map(|field| field.trim().to_string())
+                .filter(|field| !field.is_empty())
+        };
+        let form = CommentForm {
+            author: self.author.trim().to_string(),
+            url: optional(self.url),
+            email: optional(self.email),
+            body: self.body.trim().to_string(),
+        };
+
+        for (field, value, max) in [
+            ("author", Some(&form.author), MAX_COMMENT_AUTHOR),
+            ("url", form.url.as_ref(), MAX_COMMENT_URL),
+            ("email", form.email.as_ref(), MAX_COMMENT_EMAIL),
+            ("body", Some(&form.body), MAX_COMMENT_BODY),
+        ] {
+            if value.is_some_and(|value| value.chars().count() > max) {
+                return Err(format!("{field} can be at most {max} characters"));
+            }
+        }
+        if form.author.is_empty() || form.body.is_empty() {
+            return Err(String::from("author and body are required"));
+        }
+
+        // the url becomes a link, so nothing like javascript: urls
+        if let Some(url) = form.url.as_deref()
+            && !url::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+        {
+            return Err(String::from("url must be an http or https url"));
+        }
+        if form
+            .email
+            .as_deref()
+            .is_some_and(|email| !email.contains('@'))
+        {
+            return Err(String::from("email doesn't look like an email address"));
+        }
+
+        Ok(form)
+    }
+}
+
+/// Take a comment on a post to be approved later. Forms get sent back to the post, where
+/// `#comment-pending` says it's waiting.
+#[tracing::instrument(skip(app, request))]
+async fn comment_handler(
+    State(app): State<Arc<App>>,
+    Path(slug): Path<String>,
+    request: axum::extract::Request,
+) -> Response {
+    use axum::extract::FromRequest;
+
+    if !app.config().comments {
+        return (StatusCode::NOT_FOUND, "comments are turned off").into_response();
+    }
+
+    let from_form = request
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+
+    let form = if from_form {
+        match axum::Form::<CommentForm>::from_request(request, &()).await {
+            Ok(axum::Form(form)) => form,
+            Err(rejection) => return rejection.into_response(),
+        }
+    } else {
+        match Json::<CommentForm>::from_request(request, &()).await {
+            Ok(Json(form)) => form,
+            Err(rejection) => return rejection.into_response(),
+        }
+    };
+    let form = match form.validate() {
+        Ok(form) => form,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+
+    let mut tx = match app.begin_write().await {
+        Ok(tx) => tx,
+        Err(err) => return_500!(err, comment_transaction),
+    };
+
+    let (post_id, newslug) = match app.get_newest_slug(&mut *tx, &slug).await {
+        Ok(Some((id, newslug))) => match app.find_post_uuid(&mut *tx, id).await {
+            Ok(Some(post)) if !post.draft && post.deleted.is_none() => (id, newslug),
+            Ok(_) => return (StatusCode::NOT_FOUND, "post not found").into_response(),
+            Err(err) => return_500!(err, comment_find_post),
+        },
+        Ok(None) => return (StatusCode::NOT_FOUND, "post not found").into_response(),
+        Err(err) => return_500!(err, comment_newest_slug),
+    };
+
+    let id = Uuid::new_v4();
+    let now = app.config().now();
+    let inserted = sqlx::query!(
+        "insert into comment (id, post_id, author, url, email, body, created_at, status) values ($1, $2, $3, $4, $5, $6, $7, 'pending')",
+        id,
+        post_id,
+        form.author,
+        form.url,
+        form.email,
+        form.body,
+        now,
+    )
+    .execute(&mut *tx)
+    .await;
+    if let Err(err) = inserted {
+        return_500!(err, insert_comment);
+    }
+
+    if let Err(err) = tx.commit().await {
+        return_500!(err, comment_transaction_commit);
+    }
+    tracing::info!(new_comment = %id, post = %newslug);
+
+    if from_form {
+        let back = app.config().route(&format!("/{newslug}")) + "#comment-pending";
+        return axum::response::Redirect::to(&back).into_response();
+    }
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({ "id": id, "status": CommentStatus::Pending })),
+    )
+        .into_response()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CommentsQuery {
+    /// All of them if unset
+    #[serde(default)]
+    status: Option<CommentStatus>,
+}
+
+/// Comments for moderating, newest first
+#[tracing::instrument(skip(app))]
+async fn comments_handler(
+    State(app): State<Arc<App>>,
+    Query(query): Query<CommentsQuery>,
+) -> Response {
+    match sqlx::query_as::<_, Comment>(
+        "select * from comment where $1 is null or status = $1 order by created_at desc",
+    )
+    .bind(query.status)
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(comments) => Json(comments).into_response(),
+        Err(err) => return_500!(err, select_comments),
+    }
+}
+
+async fn approve_comment_handler(State(app): State<Arc<App>>, Path(id): Path<Uuid>) -> Response {
+    moderate_comment(&app, id, Some(CommentStatus::Approved)).await
+}
+
+async fn reject_comment_handler(State(app): State<Arc<App>>, Path(id): Path<Uuid>) -> Response {
+    moderate_comment(&app, id, Some(CommentStatus::Rejected)).await
+}
+
+async fn delete_comment_handler(State(app): State<Arc<App>>, Path(id): Path<Uuid>) -> Response {
+    moderate_comment(&app, id, None).await
+}
+
+/// Set a comment's status, or delete it with `None`, and re-render its post
+#[tracing::instrument(skip(app))]
+async fn moderate_comment(app: &App, id: Uuid, status: Option<CommentStatus>) -> Response {
+    let mut tx = match app.begin_write().await {
+        Ok(tx) => tx,
+        Err(err) => return_500!(err, moderate_comment_transaction),
+    };
+
+    let post_id = match sqlx::query_scalar!(
+        r#"select post_id as "post_id: Uuid" from comment where id = $1"#,
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    {
+        Ok(Some(post_id)) => post_id,
+        Ok(None) => return (StatusCode::NOT_FOUND, "comment not found").into_response(),
+        Err(err) => return_500!(err, select_comment),
+    };
+
+    let now = app.config().now();
+    let changed = match status {
+        Some(status) => {
+            sqlx::query!(
+                "update comment set status = $1, moderated_at = $2 where id = $3",
+                status,
+                now,
+                id
+            )
+            .execute(&mut *tx)
+            .await
+        }
+        None => {
+            sqlx::query!("delete from comment where id = $1", id)
+                .execute(&mut *tx)
+                .await
+        }
+    };
+    if let Err(err) = changed {
+        return_500!(err, moderate_comment);
+    }
+
+    let post = match app.find_post_uuid(&mut *tx, post_id).await {
+        Ok(post) => post,
+        Err(err) => return_500!(err, moderate_comment_post),
+    };
+    let slugs = match app.slug_history(&mut *tx, post_id).await {
+        Ok(slugs) => slugs,
+        Err(err) => return_500!(err, moderate_comment_slugs),
+    };
+
+    if let Err(err) = tx.commit().await {
+        return_500!(err, moderate_comment_transaction_commit);
+    }
+    tracing::info!(moderated_comment = %id, ?status);
+
+    if let Some(post) = post
+        && let Some(canonical) = slugs.iter().find(|entry| entry.canonical)
+    {
+        app.refresh_snapshot(post, &canonical.slug).await;
+    }
+
+    Json(json!({ "id": id, "status": status })).into_response()
+}
+
+/// How often views counted in memory get written out
+const VIEW_FLUSH: Duration = Duration::from_secs(10);
+/// Days of daily views the stats include
+const STATS_DAYS: i64 = 30;
+/// Lowercase pieces of user agents that aren't people reading
+const BOT_USER_AGENTS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "curl",
+    "wget",
+    "python",
+    "go-http-client",
+    "headless",
+    "preview",
+    "facebookexternalhit",
+    "feed",
+];
+
+fn is_bot(headers: &HeaderMap) -> bool {
+    let Some(user_agent) = headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+    else {
+        return true;
+    };
+    let user_agent = user_agent.to_ascii_lowercase();
+    BOT_USER_AGENTS.iter().any(|bot| user_agent.contains(bot))
+}
+
+impl App {
+    /// Count a view of a post if the page was actually sent to someone who isn't a bot. Only
+    /// adds to a count in memory, `flush_views` does the writing.
+    fn record_view(&self, headers: &HeaderMap, id: Uuid, response: &Response) {
+        if response.status() != StatusCode::OK || is_bot(headers) {
+            return;
+        }
+        let today = self.config().now().date_naive();
+        *self.views.lock().unwrap().entry((id, today)).or_default() += 1;
+    }
+
+    async fn flush_views(&self) {
+        let views = std::mem::take(&mut *self.views.lock().unwrap());
+        if views.is_empty() {
+            return;
+        }
+        tracing::trace!(flush_views = views.len());
+
+        let written = async {
+            let mut tx = self.begin_write().await?;
+            for ((id, date), count) in views.iter() {
+                sqlx::query!(
+                    "insert into view (post_id, date, count) values ($1, $2, $3)
+                    on conflict (post_id, date) do update set count = count + excluded.count",
+                    id,
+                    date,
+                    count,
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await
+        };
+
+        // keep them around for next time rather than lose them
+        if let Err(err) = written.await {
+            tracing::error!(flush_views = %err);
+            let mut pending = self.views.lock().unwrap();
+            for (key, count) in views {
+                *pending.entry(key).or_default() += count;
+            }
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, sqlx::FromRow)]
+struct PostStats {
+    id: Uuid,
+    title: String,
+    slug: Option<String>,
+    total: i64,
+    #[sqlx(skip)]
+    days: Vec<DayStats>,
+}
+
+#[derive(Debug, serde::Serialize, sqlx::FromRow)]
+struct DayStats {
+    date: chrono::NaiveDate,
+    count: i64,
+}
+
+/// Copy the database into backup_dir, then delete copies past backup_retention_days
+async fn backup_handler(State(app): State<Arc<App>>) -> Response {
+    let config = app.config();
+    let Some(backup_dir) = config.backup_dir.as_ref() else {
+        return (StatusCode::NOT_FOUND, "Backups need backup_dir to be set").into_response();
+    };
+
+    let Ok(_backing_up) = app.backing_up.try_lock() else {
+        let error = "A backup is already running";
+        return (StatusCode::CONFLICT, Json(json!({ "error": error }))).into_response();
+    };
+
+    if let Err(err) = tokio::fs::create_dir_all(backup_dir).await {
+        return_500!(err, create_backup_dir);
+    }
+    let path = backup::timestamped(backup_dir);
+    let size = match backup::backup(&app.pool, &path).await {
+        Ok(size) => size,
+        Err(err) => return_500!(err, backup),
+    };
+
+    // the backup itself is fine either way
+    if let Some(days) = config.backup_retention_days
+        && let Err(err) = backup::prune(backup_dir, days).await
+    {
+        tracing::error!(prune_backups = ?err);
+    }
+
+    Json(json!({ "path": path, "size": size })).into_response()
+}
+
+/// Views of each post, most viewed first, with the last `STATS_DAYS` days by day
+#[tracing::instrument(skip(app))]
+async fn stats_handler(State(app): State<Arc<App>>) -> Response {
+    app.flush_views().await;
+    let since = app.config().now().date_naive() - chrono::TimeDelta::days(STATS_DAYS - 1);
+
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, stats_connection),
+    };
+
+    let posts = sqlx::query_as::<_, PostStats>(
+        r#"
+            select post.id, post.title, slug.slug, sum(view.count) as total
+            from view
+            join post on post.id = view.post_id
+            left join slug on slug.id = post.id
+                and (slug.newslug is null or slug.newslug = slug.slug)
+            group by post.id
+            order by total desc
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await;
+    let mut posts = match posts {
+        Ok(posts) => posts,
+        Err(err) => return_500!(err, select_stats),
+    };
+
+    let days = sqlx::query!(
+        r#"
+            select post_id as "post_id: Uuid", date as "date: chrono::NaiveDate", count
+            from view
+            where date >= $1
+            order by date
+        "#,
+        since
+    )
+    .fetch_all(&mut *conn)
+    .await;
+    let days = match days {
+        Ok(days) => days,
+        Err(err) => return_500!(err, select_stats_days),
+    };
+
+    for day in days {
+        if let Some(post) = posts.iter_mut().find(|post| post.id == day.post_id) {
+            post.days.push(DayStats {
+                date: day.date,
+                count: day.count,
+            });
+        }
+    }
+
+    Json(json!({ "since": since, "posts": posts })).into_response()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Publish {
+    title: String,
+    #[serde(default)]
+    subtitle: Option<String>,
+    /// What the post gets described as in meta tags and feeds, derived from the content if missing
+    #[serde(default, alias = "description")]
+    summary: Option<String>,
+    /// An http(s) url or a path on this site like an uploaded media file's, null removes it
+    #[serde(default)]
+    image: Option<String>,
+    /// Instead of the one from the title. Updates without it go back to the title's slug, and
+    /// either way the old slug redirects to the new one.
+    #[serde(default)]
+    slug: Option<String>,
+    /// An RFC 3339 date to publish the post at instead of now, for backfilling old posts
+    #[serde(default)]
+    published: Option<String>,
+    content: String,
+    /// Markdown for new posts, and whatever the post already was for updates
+    #[serde(default)]
+    content_type: Option<ContentType>,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    extra_head: Option<String>,
+    /// The name of a loaded template to render the post with instead of the usual one
+    #[serde(default)]
+    template: Option<String>,
+    /// Replaces the post's tags, which are left alone on updates if missing
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+}
+
+impl Publish {
+    /// Everything that's wrong with the post, for publishing, updating, and previewing alike
+    fn validate(&self, config: &Config) -> std::result::Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, field: &'static str, reason: String| {
+            if !ok {
+                errors.push(FieldError { field, reason });
+            }
+        };
+
+        check(
+            !self.title.trim().is_empty(),
+            "title",
+            String::from("can't be empty"),
+        );
+        check(
+            self.title.chars().count() <= config.title_max_length,
+            "title",
+            format!("can be at most {} characters", config.title_max_length),
+        );
+        check(
+            !self.content.trim().is_empty(),
+            "content",
+            String::from("can't be empty"),
+        );
+        check(
+            self.content.len() <= MAX_CONTENT_BYTES,
+            "content",
+            format!("can be at most {MAX_CONTENT_BYTES} bytes"),
+        );
+        let chars = |text: &Option<String>| text.as_ref().map_or(0, |text| text.chars().count());
+        check(
+            chars(&self.subtitle) <= MAX_SUBTITLE_CHARS,
+            "subtitle",
+            format!("can be at most {MAX_SUBTITLE_CHARS} characters"),
+        );
+        check(
+            chars(&self.summary) <= MAX_SUMMARY_CHARS,
+            "summary",
+            format!("can be at most {MAX_SUMMARY_CHARS} characters"),
+        );
+        check(
+            self.extra_head.as_ref().map_or(0, String::len) <= MAX_EXTRA_HEAD_BYTES,
+            "extra_head",
+            format!("can be at most {MAX_EXTRA_HEAD_BYTES} bytes"),
+        );
+
+        if let Some(tags) = self.tags.as_ref() {
+            check(
+                tags.len() <= MAX_TAGS,
+                "tags",
+                format!("can have at most {MAX_TAGS}"),
+            );
+            check(
+                tags.iter().all(|tag| tag.chars().count() <= MAX_TAG_CHARS),
+                "tags",
+                format!("can each be at most {MAX_TAG_CHARS} characters"),
+            );
+        }
+
+        if let Some(published) = self.published.as_deref() {
+            let latest = config.now() + chrono::TimeDelta::minutes(FUTURE_SLACK_MINUTES);
+            match DateTime::parse_from_rfc3339(published) {
+                Ok(published) => check(
+                    config.future_published || published <= latest,
+                    "published",
+                    String::from("can't be in the future"),
+                ),
+                Err(err) => check(false, "published", format!("isn't an RFC 3339 date: {err}")),
+            }
+        }
+
+        // og:image has to be absolute, so anything else can't be made into a full url later
+        if let Some(image) = self.image.as_deref() {
+            let is_url =
+                url::Url::parse(image).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+            let is_path = image.starts_with('/') && !image.starts_with("//");
+            let reason = String::from("must be an http or https url or a path");
+            check(is_url || is_path, "image", reason);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The slug the post asked for, cleaned up, or why it can't have it
+    fn custom_slug(&self, app: &App) -> std::result::Result<Option<String>, FieldError> {
+        let Some(requested) = self.slug.as_deref() else {
+            return Ok(None);
+        };
+
+        let invalid = |reason: String| FieldError {
+            field: "slug",
+            reason,
+        };
+        let slug = slug::slugify(requested);
+        if slug.is_empty() {
+            return Err(invalid(String::from("needs at least one letter or digit")));
+        }
+        if slug.chars().count() > MAX_CUSTOM_SLUG_CHARS {
+            return Err(invalid(format!(
+                "can be at most {MAX_CUSTOM_SLUG_CHARS} characters"
+            )));
+        }
+        if app.is_reserved_slug(&slug) {
+            return Err(invalid(format!(
+                "{slug:?} is taken by one of the blog's own pages"
+            )));
+        }
+
+        Ok(Some(slug))
+    }
+
+    /// Why the post can't be rendered with the template it asked for, if it can't
+    async fn check_template(&self, app: &App) -> std::result::Result<(), FieldError> {
+        match self.template.as_deref() {
+            Some(template) if !app.has_template(template).await => Err(FieldError {
+                field: "template",
+                reason: format!("{template:?} isn't one of the loaded templates"),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The date the post asked to be published at, once it's been validated
+    fn published_at(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.published.as_deref()?).ok()
+    }
+
+    /// A post that's yet to be saved anywhere, published now unless it says otherwise
+    fn new_post(&self, now: DateTime<FixedOffset>) -> Post {
+        Post {
+            id: Uuid::new_v4(),
+            title: self.title.clone(),
+            subtitle: self.subtitle.clone(),
+            summary: self.summary.clone(),
+            image: self.image.clone(),
+            published: self.published_at().unwrap_or(now),
+            updated: now,
+            content: self.content.clone(),
+            content_type: self.content_type.unwrap_or_default(),
+            draft: self.draft,
+            extra_head: self.extra_head.as_deref().and_then(sanitize_extra_head),
+            template: self.template.clone(),
+            deleted: None,
+        }
+    }
+}
+
+/// Something wrong with one field of a request
+#[derive(Debug, serde::Serialize)]
+struct FieldError {
+    field: &'static str,
+    reason: String,
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", self.field, self.reason)
+    }
+}
+
+/// A 422 listing everything that was wrong, like
+/// `{"errors": [{"field": "title", "reason": "can't be empty"}]}`
+fn invalid_response(errors: Vec<FieldError>) -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(json!({ "errors": errors })),
+    )
+        .into_response()
+}
+
+/// What the composer page submits
+#[derive(Debug, serde::Deserialize)]
+struct PublishForm {
+    title: String,
+    #[serde(default)]
+    subtitle: String,
+    #[serde(default)]
+    summary: String,
+    content: String,
+    /// Comma separated
+    #[serde(default)]
+    tags: String,
+    /// Only there when the draft button was the one pressed
+    #[serde(default)]
+    draft: Option<String>,
+}
+
+impl From<PublishForm> for Publish {
+    fn from(form: PublishForm) -> Publish {
+        Publish {
+            title: form.title,
+            subtitle: Some(form.subtitle).filter(|subtitle| !subtitle.trim().is_empty()),
+            summary: Some(form.summary).filter(|summary| !summary.trim().is_empty()),
+            image: None,
+            slug: None,
+            published: None,
+            content: form.content,
+            content_type: None,
+            draft: form.draft.is_some(),
+            extra_head: None,
+            template: None,
+            tags: Some(form.tags.split(',').map(String::from).collect()),
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn new_handler(
+    State(app): State<Arc<App>>,
+    caller: Option<axum::Extension<Caller>>,
+    headers: HeaderMap,
+) -> Response {
+    let (session, set_cookie) = app.csrf_session(&headers);
+    let mut context = app.context();
+    context.insert("publish_url", &app.config().route_dot("/publish"));
+    context.insert("csrf_token", &app.csrf_token(caller.as_deref(), &session));
+    match app.render(NEW_TEMPLATE, &context).await {
+        Ok(rendered) => {
+            let set_cookie = set_cookie.map(|cookie| [("Set-Cookie", cookie)]);
+            ([("Cache-Control", "no-store")], set_cookie, Html(rendered)).into_response()
+        }
+        Err(err) => return_500!(err, render_new),
+    }
+}
+
+/// How a post would look if it were published, along with the slug it would get in the
+/// `Preview-Slug` header. Nothing gets saved.
+#[tracing::instrument(skip_all)]
+async fn preview_handler(State(app): State<Arc<App>>, Json(to_preview): Json<Publish>) -> Response {
+    if let Err(errors) = to_preview.validate(&app.config()) {
+        return invalid_response(errors);
+    }
+
+    let custom_slug = match to_preview.custom_slug(&app) {
+        Ok(custom_slug) => custom_slug,
+        Err(error) => return invalid_response(vec![error]),
+    };
+    if let Err(error) = to_preview.check_template(&app).await {
+        return invalid_response(vec![error]);
+    }
+
+    let post = to_preview.new_post(app.config().now());
+    tracing::debug!(preview = ?post);
+
+    let slug = custom_slug.unwrap_or_else(|| post.slug(&app.config()));
+    let slug = match app.pool.acquire().await {
+        Ok(mut conn) => match app.available_slug(&mut conn, &slug, post.id).await {
+            Ok(slug) => slug,
+            Err(err) => match err.downcast_ref::<SlugUnavailable>() {
+                Some(unavailable) => {
+                    return (unavailable.status(), unavailable.to_string()).into_response();
+                }
+                None => return_500!(err, preview_slug),
+            },
+        },
+        Err(err) => return_500!(err, preview_connection),
+    };
+
+    let tags = normalize_tags(&to_preview.tags.unwrap_or_default());
+    let links = match app.pool.acquire().await {
+        Ok(mut conn) => match app.post_links(&mut conn, &post).await {
+            Ok(links) => links,
+            Err(err) => return_500!(err, preview_links),
+        },
+        Err(err) => return_500!(err, preview_connection),
+    };
+
+    match app
+        .render_post_page(post, &slug, &tags, &[], &links, false)
+        .await
+    {
+        Ok(rendered) => (
+            [
+                ("Cache-Control", "no-store"),
+                ("Preview-Slug", slug.as_str()),
+            ],
+            Html(rendered),
+        )
+            .into_response(),
+        Err(err) => return_500!(err, render_preview),
+    }
+}
+
+/// How long a publish's Idempotency-Key is remembered, so a retry within then gets the post the
+/// first try made instead of another one
+const IDEMPOTENCY_KEY_HOURS: i64 = 24;
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Takes JSON from API clients, or a form from the composer page, which gets sent on to the
+/// new post instead of getting JSON back. Requests with an `Idempotency-Key` header only ever
+/// make one post for the key, with the same request again getting the same response.
+#[tracing::instrument(skip_all)]
+async fn publish_handler(State(app): State<Arc<App>>, request: axum::extract::Request) -> Response {
+    use axum::extract::FromRequest;
+
+    let from_form = request
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+
+    let idempotency_key = match request.headers().get("idempotency-key") {
+        None => None,
+        Some(key) => match key.to_str().map(str::trim) {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => {
+                Some(String::from(key))
+            }
+            _ => {
+                let error =
+                    format!("Idempotency-Key must be 1 to {MAX_IDEMPOTENCY_KEY_LEN} characters");
+                return (StatusCode::BAD_REQUEST, error).into_response();
+            }
+        },
+    };
+
+    // body_limit_layer has already read the body, so this is only to fingerprint it for retries
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    let body_sha256 = sha256(&body);
+    let request = axum::extract::Request::from_parts(parts, body.into());
+
+    if let Some(key) = idempotency_key.as_deref() {
+        let previous = match app.idempotent_post(key).await {
+            Ok(previous) => previous,
+            Err(err) => return_500!(err, select_idempotency_key),
+        };
+
+        if let Some((id, previous_sha256)) = previous {
+            if previous_sha256 != body_sha256 {
+                let error = "Idempotency-Key was already used for a different request";
+                return (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(json!({ "error": error })),
+                )
+                    .into_response();
+            }
+
+            tracing::debug!(idempotent_retry = %id);
+            let mut conn = match app.pool.acquire().await {
+                Ok(conn) => conn,
+                Err(err) => return_500!(err, idempotent_connection),
+            };
+            let post = match app.find_post_uuid(&mut *conn, id).await {
+                Ok(Some(post)) => post,
+                Ok(None) => return (StatusCode::NOT_FOUND, "post not found").into_response(),
+                Err(err) => return_500!(err, select_idempotent_post),
+            };
+            let slugs = match app.slug_history(&mut *conn, id).await {
+                Ok(slugs) => slugs,
+                Err(err) => return_500!(err, idempotent_slugs),
+            };
+            let slug = match slugs.into_iter().find(|entry| entry.canonical) {
+                Some(canonical) => canonical.slug,
+                None => post.slug(&app.config()),
+            };
+            return published_response(&app, from_form, &post, &slug);
+        }
+    }
+
+    let to_publish = if from_form {
+        match axum::Form::<PublishForm>::from_request(request, &()).await {
+            Ok(axum::Form(form)) => Publish::from(form),
+            Err(rejection) => return rejection.into_response(),
+        }
+    } else {
+        match Json::<Publish>::from_request(request, &()).await {
+            Ok(Json(to_publish)) => to_publish,
+            Err(rejection) => return rejection.into_response(),
+        }
+    };
+
+    if let Err(errors) = to_publish.validate(&app.config()) {
+        return invalid_response(errors);
+    }
+    let custom_slug = match to_publish.custom_slug(&app) {
+        Ok(custom_slug) => custom_slug,
+        Err(error) => return invalid_response(vec![error]),
+    };
+    if let Err(error) = to_publish.check_template(&app).await {
+        return invalid_response(vec![error]);
+    }
+
+    let post = to_publish.new_post(app.config().now());
+
+    tracing::debug!(new_post = ?post);
+
+    let mut tx = match app.begin_write().await {
+        Ok(tx) => tx,
+        Err(err) => return_500!(err, new_post_transaction),
+    };
+
+    let tags = to_publish.tags.unwrap_or_default();
+    let slug = custom_slug.unwrap_or_else(|| post.slug(&app.config()));
+    let slug = match app.create_post(&mut tx, &post, &tags, &slug).await {
+        Ok(slug) => slug,
+        Err(err) => match err.downcast_ref::<SlugUnavailable>() {
+            Some(unavailable) => {
+                return (unavailable.status(), unavailable.to_string()).into_response();
+            }
+            None => return_500!(err, create_post),
+        },
+    };
+
+    if let Some(key) = idempotency_key.as_deref() {
+        match app
+            .insert_idempotency_key(&mut tx, key, &body_sha256, post.id)
+            .await
+        {
+            Ok(()) => {}
+            // another request with the key got there first and hasn't finished, or just has
+            Err(err)
+                if err
+                    .as_database_error()
+                    .is_some_and(|err| err.is_unique_violation()) =>
+            {
+                let error = "A request with this Idempotency-Key is already in progress";
+                return (StatusCode::CONFLICT, Json(json!({ "error": error }))).into_response();
+            }
+            Err(err) => return_500!(err, insert_idempotency_key),
+        }
+    }
+
+    if let Err(err) = tx.commit().await {
+        return_500!(err, new_post_transaction_commit);
+    }
+
+    let response = published_response(&app, from_form, &post, &slug);
+    let id = post.id;
+    app.refresh_snapshot(post, &slug).await;
+    app.spawn_webmentions(id);
+    #[cfg(feature = "activitypub")]
+    activitypub::spawn_post_delivery(&app, id);
+
+    response
+}
+
+/// The composer page goes on to the new post, and API clients get its id and slug
+fn published_response(app: &App, from_form: bool, post: &Post, slug: &str) -> Response {
+    if from_form {
+        return axum::response::Redirect::to(&app.config().route(&format!("/{slug}")))
+            .into_response();
+    }
+
+    let description = post.description();
+    Json(json!({ "id": post.id, "slug": slug, "description": description })).into_response()
+}
+
+#[tracing::instrument(skip_all)]
 async fn update_handler(
     State(app): State<Arc<App>>,
-    Path(update): Path<Uuid>,
-    Json(to_publish): Json<Publish>,
+    Path(update): Path<Uuid>,
+    Json(to_publish): Json<Publish>,
+) -> Response {
+    apply_update(&app, update, |_| to_publish, true).await
+}
+
+/// An update that only names what changes. A field that's missing keeps its value, and `null`
+/// clears the ones that can be empty: `subtitle`, `summary`, `image`, `extra_head`, and
+/// `template`. `slug` works like it does in a full update, except leaving it out keeps the
+/// current slug unless the title changes.
+#[derive(Debug, serde::Deserialize)]
+struct PublishPatch {
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    subtitle: Option<Option<String>>,
+    #[serde(
+        default,
+        alias = "description",
+        deserialize_with = "deserialize_present"
+    )]
+    summary: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    image: Option<Option<String>>,
+    #[serde(default)]
+    slug: Option<String>,
+    #[serde(default)]
+    published: Option<String>,
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    content_type: Option<ContentType>,
+    #[serde(default)]
+    draft: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    extra_head: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    template: Option<Option<String>>,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+}
+
+/// `Some` for any value that's there, including `null`, so with `#[serde(default)]` a missing
+/// field is the only thing that's `None`
+fn deserialize_present<'de, D: serde::Deserializer<'de>, T: serde::Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+impl PublishPatch {
+    /// The whole post as it is after the patch
+    fn apply(self, existing: &Post) -> Publish {
+        Publish {
+            title: self.title.unwrap_or_else(|| existing.title.clone()),
+            subtitle: self.subtitle.unwrap_or_else(|| existing.subtitle.clone()),
+            summary: self.summary.unwrap_or_else(|| existing.summary.clone()),
+            image: self.image.unwrap_or_else(|| existing.image.clone()),
+            slug: self.slug,
+            published: self.published,
+            content: self.content.unwrap_or_else(|| existing.content.clone()),
+            content_type: Some(self.content_type.unwrap_or(existing.content_type)),
+            draft: self.draft.unwrap_or(existing.draft),
+            extra_head: self
+                .extra_head
+                .unwrap_or_else(|| existing.extra_head.clone()),
+            template: self.template.unwrap_or_else(|| existing.template.clone()),
+            tags: self.tags,
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn patch_handler(
+    State(app): State<Arc<App>>,
+    Path(update): Path<Uuid>,
+    Json(patch): Json<PublishPatch>,
+) -> Response {
+    apply_update(&app, update, |existing| patch.apply(existing), false).await
+}
+
+/// Replace a post with what `changes` makes of it. The old version is kept as a revision if
+/// anything a revision has changed. The slug is worked out again when `reslug` is set, or the
+/// title or `published` changed, or the update asks for one.
+async fn apply_update(
+    app: &Arc<App>,
+    update: Uuid,
+    changes: impl FnOnce(&Post) -> Publish,
+    reslug: bool,
+) -> Response {
+    let mut tx = match app.begin_write().await {
+        Ok(tx) => tx,
+        Err(err) => return_500!(err, update_post_transaction),
+    };
+
+    let existing = match app.find_post_uuid(&mut *tx, update).await {
+        Ok(Some(existing)) if existing.deleted.is_some() => {
+            tracing::trace!(update_deleted = %update);
+            return (StatusCode::GONE, "post was deleted").into_response();
+        }
+        Ok(Some(existing)) => existing,
+        // passed a uuid in the path but the post with that uuid didn't exist
+        Ok(None) => {
+            tracing::trace!(not_found = %update);
+            return (StatusCode::NOT_FOUND, "post not found").into_response();
+        }
+        Err(err) => return_500!(err, select_existing),
+    };
+    tracing::debug!(update_existing = %update);
+
+    let to_publish = changes(&existing);
+    if let Err(errors) = to_publish.validate(&app.config()) {
+        return invalid_response(errors);
+    }
+    let custom_slug = match to_publish.custom_slug(app) {
+        Ok(custom_slug) => custom_slug,
+        Err(error) => return invalid_response(vec![error]),
+    };
+    if let Err(error) = to_publish.check_template(app).await {
+        return invalid_response(vec![error]);
+    }
+
+    // published stays put so the slug does too, unless it's given or this is a draft going public
+    let now = app.config().now();
+    let published = match to_publish.published_at() {
+        Some(published) => published,
+        None if existing.draft && !to_publish.draft => now,
+        None => existing.published,
+    };
+    let new_post = Post {
+        id: existing.id,
+        title: to_publish.title,
+        subtitle: to_publish.subtitle,
+        summary: to_publish.summary,
+        image: to_publish.image,
+        published,
+        updated: now,
+        content: to_publish.content,
+        content_type: to_publish.content_type.unwrap_or(existing.content_type),
+        draft: to_publish.draft,
+        extra_head: to_publish
+            .extra_head
+            .as_deref()
+            .and_then(sanitize_extra_head),
+        template: to_publish.template,
+        deleted: None,
+    };
+
+    // these are what revisions keep, so changing anything else doesn't need one
+    let revised = new_post.title != existing.title
+        || new_post.subtitle != existing.subtitle
+        || new_post.published != existing.published
+        || new_post.content != existing.content
+        || new_post.content_type != existing.content_type;
+    if revised && let Err(err) = app.insert_revision(&mut *tx, &existing).await {
+        return_500!(err, insert_revision);
+    }
+
+    if let Err(err) = app.update_post(&mut *tx, &new_post).await {
+        return_500!(err, update_existing);
+    }
+
+    if let Some(tags) = to_publish.tags {
+        let tags = normalize_tags(&tags);
+        if let Err(err) = app.set_tags(&mut *tx, new_post.id, &tags).await {
+            return_500!(err, update_tags);
+        }
+    }
+
+    let reslug = reslug
+        || custom_slug.is_some()
+        || new_post.title != existing.title
+        || new_post.published != existing.published;
+    let slug = if reslug {
+        let slug = custom_slug.unwrap_or_else(|| new_post.slug(&app.config()));
+        match app.update_slugs(&mut *tx, &new_post, &slug).await {
+            Ok(slug) => slug,
+            Err(err) => match err.downcast_ref::<SlugUnavailable>() {
+                Some(unavailable) => {
+                    return (unavailable.status(), unavailable.to_string()).into_response();
+                }
+                None => return_500!(err, update_slugs),
+            },
+        }
+    } else {
+        let slugs = match app.slug_history(&mut *tx, new_post.id).await {
+            Ok(slugs) => slugs,
+            Err(err) => return_500!(err, update_current_slug),
+        };
+        match slugs.into_iter().find(|entry| entry.canonical) {
+            Some(canonical) => canonical.slug,
+            None => new_post.slug(&app.config()),
+        }
+    };
+
+    if let Err(err) = tx.commit().await {
+        return_500!(err, update_post_transaction_commit);
+    }
+
+    let id = new_post.id;
+    let description = new_post.description();
+    app.refresh_snapshot(new_post, &slug).await;
+    app.spawn_webmentions(id);
+
+    Json(json!({ "id": id, "slug": slug, "description": description })).into_response()
+}
+
+/// Put a post back the way it was at some revision, saving how it is now as another revision
+#[tracing::instrument(skip(app))]
+async fn restore_handler(
+    State(app): State<Arc<App>>,
+    Path((id, revision)): Path<(Uuid, i64)>,
+) -> Response {
+    let mut tx = match app.begin_write().await {
+        Ok(tx) => tx,
+        Err(err) => return_500!(err, restore_transaction),
+    };
+
+    let existing = match app.find_post_uuid(&mut *tx, id).await {
+        Ok(Some(existing)) if existing.deleted.is_some() => {
+            return (StatusCode::GONE, "post was deleted").into_response();
+        }
+        Ok(Some(existing)) => existing,
+        Ok(None) => return (StatusCode::NOT_FOUND, "post not found").into_response(),
+        Err(err) => return_500!(err, restore_existing),
+    };
+
+    let restored = match app.revisions(&mut *tx, id).await {
+        Ok(stored) => stored
+            .into_iter()
+            .find(|stored| stored.revision == revision),
+        Err(err) => return_500!(err, restore_revisions),
+    };
+    let Some(restored) = restored else {
+        let message = format!("no revision {revision} of this post, see the revisions list");
+        return (StatusCode::NOT_FOUND, message).into_response();
+    };
+
+    if let Err(err) = app.insert_revision(&mut *tx, &existing).await {
+        return_500!(err, restore_insert_revision);
+    }
+
+    let new_post = Post {
+        title: restored.title,
+        subtitle: restored.subtitle,
+        content: restored.content,
+        content_type: restored.content_type,
+        updated: app.config().now(),
+        ..existing
+    };
+
+    if let Err(err) = app.update_post(&mut *tx, &new_post).await {
+        return_500!(err, restore_update);
+    }
+
+    let slug = new_post.slug(&app.config());
+    let slug = match app.update_slugs(&mut *tx, &new_post, &slug).await {
+        Ok(slug) => slug,
+        Err(err) => match err.downcast_ref::<SlugUnavailable>() {
+            Some(unavailable) => {
+                return (unavailable.status(), unavailable.to_string()).into_response();
+            }
+            None => return_500!(err, restore_slugs),
+        },
+    };
+
+    if let Err(err) = tx.commit().await {
+        return_500!(err, restore_transaction_commit);
+    }
+
+    app.refresh_snapshot(new_post, &slug).await;
+
+    Json(json!({ "id": id, "slug": slug })).into_response()
+}
+
+/// Mark a post deleted, leaving everything else about it in place. Deleting it again just
+/// reports when it was first deleted.
+#[tracing::instrument(skip(app))]
+async fn delete_handler(State(app): State<Arc<App>>, Path(id): Path<Uuid>) -> Response {
+    let mut tx = match app.begin_write().await {
+        Ok(tx) => tx,
+        Err(err) => return_500!(err, delete_post_transaction),
+    };
+
+    let deleted = match app.find_post_uuid(&mut *tx, id).await {
+        Ok(Some(Post {
+            deleted: Some(deleted),
+            ..
+        })) => deleted,
+
+        Ok(Some(post)) => {
+            let now = app.config().now();
+            if let Err(err) = app.delete_post(&mut *tx, id, now).await {
+                return_500!(err, delete_post);
+            }
+
+            let slugs = match app.slug_history(&mut *tx, id).await {
+                Ok(slugs) => slugs,
+                Err(err) => return_500!(err, deleted_post_slugs),
+            };
+
+            if let Err(err) = tx.commit().await {
+                return_500!(err, delete_post_transaction_commit);
+            }
+
+            if let Some(canonical) = slugs.iter().find(|entry| entry.canonical) {
+                let post = Post {
+                    deleted: Some(now),
+                    ..post
+                };
+                app.refresh_snapshot(post, &canonical.slug).await;
+            }
+
+            now
+        }
+
+        Ok(None) => {
+            tracing::trace!(not_found = %id);
+            return (StatusCode::NOT_FOUND, "post not found").into_response();
+        }
+
+        Err(err) => return_500!(err, select_deleted),
+    };
+
+    Json(json!({ "id": id, "deleted": deleted })).into_response()
+}
+
+#[tracing::instrument(skip(app))]
+async fn unpublish_handler(State(app): State<Arc<App>>, Path(id): Path<Uuid>) -> Response {
+    set_draft(&app, id, true).await
+}
+
+#[tracing::instrument(skip(app))]
+async fn republish_handler(State(app): State<Arc<App>>, Path(id): Path<Uuid>) -> Response {
+    set_draft(&app, id, false).await
+}
+
+/// Move a post in or out of the drafts without touching anything else about it, so it keeps its
+/// slugs and `published` date. It does count as an update.
+async fn set_draft(app: &App, id: Uuid, draft: bool) -> Response {
+    let mut tx = match app.begin_write().await {
+        Ok(tx) => tx,
+        Err(err) => return_500!(err, set_draft_transaction),
+    };
+
+    let post = match app.find_post_uuid(&mut *tx, id).await {
+        Ok(Some(post)) if post.deleted.is_some() => {
+            return (StatusCode::GONE, "post was deleted").into_response();
+        }
+        Ok(Some(post)) => post,
+        Ok(None) => {
+            tracing::trace!(not_found = %id);
+            return (StatusCode::NOT_FOUND, "post not found").into_response();
+        }
+        Err(err) => return_500!(err, select_set_draft),
+    };
+
+    let updated = app.config().now();
+    if let Err(err) = sqlx::query!(
+        "update post set draft = $1, updated = $2 where id = $3",
+        draft,
+        updated,
+        id
+    )
+    .execute(&mut *tx)
+    .await
+    {
+        return_500!(err, set_draft);
+    }
+
+    let slugs = match app.slug_history(&mut *tx, id).await {
+        Ok(slugs) => slugs,
+        Err(err) => return_500!(err, set_draft_slugs),
+    };
+
+    if let Err(err) = tx.commit().await {
+        return_500!(err, set_draft_transaction_commit);
+    }
+
+    if let Some(canonical) = slugs.iter().find(|entry| entry.canonical) {
+        let post = Post {
+            draft,
+            updated,
+            ..post
+        };
+        app.refresh_snapshot(post, &canonical.slug).await;
+    }
+
+    Json(json!({ "id": id, "draft": draft })).into_response()
+}
+
+#[tracing::instrument(skip_all)]
+async fn drafts_handler(State(app): State<Arc<App>>) -> Response {
+    match sqlx::query_as::<_, Recent>(
+        r#"
+            select slug, title, subtitle, summary, published, updated, content, content_type
+            from post
+            join slug on post.id = slug.id
+            where draft is true
+                and deleted is null
+            group by post.id
+            order by published desc
+        "#,
+    )
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(mut posts) => {
+            Recent::describe_all(&mut posts, &app.config());
+            let mut context = app.context();
+            context.insert("blog_title", &format!("Editing {}", app.config().title));
+            context.insert("posts", &posts);
+            context.insert("is_authenticated", &true);
+            match app.render(INDEX_TEMPLATE, &context).await {
+                Ok(rendered) => Html(rendered).into_response(),
+                Err(err) => return_500!(err, render_index),
+            }
+        }
+        Err(err) => return_500!(err, select_recent_posts),
+    }
+}
+
+/// Tags as they're stored: slugified, deduplicated, and sorted
+fn normalize_tags(tags: &[String]) -> Vec<String> {
+    tags.iter()
+        .map(slug::slugify)
+        .filter(|tag| !tag.is_empty())
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Whether an If-None-Match header matches an entity tag, weakly as the header is meant to be
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(axum::http::header::IF_NONE_MATCH)
+        .and_then(|if_none_match| if_none_match.to_str().ok())
+        .is_some_and(|if_none_match| {
+            if_none_match
+                .split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        })
+}
+
+/// Whether the copy a client has from some time in If-Modified-Since is still good, to the second
+/// since that's all HTTP dates have. Only if there's no If-None-Match, which is more precise, and
+/// dates that don't parse are ignored.
+fn not_modified_since(headers: &HeaderMap, last_modified: DateTime<Utc>) -> bool {
+    if headers.contains_key(axum::http::header::IF_NONE_MATCH) {
+        return false;
+    }
+
+    headers
+        .get(axum::http::header::IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(|since| httpdate::parse_http_date(since).ok())
+        .is_some_and(|since| last_modified.timestamp() <= DateTime::<Utc>::from(since).timestamp())
+}
+
+fn http_date(date: DateTime<Utc>) -> String {
+    httpdate::fmt_http_date(date.into())
+}
+
+/// A slug with no `newslug` is its own canonical slug
+fn canonical_slug(slug: &str, newslug: Option<String>) -> String {
+    newslug.unwrap_or_else(|| String::from(slug))
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SlugMapping {
+    slug: String,
+    canonical: String,
+    id: Uuid,
+}
+
+#[tracing::instrument(skip_all)]
+async fn slugs_handler(State(app): State<Arc<App>>) -> Response {
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, slugs_connection),
+    };
+
+    match app.all_slugs(&mut *conn).await {
+        Ok(slugs) => Json(slugs).into_response(),
+        Err(err) => return_500!(err, all_slugs),
+    }
+}
+
+/// Every slug that redirects somewhere, whether from a rename or added by hand
+#[tracing::instrument(skip_all)]
+async fn redirects_handler(State(app): State<Arc<App>>) -> Response {
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, redirects_connection),
+    };
+
+    match app.all_slugs(&mut *conn).await {
+        Ok(slugs) => {
+            let redirects = slugs
+                .into_iter()
+                .filter(|mapping| mapping.slug != mapping.canonical)
+                .collect::<Vec<_>>();
+            Json(redirects).into_response()
+        }
+        Err(err) => return_500!(err, redirects),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct NewRedirect {
+    /// Under page_root, like `my-old-post.html` to keep a link from an old blog working
+    from: String,
+    /// Any slug of the post, the redirect goes to whichever is canonical
+    to: String,
+}
+
+#[tracing::instrument(skip(app))]
+async fn create_redirect_handler(
+    State(app): State<Arc<App>>,
+    Json(redirect): Json<NewRedirect>,
+) -> Response {
+    let from = redirect.from.trim_matches('/');
+    if from.is_empty() || from.contains('/') {
+        let message = "from has to be a single path segment";
+        return (StatusCode::UNPROCESSABLE_ENTITY, message).into_response();
+    }
+    if app.is_reserved_slug(from) {
+        let message = SlugUnavailable::Reserved(String::from(from)).to_string();
+        return (StatusCode::UNPROCESSABLE_ENTITY, message).into_response();
+    }
+
+    let mut tx = match app.begin_write().await {
+        Ok(tx) => tx,
+        Err(err) => return_500!(err, create_redirect_transaction),
+    };
+
+    let (id, canonical) = match app
+        .get_newest_slug(&mut *tx, redirect.to.trim_matches('/'))
+        .await
+    {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            let message = format!("no post has the slug {:?}", redirect.to);
+            return (StatusCode::NOT_FOUND, message).into_response();
+        }
+        Err(err) => return_500!(err, redirect_destination),
+    };
+
+    // redirects only ever point at canonical slugs, so this is the only way to make a loop
+    if canonical == from {
+        let message = format!("{from:?} is where the redirect would go");
+        return (StatusCode::UNPROCESSABLE_ENTITY, message).into_response();
+    }
+
+    match app.get_newest_slug(&mut *tx, from).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            let message = format!("{from:?} is already a slug, delete it first if it's a redirect");
+            return (StatusCode::CONFLICT, message).into_response();
+        }
+        Err(err) => return_500!(err, redirect_source),
+    }
+
+    if let Err(err) = app.insert_redirect(&mut *tx, from, id, &canonical).await {
+        return_500!(err, insert_redirect);
+    }
+
+    if let Err(err) = tx.commit().await {
+        return_500!(err, create_redirect_transaction_commit);
+    }
+
+    Json(SlugMapping {
+        slug: String::from(from),
+        canonical,
+        id,
+    })
+    .into_response()
+}
+
+/// Stop a slug redirecting, so it 404s. Canonical slugs can't be deleted this way.
+#[tracing::instrument(skip(app))]
+async fn delete_redirect_handler(
+    State(app): State<Arc<App>>,
+    Path(from): Path<String>,
+) -> Response {
+    let deleted = sqlx::query!(
+        "delete from slug where slug = $1 and newslug is not null",
+        from
+    )
+    .execute(&app.pool)
+    .await;
+
+    match deleted {
+        Ok(deleted) if deleted.rows_affected() == 0 => {
+            (StatusCode::NOT_FOUND, "no redirect from there").into_response()
+        }
+        Ok(_) => Json(json!({ "slug": from, "deleted": true })).into_response(),
+        Err(err) => return_500!(err, delete_redirect),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ChangesQuery {
+    since: DateTime<FixedOffset>,
+}
+
+/// Every post, drafts and deleted posts included, changed at or after `since`. `server_time` is
+/// taken before reading, so passing it back as the next `since` can repeat an edit made at that
+/// exact moment but never skips one.
+#[tracing::instrument(skip(app))]
+async fn api_changes_handler(
+    State(app): State<Arc<App>>,
+    Query(ChangesQuery { since }): Query<ChangesQuery>,
+) -> Response {
+    let server_time = app.config().now();
+
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, changes_connection),
+    };
+
+    match app.changed_posts(&mut *conn, since).await {
+        Ok(posts) => Json(json!({ "server_time": server_time, "posts": posts })).into_response(),
+        Err(err) => return_500!(err, changed_posts),
+    }
+}
+
+/// Most posts `GET /.blog3/posts` gives at once
+const MAX_POSTS_LIMIT: u32 = 500;
+
+#[derive(Debug, serde::Deserialize)]
+struct PostsQuery {
+    #[serde(default = "default_posts_limit")]
+    limit: u32,
+    #[serde(default)]
+    offset: u32,
+    #[serde(default)]
+    include_content: bool,
+}
+
+fn default_posts_limit() -> u32 {
+    50
+}
+
+#[derive(Debug, sqlx::FromRow, serde::Serialize)]
+struct PostListing {
+    id: Uuid,
+    title: String,
+    subtitle: Option<String>,
+    published: DateTime<FixedOffset>,
+    updated: DateTime<FixedOffset>,
+    /// The canonical one
+    slug: Option<String>,
+    /// `published` or `draft`
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+/// Every post that hasn't been deleted, drafts included, newest first
+#[tracing::instrument(skip(app))]
+async fn posts_handler(State(app): State<Arc<App>>, Query(query): Query<PostsQuery>) -> Response {
+    let limit = query.limit.clamp(1, MAX_POSTS_LIMIT);
+
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, posts_connection),
+    };
+
+    let total =
+        sqlx::query_scalar!(r#"select count(*) as "total: i64" from post where deleted is null"#)
+            .fetch_one(&mut *conn)
+            .await;
+    let total = match total {
+        Ok(total) => total,
+        Err(err) => return_500!(err, count_posts),
+    };
+
+    let posts = sqlx::query_as::<_, PostListing>(
+        r#"
+            select
+                post.id, post.title, post.subtitle, post.published, post.updated, slug.slug,
+                case when post.draft then 'draft' else 'published' end as status,
+                case when $1 then post.content end as content
+            from post
+            left join slug on slug.id = post.id
+                and (slug.newslug is null or slug.newslug = slug.slug)
+            where post.deleted is null
+            order by post.published desc, post.id
+            limit $2 offset $3
+        "#,
+    )
+    .bind(query.include_content)
+    .bind(limit)
+    .bind(query.offset)
+    .fetch_all(&mut *conn)
+    .await;
+    let posts = match posts {
+        Ok(posts) => posts,
+        Err(err) => return_500!(err, select_posts),
+    };
+
+    Json(json!({
+        "total": total,
+        "limit": limit,
+        "offset": query.offset,
+        "posts": posts,
+    }))
+    .into_response()
+}
+
+/// One post as the editor needs it, deleted or not. Takes the id as a string so a malformed one
+/// gets a 400 saying so.
+#[tracing::instrument(skip(app, headers))]
+async fn post_json_handler(
+    State(app): State<Arc<App>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return (StatusCode::BAD_REQUEST, "malformed post id").into_response();
+    };
+
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, post_json_connection),
+    };
+
+    let post = match app.find_post_uuid(&mut *conn, id).await {
+        Ok(Some(post)) => post,
+        Ok(None) => {
+            tracing::trace!(not_found = %id);
+            return (StatusCode::NOT_FOUND, "post not found").into_response();
+        }
+        Err(err) => return_500!(err, select_post_json),
+    };
+
+    let etag = format!("\"{}\"", post.version());
+    if etag_matches(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(axum::http::header::ETAG, etag)]).into_response();
+    }
+
+    let tags = match app.post_tags(&mut *conn, id).await {
+        Ok(tags) => tags,
+        Err(err) => return_500!(err, post_json_tags),
+    };
+    let slugs = match app.slug_history(&mut *conn, id).await {
+        Ok(slugs) => slugs,
+        Err(err) => return_500!(err, post_json_slugs),
+    };
+    let slug = slugs
+        .into_iter()
+        .find(|entry| entry.canonical)
+        .map(|entry| entry.slug);
+
+    let mut body = json!(post);
+    body["slug"] = json!(slug);
+    body["tags"] = json!(tags);
+    body["status"] = json!(post.status());
+    body["version"] = json!(post.version());
+    ([(axum::http::header::ETAG, etag)], Json(body)).into_response()
+}
+
+/// A post as it was stored as JSON in `old`, before revisions got their own table. Old rows are
+/// missing fields that were added to posts later.
+#[derive(Debug, serde::Deserialize)]
+struct StoredPost {
+    title: String,
+    #[serde(default)]
+    subtitle: Option<String>,
+    published: DateTime<FixedOffset>,
+    #[serde(default)]
+    updated: Option<DateTime<FixedOffset>>,
+    content: String,
+    #[serde(default)]
+    content_type: Option<ContentType>,
+}
+
+/// A post as it was before some update, numbered from 1 for each post
+#[derive(Debug, sqlx::FromRow)]
+struct Revision {
+    revision: i64,
+    title: String,
+    subtitle: Option<String>,
+    published: DateTime<FixedOffset>,
+    content: String,
+    content_type: ContentType,
+    created_at: DateTime<FixedOffset>,
+}
+
+/// One version of a post, `revision` being either `current` or the one to ask for it by
+#[derive(Debug, serde::Serialize)]
+struct RevisionEntry {
+    revision: String,
+    title: String,
+    subtitle: Option<String>,
+    published: DateTime<FixedOffset>,
+    /// When this version was saved
+    created_at: DateTime<FixedOffset>,
+}
+
+#[tracing::instrument(skip(app))]
+async fn revisions_handler(State(app): State<Arc<App>>, Path(id): Path<Uuid>) -> Response {
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, revisions_connection),
+    };
+
+    let current = match app.find_post_uuid(&mut *conn, id).await {
+        Ok(Some(post)) => post,
+        Ok(None) => return (StatusCode::NOT_FOUND, "post not found").into_response(),
+        Err(err) => return_500!(err, revisions_current),
+    };
+
+    let stored = match app.revisions(&mut *conn, id).await {
+        Ok(stored) => stored,
+        Err(err) => return_500!(err, revisions),
+    };
+
+    let mut revisions = vec![RevisionEntry {
+        revision: String::from("current"),
+        title: current.title,
+        subtitle: current.subtitle,
+        published: current.published,
+        created_at: current.updated,
+    }];
+    revisions.extend(stored.into_iter().map(|revision| RevisionEntry {
+        revision: revision.revision.to_string(),
+        title: revision.title,
+        subtitle: revision.subtitle,
+        published: revision.published,
+        created_at: revision.created_at,
+    }));
+
+    Json(revisions).into_response()
+}
+
+/// Edits past this many in one diff just replace whatever's left, since finding the shortest diff
+/// takes memory quadratic in the number of edits
+const MAX_DIFF_EDITS: usize = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edit {
+    Same(usize, usize),
+    Removed(usize),
+    Added(usize),
+}
+
+/// The shortest line edit script from `from` to `to`, by Myers' algorithm
+fn diff_lines(from: &[&str], to: &[&str]) -> Vec<Edit> {
+    let prefix = from.iter().zip(to).take_while(|(a, b)| a == b).count();
+    let suffix = from[prefix..]
+        .iter()
+        .rev()
+        .zip(to[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let (a, b) = (
+        &from[prefix..from.len() - suffix],
+        &to[prefix..to.len() - suffix],
+    );
+    let (n, m) = (a.len() as isize, b.len() as isize);
+
+    let mut edits = (0..prefix).map(|i| Edit::Same(i, i)).collect::<Vec<_>>();
+
+    // furthest x reached on each diagonal k = x - y, and what that looked like before each step
+    let offset = n + m + 1;
+    let mut v = vec![0isize; (2 * offset + 1) as usize];
+    let mut trace = Vec::new();
+    let mut found = false;
+
+    for d in 0..=(n + m).min(MAX_DIFF_EDITS as isize) {
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+
+        for k in (-d..=d).step_by(2) {
+            let at = |k: isize| v[(offset + k) as usize];
+            // step down from the diagonal above, or right from the one below
+            let mut x = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+                at(k + 1)
+            } else {
+                at(k - 1) + 1
+            };
+            let mut y = x - k;
+
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+
+            v[(offset + k) as usize] = x;
+            if x >= n && y >= m {
+                found = true;
+                break;
+            }
+        }
+
+        if found {
+            break;
+        }
+    }
+
+    let mut middle = Vec::new();
+    if found {
+        let (mut x, mut y) = (n, m);
+        for (d, v) in trace.iter().enumerate().rev() {
+            let d = d as isize;
+            let at = |k: isize| v[(k + d) as usize];
+            let k = x - y;
+
+            // the first snake starts from the very beginning
+            let (prev_x, prev_y) = if d == 0 {
+                (0, 0)
+            } else {
+                let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+                    k + 1
+                } else {
+                    k - 1
+                };
+                (at(prev_k), at(prev_k) - prev_k)
+            };
+
+            while x > prev_x && y > prev_y {
+                x -= 1;
+                y -= 1;
+                middle.push(Edit::Same(prefix + x as usize, prefix + y as usize));
+            }
+
+            if d > 0 {
+                if x == prev_x {
+                    middle.push(Edit::Added(prefix + prev_y as usize));
+                } else {
+                    middle.push(Edit::Removed(prefix + prev_x as usize));
+                }
+            }
+
+            (x, y) = (prev_x, prev_y);
+        }
+        middle.reverse();
+    } else {
+        tracing::debug!("diff too long, replacing the middle");
+        middle.extend((0..a.len()).map(|i| Edit::Removed(prefix + i)));
+        middle.extend((0..b.len()).map(|i| Edit::Added(prefix + i)));
+    }
+
+    edits.extend(middle);
+    edits.extend((0..suffix).map(|i| Edit::Same(from.len() - suffix + i, to.len() - suffix + i)));
+    edits
+}
+
+/// Unchanged lines kept around each change
+const DIFF_CONTEXT: usize = 3;
+
+#[derive(Debug, serde::Serialize)]
+struct DiffLine {
+    op: &'static str,
+    text: String,
+}
+
+/// A run of changes with some context, numbered from 1 like a unified diff
+#[derive(Debug, serde::Serialize)]
+struct DiffHunk {
+    from_start: usize,
+    from_lines: usize,
+    to_start: usize,
+    to_lines: usize,
+    lines: Vec<DiffLine>,
+}
+
+fn diff_hunks(from: &str, to: &str) -> Vec<DiffHunk> {
+    let from = from.lines().collect::<Vec<_>>();
+    let to = to.lines().collect::<Vec<_>>();
+    let edits = diff_lines(&from, &to);
+
+    // where each edit leaves the line numbers, to start hunks from
+    let mut positions = Vec::with_capacity(edits.len());
+    let (mut from_line, mut to_line) = (0, 0);
+    for edit in edits.iter() {
+        positions.push((from_line, to_line));
+        match edit {
+            Edit::Same(..) => (from_line, to_line) = (from_line + 1, to_line + 1),
+            Edit::Removed(_) => from_line += 1,
+            Edit::Added(_) => to_line += 1,
+        }
+    }
+
+    let changes = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, edit)| !matches!(edit, Edit::Same(..)))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    // group changes whose context would overlap
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for change in changes {
+        let start = change.saturating_sub(DIFF_CONTEXT);
+        let end = (change + 1 + DIFF_CONTEXT).min(edits.len());
+        match ranges.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(start, end)| {
+            let lines = edits[start..end]
+                .iter()
+                .map(|edit| match *edit {
+                    Edit::Same(i, _) => DiffLine {
+                        op: "same",
+                        text: String::from(from[i]),
+                    },
+                    Edit::Removed(i) => DiffLine {
+                        op: "removed",
+                        text: String::from(from[i]),
+                    },
+                    Edit::Added(j) => DiffLine {
+                        op: "added",
+                        text: String::from(to[j]),
+                    },
+                })
+                .collect::<Vec<_>>();
+
+            let (from_start, to_start) = positions[start];
+            DiffHunk {
+                from_start: from_start + 1,
+                from_lines: lines.iter().filter(|line| line.op != "added").count(),
+                to_start: to_start + 1,
+                to_lines: lines.iter().filter(|line| line.op != "removed").count(),
+                lines,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, serde::Serialize)]
+struct FieldChange<T> {
+    from: T,
+    to: T,
+}
+
+fn field_change<T: PartialEq>(from: T, to: T) -> Option<FieldChange<T>> {
+    (from != to).then_some(FieldChange { from, to })
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DiffQuery {
+    from: String,
+    to: String,
+}
+
+/// What changed between two revisions of a post, either of which can be `current`. JSON unless
+/// the client would rather have a page.
+#[tracing::instrument(skip(app, headers))]
+async fn revision_diff_handler(
+    State(app): State<Arc<App>>,
+    Path(id): Path<Uuid>,
+    Query(DiffQuery { from, to }): Query<DiffQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, diff_connection),
+    };
+
+    let current = match app.find_post_uuid(&mut *conn, id).await {
+        Ok(Some(post)) => post,
+        Ok(None) => return (StatusCode::NOT_FOUND, "post not found").into_response(),
+        Err(err) => return_500!(err, diff_current),
+    };
+
+    let stored = match app.revisions(&mut *conn, id).await {
+        Ok(stored) => stored,
+        Err(err) => return_500!(err, diff_revisions),
+    };
+
+    let find = |revision: &str| -> Option<(String, Option<String>, String)> {
+        if revision == "current" {
+            return Some((
+                current.title.clone(),
+                current.subtitle.clone(),
+                current.content.clone(),
+            ));
+        }
+
+        let revision = revision.parse::<i64>().ok()?;
+        stored
+            .iter()
+            .find(|stored| stored.revision == revision)
+            .map(|stored| {
+                (
+                    stored.title.clone(),
+                    stored.subtitle.clone(),
+                    stored.content.clone(),
+                )
+            })
+    };
+
+    let Some((from_title, from_subtitle, from_content)) = find(&from) else {
+        let message = format!("no revision {from} of this post, see the revisions list");
+        return (StatusCode::NOT_FOUND, message).into_response();
+    };
+    let Some((to_title, to_subtitle, to_content)) = find(&to) else {
+        let message = format!("no revision {to} of this post, see the revisions list");
+        return (StatusCode::NOT_FOUND, message).into_response();
+    };
+
+    let hunks = diff_hunks(&from_content, &to_content);
+    let diff = json!({
+        "id": id,
+        "from": from,
+        "to": to,
+        "title": field_change(from_title, to_title.clone()),
+        "subtitle": field_change(from_subtitle, to_subtitle),
+        "hunks": hunks,
+    });
+
+    let wants_html = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if !wants_html {
+        return Json(diff).into_response();
+    }
+
+    let mut context = app.context();
+    context.insert("title", &to_title);
+    context.insert("diff", &diff);
+    match app.render(DIFF_TEMPLATE, &context).await {
+        Ok(rendered) => Html(rendered).into_response(),
+        Err(err) => return_500!(err, render_diff),
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SlugHistoryEntry {
+    slug: String,
+    newslug: Option<String>,
+    canonical: bool,
+}
+
+#[tracing::instrument(skip(app))]
+async fn slug_history_handler(State(app): State<Arc<App>>, Path(id): Path<Uuid>) -> Response {
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, slug_history_connection),
+    };
+
+    let history = match app.slug_history(&mut *conn, id).await {
+        Ok(history) => history,
+        Err(err) => return_500!(err, slug_history),
+    };
+
+    let Some(canonical) = history.iter().find(|entry| entry.canonical) else {
+        tracing::trace!(not_found = %id);
+        return (StatusCode::NOT_FOUND, "post not found").into_response();
+    };
+
+    Json(json!({ "id": id, "canonical": canonical.slug, "slugs": history })).into_response()
+}
+
+#[derive(Debug, serde::Serialize)]
+struct MaybePost {
+    id: Option<Uuid>,
+    title: String,
+    subtitle: Option<String>,
+    summary: Option<String>,
+    image: Option<String>,
+    published: DateTime<FixedOffset>,
+    content: String,
+    content_rendered: String,
+    content_type: ContentType,
+    draft: bool,
+    extra_head: Option<String>,
+    template: Option<String>,
+}
+
+#[tracing::instrument(skip_all)]
+async fn edit_handler(
+    State(app): State<Arc<App>>,
+    caller: Option<axum::Extension<Caller>>,
+    headers: HeaderMap,
+    page: Option<Path<String>>,
+) -> Response {
+    tracing::trace!(?page);
+
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, edit_connection),
+    };
+
+    let post = match page {
+        Some(Path(uuid_or_slug)) => {
+            let uuid = match Uuid::parse_str(&uuid_or_slug) {
+                Ok(uuid) => uuid,
+                _ => match app.get_newest_slug(&mut *conn, &uuid_or_slug).await {
+                    Ok(Some((id, _))) => id,
+                    Ok(None) => return (StatusCode::NOT_FOUND, "no such post").into_response(),
+                    Err(err) => return_500!(err, get_id_from_slug),
+                },
+            };
+
+            match app.find_post_uuid(&mut *conn, uuid).await {
+                Ok(None) => return (StatusCode::NOT_FOUND, "no such post").into_response(),
+                Ok(Some(Post {
+                    deleted: Some(_), ..
+                })) => return (StatusCode::GONE, "post was deleted").into_response(),
+                Ok(Some(post)) => MaybePost {
+                    id: Some(post.id),
+                    title: post.title,
+                    subtitle: post.subtitle,
+                    summary: post.summary,
+                    image: post.image,
+                    published: post.published,
+                    content_rendered: app
+                        .config()
+                        .render_content(post.content_type, &post.content),
+                    content: post.content,
+                    content_type: post.content_type,
+                    draft: post.draft,
+                    extra_head: post.extra_head,
+                    template: post.template,
+                },
+                Err(err) => return_500!(err, get_post),
+            }
+        }
+
+        None => MaybePost {
+            id: None,
+            title: String::from("Draft post"),
+            subtitle: None,
+            summary: None,
+            image: None,
+            published: app.config().now(),
+            content: String::from("some contents"),
+            content_rendered: ContentType::Markdown.render("preview will appear here"),
+            content_type: ContentType::Markdown,
+            draft: true,
+            extra_head: None,
+            template: None,
+        },
+    };
+
+    let tags = match post.id {
+        Some(id) => match app.post_tags(&mut *conn, id).await {
+            Ok(tags) => tags,
+            Err(err) => return_500!(err, edit_tags),
+        },
+        None => Vec::new(),
+    };
+
+    let publish_url = match post.id {
+        Some(id) => app.config().route_dot(&format!("/publish/{id}")),
+        None => app.config().route_dot("/publish"),
+    };
+
+    let mut context = app.context();
+    context.insert("post", &post);
+    context.insert("tags", &tags);
+    context.insert("publish_url", &publish_url);
+    let (session, set_cookie) = app.csrf_session(&headers);
+    context.insert("csrf_token", &app.csrf_token(caller.as_deref(), &session));
+    match app.render(EDIT_TEMPLATE, &context).await {
+        // the page holds unpublished work, so it shouldn't end up anywhere but the browser
+        Ok(rendered) => {
+            let set_cookie = set_cookie.map(|cookie| [("Set-Cookie", cookie)]);
+            ([("Cache-Control", "no-store")], set_cookie, Html(rendered)).into_response()
+        }
+        Err(err) => return_500!(err, render_edit),
+    }
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+struct Recent {
+    slug: String,
+    title: String,
+    subtitle: Option<String>,
+    summary: Option<String>,
+    published: DateTime<FixedOffset>,
+    updated: DateTime<FixedOffset>,
+    #[serde(skip)]
+    content: String,
+    #[serde(skip)]
+    content_type: ContentType,
+    #[sqlx(skip)]
+    excerpt: Option<String>,
+    #[sqlx(skip)]
+    description: String,
+    #[sqlx(skip)]
+    published_local: String,
+    #[sqlx(skip)]
+    updated_local: String,
+    #[sqlx(skip)]
+    word_count: usize,
+    #[sqlx(skip)]
+    reading_time_minutes: usize,
+}
+
+impl Recent {
+    /// Fill in what each post is described as and when, for templates to show or put in meta tags
+    fn describe_all(posts: &mut [Recent], config: &Config) {
+        for post in posts {
+            post.description = describe(
+                &post.summary,
+                &post.subtitle,
+                &post.content,
+                post.content_type,
+            );
+            let format = |date| config.local(date).format(LOCAL_DATETIME_FORMAT).to_string();
+            post.published_local = format(post.published);
+            post.updated_local = format(post.updated);
+            (post.word_count, post.reading_time_minutes) =
+                reading_time(&post.content, post.content_type, config.words_per_minute);
+        }
+    }
+}
+
+/// Pages past this are treated as this one, they'd be empty anyway
+const MAX_PAGE: usize = 100_000;
+
+#[derive(Debug, serde::Deserialize)]
+struct IndexQuery {
+    /// Kept as a string so a bad page number gets the first page instead of a 400
+    #[serde(default)]
+    page: Option<String>,
+}
+
+async fn index_handler(
+    State(app): State<Arc<App>>,
+    Query(query): Query<IndexQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let loaded = app.config().loaded;
+    let last_modified = match app.last_change(&app.pool).await {
+        Ok(last_change) => last_change.unwrap_or(loaded).max(loaded),
+        Err(err) => return_500!(err, index_last_change),
+    };
+    let last_modified_header = [(axum::http::header::LAST_MODIFIED, http_date(last_modified))];
+
+    if not_modified_since(&headers, last_modified) {
+        tracing::trace!("not modified");
+        return (StatusCode::NOT_MODIFIED, last_modified_header).into_response();
+    }
+
+    let page = query
+        .page
+        .and_then(|page| page.parse::<usize>().ok())
+        .unwrap_or(1)
+        .clamp(1, MAX_PAGE);
+    let per_page = app.config().posts_per_page.max(1);
+    let is_authenticated = app.is_authenticated(&headers).await;
+
+    // one extra to tell whether there's a next page
+    let limit = (per_page + 1) as i64;
+    let offset = ((page - 1) * per_page) as i64;
+
+    match sqlx::query_as::<_, Recent>(
+        r#"
+            select slug, title, subtitle, summary, published, updated, content, content_type
+            from post
+            join slug on post.id = slug.id
+            where draft is false
+                and deleted is null
+            group by post.id
+            order by published desc
+            limit $1 offset $2
+        "#,
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(mut posts) => {
+            let has_next = posts.len() > per_page;
+            posts.truncate(per_page);
+            Recent::describe_all(&mut posts, &app.config());
+
+            let archive_years = match app.archive_years(&app.pool).await {
+                Ok(years) => years,
+                Err(err) => return_500!(err, archive_years),
+            };
+
+            let tag_cloud = match app.tag_counts(&app.pool).await {
+                Ok(tags) => tags,
+                Err(err) => return_500!(err, tag_counts),
+            };
+
+            if app.config().index_excerpt_chars > 0 {
+                // a summary's been written on purpose, so it's shown instead
+                let unsummarized = posts.iter_mut().filter(|post| {
+                    post.summary
+                        .as_ref()
+                        .is_none_or(|summary| summary.trim().is_empty())
+                });
+                for post in unsummarized {
+                    post.excerpt = Some(excerpt(
+                        &post.content,
+                        post.content_type,
+                        app.config().index_excerpt_chars,
+                    ));
+                }
+            }
+
+            let mut context = app.context();
+            context.insert("posts", &posts);
+            context.insert("page", &page);
+            context.insert("has_prev", &(page > 1));
+            context.insert("has_next", &has_next);
+            context.insert("archive_years", &archive_years);
+            context.insert("tag_cloud", &tag_cloud);
+            context.insert("is_authenticated", &is_authenticated);
+            match app.render(INDEX_TEMPLATE, &context).await {
+                Ok(rendered) => {
+                    let cache = app.config().cache.html_page(is_authenticated);
+                    (last_modified_header, cache, Html(rendered)).into_response()
+                }
+                Err(err) => return_500!(err, render_index),
+            }
+        }
+        Err(err) => return_500!(err, select_recent_posts),
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn post_handler(
+    State(app): State<Arc<App>>,
+    Path(slug): Path<String>,
+    headers: HeaderMap,
 ) -> Response {
+    // slugs can't be bare numbers, so this can only be a year
+    if slug.bytes().all(|b| b.is_ascii_digit()) {
+        return archive(&app, &slug, None).await;
+    }
+
+    // the route with this name didn't match, say if it needed more after it
+    if app.is_reserved_slug(&slug) {
+        return app
+            .not_found(&app.config().route(&format!("/{slug}")))
+            .await;
+    }
+
+    let is_authenticated = app.is_authenticated(&headers).await;
+
+    // only ever holds pages under their canonical slug, redirects always go to the database
+    if !is_authenticated && let Some(page) = app.render_cache.lock().unwrap().get(&slug) {
+        let (etag, last_modified) = (page.etag, page.last_modified);
+        let response = post_response(&app, false, &headers, etag, last_modified, page.html);
+        app.record_view(&headers, page.id, &response);
+        return response;
+    }
+    let generation = app.render_cache.lock().unwrap().generation;
+
     let mut tx = match app.pool.begin().await {
         Ok(tx) => tx,
-        Err(err) => return_500!(err, update_post_transaction),
+        Err(err) => return_500!(err, page_handler_transaction),
+    };
+
+    match app.get_newest_slug(&mut *tx, &slug).await {
+        Ok(Some((id, newslug))) => {
+            if newslug != slug {
+                tracing::debug!(redirected = %slug, to = %newslug);
+                return (
+                    StatusCode::MOVED_PERMANENTLY,
+                    [("Location", app.config().absolute(&format!("/{newslug}")))],
+                )
+                    .into_response();
+            }
+
+            match app.find_post_uuid(&mut *tx, id).await {
+                Ok(Some(post)) => {
+                    tracing::trace!(found_post = %post.id, slug = %newslug);
+
+                    if post.deleted.is_some() {
+                        tracing::debug!("deleted");
+                        return (StatusCode::GONE, "this post was deleted").into_response();
+                    }
+
+                    // drafts don't exist as far as anonymous visitors can tell
+                    if post.draft && !is_authenticated {
+                        tracing::debug!("draft");
+                        return app
+                            .not_found(&app.config().route(&format!("/{slug}")))
+                            .await;
+                    }
+
+                    let comments = match app.comment_summary(&mut *tx, id).await {
+                        Ok(comments) => comments,
+                        Err(err) => return_500!(err, post_handler_comments),
+                    };
+
+                    let links_updated = match app.links_updated(&mut *tx).await {
+                        Ok(links_updated) => links_updated,
+                        Err(err) => return_500!(err, post_handler_links_updated),
+                    };
+
+                    // pages can look different after a restart even if the post didn't change
+                    let latest = |date: Option<DateTime<FixedOffset>>| {
+                        date.map(|date| date.to_utc()).unwrap_or_default()
+                    };
+                    let last_modified = post
+                        .updated
+                        .to_utc()
+                        .max(app.config().loaded)
+                        .max(latest(comments.latest))
+                        .max(latest(links_updated));
+                    let etag = app.post_etag(&post, &comments, links_updated, is_authenticated);
+
+                    if etag_matches(&headers, &etag) || not_modified_since(&headers, last_modified)
+                    {
+                        return post_response(
+                            &app,
+                            is_authenticated,
+                            &headers,
+                            etag,
+                            last_modified,
+                            Default::default(),
+                        );
+                    }
+
+                    // snapshots are rendered for anonymous visitors
+                    let snapshot = if is_authenticated {
+                        None
+                    } else {
+                        app.read_snapshot(&post, &comments, links_updated, &newslug)
+                            .await
+                    };
+                    let rendered = match snapshot {
+                        Some(snapshot) => {
+                            tracing::trace!("serving snapshot");
+                            Ok(snapshot)
+                        }
+                        None => app.render_post(post, &newslug, is_authenticated).await,
+                    };
+
+                    match rendered {
+                        Ok(rendered) => {
+                            let html = axum::body::Bytes::from(rendered);
+                            if !is_authenticated {
+                                let page = CachedPage {
+                                    id,
+                                    html: html.clone(),
+                                    etag: etag.clone(),
+                                    last_modified,
+                                };
+                                app.render_cache
+                                    .lock()
+                                    .unwrap()
+                                    .insert(&newslug, page, generation);
+                            }
+                            let response = post_response(
+                                &app,
+                                is_authenticated,
+                                &headers,
+                                etag,
+                                last_modified,
+                                html,
+                            );
+                            if !is_authenticated {
+                                app.record_view(&headers, id, &response);
+                            }
+                            response
+                        }
+                        Err(err) => {
+                            let error = AppError::new(&err);
+                            tracing::error!(
+                                error_id = %error.id,
+                                render_page = ?err,
+                                post = %id,
+                                %slug
+                            );
+                            error.into_response()
+                        }
+                    }
+                }
+
+                Ok(None) => {
+                    let error = AppError::new(&"page not in database?");
+                    tracing::error!(
+                        error_id = %error.id,
+                        find_post_returned_nothing_wat = %id,
+                        %newslug,
+                        oldslug = %slug
+                    );
+                    error.into_response()
+                }
+
+                Err(err) => return_500!(err, page_handler_find_post),
+            }
+        }
+
+        Ok(None) => {
+            app.not_found(&app.config().route(&format!("/{slug}")))
+                .await
+        }
+
+        Err(err) => return_500!(err, get_newest_slug_page_handler),
+    }
+}
+
+/// A post page with its validators, or just the validators if the client's copy is current
+fn post_response(
+    app: &App,
+    is_authenticated: bool,
+    headers: &HeaderMap,
+    etag: String,
+    last_modified: DateTime<Utc>,
+    html: axum::body::Bytes,
+) -> Response {
+    let not_modified = etag_matches(headers, &etag) || not_modified_since(headers, last_modified);
+    let cache_headers = [
+        (axum::http::header::ETAG, etag),
+        (axum::http::header::LAST_MODIFIED, http_date(last_modified)),
+    ];
+
+    if not_modified {
+        tracing::trace!("not modified");
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
+    let cache = app.config().cache.html_page(is_authenticated);
+    (cache_headers, cache, Html(html)).into_response()
+}
+
+#[tracing::instrument(skip(app))]
+async fn archive_month_handler(
+    State(app): State<Arc<App>>,
+    Path((year, month)): Path<(String, String)>,
+) -> Response {
+    archive(&app, &year, Some(&month)).await
+}
+
+/// Public posts published during a year or a month of one, by the date in their slugs
+async fn archive(app: &App, year: &str, month: Option<&str>) -> Response {
+    let path = match month {
+        Some(month) => app.config().route(&format!("/{year}/{month}")),
+        None => app.config().route(&format!("/{year}")),
+    };
+    let not_found = || app.not_found(&path);
+
+    // the end of the range has to stay four digits
+    let Some(year) = year
+        .parse::<i32>()
+        .ok()
+        .filter(|year| year.to_string().len() == 4 && *year < 9999)
+    else {
+        return not_found().await;
+    };
+
+    let month = match month.map(|month| month.parse::<u8>().map(chrono::Month::try_from)) {
+        None => None,
+        Some(Ok(Ok(month))) => Some(month),
+        Some(_) => return not_found().await,
+    };
+
+    // published is stored as rfc3339 text, so comparing it as text keeps to the index and uses
+    // the post's own offset, the same as its slug. the trailing dashes stop sqlite from turning
+    // the bounds into numbers, since the column is declared as a datetime.
+    let (start, end, period) = match month {
+        Some(month) => (
+            format!("{year}-{:02}-", month.number_from_month()),
+            match month.succ() {
+                chrono::Month::January => format!("{}-", year + 1),
+                next => format!("{year}-{:02}-", next.number_from_month()),
+            },
+            format!("{} {year}", month.name()),
+        ),
+        None => (
+            format!("{year}-"),
+            format!("{}-", year + 1),
+            format!("{year}"),
+        ),
+    };
+
+    let posts = match sqlx::query_as::<_, Recent>(
+        r#"
+            select slug, title, subtitle, summary, published, updated, content, content_type
+            from post
+            join slug on post.id = slug.id
+            where draft is false
+                and deleted is null
+                and published >= $1
+                and published < $2
+            group by post.id
+            order by published desc
+        "#,
+    )
+    .bind(start)
+    .bind(end)
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(mut posts) => {
+            Recent::describe_all(&mut posts, &app.config());
+            posts
+        }
+        Err(err) => return_500!(err, archive_posts),
+    };
+
+    if posts.is_empty() {
+        return not_found().await;
+    }
+
+    let mut context = app.context();
+    context.insert("heading", &period);
+    context.insert("posts", &posts);
+    match app.render(ARCHIVE_TEMPLATE, &context).await {
+        Ok(rendered) => (app.config().cache.html_page(false), Html(rendered)).into_response(),
+        Err(err) => return_500!(err, render_archive),
+    }
+}
+
+#[tracing::instrument(skip(app))]
+async fn tag_handler(State(app): State<Arc<App>>, Path(tag): Path<String>) -> Response {
+    let tag = slug::slugify(tag);
+
+    let posts = match sqlx::query_as::<_, Recent>(
+        r#"
+            select slug, title, subtitle, summary, published, updated, content, content_type
+            from post
+            join slug on post.id = slug.id
+            join tag on post.id = tag.post_id
+            where draft is false
+                and deleted is null
+                and tag.tag = $1
+            group by post.id
+            order by published desc
+        "#,
+    )
+    .bind(&tag)
+    .fetch_all(&app.pool)
+    .await
+    {
+        Ok(mut posts) => {
+            Recent::describe_all(&mut posts, &app.config());
+            posts
+        }
+        Err(err) => return_500!(err, tag_posts),
     };
 
-    match app.find_post_uuid(&mut *tx, update).await {
-        Ok(Some(existing)) => {
-            tracing::debug!(update_existing = %update);
+    if posts.is_empty() {
+        return app
+            .not_found(&app.config().route(&format!("/tag/{tag}")))
+            .await;
+    }
+
+    let mut context = app.context();
+    context.insert("heading", &format!("Tagged {tag}"));
+    context.insert("posts", &posts);
+    match app.render(ARCHIVE_TEMPLATE, &context).await {
+        Ok(rendered) => (app.config().cache.html_page(false), Html(rendered)).into_response(),
+        Err(err) => return_500!(err, render_tag),
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct TagCount {
+    tag: String,
+    count: i64,
+}
+
+impl App {
+    async fn insert_post(&self, conn: &mut SqliteConnection, post: &Post) -> Result<()> {
+        tracing::trace!(insert_post = %post.id);
+
+        sqlx::query!(
+            "insert into post (id, title, subtitle, summary, image, published, updated, content, content_type, draft, extra_head, template) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            post.id,
+            post.title,
+            post.subtitle,
+            post.summary,
+            post.image,
+            post.published,
+            post.updated,
+            post.content,
+            post.content_type,
+            post.draft,
+            post.extra_head,
+            post.template,
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Save a new post with its tags and a slug like `slug`, the way publishing does, for the
+    /// caller to commit. Fails with SlugUnavailable if `slug_collision` says no.
+    async fn create_post(
+        &self,
+        conn: &mut SqliteConnection,
+        post: &Post,
+        tags: &[String],
+        slug: &str,
+    ) -> Result<String> {
+        self.insert_post(&mut *conn, post).await?;
+        self.set_tags(&mut *conn, post.id, &normalize_tags(tags))
+            .await?;
+        let (slug, _) = self.claim_slug(&mut *conn, slug, post.id).await?;
+        Ok(slug)
+    }
+
+    /// The post an Idempotency-Key made and the fingerprint of the request that made it, if the
+    /// key was used recently enough. Older keys get forgotten along the way.
+    async fn idempotent_post(&self, key: &str) -> Result<Option<(Uuid, Vec<u8>)>> {
+        let cutoff = self.config().now() - chrono::TimeDelta::hours(IDEMPOTENCY_KEY_HOURS);
+        sqlx::query!(
+            "delete from idempotency_key where julianday(created_at) < julianday($1)",
+            cutoff
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let previous = sqlx::query!(
+            r#"select post_id as "post_id: Uuid", body_sha256 from idempotency_key where key = $1"#,
+            key
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(previous.map(|previous| (previous.post_id, previous.body_sha256)))
+    }
+
+    async fn insert_idempotency_key(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &str,
+        body_sha256: &[u8],
+        post_id: Uuid,
+    ) -> std::result::Result<(), sqlx::Error> {
+        let now = self.config().now();
+        sqlx::query!(
+            "insert into idempotency_key (key, body_sha256, post_id, created_at) values ($1, $2, $3, $4)",
+            key,
+            body_sha256,
+            post_id,
+            now
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    async fn insert_slug(&self, conn: &mut SqliteConnection, slug: &str, id: Uuid) -> Result<()> {
+        tracing::trace!(insert_slug = ?slug, post = %id);
+        sqlx::query!("insert into slug (slug, id) values ($1, $2)", slug, id)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Who has `slug`, and the slugs starting with `slug-` that a collision could turn it into
+    async fn slug_owners(
+        &self,
+        conn: &mut SqliteConnection,
+        slug: &str,
+    ) -> Result<HashMap<String, Uuid>> {
+        let suffixed = format!("{slug}-%");
+        let owners = sqlx::query!(
+            r#"select slug, id as "id: Uuid" from slug where slug = $1 or slug like $2"#,
+            slug,
+            suffixed
+        )
+        .fetch_all(conn)
+        .await?;
+
+        Ok(owners
+            .into_iter()
+            .map(|owner| (owner.slug, owner.id))
+            .collect())
+    }
+
+    /// Whether a post with this slug would be hidden by one of the blog's own routes, or be
+    /// mistaken for a year's archive
+    fn is_reserved_slug(&self, slug: &str) -> bool {
+        slug.bytes().all(|b| b.is_ascii_digit())
+            || self
+                .reserved_slugs
+                .get()
+                .is_some_and(|reserved| reserved.contains(slug))
+    }
+
+    /// The slug post `id` ends up with when it wants `slug`, going by `slug_collision`. Slugs the
+    /// post already has count as free, so renaming back to one of them keeps it, and reserved
+    /// ones count as taken.
+    fn pick_slug(&self, slug: &str, id: Uuid, owners: &HashMap<String, Uuid>) -> Result<String> {
+        let free = |candidate: &str| {
+            !self.is_reserved_slug(candidate)
+                && owners.get(candidate).is_none_or(|owner| *owner == id)
+        };
+        if free(slug) {
+            return Ok(String::from(slug));
+        }
+
+        let numbered_from = match self.config().slug_collision {
+            SlugCollision::Reject if self.is_reserved_slug(slug) => {
+                return Err(SlugUnavailable::Reserved(String::from(slug)).into());
+            }
+            SlugCollision::Reject => return Err(SlugUnavailable::Taken(String::from(slug)).into()),
+            SlugCollision::SuffixNumber => String::from(slug),
+            SlugCollision::SuffixShortid => {
+                let candidate = format!("{slug}-{}", &id.simple().to_string()[..8]);
+                if free(&candidate) {
+                    return Ok(candidate);
+                }
+                // only if another post asked for exactly this as its custom slug
+                candidate
+            }
+        };
+
+        Ok((1..)
+            .map(|suffix| format!("{numbered_from}-{suffix}"))
+            .find(|candidate| free(candidate))
+            .expect("some suffix is free"))
+    }
+
+    /// Give post `id` the slug `pick_slug` says it gets, returning it and whether the post didn't
+    /// have it before. Picks again if another post gets to it first.
+    async fn claim_slug(
+        &self,
+        conn: &mut SqliteConnection,
+        slug: &str,
+        id: Uuid,
+    ) -> Result<(String, bool)> {
+        loop {
+            let owners = self.slug_owners(&mut *conn, slug).await?;
+            let candidate = self.pick_slug(slug, id, &owners)?;
+            if owners.contains_key(&candidate) {
+                return Ok((candidate, false));
+            }
+
+            match self.insert_slug(&mut *conn, &candidate, id).await {
+                Ok(()) => return Ok((candidate, true)),
+
+                Err(err)
+                    if err
+                        .downcast_ref::<sqlx::Error>()
+                        .and_then(|err| err.as_database_error())
+                        .is_some_and(|err| err.is_unique_violation()) =>
+                {
+                    tracing::debug!(slug_taken = %candidate);
+                }
+
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// What claim_slug would end up with, without inserting anything
+    async fn available_slug(
+        &self,
+        conn: &mut SqliteConnection,
+        slug: &str,
+        id: Uuid,
+    ) -> Result<String> {
+        let owners = self.slug_owners(&mut *conn, slug).await?;
+        self.pick_slug(slug, id, &owners)
+    }
+
+    /// Delete the oldest superseded slugs of a post so that at most `keep` remain, never deleting
+    /// `canonical`. The deleted slugs 404 from then on instead of redirecting.
+    async fn prune_slugs(
+        &self,
+        conn: &mut SqliteConnection,
+        id: Uuid,
+        canonical: &str,
+        keep: usize,
+    ) -> Result<()> {
+        let keep_old = keep.saturating_sub(1) as i64;
+
+        let pruned = sqlx::query!(
+            r#"
+                delete from slug
+                where id = $1
+                    and slug != $2
+                    and rowid not in (
+                        select rowid from slug
+                        where id = $1 and slug != $2
+                        order by rowid desc
+                        limit $3
+                    )
+            "#,
+            id,
+            canonical,
+            keep_old,
+        )
+        .execute(conn)
+        .await?;
+
+        tracing::trace!(prune_slugs = %id, pruned = pruned.rows_affected());
+
+        Ok(())
+    }
+
+    async fn get_newest_slug(
+        &self,
+        conn: &mut SqliteConnection,
+        slug: &str,
+    ) -> Result<Option<(Uuid, String)>> {
+        tracing::trace!(get_newest_slug = ?slug);
+
+        let row = sqlx::query!("select id, newslug from slug where slug = $1", slug)
+            .fetch_optional(conn)
+            .await?;
+
+        Ok(row.map(|row| {
+            (
+                Uuid::from_slice(&row.id).expect("valid uuids in database"),
+                canonical_slug(slug, row.newslug),
+            )
+        }))
+    }
+
+    /// Every slug that points at a post, oldest first
+    async fn slug_history(
+        &self,
+        conn: &mut SqliteConnection,
+        id: Uuid,
+    ) -> Result<Vec<SlugHistoryEntry>> {
+        tracing::trace!(slug_history = %id);
+
+        let rows = sqlx::query!(
+            "select slug, newslug from slug where id = $1 order by rowid",
+            id
+        )
+        .fetch_all(conn)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| SlugHistoryEntry {
+                canonical: canonical_slug(&row.slug, row.newslug.clone()) == row.slug,
+                slug: row.slug,
+                newslug: row.newslug,
+            })
+            .collect())
+    }
+
+    async fn all_slugs(&self, conn: &mut SqliteConnection) -> Result<Vec<SlugMapping>> {
+        tracing::trace!("all_slugs");
+
+        let rows = sqlx::query!("select slug, id, newslug from slug order by slug")
+            .fetch_all(conn)
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| SlugMapping {
+                canonical: canonical_slug(&row.slug, row.newslug),
+                slug: row.slug,
+                id: Uuid::from_slice(&row.id).expect("valid uuids in database"),
+            })
+            .collect())
+    }
+
+    async fn insert_redirect(
+        &self,
+        conn: &mut SqliteConnection,
+        slug: &str,
+        id: Uuid,
+        canonical: &str,
+    ) -> Result<()> {
+        tracing::trace!(insert_redirect = ?slug, post = %id, ?canonical);
+        sqlx::query!(
+            "insert into slug (slug, id, newslug) values ($1, $2, $3)",
+            slug,
+            id,
+            canonical
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    async fn find_post_uuid(&self, conn: &mut SqliteConnection, id: Uuid) -> Result<Option<Post>> {
+        tracing::trace!(find_post = %id);
+
+        let post = sqlx::query_as::<_, Post>("select * from post where id = $1 limit 1")
+            .bind(id)
+            .fetch_optional(conn)
+            .await?;
+
+        Ok(post)
+    }
+
+    /// The stored versions of a post, newest first
+    async fn revisions(&self, conn: &mut SqliteConnection, id: Uuid) -> Result<Vec<Revision>> {
+        tracing::trace!(revisions = %id);
+
+        let revisions = sqlx::query_as::<_, Revision>(
+            r#"
+                select revision, title, subtitle, published, content, content_type, created_at
+                from revision
+                where id = $1
+                order by revision desc
+            "#,
+        )
+        .bind(id)
+        .fetch_all(conn)
+        .await?;
+
+        Ok(revisions)
+    }
+
+    /// Keep a post as its next revision
+    async fn insert_revision(&self, conn: &mut SqliteConnection, post: &Post) -> Result<()> {
+        tracing::trace!(insert_revision = %post.id);
+
+        let created_at = self.config().now();
+
+        sqlx::query!(
+            r#"
+                insert into revision (id, revision, title, subtitle, published, content, content_type, created_at)
+                values ($1, (select coalesce(max(revision), 0) + 1 from revision where id = $1), $2, $3, $4, $5, $6, $7)
+            "#,
+            post.id,
+            post.title,
+            post.subtitle,
+            post.published,
+            post.content,
+            post.content_type,
+            created_at,
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Copy revisions from the JSON in `old` into `revision`, once each. The rows in `old` are
+    /// left alone, and ones that can't be read are skipped so they can be fixed by hand.
+    async fn migrate_old_revisions(&self) -> Result<()> {
+        let mut tx = self.begin_write().await?;
+
+        let rows = sqlx::query!(
+            r#"
+                select rowid as "rowid!: i64", id, data
+                from old
+                where rowid not in (select old_rowid from revision where old_rowid is not null)
+                order by rowid
+            "#
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let (mut migrated, mut unreadable) = (0, 0);
+        for row in rows {
+            let data = row.data.as_deref().unwrap_or_default();
+            let post = match serde_json::from_str::<StoredPost>(data) {
+                Ok(post) => post,
+                Err(err) => {
+                    tracing::warn!(unreadable_old_revision = row.rowid, ?err);
+                    unreadable += 1;
+                    continue;
+                }
+            };
+
+            let content_type = post.content_type.unwrap_or_default();
+            let created_at = post.updated.unwrap_or(post.published);
+            sqlx::query!(
+                r#"
+                    insert into revision (id, revision, title, subtitle, published, content, content_type, created_at, old_rowid)
+                    values ($1, (select coalesce(max(revision), 0) + 1 from revision where id = $1), $2, $3, $4, $5, $6, $7, $8)
+                "#,
+                row.id,
+                post.title,
+                post.subtitle,
+                post.published,
+                post.content,
+                content_type,
+                created_at,
+                row.rowid,
+            )
+            .execute(&mut *tx)
+            .await?;
+            migrated += 1;
+        }
+
+        tx.commit().await?;
 
-            // have an existing post, copy it into old. TODO make this not a json string
-            if let Err(err) = app.insert_old(&mut *tx, &existing).await {
-                return_500!(err, insert_old);
-            };
+        if migrated > 0 || unreadable > 0 {
+            tracing::info!(
+                migrated,
+                unreadable,
+                "moved old revisions into the revision table"
+            );
+        }
 
-            let new_post = Post {
-                id: existing.id,
-                title: to_publish.title,
-                subtitle: to_publish.subtitle,
-                published: Local::now().fixed_offset(),
-                content: to_publish.content,
-                draft: to_publish.draft,
-            };
+        Ok(())
+    }
 
-            // update the existing post
-            if let Err(err) = app.update_post(&mut *tx, &new_post).await {
-                return_500!(err, update_existing);
-            }
+    async fn update_post(&self, conn: &mut SqliteConnection, post: &Post) -> Result<()> {
+        tracing::trace!(update_post = %post.id);
 
-            let slug = new_post.slug();
-            let ids_with_slug = match app.find_ids_with_similar_slugs(&mut *tx, &slug).await {
-                Ok(posts) => posts,
-                Err(err) => return_500!(err, new_post_slug),
-            };
+        sqlx::query!(
+            r#"
+                update post
+                    set title = $1,
+                        subtitle = $2,
+                        summary = $3,
+                        image = $4,
+                        published = $5,
+                        updated = $6,
+                        content = $7,
+                        content_type = $8,
+                        draft = $9,
+                        extra_head = $10,
+                        template = $11
+                    where id = $12
+            "#,
+            post.title,
+            post.subtitle,
+            post.summary,
+            post.image,
+            post.published,
+            post.updated,
+            post.content,
+            post.content_type,
+            post.draft,
+            post.extra_head,
+            post.template,
+            post.id,
+        )
+        .execute(conn)
+        .await?;
 
-            let renaming_to_new_slug = !ids_with_slug.contains_key(&new_post.id);
+        Ok(())
+    }
 
-            tracing::trace!(try_slug = %slug, ids_with_slug = ?ids_with_slug, ?renaming_to_new_slug);
+    /// When any post was last updated, deleted, or moved in or out of the drafts
+    async fn last_change(&self, pool: &SqlitePool) -> Result<Option<DateTime<Utc>>> {
+        let row = sqlx::query!(
+            r#"
+                select max(max(
+                    cast(strftime('%s', updated) as integer),
+                    coalesce(cast(strftime('%s', deleted) as integer), 0)
+                )) as "last_change: i64"
+                from post
+            "#
+        )
+        .fetch_one(pool)
+        .await?;
 
-            let slug = if ids_with_slug.len() > 0 && renaming_to_new_slug {
-                format!("{slug}-{}", ids_with_slug.len())
-            } else if !renaming_to_new_slug {
-                // SAFETY: should already exist if we're renaming to an existing slug
-                ids_with_slug[&new_post.id].clone()
-            } else {
-                slug
-            };
+        Ok(row
+            .last_change
+            .and_then(|last_change| DateTime::from_timestamp(last_change, 0)))
+    }
 
-            tracing::trace!(updated_slug = %slug);
+    /// Every year with a public post, newest first
+    async fn archive_years(&self, pool: &SqlitePool) -> Result<Vec<String>> {
+        let rows = sqlx::query!(
+            r#"
+                select distinct substr(published, 1, 4) as "year!: String"
+                from post
+                where draft is false
+                    and deleted is null
+                order by 1 desc
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
 
-            if renaming_to_new_slug
-                && let Err(err) = app.insert_slug(&mut *tx, &slug, new_post.id).await
-            {
-                return_500!(err, update_slug);
-            };
+        Ok(rows.into_iter().map(|row| row.year).collect())
+    }
 
-            if let Err(err) = app.update_old_slugs(&mut *tx, new_post.id, &slug).await {
-                return_500!(err, update_old_slug);
-            }
+    /// Replace the tags of a post
+    async fn set_tags(&self, conn: &mut SqliteConnection, id: Uuid, tags: &[String]) -> Result<()> {
+        tracing::trace!(set_tags = %id, ?tags);
 
-            if let Err(err) = tx.commit().await {
-                return_500!(err, update_post_transaction_commit);
-            }
+        sqlx::query!("delete from tag where post_id = $1", id)
+            .execute(&mut *conn)
+            .await?;
 
-            Json(json!({ "id": new_post.id, "slug": slug })).into_response()
+        for tag in tags {
+            sqlx::query!("insert into tag (post_id, tag) values ($1, $2)", id, tag)
+                .execute(&mut *conn)
+                .await?;
         }
 
-        // passed a uuid in the path but the post with that uuid didn't exist
-        Ok(None) => {
-            tracing::trace!(not_found = %update);
-            (StatusCode::NOT_FOUND, "post not found").into_response()
+        Ok(())
+    }
+
+    async fn post_tags(&self, conn: &mut SqliteConnection, id: Uuid) -> Result<Vec<String>> {
+        let rows = sqlx::query!("select tag from tag where post_id = $1 order by tag", id)
+            .fetch_all(conn)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| row.tag).collect())
+    }
+
+    /// How many public posts have each tag
+    async fn tag_counts(&self, pool: &SqlitePool) -> Result<Vec<TagCount>> {
+        let rows = sqlx::query_as!(
+            TagCount,
+            r#"
+                select tag.tag, count(*) as "count!: i64"
+                from tag
+                join post on post.id = tag.post_id
+                where draft is false
+                    and deleted is null
+                group by tag.tag
+                order by tag.tag
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Give a post `slug`, or what `slug_collision` makes of it, if it doesn't have it already,
+    /// and point its old slugs at it. Returns the canonical slug.
+    async fn update_slugs(
+        &self,
+        conn: &mut SqliteConnection,
+        post: &Post,
+        slug: &str,
+    ) -> Result<String> {
+        let (slug, renaming_to_new_slug) = self.claim_slug(&mut *conn, slug, post.id).await?;
+
+        tracing::trace!(updated_slug = %slug, ?renaming_to_new_slug);
+
+        if renaming_to_new_slug && let Some(max_slugs) = self.config().max_slugs_per_post {
+            self.prune_slugs(&mut *conn, post.id, &slug, max_slugs)
+                .await?;
         }
 
-        Err(err) => return_500!(err, select_existing),
+        self.update_old_slugs(&mut *conn, post.id, &slug).await?;
+
+        Ok(slug)
+    }
+
+    async fn delete_post(
+        &self,
+        conn: &mut SqliteConnection,
+        id: Uuid,
+        deleted: DateTime<FixedOffset>,
+    ) -> Result<()> {
+        tracing::trace!(delete_post = %id);
+
+        sqlx::query!("update post set deleted = $1 where id = $2", deleted, id)
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn update_old_slugs(
+        &self,
+        conn: &mut SqliteConnection,
+        id: Uuid,
+        new_slug: &str,
+    ) -> Result<()> {
+        tracing::trace!(update_old_slugs = %id, ?new_slug);
+
+        sqlx::query!("update slug set newslug = $1 where id = $2", new_slug, id)
+            .execute(conn)
+            .await?;
+
+        Ok(())
     }
 }
 
-#[tracing::instrument(skip_all)]
-async fn drafts_handler(State(app): State<Arc<App>>) -> Response {
-    match sqlx::query_as::<_, Recent>(
-        r#"
-            select slug, title, subtitle, published
-            from post
-            join slug on post.id = slug.id
-            where draft is true
-            group by post.id
-            order by published desc
-        "#,
-    )
-    .fetch_all(&app.pool)
-    .await
-    {
-        Ok(posts) => {
-            let mut context = Context::new();
-            context.insert("blog_title", &format!("Editing {}", app.config.title));
-            context.insert("page_root", &app.config.page_root);
-            context.insert("posts", &posts);
-            match app.render(INDEX_TEMPLATE, &context).await {
-                Ok(rendered) => Html(rendered).into_response(),
-                Err(err) => return_500!(err, render_index),
-            }
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
         }
-        Err(err) => return_500!(err, select_recent_posts),
     }
+    escaped
 }
 
-#[derive(Debug, serde::Serialize)]
-struct MaybePost {
-    id: Option<Uuid>,
-    title: String,
-    subtitle: Option<String>,
-    published: DateTime<FixedOffset>,
-    content: String,
-    content_rendered: String,
-    draft: bool,
+/// A post along with its canonical slug
+#[derive(sqlx::FromRow, serde::Serialize)]
+struct CanonicalPost {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    post: Post,
+    slug: String,
 }
 
-#[tracing::instrument(skip_all)]
-async fn edit_handler(State(app): State<Arc<App>>, page: Option<Path<String>>) -> Response {
-    tracing::trace!(?page);
+/// Which timestamp a feed orders and dates its items by
+#[derive(Clone, Copy)]
+enum FeedDate {
+    Published,
+    Updated,
+}
 
-    let post = match page {
-        Some(Path(uuid_or_slug)) => {
-            let uuid = match Uuid::parse_str(&uuid_or_slug) {
-                Ok(uuid) => uuid,
-                _ => {
-                    match sqlx::query!("select id from slug where slug = $1 limit 1", uuid_or_slug)
-                        .fetch_one(&app.pool)
-                        .await
-                    {
-                        Ok(row) => Uuid::from_slice(&row.id).expect("valid uuids in database"),
-                        Err(err) => return_500!(err, get_id_from_slug),
-                    }
+impl FeedDate {
+    fn of(self, post: &Post) -> DateTime<FixedOffset> {
+        match self {
+            FeedDate::Published => post.published,
+            FeedDate::Updated => post.updated,
+        }
+    }
+}
+
+impl App {
+    /// The newest public posts by `date`, with their canonical slugs
+    async fn feed_posts(
+        &self,
+        conn: &mut SqliteConnection,
+        date: FeedDate,
+    ) -> Result<Vec<CanonicalPost>> {
+        tracing::trace!("feed_posts");
+
+        let order = match date {
+            FeedDate::Published => "published",
+            FeedDate::Updated => "updated",
+        };
+
+        let posts = sqlx::query_as::<_, CanonicalPost>(&format!(
+            r#"
+                select post.*, slug.slug
+                from post
+                join slug on post.id = slug.id
+                where draft is false
+                    and deleted is null
+                    and (slug.newslug is null or slug.newslug = slug.slug)
+                order by {order} desc
+                limit 50
+            "#
+        ))
+        .fetch_all(conn)
+        .await?;
+
+        Ok(posts)
+    }
+
+    /// Posts updated or deleted at or after `since`, oldest change first. Timestamps are compared
+    /// as instants since they're stored with whatever offset they were made with.
+    async fn changed_posts(
+        &self,
+        conn: &mut SqliteConnection,
+        since: DateTime<FixedOffset>,
+    ) -> Result<Vec<CanonicalPost>> {
+        tracing::trace!(changed_posts_since = %since);
+
+        let posts = sqlx::query_as::<_, CanonicalPost>(
+            r#"
+                select post.*, slug.slug
+                from post
+                join slug on post.id = slug.id
+                where (julianday(updated) >= julianday($1) or julianday(deleted) >= julianday($1))
+                    and (slug.newslug is null or slug.newslug = slug.slug)
+                order by max(julianday(updated), coalesce(julianday(deleted), 0))
+            "#,
+        )
+        .bind(since)
+        .fetch_all(conn)
+        .await?;
+
+        Ok(posts)
+    }
+
+    /// An Atom document for some posts, the newest published first
+    fn render_atom(&self, self_route: &str, posts: &[CanonicalPost]) -> String {
+        let updated = posts
+            .iter()
+            .map(|post| post.post.published)
+            .max()
+            .unwrap_or_else(|| self.config().now());
+
+        let mut atom = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+        atom += r#"<feed xmlns="http://www.w3.org/2005/Atom">"#;
+        atom += &format!("<title>{}</title>", xml_escape(&self.config().title));
+        atom += &format!("<id>{}</id>", xml_escape(&self.config().absolute("/")));
+        atom += &format!("<updated>{}</updated>", updated.to_rfc3339());
+        atom += &format!(
+            r#"<link rel="alternate" type="text/html" href="{}" />"#,
+            xml_escape(&self.config().absolute("/"))
+        );
+        atom += &format!(
+            r#"<link rel="self" type="application/atom+xml" href="{}" />"#,
+            xml_escape(&self.config().absolute(self_route))
+        );
+
+        atom += "<author>";
+        match self.config().author.as_ref() {
+            Some(AuthorConfig { name, email, uri }) => {
+                atom += &format!("<name>{}</name>", xml_escape(name));
+                if let Some(email) = email {
+                    atom += &format!("<email>{}</email>", xml_escape(email));
                 }
-            };
+                if let Some(uri) = uri {
+                    atom += &format!("<uri>{}</uri>", xml_escape(uri));
+                }
+            }
+            None => atom += &format!("<name>{}</name>", xml_escape(&self.config().title)),
+        }
+        atom += "</author>";
 
-            match sqlx::query_as::<_, Post>("select * from post where id = $1 limit 1")
-                .bind(&uuid)
-                .fetch_one(&app.pool)
-                .await
-            {
-                Ok(post) => MaybePost {
-                    id: Some(post.id),
-                    title: post.title,
-                    subtitle: post.subtitle,
-                    published: post.published,
-                    content_rendered: markdown::to_html_with_options(
-                        &post.content,
-                        &markdown::Options::gfm(),
-                    )
-                    .expect("valid markdown"),
-                    content: post.content,
-                    draft: post.draft,
-                },
-                Err(err) => return_500!(err, get_post),
+        for CanonicalPost { post, slug } in posts {
+            atom += "<entry>";
+            atom += &format!("<title>{}</title>", xml_escape(&post.title));
+            atom += &format!("<id>urn:uuid:{}</id>", post.id);
+            atom += &format!(
+                r#"<link rel="alternate" type="text/html" href="{}" />"#,
+                xml_escape(&self.config().absolute(&format!("/{slug}")))
+            );
+            atom += &format!("<published>{}</published>", post.published.to_rfc3339());
+            atom += &format!("<updated>{}</updated>", post.updated.to_rfc3339());
+            atom += &format!("<summary>{}</summary>", xml_escape(&post.description()));
+            if let Some(image) = post.image.as_deref() {
+                atom += &format!(
+                    r#"<link rel="enclosure" href="{}" />"#,
+                    xml_escape(&self.config().absolute_url(image))
+                );
             }
+            atom += &format!(
+                r#"<content type="html">{}</content>"#,
+                xml_escape(
+                    &self
+                        .config()
+                        .render_content(post.content_type, &post.content)
+                )
+            );
+            atom += "</entry>";
         }
 
-        None => MaybePost {
-            id: None,
-            title: String::from("Draft post"),
-            subtitle: None,
-            published: Local::now().fixed_offset(),
-            content: String::from("some contents"),
-            content_rendered: markdown::to_html_with_options(
-                "preview will appear here",
-                &markdown::Options::gfm(),
-            )
-            .expect("valid markdown"),
-            draft: true,
-        },
+        atom += "</feed>";
+        atom
+    }
+
+    /// An RSS 2.0 document for some posts, dated by `date`
+    fn render_rss(
+        &self,
+        title: &str,
+        self_route: &str,
+        posts: &[CanonicalPost],
+        date: FeedDate,
+    ) -> String {
+        let last_build = posts
+            .iter()
+            .map(|post| date.of(&post.post))
+            .max()
+            .unwrap_or_else(|| self.config().now());
+
+        let mut rss = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+        rss += r#"<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom" "#;
+        rss += r#"xmlns:media="http://search.yahoo.com/mrss/"><channel>"#;
+        rss += &format!("<title>{}</title>", xml_escape(title));
+        rss += &format!("<link>{}</link>", xml_escape(&self.config().absolute("/")));
+        rss += &format!("<description>{}</description>", xml_escape(title));
+        rss += &format!(
+            r#"<atom:link href="{}" rel="self" type="application/rss+xml" />"#,
+            xml_escape(&self.config().absolute(self_route))
+        );
+        rss += &format!("<lastBuildDate>{}</lastBuildDate>", last_build.to_rfc2822());
+
+        for CanonicalPost { post, slug } in posts {
+            rss += "<item>";
+            rss += &format!("<title>{}</title>", xml_escape(&post.title));
+            rss += &format!(
+                "<link>{}</link>",
+                xml_escape(&self.config().absolute(&format!("/{slug}")))
+            );
+            rss += &format!(r#"<guid isPermaLink="false">{}</guid>"#, post.id);
+            rss += &format!("<pubDate>{}</pubDate>", date.of(post).to_rfc2822());
+            rss += &format!(
+                "<description>{}</description>",
+                xml_escape(
+                    &self
+                        .config()
+                        .render_content(post.content_type, &post.content)
+                )
+            );
+            if let Some(image) = post.image.as_deref() {
+                rss += &format!(
+                    r#"<media:content url="{}" medium="image" />"#,
+                    xml_escape(&self.config().absolute_url(image))
+                );
+            }
+            rss += "</item>";
+        }
+
+        rss += "</channel></rss>";
+        rss
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn feed_handler(State(app): State<Arc<App>>) -> Response {
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, feed_connection),
     };
 
-    let mut context = Context::new();
-    context.insert("page_root", &app.config.page_root);
-    context.insert("post", &post);
-    match app.render(EDIT_TEMPLATE, &context).await {
-        Ok(rendered) => Html(rendered).into_response(),
-        Err(err) => return_500!(err, render_index),
+    match app.feed_posts(&mut *conn, FeedDate::Published).await {
+        Ok(posts) => (
+            [("Content-Type", "application/rss+xml")],
+            app.config().cache.feed(),
+            app.render_rss(
+                &app.config().title,
+                &format!("/{}/feed.xml", app.config().admin_prefix),
+                &posts,
+                FeedDate::Published,
+            ),
+        )
+            .into_response(),
+        Err(err) => return_500!(err, feed_posts),
     }
 }
 
-#[derive(sqlx::FromRow, serde::Serialize)]
-struct Recent {
+#[tracing::instrument(skip_all)]
+async fn atom_handler(State(app): State<Arc<App>>) -> Response {
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, atom_connection),
+    };
+
+    match app.feed_posts(&mut *conn, FeedDate::Published).await {
+        Ok(posts) => (
+            [("Content-Type", "application/atom+xml")],
+            app.config().cache.feed(),
+            app.render_atom(&format!("/{}/atom.xml", app.config().admin_prefix), &posts),
+        )
+            .into_response(),
+        Err(err) => return_500!(err, atom_posts),
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn json_feed_handler(State(app): State<Arc<App>>) -> Response {
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, json_feed_connection),
+    };
+
+    let posts = match app.feed_posts(&mut *conn, FeedDate::Published).await {
+        Ok(posts) => posts,
+        Err(err) => return_500!(err, json_feed_posts),
+    };
+
+    let items = posts
+        .iter()
+        .map(|CanonicalPost { post, slug }| {
+            json!({
+                "id": post.id,
+                "url": app.config().absolute(&format!("/{slug}")),
+                "title": post.title,
+                "summary": post.description(),
+                "image": post.image.as_deref().map(|image| app.config().absolute_url(image)),
+                "date_published": post.published.to_rfc3339(),
+                "date_modified": post.updated.to_rfc3339(),
+                "content_html": app.config().render_content(post.content_type, &post.content),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let feed = json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": app.config().title,
+        "home_page_url": app.config().absolute("/"),
+        "feed_url": app.config().absolute(&format!("/{}/feed.json", app.config().admin_prefix)),
+        "items": items,
+    });
+
+    (
+        [("Content-Type", "application/feed+json")],
+        app.config().cache.feed(),
+        feed.to_string(),
+    )
+        .into_response()
+}
+
+/// How long the health check waits for the database before calling it down
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, serde::Deserialize)]
+struct HealthQuery {
+    #[serde(default)]
+    verbose: Option<String>,
+}
+
+/// For monitoring to poll. Failures are only warnings since a probe failing is what it's for.
+async fn healthz_handler(
+    State(app): State<Arc<App>>,
+    Query(query): Query<HealthQuery>,
+) -> Response {
+    let db = tokio::time::timeout(HEALTH_TIMEOUT, async {
+        let mut conn = app.pool.acquire().await?;
+        sqlx::query("select 1").execute(&mut *conn).await
+    })
+    .await;
+    let db = match db {
+        Ok(Ok(_)) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some(String::from("timed out")),
+    };
+    if let Some(err) = db.as_deref() {
+        tracing::warn!(health_check_db = err);
+    }
+    let mut healthy = db.is_none();
+
+    let mut health = json!({ "db": db.as_deref().unwrap_or("ok") });
+    if query
+        .verbose
+        .is_some_and(|verbose| !matches!(verbose.as_str(), "" | "0" | "false"))
+    {
+        let tera = app.tera.read().await;
+        let missing = [
+            POST_TEMPLATE,
+            INDEX_TEMPLATE,
+            EDIT_TEMPLATE,
+            NEW_TEMPLATE,
+            ARCHIVE_TEMPLATE,
+            DIFF_TEMPLATE,
+            NOT_FOUND_TEMPLATE,
+            ERROR_TEMPLATE,
+        ]
+        .into_iter()
+        .filter(|name| tera.get_template(name).is_err())
+        .collect::<Vec<_>>();
+        healthy &= missing.is_empty();
+
+        health["templates"] = if missing.is_empty() {
+            json!("ok")
+        } else {
+            json!({ "missing": missing })
+        };
+        health["uptime_secs"] = json!(app.started.elapsed().as_secs());
+    }
+
+    health["status"] = json!(if healthy { "ok" } else { "error" });
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, [("Cache-Control", "no-store")], Json(health)).into_response()
+}
+
+async fn robots_handler(State(app): State<Arc<App>>) -> Response {
+    (
+        [("Content-Type", "text/plain")],
+        app.config().robots_rendered.clone(),
+    )
+        .into_response()
+}
+
+#[derive(sqlx::FromRow)]
+struct SitemapEntry {
     slug: String,
-    title: String,
-    subtitle: Option<String>,
-    published: DateTime<FixedOffset>,
+    updated: DateTime<FixedOffset>,
 }
 
-async fn index_handler(State(app): State<Arc<App>>) -> Response {
-    match sqlx::query_as::<_, Recent>(
+#[tracing::instrument(skip_all)]
+async fn sitemap_handler(State(app): State<Arc<App>>) -> Response {
+    // only the canonical slug of each post, and no content
+    let entries = match sqlx::query_as::<_, SitemapEntry>(
         r#"
-            select slug, title, subtitle, published
+            select slug.slug, post.updated
             from post
             join slug on post.id = slug.id
             where draft is false
-            group by post.id
+                and deleted is null
+                and (slug.newslug is null or slug.newslug = slug.slug)
             order by published desc
-            limit 50
         "#,
     )
     .fetch_all(&app.pool)
     .await
     {
-        Ok(posts) => {
-            let mut context = Context::new();
-            context.insert("blog_title", &app.config.title);
-            context.insert("page_root", &app.config.page_root);
-            context.insert("posts", &posts);
-            match app.render(INDEX_TEMPLATE, &context).await {
-                Ok(rendered) => Html(rendered).into_response(),
-                Err(err) => return_500!(err, render_index),
-            }
-        }
-        Err(err) => return_500!(err, select_recent_posts),
+        Ok(entries) => entries,
+        Err(err) => return_500!(err, sitemap_entries),
+    };
+
+    let mut sitemap = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    sitemap += r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#;
+    sitemap += &format!(
+        "<url><loc>{}</loc></url>",
+        xml_escape(&app.config().absolute("/"))
+    );
+    for SitemapEntry { slug, updated } in entries {
+        sitemap += &format!(
+            "<url><loc>{}</loc><lastmod>{}</lastmod></url>",
+            xml_escape(&app.config().absolute(&format!("/{slug}"))),
+            updated.to_rfc3339(),
+        );
     }
+    sitemap += "</urlset>";
+
+    ([("Content-Type", "application/xml")], sitemap).into_response()
 }
 
 #[tracing::instrument(skip_all)]
-async fn post_handler(State(app): State<Arc<App>>, Path(slug): Path<String>) -> Response {
-    let mut tx = match app.pool.begin().await {
-        Ok(tx) => tx,
-        Err(err) => {
-            tracing::error!(page_handler_transaction = %err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
-        }
+async fn updated_feed_handler(State(app): State<Arc<App>>) -> Response {
+    let mut conn = match app.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(err) => return_500!(err, updated_feed_connection),
     };
 
-    match app.get_newest_slug(&mut *tx, &slug).await {
-        Ok(Some((id, newslug))) => {
-            if newslug != slug {
-                tracing::debug!(redirected = %slug, to = %newslug);
-                return (
-                    StatusCode::MOVED_PERMANENTLY,
-                    [("Location", app.config.route(&format!("/{newslug}")))],
-                )
-                    .into_response();
-            }
+    match app.feed_posts(&mut *conn, FeedDate::Updated).await {
+        Ok(posts) => (
+            [("Content-Type", "application/rss+xml")],
+            app.config().cache.feed(),
+            app.render_rss(
+                &format!("{} (updated posts)", app.config().title),
+                "/updated.xml",
+                &posts,
+                FeedDate::Updated,
+            ),
+        )
+            .into_response(),
+        Err(err) => return_500!(err, updated_feed_posts),
+    }
+}
 
-            match app.find_post_uuid(&mut *tx, id).await {
-                Ok(Some(mut post)) => {
-                    tracing::trace!(found_post = %post.id, slug = %newslug);
+async fn fallback_handler(
+    State(app): State<Arc<App>>,
+    request: axum::extract::Request,
+) -> Response {
+    tracing::debug!(not_found = %request.uri());
+    app.not_found(request.uri().path()).await
+}
 
-                    if post.draft {
-                        tracing::debug!("redirecting to edit");
-                        return (
-                            StatusCode::TEMPORARY_REDIRECT,
-                            [("Location", app.config.route(&format!("/edit/{}", post.id)))],
-                        )
-                            .into_response();
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{Body, Bytes};
 
-                    post.content =
-                        markdown::to_html_with_options(&post.content, &markdown::Options::gfm())
-                            .expect("valid markdown");
+    /// The keys every config needs, except the database
+    const BASE_CONFIG: &str = "page_root = \"/\"\nbind = \"127.0.0.1:0\"\ntitle = \"Test\"\n";
 
-                    let mut context = Context::new();
+    /// A config that isn't loaded, so nothing about it gets checked or filled in
+    fn test_config() -> Config {
+        toml::from_str(&format!("{BASE_CONFIG}database = \"test.sqlite3\"\n")).unwrap()
+    }
 
-                    context.insert("blog_title", &app.config.title);
-                    context.insert("post", &post);
-                    context.insert("page_root", &app.config.page_root);
+    /// An app with a database of its own, configured with `config` after the keys every config
+    /// needs
+    async fn test_app(config: &str) -> Arc<App> {
+        let dir = std::env::temp_dir().join(format!("blog3-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        test_app_with_database(&dir.join("blog3.sqlite3"), config).await
+    }
 
-                    match app.render(POST_TEMPLATE, &context).await {
-                        Ok(rendered) => Html(rendered).into_response(),
-                        Err(err) => {
-                            tracing::error!(render_page = ?err, post = %id, %slug);
-                            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
-                        }
-                    }
-                }
+    /// An app using `database`, which is created if it isn't there
+    async fn test_app_with_database(database: &std::path::Path, config: &str) -> Arc<App> {
+        let dir = database.parent().unwrap();
+        let config = format!(
+            "{BASE_CONFIG}database = {:?}\n{config}",
+            database.display().to_string(),
+        );
+        let path = dir.join("config.toml");
+        std::fs::write(&path, config).unwrap();
+        Arc::new(load_app(&path).await.unwrap())
+    }
 
-                Ok(None) => {
-                    tracing::error!(find_post_returned_nothing_wat = %id, %newslug, oldslug = %slug);
-                    (StatusCode::INTERNAL_SERVER_ERROR, "page not in database?").into_response()
-                }
+    /// A token for `api`, which skips the CSRF checks browsers get
+    const API_TOKEN: &str = "[[api_tokens]]\nname = \"test\"\ntoken = \"test\"\n";
 
-                Err(err) => {
-                    tracing::error!(page_handler_find_post = %err);
-                    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
-                }
+    /// `me:pw`, for `Authorization: Basic bWU6cHc=`
+    const BASIC_AUTH: &str = "[basic_auth]\nuser = \"me\"\npassword = \"pw\"\n";
+
+    async fn send(router: &Router, request: axum::http::Request<Body>) -> Response {
+        use tower::ServiceExt;
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn body_bytes(response: Response) -> Bytes {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+    }
+
+    /// A JSON request with `API_TOKEN`, and the JSON that comes back
+    async fn api(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", "Bearer test")
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = send(router, request).await;
+        let status = response.status();
+        let body = body_bytes(response).await;
+        // errors come back as text
+        let body =
+            serde_json::from_slice(&body).unwrap_or_else(|_| json!(String::from_utf8_lossy(&body)));
+        (status, body)
+    }
+
+    async fn publish(router: &Router, post: serde_json::Value) -> serde_json::Value {
+        let (status, published) = api(router, "POST", "/.blog3/publish", post).await;
+        assert_eq!(status, StatusCode::OK, "{published}");
+        published
+    }
+
+    #[test]
+    fn extra_head_keeps_styles_and_links_but_not_scripts() {
+        let head = sanitize_extra_head(
+            "<style>p { color: red; }</style><link rel=\"stylesheet\" href=\"/a.css\" \
+             onload=\"alert(1)\"><script>alert(1)</script>",
+        );
+        assert_eq!(
+            head.as_deref(),
+            Some("<style>p { color: red; }</style>\n<link rel=\"stylesheet\" href=\"/a.css\" />"),
+        );
+
+        // the style ends at `</style x>`, and what's after it isn't a style
+        let head = sanitize_extra_head("<style>a</style x><script>alert(1)</script></style>");
+        assert_eq!(head.as_deref(), Some("<style>a</style>"));
+        assert_eq!(sanitize_extra_head("<style>a</stylex></style>"), None);
+
+        for href in [
+            "javascript:alert(1)",
+            "javascript&#0058;alert(1)",
+            "data:text/css,a",
+        ] {
+            let head = format!("<link rel=\"stylesheet\" href=\"{href}\">");
+            assert_eq!(sanitize_extra_head(&head), None, "{href}");
+        }
+    }
+
+    #[tokio::test]
+    async fn every_pooled_connection_gets_wal_and_the_busy_timeout() {
+        let app = test_app("busy_timeout_ms = 1234\n").await;
+
+        // all held at once, so each one is a connection of its own
+        let mut connections = Vec::new();
+        for _ in 0..4 {
+            connections.push(app.pool.acquire().await.unwrap());
+        }
+        for connection in &mut connections {
+            let journal_mode = sqlx::query_scalar::<_, String>("pragma journal_mode")
+                .fetch_one(&mut **connection)
+                .await
+                .unwrap();
+            assert_eq!(journal_mode, "wal");
+            let busy_timeout = sqlx::query_scalar::<_, i64>("pragma busy_timeout")
+                .fetch_one(&mut **connection)
+                .await
+                .unwrap();
+            assert_eq!(busy_timeout, 1234);
+        }
+    }
+
+    #[tokio::test]
+    async fn databases_from_before_migrations_are_brought_up_to_date() {
+        let dir = std::env::temp_dir().join(format!("blog3-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let database = dir.join("blog3.sqlite3");
+
+        // the first migration is what generate.sql used to make, without anything to say it ran
+        let options = SqliteConnectOptions::new()
+            .filename(&database)
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::raw_sql(include_str!("../migrations/20261014000000_schema.sql"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let id = Uuid::new_v4();
+        let published = "2020-01-02T03:04:05+00:00";
+        sqlx::query("insert into post (id, title, published, content) values ($1, $2, $3, $4)")
+            .bind(id)
+            .bind("Old")
+            .bind(published)
+            .bind("*old*")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("insert into slug (slug, id) values ($1, $2)")
+            .bind("old")
+            .bind(id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let app = test_app_with_database(&database, API_TOKEN).await;
+        let router = router(&app);
+        let (status, post) = api(&router, "GET", &format!("/.blog3/posts/{id}"), json!({})).await;
+        assert_eq!(status, StatusCode::OK, "{post}");
+        assert_eq!(post["slug"], "old");
+        assert_eq!(post["content_type"], "markdown");
+        let updated = DateTime::parse_from_rfc3339(post["updated"].as_str().unwrap());
+        assert_eq!(
+            updated.unwrap(),
+            DateTime::parse_from_rfc3339(published).unwrap()
+        );
+
+        let request = axum::http::Request::get("/old")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&router, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // and everything added since works on it
+        let tagged = json!({ "title": "New", "content": "new", "tags": ["a"], "summary": "s" });
+        let new = publish(&router, tagged).await;
+        let uri = format!("/.blog3/publish/{}", new["id"].as_str().unwrap());
+        let (status, body) = api(&router, "PATCH", &uri, json!({ "content": "newer" })).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, body) = api(&router, "POST", &format!("/.blog3/delete/{id}"), json!({})).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_publishes_wait_for_each_other() {
+        let app = test_app(API_TOKEN).await;
+        let router = router(&app);
+
+        let publishes = (0..8).map(|i| {
+            let router = router.clone();
+            tokio::spawn(async move {
+                let post = json!({ "title": format!("Post {i}"), "content": "hi" });
+                api(&router, "POST", "/.blog3/publish", post).await
+            })
+        });
+        for publish in publishes.collect::<Vec<_>>() {
+            let (status, published) = publish.await.unwrap();
+            assert_eq!(status, StatusCode::OK, "{published}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn racing_renames_get_different_slugs() {
+        let app = test_app(API_TOKEN).await;
+        let router = router(&app);
+
+        let mut ids = Vec::new();
+        for title in ["First", "Second"] {
+            let post = publish(&router, json!({ "title": title, "content": title })).await;
+            ids.push(String::from(post["id"].as_str().unwrap()));
+        }
+
+        // both want the slug the new title gives them
+        let renames = ids.iter().map(|id| {
+            let (router, uri) = (router.clone(), format!("/.blog3/publish/{id}"));
+            tokio::spawn(async move {
+                let rename = json!({ "title": "Same title", "content": "renamed" });
+                api(&router, "POST", &uri, rename).await
+            })
+        });
+        let mut slugs = Vec::new();
+        for rename in renames.collect::<Vec<_>>() {
+            let (status, renamed) = rename.await.unwrap();
+            assert_eq!(status, StatusCode::OK, "{renamed}");
+            slugs.push(String::from(renamed["slug"].as_str().unwrap()));
+        }
+        assert_ne!(slugs[0], slugs[1]);
+
+        for (id, slug) in ids.iter().zip(&slugs) {
+            let (status, post) =
+                api(&router, "GET", &format!("/.blog3/posts/{id}"), json!({})).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(post["slug"], json!(slug));
+            assert_eq!(post["title"], "Same title");
+            assert_eq!(post["content"], "renamed");
+        }
+    }
+
+    #[tokio::test]
+    async fn same_titles_on_the_same_day_under_each_collision_strategy() {
+        let slug = "same-title-2024-05-06";
+        for strategy in ["suffix-number", "suffix-shortid", "reject"] {
+            let config = format!("timezone = \"UTC\"\nslug_collision = {strategy:?}\n{API_TOKEN}");
+            let app = test_app(&config).await;
+            let router = router(&app);
+
+            let mut published = Vec::new();
+            for hour in ["09", "12", "15"] {
+                let post = json!({
+                    "title": "Same title",
+                    "published": format!("2024-05-06T{hour}:00:00Z"),
+                    "content": "hi",
+                });
+                published.push(api(&router, "POST", "/.blog3/publish", post).await);
             }
+            let shortid =
+                |post: &serde_json::Value| String::from(&post["id"].as_str().unwrap()[..8]);
+            let expected = match strategy {
+                "suffix-number" => vec![
+                    (StatusCode::OK, String::from(slug)),
+                    (StatusCode::OK, format!("{slug}-1")),
+                    (StatusCode::OK, format!("{slug}-2")),
+                ],
+                "suffix-shortid" => vec![
+                    (StatusCode::OK, String::from(slug)),
+                    (
+                        StatusCode::OK,
+                        format!("{slug}-{}", shortid(&published[1].1)),
+                    ),
+                    (
+                        StatusCode::OK,
+                        format!("{slug}-{}", shortid(&published[2].1)),
+                    ),
+                ],
+                _ => vec![
+                    (StatusCode::OK, String::from(slug)),
+                    (
+                        StatusCode::CONFLICT,
+                        format!("slug {slug:?} is already another post's"),
+                    ),
+                    (
+                        StatusCode::CONFLICT,
+                        format!("slug {slug:?} is already another post's"),
+                    ),
+                ],
+            };
+            let got = published
+                .iter()
+                .map(|(status, post)| match *status {
+                    StatusCode::OK => (*status, String::from(post["slug"].as_str().unwrap())),
+                    _ => (*status, String::from(post.as_str().unwrap())),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(got, expected, "{strategy}");
+
+            // renaming back gets the post its own slug again, not a suffix or a conflict
+            let (_, last) = published
+                .iter()
+                .rfind(|(status, _)| *status == StatusCode::OK)
+                .unwrap();
+            let uri = format!("/.blog3/publish/{}", last["id"].as_str().unwrap());
+            let (status, renamed) = api(&router, "PATCH", &uri, json!({ "title": "Other" })).await;
+            assert_eq!(status, StatusCode::OK, "{strategy} {renamed}");
+            assert_eq!(renamed["slug"], "other-2024-05-06", "{strategy}");
+            let back = json!({ "title": "Same title" });
+            let (status, renamed) = api(&router, "PATCH", &uri, back).await;
+            assert_eq!(status, StatusCode::OK, "{strategy} {renamed}");
+            assert_eq!(renamed["slug"], last["slug"], "{strategy}");
         }
+    }
 
-        Ok(None) => (StatusCode::NOT_FOUND, "todo: nice 404 page").into_response(),
+    #[tokio::test]
+    async fn slugs_never_take_the_blogs_own_routes() {
+        for strategy in ["suffix-number", "suffix-shortid", "reject"] {
+            let config = format!("timezone = \"UTC\"\nslug_collision = {strategy:?}\n{API_TOKEN}");
+            let app = test_app(&config).await;
+            let router = router(&app);
 
-        Err(err) => {
-            tracing::error!(get_newest_slug_page_handler = %err);
-            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+            // titles get the date after them, so they can't come out as a route's name
+            for (title, slug) in [
+                ("New", "new-2024-05-06"),
+                ("Edit something", "edit-something-2024-05-06"),
+                (".blog3", "blog3-2024-05-06"),
+            ] {
+                let post =
+                    json!({ "title": title, "published": "2024-05-06T09:00:00Z", "content": "hi" });
+                let published = publish(&router, post).await;
+                assert_eq!(published["slug"], slug, "{strategy} {title}");
+                let request = axum::http::Request::get(format!("/{slug}")).body(Body::empty());
+                let response = send(&router, request.unwrap()).await;
+                assert_eq!(response.status(), StatusCode::OK, "{strategy} {slug}");
+            }
+
+            // one that would be is treated like another post's, suffixed or refused
+            let id = Uuid::new_v4();
+            let picked = app.pick_slug("new", id, &HashMap::new());
+            match strategy {
+                "suffix-number" => assert_eq!(picked.unwrap(), "new-1"),
+                "suffix-shortid" => {
+                    assert_eq!(
+                        picked.unwrap(),
+                        format!("new-{}", &id.simple().to_string()[..8])
+                    );
+                }
+                _ => {
+                    let err = picked.unwrap_err();
+                    let unavailable = err.downcast_ref::<SlugUnavailable>().unwrap();
+                    assert_eq!(unavailable.status(), StatusCode::UNPROCESSABLE_ENTITY);
+                }
+            }
+
+            // asking for one by name is a mistake to point out, rather than work around
+            for slug in ["new", "edit", "drafts", "tag"] {
+                let post = json!({ "title": "Custom", "slug": slug, "content": "hi" });
+                let (status, body) = api(&router, "POST", "/.blog3/publish", post).await;
+                assert_eq!(
+                    status,
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "{strategy} {slug}"
+                );
+                assert_eq!(body["errors"][0]["field"], "slug", "{strategy} {slug}");
+            }
         }
     }
-}
 
-impl App {
-    async fn insert_post(&self, conn: &mut SqliteConnection, post: &Post) -> Result<()> {
-        tracing::trace!(insert_post = %post.id);
+    #[tokio::test]
+    async fn reserved_paths_are_never_served_as_posts() {
+        let app = test_app(API_TOKEN).await;
+        let router = router(&app);
+        let get = |path: String| {
+            let request = axum::http::Request::get(path).body(Body::empty()).unwrap();
+            send(&router, request)
+        };
 
-        sqlx::query!(
-            "insert into post (id, title, subtitle, published, content, draft) values ($1, $2, $3, $4, $5, $6)",
-            post.id,
-            post.title,
-            post.subtitle,
-            post.published,
-            post.content,
-            post.draft,
+        // as if a post had got every reserved name as a slug before they were checked
+        let post = publish(
+            &router,
+            json!({ "title": "Shadowing", "content": "shadowing" }),
         )
-        .execute(conn)
-        .await?;
+        .await;
+        let id: Uuid = post["id"].as_str().unwrap().parse().unwrap();
+        let page = body_bytes(get(format!("/{}", post["slug"].as_str().unwrap())).await).await;
+        let reserved = app.reserved_slugs.get().unwrap().clone();
+        for name in &reserved {
+            sqlx::query("insert into slug (slug, id) values ($1, $2)")
+                .bind(name)
+                .bind(id)
+                .execute(&app.pool)
+                .await
+                .unwrap();
+        }
 
-        Ok(())
+        for name in [
+            "new",
+            "edit",
+            "drafts",
+            "tag",
+            "sitemap.xml",
+            "updated.xml",
+            ".blog3",
+        ] {
+            assert!(reserved.contains(name), "{name}");
+        }
+        for name in &reserved {
+            let response = get(format!("/{name}")).await;
+            let status = response.status();
+            assert!(!status.is_redirection(), "{name} {status}");
+            assert_ne!(body_bytes(response).await, page, "{name}");
+            // nothing else takes these without more after them, so they get to post_handler
+            if ["tag", ".blog3"].contains(&name.as_str()) {
+                assert_eq!(status, StatusCode::NOT_FOUND, "{name}");
+            }
+        }
+        assert_eq!(
+            get(String::from("/new")).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
     }
 
-    async fn insert_slug(&self, conn: &mut SqliteConnection, slug: &str, id: Uuid) -> Result<()> {
-        tracing::trace!(insert_slug = ?slug, post = %id);
-        sqlx::query!("insert into slug (slug, id) values ($1, $2)", slug, id)
-            .execute(conn)
-            .await?;
-        Ok(())
+    #[test]
+    fn slugs_of_titles_in_any_script() {
+        let config = test_config();
+        let published = DateTime::parse_from_rfc3339("2026-10-14T12:00:00Z").unwrap();
+        let slug = |title: &str| {
+            let publish =
+                serde_json::from_value::<Publish>(json!({ "title": title, "content": "" }));
+            publish.unwrap().new_post(published).slug(&config)
+        };
+
+        for (title, expected) in [
+            ("Писать по-русски очень весело", "pisat-po-russki-ochen"),
+            ("日本語で書くのはとても楽しいです", "ri-ben-yu-deshu"),
+            (
+                "🎉🎉🎉 Party time 🎉🎉🎉 with lots of emoji 🎉",
+                "tada-tada-tada-party-time",
+            ),
+            ("Crème brûlée à la carte", "creme-brulee-a-la-carte"),
+            // the same, with the accents as combining characters
+            (
+                "Cre\u{300}me bru\u{302}le\u{301}e a\u{300} la carte",
+                "creme-brulee-a-la-carte",
+            ),
+        ] {
+            let slug = slug(title);
+            assert_eq!(slug, format!("{expected}-2026-10-14"), "{title}");
+            assert!(expected.len() <= config.slug_max_length, "{title}");
+        }
     }
 
-    async fn get_newest_slug(
-        &self,
-        conn: &mut SqliteConnection,
-        slug: &str,
-    ) -> Result<Option<(Uuid, String)>> {
-        tracing::trace!(get_newest_slug = ?slug);
+    #[tokio::test]
+    async fn index_is_not_modified_until_something_is_published() {
+        let app = test_app(API_TOKEN).await;
+        let router = router(&app);
+        publish(&router, json!({ "title": "First", "content": "first" })).await;
 
-        let row = sqlx::query!("select id, newslug from slug where slug = $1", slug)
-            .fetch_optional(conn)
-            .await?;
+        let index = |since: Option<&str>| {
+            let mut request = axum::http::Request::builder().uri("/");
+            if let Some(since) = since {
+                request = request.header("If-Modified-Since", since);
+            }
+            send(&router, request.body(Body::empty()).unwrap())
+        };
 
-        Ok(row.map(|row| {
-            (
-                Uuid::from_slice(&row.id).expect("valid uuids in database"),
-                row.newslug.unwrap_or_else(|| String::from(slug)),
-            )
-        }))
+        let response = index(None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let last_modified = response.headers()["Last-Modified"]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        assert_eq!(
+            index(Some(&last_modified)).await.status(),
+            StatusCode::NOT_MODIFIED
+        );
+        // ignored rather than an error
+        assert_eq!(index(Some("yesterday")).await.status(), StatusCode::OK);
+
+        // HTTP dates only go down to the second
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        publish(&router, json!({ "title": "Second", "content": "second" })).await;
+        let response = index(Some(&last_modified)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()["Last-Modified"], last_modified.as_str());
     }
 
-    async fn find_post_uuid(&self, conn: &mut SqliteConnection, id: Uuid) -> Result<Option<Post>> {
-        tracing::trace!(find_post = %id);
+    #[tokio::test]
+    async fn index_is_gzipped_for_clients_that_take_it() {
+        let app = test_app(API_TOKEN).await;
+        let router = router(&app);
+        let content = "Enough words that the page is worth compressing. ".repeat(20);
+        publish(&router, json!({ "title": "Long", "content": content })).await;
 
-        let post = sqlx::query_as::<_, Post>("select * from post where id = $1 limit 1")
-            .bind(&id)
-            .fetch_optional(conn)
-            .await?;
+        let plain = send(
+            &router,
+            axum::http::Request::get("/").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(plain.headers().get("Content-Encoding"), None);
+        let html = body_bytes(plain).await;
 
-        Ok(post)
+        let request = axum::http::Request::get("/")
+            .header("Accept-Encoding", "gzip, deflate")
+            .body(Body::empty())
+            .unwrap();
+        let gzipped = send(&router, request).await;
+        assert_eq!(gzipped.status(), StatusCode::OK);
+        assert_eq!(gzipped.headers()["Content-Encoding"], "gzip");
+        let body = body_bytes(gzipped).await;
+        assert!(body.len() < html.len());
+        assert_eq!(gzip::tests::decompress(&body), html);
     }
 
-    async fn insert_old(&self, conn: &mut SqliteConnection, post: &Post) -> Result<()> {
-        tracing::trace!(insert_old = %post.id);
+    #[tokio::test]
+    async fn publishing_from_a_browser_has_to_be_same_origin() {
+        let app = test_app(BASIC_AUTH).await;
+        let router = router(&app);
 
-        let old = serde_json::to_string(&post).expect("post is valid json");
+        let publish = |headers: &[(&str, &str)], content_type: &str, body: String| {
+            let mut request = axum::http::Request::post("/.blog3/publish")
+                .header("Host", "blog.example")
+                .header("Authorization", "Basic bWU6cHc=")
+                .header("Content-Type", content_type);
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            send(&router, request.body(Body::from(body)).unwrap())
+        };
+        let json = |title: &str| json!({ "title": title, "content": "hi" }).to_string();
+        let form = "application/x-www-form-urlencoded";
 
-        sqlx::query!("insert into old (id, data) values ($1, $2)", post.id, old,)
-            .execute(conn)
-            .await?;
+        let same_origin = [
+            ("Origin", "http://blog.example"),
+            ("Sec-Fetch-Site", "same-origin"),
+        ];
+        let response = publish(&same_origin, "application/json", json("Same origin")).await;
+        assert_eq!(response.status(), StatusCode::OK);
 
-        Ok(())
+        for cross_origin in [
+            &[("Origin", "https://evil.example")][..],
+            &[("Sec-Fetch-Site", "cross-site")],
+            &[
+                ("Origin", "http://blog.example"),
+                ("Sec-Fetch-Site", "same-site"),
+            ],
+        ] {
+            let response = publish(cross_origin, "application/json", json("Cross origin")).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{cross_origin:?}");
+        }
+
+        // forms can come from anywhere, so they need the token too
+        let response = publish(&same_origin, form, String::from("title=Form&content=hi")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // which the new post page hands out along with the session it belongs to
+        let request = axum::http::Request::get("/new").header("Authorization", "Basic bWU6cHc=");
+        let response = send(&router, request.body(Body::empty()).unwrap()).await;
+        let set_cookie = response.headers()["set-cookie"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(
+            set_cookie.contains("HttpOnly; SameSite=Strict"),
+            "{set_cookie}"
+        );
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        let page = String::from_utf8(body_bytes(response).await.to_vec()).unwrap();
+        let token = page
+            .split("name=\"csrf_token\" value=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap()
+            .to_string();
+        let session = cookie.strip_prefix("blog3_csrf=").unwrap();
+        assert_eq!(
+            token,
+            app.csrf_token(Some(&Caller::User(String::from("me"))), session)
+        );
+
+        // coming back with the cookie doesn't start another session
+        let request = axum::http::Request::get("/new")
+            .header("Authorization", "Basic bWU6cHc=")
+            .header("Cookie", &cookie);
+        let response = send(&router, request.body(Body::empty()).unwrap()).await;
+        assert!(response.headers().get("set-cookie").is_none());
+
+        let body = format!("title=Form&content=hi&csrf_token={token}");
+        for other_session in [&[][..], &[("Cookie", "blog3_csrf=someone-elses")]] {
+            let headers = [&same_origin[..], other_session].concat();
+            let response = publish(&headers, form, body.clone()).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::FORBIDDEN,
+                "{other_session:?}"
+            );
+        }
+        let headers = [&same_origin[..], &[("Cookie", cookie.as_str())]].concat();
+        let response = publish(&headers, form, body).await;
+        // to the new post
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
     }
 
-    async fn update_post(&self, conn: &mut SqliteConnection, post: &Post) -> Result<()> {
-        tracing::trace!(update_post = %post.id);
+    // the default test runtime is current-thread, where hashing on the worker would panic
+    #[tokio::test]
+    async fn password_hashes_are_checked_off_the_runtime() {
+        let hash = argon2::hash(b"pw").unwrap();
+        let app = test_app(&format!(
+            "[basic_auth]\nuser = \"me\"\npassword_hash = \"{hash}\"\n"
+        ))
+        .await;
+        let router = router(&app);
 
-        sqlx::query!(
-            r#"
-                update post
-                    set title = $1,
-                        subtitle = $2,
-                        published = $3,
-                        content = $4,
-                        draft = $5
-                    where id = $6
-            "#,
-            post.title,
-            post.subtitle,
-            post.published,
-            post.content,
-            post.draft,
-            post.id,
+        let new = |authorization: &str| {
+            let request = axum::http::Request::get("/new").header("Authorization", authorization);
+            send(&router, request.body(Body::empty()).unwrap())
+        };
+        // me:pw, then me:wrong, then someone:pw
+        assert_eq!(new("Basic bWU6cHc=").await.status(), StatusCode::OK);
+        assert_eq!(
+            new("Basic bWU6d3Jvbmc=").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            new("Basic c29tZW9uZTpwdw==").await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_are_turned_away() {
+        let app = test_app(&format!("max_body_size = 200\n{API_TOKEN}")).await;
+        let router = router(&app);
+
+        // a post whose JSON is `length` bytes
+        let body = |length: usize| {
+            let padding =
+                "a".repeat(length - json!({ "title": "Sized", "content": "" }).to_string().len());
+            json!({ "title": "Sized", "content": padding }).to_string()
+        };
+        let publish = |body: String, content_length: bool| {
+            let mut request = axum::http::Request::post("/.blog3/publish")
+                .header("Authorization", "Bearer test")
+                .header("Content-Type", "application/json");
+            if content_length {
+                request = request.header("Content-Length", body.len());
+            }
+            send(&router, request.body(Body::from(body)).unwrap())
+        };
+
+        // whether or not the client says how long it is up front
+        for content_length in [true, false] {
+            assert_eq!(body(200).len(), 200);
+            let response = publish(body(200), content_length).await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let response = publish(body(201), content_length).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let error = serde_json::from_slice::<serde_json::Value>(&body_bytes(response).await);
+            assert_eq!(error.unwrap()["max_body_size"], 200);
+        }
+    }
+
+    #[test]
+    fn sanitized_html_keeps_content_but_not_scripts() {
+        let allowed = SanitizeConfig::default();
+        let html = sanitize_html(
+            "<p onclick=\"alert(1)\">Hi <a href=\"https://example.com/\" \
+             onmouseover='alert(1)'>there</a> <img src=\"/a.png\" alt=\"A\" onerror=alert(1)> \
+             <code>x &lt; y</code></p><script>alert(1)</script><SCRIPT >alert(1)</script x>\
+             <iframe src=\"https://example.com/\">framed</iframe>",
+            &allowed,
+        );
+        assert_eq!(
+            html,
+            "<p>Hi <a href=\"https://example.com/\">there</a> <img src=\"/a.png\" alt=\"A\"> \
+             <code>x &lt; y</code></p>framed",
+        );
+
+        for href in [
+            "javascript:alert(1)",
+            "JavaScript:alert(1)",
+            " java\tscript:alert(1)",
+            "javascript&colon;alert(1)",
+            "javascript&#58;alert(1)",
+            "javascript&#0058;alert(1)",
+            "javascript&#58alert(1)",
+            "javascript&#x3a;alert(1)",
+            "javascript&#X3A;alert(1)",
+            "javascript&#x00003A;alert(1)",
+            "java&Tab;script:alert(1)",
+            "javascript&unknown;:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+        ] {
+            let html = sanitize_html(&format!("<a href=\"{href}\">a</a>"), &allowed);
+            assert_eq!(html, "<a>a</a>", "{href}");
+        }
+        for href in [
+            "/posts/a?b=1&amp;c=2",
+            "#top",
+            "mailto:me@example.com",
+            "HTTPS://example.com/a:b",
+        ] {
+            let html = sanitize_html(&format!("<a href=\"{href}\">a</a>"), &allowed);
+            assert_eq!(html, format!("<a href=\"{href}\">a</a>"), "{href}");
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn markdown_filter_survives_reloading_templates() {
+        let templates = std::env::temp_dir().join(format!("blog3-templates-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&templates).unwrap();
+        std::fs::write(
+            templates.join("markdown.html.tera"),
+            "[{{ text | markdown }}][{{ none | markdown }}][{{ empty | markdown }}]",
         )
-        .execute(conn)
-        .await?;
+        .unwrap();
+        let app = test_app(&format!(
+            "template_dir = {:?}\n",
+            templates.display().to_string()
+        ))
+        .await;
 
-        Ok(())
+        let mut context = app.context();
+        context.insert("text", "*hi* & bye");
+        context.insert("none", &None::<String>);
+        context.insert("empty", "");
+        // debug builds load the templates again for every render, and here once more before it
+        app.reload_templates().await.unwrap();
+        let page = app.render("markdown.html.tera", &context).await.unwrap();
+        // the live reload script comes after
+        assert!(
+            page.starts_with("[<p><em>hi</em> &amp; bye</p>][][]"),
+            "{page}"
+        );
     }
 
-    async fn count_ids_with_similar_slugs(
-        &self,
-        conn: &mut SqliteConnection,
-        slug: &str,
-    ) -> Result<usize> {
-        Ok(self.find_ids_with_similar_slugs(conn, slug).await?.len())
+    #[test]
+    fn patches_tell_null_from_missing() {
+        type Field = fn(&PublishPatch) -> &Option<Option<String>>;
+        let fields: [(&str, Field); 6] = [
+            ("subtitle", |patch| &patch.subtitle),
+            ("summary", |patch| &patch.summary),
+            ("description", |patch| &patch.summary),
+            ("image", |patch| &patch.image),
+            ("extra_head", |patch| &patch.extra_head),
+            ("template", |patch| &patch.template),
+        ];
+        let patch = |json| serde_json::from_value::<PublishPatch>(json).unwrap();
+        for (name, field) in fields {
+            assert_eq!(*field(&patch(json!({}))), None, "{name}");
+            assert_eq!(*field(&patch(json!({ name: null }))), Some(None), "{name}");
+            let set = patch(json!({ name: "x" }));
+            assert_eq!(*field(&set), Some(Some(String::from("x"))), "{name}");
+        }
     }
 
-    #[tracing::instrument(skip_all)]
-    async fn find_ids_with_similar_slugs(
-        &self,
-        conn: &mut SqliteConnection,
-        slug: &str,
-    ) -> Result<HashMap<Uuid, String>> {
-        tracing::trace!(find_similar_slugs = %slug);
+    #[tokio::test]
+    async fn patching_null_clears_and_missing_keeps() {
+        let app = test_app(API_TOKEN).await;
+        let router = router(&app);
+        let post = publish(
+            &router,
+            json!({
+                "title": "Patched",
+                "subtitle": "Sub",
+                "summary": "Sum",
+                "image": "/a.png",
+                "extra_head": "<style>p { color: red; }</style>",
+                "content": "hi",
+            }),
+        )
+        .await;
+        let id = post["id"].as_str().unwrap();
 
-        let slug_like = format!("{slug}%");
+        let uri = format!("/.blog3/publish/{id}");
+        let patch = json!({ "subtitle": null, "image": null, "content": "bye" });
+        let (status, body) = api(&router, "PATCH", &uri, patch).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
 
-        let row = sqlx::query!("select id, slug from slug where slug like $1", slug_like)
-            .fetch_all(conn)
-            .await?;
+        let mut conn = app.pool.acquire().await.unwrap();
+        let post = app.find_post_uuid(&mut conn, id.parse().unwrap()).await;
+        let post = post.unwrap().unwrap();
+        assert_eq!(post.title, "Patched");
+        assert_eq!(post.subtitle, None);
+        assert_eq!(post.summary.as_deref(), Some("Sum"));
+        assert_eq!(post.image, None);
+        assert_eq!(
+            post.extra_head.as_deref(),
+            Some("<style>p { color: red; }</style>")
+        );
+        assert_eq!(post.content, "bye");
+    }
 
-        Ok(row
-            .into_iter()
-            .map(|row| {
-                (
-                    Uuid::from_slice(&row.id).expect("valid uuids in database"),
-                    row.slug,
-                )
-            })
-            .collect())
+    #[tokio::test]
+    async fn robots_txt_is_made_up_or_read_from_robots_file() {
+        let robots = |config: String| async move {
+            let app = test_app(&config).await;
+            let request = axum::http::Request::get("/robots.txt")
+                .body(Body::empty())
+                .unwrap();
+            let response = send(&router(&app), request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            String::from_utf8(body_bytes(response).await.to_vec()).unwrap()
+        };
+
+        let default = robots(String::from("base_url = \"https://blog.example\"\n")).await;
+        let lines = default.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "User-agent: *");
+        for admin in [
+            "/.blog3/publish",
+            "/.blog3/api/",
+            "/.blog3/stats",
+            "/new",
+            "/*/edit",
+        ] {
+            assert!(
+                lines.contains(&format!("Disallow: {admin}").as_str()),
+                "{admin}"
+            );
+        }
+        assert!(lines.contains(&"Allow: /"));
+        assert!(!lines.contains(&"Disallow: /"));
+        assert_eq!(
+            lines.last(),
+            Some(&"Sitemap: https://blog.example/sitemap.xml")
+        );
+
+        let custom = "User-agent: SomeBot\nDisallow: /\n\n# the rest are fine\n";
+        let path = std::env::temp_dir().join(format!("blog3-robots-{}.txt", Uuid::new_v4()));
+        std::fs::write(&path, custom).unwrap();
+        let config = format!("robots_file = {:?}\n", path.display().to_string());
+        assert_eq!(robots(config).await, custom);
     }
 
-    #[tracing::instrument(skip_all)]
-    async fn update_old_slugs(
-        &self,
-        conn: &mut SqliteConnection,
-        id: Uuid,
-        new_slug: &str,
-    ) -> Result<()> {
-        tracing::trace!(update_old_slugs = %id, ?new_slug);
+    #[test]
+    fn asset_paths_stay_in_the_assets_directory() {
+        for path in [
+            "index.css",
+            "fonts/body.woff2",
+            "img/a.b.png",
+            "no-extension",
+        ] {
+            assert!(is_safe_asset_path(path), "{path}");
+        }
+        for path in [
+            "",
+            "..",
+            "../config.toml",
+            "fonts/../../config.toml",
+            "/etc/passwd",
+            "fonts//body.woff2",
+            "fonts/",
+            ".hidden",
+            "fonts/.hidden",
+            "..\\config.toml",
+            "fonts\\..\\..\\config.toml",
+        ] {
+            assert!(!is_safe_asset_path(path), "{path}");
+        }
+    }
 
-        sqlx::query!("update slug set newslug = $1 where id = $2", new_slug, id)
-            .execute(conn)
-            .await?;
+    #[tokio::test]
+    async fn assets_are_served_from_the_directory_and_nowhere_else() {
+        let dir = std::env::temp_dir().join(format!("blog3-assets-{}", Uuid::new_v4()));
+        let assets = dir.join("assets");
+        std::fs::create_dir_all(assets.join("fonts")).unwrap();
+        std::fs::write(assets.join("site.css"), "p {}").unwrap();
+        std::fs::write(assets.join("fonts/body.xyz"), "font").unwrap();
+        std::fs::write(assets.join(".hidden"), "hidden").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), assets.join("link.txt")).unwrap();
 
-        Ok(())
+        let app = test_app(&format!("assets = {:?}\n", assets.display().to_string())).await;
+        let router = router(&app);
+        let get = |path: &str| {
+            let request = axum::http::Request::get(path).body(Body::empty()).unwrap();
+            send(&router, request)
+        };
+
+        let response = get("/.blog3/assets/site.css").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "text/css");
+        let response = get("/.blog3/assets/fonts/body.xyz").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "application/octet-stream"
+        );
+        assert_eq!(body_bytes(response).await, "font");
+
+        for path in [
+            "/.blog3/assets/../secret.txt",
+            "/.blog3/assets/fonts/../../secret.txt",
+            "/.blog3/assets/%2e%2e/secret.txt",
+            "/.blog3/assets/fonts%2F..%2F..%2Fsecret.txt",
+            "/.blog3/assets/fonts%2f..%2f..%2fsecret.txt",
+            "/.blog3/assets/..%5Csecret.txt",
+            "/.blog3/assets//etc/passwd",
+            "/.blog3/assets/%2Fetc%2Fpasswd",
+            "/.blog3/assets/.hidden",
+            "/.blog3/assets/link.txt",
+            "/.blog3/assets/missing.css",
+        ] {
+            let response = get(path).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        }
     }
-}
 
-async fn fallback_handler(request: axum::extract::Request) -> Response {
-    tracing::debug!(not_found = %request.uri());
-    StatusCode::NOT_FOUND.into_response()
+    #[tokio::test]
+    async fn fetch_only_goes_to_public_addresses() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://localhost:8080/",
+            "http://[::1]/",
+            "http://10.1.2.3/",
+            "https://169.254.169.254/latest/meta-data/",
+            "http://[::ffff:192.168.0.1]/",
+            "http://[fd00::1]/",
+            "http://0.0.0.0/",
+            "file:///etc/passwd",
+        ] {
+            let url = url::Url::parse(url).unwrap();
+            assert!(public_addresses(&url).await.is_err(), "{url}");
+            assert!(fetch(&url, &[], FetchBody::None).await.is_err(), "{url}");
+        }
+
+        let url = url::Url::parse("https://93.184.215.14/").unwrap();
+        let addresses = public_addresses(&url).await.unwrap();
+        assert_eq!(addresses, ["93.184.215.14:443".parse().unwrap()]);
+    }
 }
@@ -0,0 +1,83 @@
+//! Other looks for the whole blog, picked with `theme` in the config. A theme is a directory of
+//! templates and an `assets` directory, and anything it doesn't have comes from the default
+//! theme, which is frontend and frontend/assets. Themes are read from `themes_dir`, or the ones
+//! in frontend/themes when the binary was built.
+
+use crate::EmbeddedAsset;
+use std::path::{Path, PathBuf};
+
+/// Not a directory, just the templates and assets there are without a theme
+pub const DEFAULT: &str = "default";
+
+/// A directory from frontend/themes
+pub struct EmbeddedTheme {
+    pub name: &'static str,
+    /// By template name
+    pub templates: &'static [(&'static str, &'static str)],
+    pub assets: &'static [EmbeddedAsset],
+}
+
+/// Generated by build.rs
+static EMBEDDED_THEMES: &[EmbeddedTheme] = include!(concat!(env!("OUT_DIR"), "/themes.rs"));
+
+/// Where the files of the theme in use come from
+#[derive(Debug, Clone, Default)]
+pub enum Theme {
+    #[default]
+    Default,
+    Dir(PathBuf),
+    Embedded(&'static EmbeddedTheme),
+}
+
+impl std::fmt::Debug for EmbeddedTheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Theme {
+    /// The theme called `name` in `themes_dir`, or built in if that's unset. The error lists the
+    /// themes there are instead.
+    pub fn find(name: &str, themes_dir: Option<&Path>) -> Result<Theme, String> {
+        if name == DEFAULT {
+            return Ok(Theme::Default);
+        }
+
+        let Some(themes_dir) = themes_dir else {
+            if let Some(theme) = EMBEDDED_THEMES.iter().find(|theme| theme.name == name) {
+                return Ok(Theme::Embedded(theme));
+            }
+            let names = EMBEDDED_THEMES.iter().map(|theme| theme.name);
+            return Err(format!("no theme {name:?} built in, found {}", list(names)));
+        };
+
+        let mut names = std::fs::read_dir(themes_dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.path().is_dir())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        names.sort();
+
+        if names.iter().any(|found| found == name) {
+            Ok(Theme::Dir(themes_dir.join(name)))
+        } else {
+            let names = names.iter().map(String::as_str);
+            Err(format!(
+                "no theme {name:?} in {}, found {}",
+                themes_dir.display(),
+                list(names)
+            ))
+        }
+    }
+}
+
+fn list<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    std::iter::once(DEFAULT)
+        .chain(names)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,231 @@
+//! ChaCha20-Poly1305 (RFC 8439), the one cipher suite served. Unlike AES it's fast and constant
+//! time without hardware support.
+
+pub const TAG_BYTES: usize = 16;
+
+/// `plaintext` encrypted in place, with the tag appended
+pub fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &mut Vec<u8>) {
+    let poly_key = poly_key(key, nonce);
+    chacha20(key, nonce, 1, plaintext);
+    let tag = poly1305(&poly_key, &mac_data(aad, plaintext));
+    plaintext.extend_from_slice(&tag);
+}
+
+/// Checks the tag on the end of `sealed` and decrypts what comes before it
+pub fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    use subtle::ConstantTimeEq;
+
+    let (ciphertext, tag) = sealed.split_at_checked(sealed.len().checked_sub(TAG_BYTES)?)?;
+    let expected = poly1305(&poly_key(key, nonce), &mac_data(aad, ciphertext));
+    if !bool::from(expected.ct_eq(tag)) {
+        return None;
+    }
+
+    let mut plaintext = ciphertext.to_vec();
+    chacha20(key, nonce, 1, &mut plaintext);
+    Some(plaintext)
+}
+
+fn poly_key(key: &[u8; 32], nonce: &[u8; 12]) -> [u8; 32] {
+    block(key, nonce, 0)[..32].try_into().unwrap()
+}
+
+/// What the tag covers, each part padded to 16 bytes and then both lengths
+fn mac_data(aad: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let pad = |len: usize| (16 - len % 16) % 16;
+    let mut data = Vec::with_capacity(aad.len() + ciphertext.len() + 48);
+    data.extend_from_slice(aad);
+    data.resize(data.len() + pad(aad.len()), 0);
+    data.extend_from_slice(ciphertext);
+    data.resize(data.len() + pad(ciphertext.len()), 0);
+    data.extend((aad.len() as u64).to_le_bytes());
+    data.extend((ciphertext.len() as u64).to_le_bytes());
+    data
+}
+
+fn chacha20(key: &[u8; 32], nonce: &[u8; 12], counter: u32, data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let stream = block(key, nonce, counter.wrapping_add(i as u32));
+        for (byte, key) in chunk.iter_mut().zip(stream) {
+            *byte ^= key;
+        }
+    }
+}
+
+fn block(key: &[u8; 32], nonce: &[u8; 12], counter: u32) -> [u8; 64] {
+    let word =
+        |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+    let mut initial = [0; 16];
+    initial[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for i in 0..8 {
+        initial[4 + i] = word(key, i);
+    }
+    initial[12] = counter;
+    for i in 0..3 {
+        initial[13 + i] = word(nonce, i);
+    }
+
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut out = [0; 64];
+    for (i, chunk) in out.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&state[i].wrapping_add(initial[i]).to_le_bytes());
+    }
+    out
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// With three limbs of 44, 44 and 42 bits, so products fit in a u128
+fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; 16] {
+    const MASK44: u64 = (1 << 44) - 1;
+    const MASK42: u64 = (1 << 42) - 1;
+    let word = |bytes: &[u8], at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+
+    let (t0, t1) = (word(key, 0), word(key, 8));
+    let r0 = t0 & 0xffc0fffffff;
+    let r1 = ((t0 >> 44) | (t1 << 20)) & 0xfffffc0ffff;
+    let r2 = (t1 >> 24) & 0x00ffffffc0f;
+    // 2^130 is 5, and the limbs above 2^132 wrap around to the bottom times 20
+    let (s1, s2) = (r1 * 20, r2 * 20);
+
+    let (mut h0, mut h1, mut h2) = (0u64, 0u64, 0u64);
+    for chunk in message.chunks(16) {
+        let mut block = [0; 16];
+        block[..chunk.len()].copy_from_slice(chunk);
+        // the byte past the end of the message is a 1, which is bit 128 for whole blocks
+        let high = match chunk.len() {
+            16 => 1 << 40,
+            len => {
+                block[len] = 1;
+                0
+            }
+        };
+
+        let (t0, t1) = (word(&block, 0), word(&block, 8));
+        h0 += t0 & MASK44;
+        h1 += ((t0 >> 44) | (t1 << 20)) & MASK44;
+        h2 += ((t1 >> 24) & MASK42) | high;
+
+        let m = |x: u64, y: u64| x as u128 * y as u128;
+        let d0 = m(h0, r0) + m(h1, s2) + m(h2, s1);
+        let mut d1 = m(h0, r1) + m(h1, r0) + m(h2, s2);
+        let mut d2 = m(h0, r2) + m(h1, r1) + m(h2, r0);
+
+        d1 += d0 >> 44;
+        h0 = d0 as u64 & MASK44;
+        d2 += d1 >> 44;
+        h1 = d1 as u64 & MASK44;
+        h2 = d2 as u64 & MASK42;
+        h0 += (d2 >> 42) as u64 * 5;
+        h1 += h0 >> 44;
+        h0 &= MASK44;
+    }
+
+    // fully carried, and then p subtracted if h is at least p
+    for _ in 0..2 {
+        h2 += h1 >> 44;
+        h1 &= MASK44;
+        h0 += (h2 >> 42) * 5;
+        h2 &= MASK42;
+        h1 += h0 >> 44;
+        h0 &= MASK44;
+    }
+    let g0 = h0 + 5;
+    let g1 = h1 + (g0 >> 44);
+    let g2 = (h2 + (g1 >> 44)).wrapping_sub(1 << 42);
+    let (g0, g1) = (g0 & MASK44, g1 & MASK44);
+    let keep_g = (g2 >> 63).wrapping_sub(1);
+    h0 = (h0 & !keep_g) | (g0 & keep_g);
+    h1 = (h1 & !keep_g) | (g1 & keep_g);
+    h2 = (h2 & !keep_g) | (g2 & keep_g);
+
+    let (s0, s1) = (word(key, 16), word(key, 24));
+    h0 += s0 & MASK44;
+    h1 += (((s0 >> 44) | (s1 << 20)) & MASK44) + (h0 >> 44);
+    h0 &= MASK44;
+    h2 += ((s1 >> 24) & MASK42) + (h1 >> 44);
+    h1 &= MASK44;
+
+    let mut tag = [0; 16];
+    tag[..8].copy_from_slice(&(h0 | (h1 << 44)).to_le_bytes());
+    tag[8..].copy_from_slice(&((h1 >> 20) | (h2 << 24)).to_le_bytes());
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn poly1305_rfc_8439() {
+        // section 2.5.2
+        let key = unhex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+        assert_eq!(
+            poly1305(
+                &key.try_into().unwrap(),
+                b"Cryptographic Forum Research Group"
+            )
+            .to_vec(),
+            unhex("a8061dc1305136c6c22b8baf0c0127a9"),
+        );
+    }
+
+    #[test]
+    fn chacha20_poly1305_rfc_8439() {
+        // section 2.8.2
+        let key = unhex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f");
+        let key = key.try_into().unwrap();
+        let nonce = unhex("070000004041424344454647").try_into().unwrap();
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only \
+            one tip for the future, sunscreen would be it.";
+
+        let mut sealed = plaintext.to_vec();
+        seal(&key, &nonce, &aad, &mut sealed);
+        assert_eq!(
+            sealed,
+            unhex(
+                "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+                 3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+                 92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+                 3ff4def08e4b7a9de576d26586cec64b6116\
+                 1ae10b594f09e26a7e902ecbd0600691"
+            ),
+        );
+        assert_eq!(open(&key, &nonce, &aad, &sealed).unwrap(), plaintext);
+
+        for tampered in 0..sealed.len() {
+            let mut sealed = sealed.clone();
+            sealed[tampered] ^= 1;
+            assert_eq!(open(&key, &nonce, &aad, &sealed), None);
+        }
+        assert_eq!(open(&key, &nonce, b"other", &sealed), None);
+    }
+}
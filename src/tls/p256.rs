@@ -0,0 +1,467 @@
+//! ECDSA signatures over P-256 (FIPS 186-5), for certificates with EC keys like the ones
+//! certbot makes by default. Nonces are derived from the key and message (RFC 6979), and
+//! nothing branches on or indexes by anything secret.
+
+use hmac::Mac;
+use sha2::{Digest, Sha256};
+
+/// Little endian 64 bit limbs
+type Limbs = [u64; 4];
+
+/// Numbers mod `m`, kept times 2^256 (Montgomery form) so multiplying needs no division
+struct Modulus {
+    m: Limbs,
+    /// -m^-1 mod 2^64
+    m_inv: u64,
+    /// 2^512 mod m, which turns numbers into Montgomery form
+    r2: Limbs,
+}
+
+/// The field the curve's coordinates are in
+const P: Modulus = Modulus {
+    m: [
+        0xffffffffffffffff,
+        0x00000000ffffffff,
+        0x0000000000000000,
+        0xffffffff00000001,
+    ],
+    m_inv: 1,
+    r2: [
+        0x0000000000000003,
+        0xfffffffbffffffff,
+        0xfffffffffffffffe,
+        0x00000004fffffffd,
+    ],
+};
+
+/// The order of the group, which scalars and signatures are mod
+const N: Modulus = Modulus {
+    m: [
+        0xf3b9cac2fc632551,
+        0xbce6faada7179e84,
+        0xffffffffffffffff,
+        0xffffffff00000000,
+    ],
+    m_inv: 0xccd1c8aaee00bc4f,
+    r2: [
+        0x83244c95be79eea2,
+        0x4699799c49bd6fa6,
+        0x2845b2392b6bec59,
+        0x66e12d94f3d95620,
+    ],
+};
+
+const B: [u8; 32] = hex32("5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b");
+const GX: [u8; 32] = hex32("6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296");
+const GY: [u8; 32] = hex32("4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5");
+
+pub struct SecretKey {
+    /// Big endian, between 1 and n - 1
+    d: [u8; 32],
+}
+
+impl SecretKey {
+    pub fn from_bytes(d: &[u8]) -> Option<SecretKey> {
+        let d: [u8; 32] = match d.len() {
+            32 => d.try_into().ok()?,
+            // DER integers get a leading zero when the top bit is set, some keys keep it
+            33 if d[0] == 0 => d[1..].try_into().ok()?,
+            _ => return None,
+        };
+        let limbs = from_be_bytes(&d);
+        (limbs != [0; 4] && lt(&limbs, &N.m)).then_some(SecretKey { d })
+    }
+
+    /// The uncompressed point, 0x04 followed by x and y, like certificates hold it
+    pub fn public_key(&self) -> Vec<u8> {
+        let (x, y) = to_affine(&scalar_mul(&self.d, &generator()));
+        [&[4][..], &x, &y].concat()
+    }
+
+    /// A DER encoded signature of the SHA-256 of `message`
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let hash: [u8; 32] = Sha256::digest(message).into();
+        let z = reduce(&from_be_bytes(&hash), &N);
+        let d = from_be_bytes(&self.d);
+
+        let mut nonces = Rfc6979::new(&self.d, &to_be_bytes(&z));
+        loop {
+            let k = nonces.next();
+            let (x, _) = to_affine(&scalar_mul(&k, &generator()));
+            let r = reduce(&from_be_bytes(&x), &N);
+            if r == [0; 4] {
+                continue;
+            }
+
+            // s = (z + r d) / k
+            let k = to_montgomery(&from_be_bytes(&k), &N);
+            let rd = mul(&to_montgomery(&r, &N), &to_montgomery(&d, &N), &N);
+            let s = mul(&add(&to_montgomery(&z, &N), &rd, &N), &invert(&k, &N), &N);
+            let s = from_montgomery(&s, &N);
+            if s == [0; 4] {
+                continue;
+            }
+
+            return der_signature(&to_be_bytes(&r), &to_be_bytes(&s));
+        }
+    }
+}
+
+/// Deterministic nonces from HMAC-SHA256, section 3.2 of RFC 6979
+struct Rfc6979 {
+    k: [u8; 32],
+    v: [u8; 32],
+}
+
+impl Rfc6979 {
+    fn new(d: &[u8; 32], hash: &[u8; 32]) -> Rfc6979 {
+        let mut state = Rfc6979 {
+            k: [0; 32],
+            v: [1; 32],
+        };
+        for separator in [0, 1] {
+            state.k = state.hmac(&[&state.v, &[separator], d, hash]);
+            state.v = state.hmac(&[&state.v]);
+        }
+        state
+    }
+
+    fn hmac(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut mac =
+            hmac::Hmac::<Sha256>::new_from_slice(&self.k).expect("hmac takes any key length");
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().into()
+    }
+
+    fn next(&mut self) -> [u8; 32] {
+        loop {
+            self.v = self.hmac(&[&self.v]);
+            let k = self.v;
+            let limbs = from_be_bytes(&k);
+            // whether a candidate gets skipped says nothing about the one that's used
+            let usable = limbs != [0; 4] && lt(&limbs, &N.m);
+            self.k = self.hmac(&[&self.v, &[0]]);
+            self.v = self.hmac(&[&self.v]);
+            if usable {
+                return k;
+            }
+        }
+    }
+}
+
+fn der_signature(r: &[u8; 32], s: &[u8; 32]) -> Vec<u8> {
+    let integer = |value: &[u8; 32]| {
+        let start = value.iter().position(|&b| b != 0).unwrap_or(31);
+        let mut der = vec![0x02, 0];
+        if value[start] & 0x80 != 0 {
+            der.push(0);
+        }
+        der.extend_from_slice(&value[start..]);
+        der[1] = (der.len() - 2) as u8;
+        der
+    };
+    let body = [integer(r), integer(s)].concat();
+    [vec![0x30, body.len() as u8], body].concat()
+}
+
+/// Projective coordinates in Montgomery form, (0, 1, 0) being the point at infinity
+#[derive(Clone, Copy)]
+struct Point {
+    x: Limbs,
+    y: Limbs,
+    z: Limbs,
+}
+
+fn generator() -> Point {
+    Point {
+        x: to_montgomery(&from_be_bytes(&GX), &P),
+        y: to_montgomery(&from_be_bytes(&GY), &P),
+        z: to_montgomery(&[1, 0, 0, 0], &P),
+    }
+}
+
+/// Double and add always, the same operations whatever the bits of `k` are
+fn scalar_mul(k: &[u8; 32], point: &Point) -> Point {
+    let mut out = Point {
+        x: [0; 4],
+        y: to_montgomery(&[1, 0, 0, 0], &P),
+        z: [0; 4],
+    };
+    for bit in (0..256).rev() {
+        out = point_add(&out, &out);
+        let sum = point_add(&out, point);
+        let set = ((k[31 - bit / 8] >> (bit % 8)) & 1) as u64;
+        out = Point {
+            x: select(set, &sum.x, &out.x),
+            y: select(set, &sum.y, &out.y),
+            z: select(set, &sum.z, &out.z),
+        };
+    }
+    out
+}
+
+/// Complete addition for a = -3, algorithm 4 of Renes, Costello and Batina's "Complete
+/// addition formulas for prime order elliptic curves". Works for doubling and the point at
+/// infinity too, so there are no special cases to branch on.
+fn point_add(p1: &Point, p2: &Point) -> Point {
+    let b = to_montgomery(&from_be_bytes(&B), &P);
+    let (x1, y1, z1) = (&p1.x, &p1.y, &p1.z);
+    let (x2, y2, z2) = (&p2.x, &p2.y, &p2.z);
+    let mul = |a: &Limbs, b: &Limbs| mul(a, b, &P);
+    let add = |a: &Limbs, b: &Limbs| add(a, b, &P);
+    let sub = |a: &Limbs, b: &Limbs| sub(a, b, &P);
+
+    let mut t0 = mul(x1, x2);
+    let mut t1 = mul(y1, y2);
+    let mut t2 = mul(z1, z2);
+    let mut t3 = add(x1, y1);
+    let mut t4 = add(x2, y2);
+    t3 = mul(&t3, &t4);
+    t4 = add(&t0, &t1);
+    t3 = sub(&t3, &t4);
+    t4 = add(y1, z1);
+    let mut x3 = add(y2, z2);
+    t4 = mul(&t4, &x3);
+    x3 = add(&t1, &t2);
+    t4 = sub(&t4, &x3);
+    x3 = add(x1, z1);
+    let mut y3 = add(x2, z2);
+    x3 = mul(&x3, &y3);
+    y3 = add(&t0, &t2);
+    y3 = sub(&x3, &y3);
+    let mut z3 = mul(&b, &t2);
+    x3 = sub(&y3, &z3);
+    z3 = add(&x3, &x3);
+    x3 = add(&x3, &z3);
+    z3 = sub(&t1, &x3);
+    x3 = add(&t1, &x3);
+    y3 = mul(&b, &y3);
+    t1 = add(&t2, &t2);
+    t2 = add(&t1, &t2);
+    y3 = sub(&y3, &t2);
+    y3 = sub(&y3, &t0);
+    t1 = add(&y3, &y3);
+    y3 = add(&t1, &y3);
+    t1 = add(&t0, &t0);
+    t0 = add(&t1, &t0);
+    t0 = sub(&t0, &t2);
+    t1 = mul(&t4, &y3);
+    t2 = mul(&t0, &y3);
+    y3 = mul(&x3, &z3);
+    y3 = add(&y3, &t2);
+    x3 = mul(&t3, &x3);
+    x3 = sub(&x3, &t1);
+    z3 = mul(&t4, &z3);
+    t1 = mul(&t3, &t0);
+    z3 = add(&z3, &t1);
+
+    Point {
+        x: x3,
+        y: y3,
+        z: z3,
+    }
+}
+
+/// Big endian x and y
+fn to_affine(point: &Point) -> ([u8; 32], [u8; 32]) {
+    let z_inv = invert(&point.z, &P);
+    let x = from_montgomery(&mul(&point.x, &z_inv, &P), &P);
+    let y = from_montgomery(&mul(&point.y, &z_inv, &P), &P);
+    (to_be_bytes(&x), to_be_bytes(&y))
+}
+
+fn adc(a: u64, b: u64, carry: u64) -> (u64, u64) {
+    let sum = a as u128 + b as u128 + carry as u128;
+    (sum as u64, (sum >> 64) as u64)
+}
+
+fn sbb(a: u64, b: u64, borrow: u64) -> (u64, u64) {
+    let difference = (a as u128).wrapping_sub(b as u128 + borrow as u128);
+    (difference as u64, (difference >> 127) as u64)
+}
+
+/// a + b c + carry
+fn mac(a: u64, b: u64, c: u64, carry: u64) -> (u64, u64) {
+    let sum = a as u128 + b as u128 * c as u128 + carry as u128;
+    (sum as u64, (sum >> 64) as u64)
+}
+
+/// `a` where `choice` is 1, `b` where it's 0
+fn select(choice: u64, a: &Limbs, b: &Limbs) -> Limbs {
+    let mask = 0u64.wrapping_sub(choice);
+    std::array::from_fn(|i| (a[i] & mask) | (b[i] & !mask))
+}
+
+/// a - b, and whether that borrowed
+fn sub_limbs(a: &Limbs, b: &Limbs) -> (Limbs, u64) {
+    let mut out = [0; 4];
+    let mut borrow = 0;
+    for i in 0..4 {
+        (out[i], borrow) = sbb(a[i], b[i], borrow);
+    }
+    (out, borrow)
+}
+
+fn lt(a: &Limbs, b: &Limbs) -> bool {
+    sub_limbs(a, b).1 == 1
+}
+
+/// `a` taken down below `m`, when it's already below 2m
+fn reduce(a: &Limbs, m: &Modulus) -> Limbs {
+    let (reduced, borrow) = sub_limbs(a, &m.m);
+    select(borrow, a, &reduced)
+}
+
+fn add(a: &Limbs, b: &Limbs, m: &Modulus) -> Limbs {
+    let mut sum = [0; 4];
+    let mut carry = 0;
+    for i in 0..4 {
+        (sum[i], carry) = adc(a[i], b[i], carry);
+    }
+    let (reduced, borrow) = sub_limbs(&sum, &m.m);
+    // the sum is kept only when it's below m, which it can't be if it carried out
+    select(borrow & !carry & 1, &sum, &reduced)
+}
+
+fn sub(a: &Limbs, b: &Limbs, m: &Modulus) -> Limbs {
+    let (difference, borrow) = sub_limbs(a, b);
+    let mask = 0u64.wrapping_sub(borrow);
+    let mut out = [0; 4];
+    let mut carry = 0;
+    for i in 0..4 {
+        (out[i], carry) = adc(difference[i], m.m[i] & mask, carry);
+    }
+    out
+}
+
+/// a b / 2^256 mod m
+fn mul(a: &Limbs, b: &Limbs, m: &Modulus) -> Limbs {
+    let mut t = [0u64; 6];
+    for &b in b {
+        let mut carry = 0;
+        for j in 0..4 {
+            (t[j], carry) = mac(t[j], a[j], b, carry);
+        }
+        (t[4], t[5]) = adc(t[4], carry, 0);
+
+        // adding a multiple of m that clears the lowest limb, which then gets shifted out
+        let factor = t[0].wrapping_mul(m.m_inv);
+        let (_, mut carry) = mac(t[0], factor, m.m[0], 0);
+        for j in 1..4 {
+            (t[j - 1], carry) = mac(t[j], factor, m.m[j], carry);
+        }
+        (t[3], carry) = adc(t[4], carry, 0);
+        t[4] = t[5] + carry;
+        t[5] = 0;
+    }
+
+    let low = [t[0], t[1], t[2], t[3]];
+    let (reduced, borrow) = sub_limbs(&low, &m.m);
+    select(borrow & !t[4] & 1, &low, &reduced)
+}
+
+fn to_montgomery(a: &Limbs, m: &Modulus) -> Limbs {
+    mul(a, &m.r2, m)
+}
+
+fn from_montgomery(a: &Limbs, m: &Modulus) -> Limbs {
+    mul(a, &[1, 0, 0, 0], m)
+}
+
+/// a^(m - 2), both moduli being prime. The exponent is public, so branching on it is fine.
+fn invert(a: &Limbs, m: &Modulus) -> Limbs {
+    let (exponent, _) = sub_limbs(&m.m, &[2, 0, 0, 0]);
+    let mut out = to_montgomery(&[1, 0, 0, 0], m);
+    for bit in (0..256).rev() {
+        out = mul(&out, &out, m);
+        if (exponent[bit / 64] >> (bit % 64)) & 1 == 1 {
+            out = mul(&out, a, m);
+        }
+    }
+    out
+}
+
+fn from_be_bytes(bytes: &[u8; 32]) -> Limbs {
+    std::array::from_fn(|i| u64::from_be_bytes(bytes[24 - i * 8..32 - i * 8].try_into().unwrap()))
+}
+
+fn to_be_bytes(limbs: &Limbs) -> [u8; 32] {
+    let mut out = [0; 32];
+    for (i, limb) in limbs.iter().enumerate() {
+        out[24 - i * 8..32 - i * 8].copy_from_slice(&limb.to_be_bytes());
+    }
+    out
+}
+
+const fn hex32(hex: &str) -> [u8; 32] {
+    const fn digit(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            _ => c - b'a' + 10,
+        }
+    }
+
+    let hex = hex.as_bytes();
+    let mut out = [0; 32];
+    let mut i = 0;
+    while i < 32 {
+        out[i] = digit(hex[i * 2]) << 4 | digit(hex[i * 2 + 1]);
+        i += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ecdsa_rfc_6979() {
+        // appendix A.2.5
+        let key = SecretKey::from_bytes(&hex32(
+            "c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721",
+        ))
+        .unwrap();
+        let public = [
+            &[4][..],
+            &hex32("60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6"),
+            &hex32("7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299"),
+        ]
+        .concat();
+        assert_eq!(key.public_key(), public);
+
+        let hash = Sha256::digest(b"sample").into();
+        let mut nonces = Rfc6979::new(&key.d, &to_be_bytes(&reduce(&from_be_bytes(&hash), &N)));
+        assert_eq!(
+            nonces.next(),
+            hex32("a6e3c57dd01abe90086538398355dd4c3b17aa873382b0f24d6129493d8aad60"),
+        );
+
+        assert_eq!(
+            key.sign(b"sample"),
+            der_signature(
+                &hex32("efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716"),
+                &hex32("f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8"),
+            ),
+        );
+        assert_eq!(
+            key.sign(b"test"),
+            der_signature(
+                &hex32("f1abb023518351cd71d881567b1ea663ed3efcf6c5132b354f28d3b0b7d38367"),
+                &hex32("019f4113742a2b14bd25926b49c649155f267e60d3814b4c0cc84250e46f0083"),
+            ),
+        );
+    }
+
+    #[test]
+    fn keys_have_to_be_in_range() {
+        assert!(SecretKey::from_bytes(&[0; 32]).is_none());
+        assert!(SecretKey::from_bytes(&to_be_bytes(&N.m)).is_none());
+        assert!(SecretKey::from_bytes(&[0xff; 32]).is_none());
+        assert!(SecretKey::from_bytes(&[1; 31]).is_none());
+        let (below_n, _) = sub_limbs(&N.m, &[1, 0, 0, 0]);
+        assert!(SecretKey::from_bytes(&to_be_bytes(&below_n)).is_some());
+    }
+}
@@ -0,0 +1,222 @@
+//! X25519 (RFC 7748), the key exchange handshakes use. Field elements are five 51 bit limbs,
+//! and the ladder swaps with masks so it takes as long whatever the scalar is.
+
+type Fe = [u64; 5];
+
+const MASK: u64 = (1 << 51) - 1;
+/// (A - 2) / 4 for curve25519
+const A24: u64 = 121665;
+
+pub const BASEPOINT: [u8; 32] = {
+    let mut point = [0; 32];
+    point[0] = 9;
+    point
+};
+
+/// `scalar` times `point`, both little endian as they go over the wire
+pub fn x25519(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+
+    let x1 = load(point);
+    let (mut x2, mut z2) = ([1, 0, 0, 0, 0], [0; 5]);
+    let (mut x3, mut z3) = (x1, [1, 0, 0, 0, 0]);
+    let mut swap = 0;
+
+    for t in (0..255).rev() {
+        let bit = ((k[t / 8] >> (t % 8)) & 1) as u64;
+        swap ^= bit;
+        cswap(swap, &mut x2, &mut x3);
+        cswap(swap, &mut z2, &mut z3);
+        swap = bit;
+
+        let a = add(&x2, &z2);
+        let aa = mul(&a, &a);
+        let b = sub(&x2, &z2);
+        let bb = mul(&b, &b);
+        let e = sub(&aa, &bb);
+        let c = add(&x3, &z3);
+        let d = sub(&x3, &z3);
+        let da = mul(&d, &a);
+        let cb = mul(&c, &b);
+        let sum = add(&da, &cb);
+        x3 = mul(&sum, &sum);
+        let difference = sub(&da, &cb);
+        z3 = mul(&x1, &mul(&difference, &difference));
+        x2 = mul(&aa, &bb);
+        z2 = mul(&e, &add(&aa, &mul(&[A24, 0, 0, 0, 0], &e)));
+    }
+    cswap(swap, &mut x2, &mut x3);
+    cswap(swap, &mut z2, &mut z3);
+
+    store(&mul(&x2, &invert(&z2)))
+}
+
+fn load(bytes: &[u8; 32]) -> Fe {
+    let word = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    // the top bit is ignored
+    [
+        word(0) & MASK,
+        (word(6) >> 3) & MASK,
+        (word(12) >> 6) & MASK,
+        (word(19) >> 1) & MASK,
+        (word(24) >> 12) & MASK,
+    ]
+}
+
+fn store(h: &Fe) -> [u8; 32] {
+    let mut t = *h;
+    carry(&mut t);
+    carry(&mut t);
+
+    // t is below 2p now, so it's reduced by subtracting p once if t + 19 reaches 2^255
+    let mut q = (t[0] + 19) >> 51;
+    for limb in &t[1..] {
+        q = (limb + q) >> 51;
+    }
+    t[0] += 19 * q;
+    for i in 0..4 {
+        t[i + 1] += t[i] >> 51;
+        t[i] &= MASK;
+    }
+    t[4] &= MASK;
+
+    let words = [
+        t[0] | (t[1] << 51),
+        (t[1] >> 13) | (t[2] << 38),
+        (t[2] >> 26) | (t[3] << 25),
+        (t[3] >> 39) | (t[4] << 12),
+    ];
+    let mut out = [0; 32];
+    for (chunk, word) in out.chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+/// Brings every limb back under 2^51, plus a little in the lowest one
+fn carry(t: &mut Fe) {
+    for i in 0..4 {
+        t[i + 1] += t[i] >> 51;
+        t[i] &= MASK;
+    }
+    t[0] += 19 * (t[4] >> 51);
+    t[4] &= MASK;
+}
+
+fn add(a: &Fe, b: &Fe) -> Fe {
+    std::array::from_fn(|i| a[i] + b[i])
+}
+
+/// Adds 2p first so nothing goes below zero, which needs `b` to have been carried
+fn sub(a: &Fe, b: &Fe) -> Fe {
+    const TWO_P: Fe = [
+        0xfffffffffffda,
+        0xffffffffffffe,
+        0xffffffffffffe,
+        0xffffffffffffe,
+        0xffffffffffffe,
+    ];
+    let mut out = std::array::from_fn(|i| a[i] + TWO_P[i] - b[i]);
+    carry(&mut out);
+    out
+}
+
+fn mul(a: &Fe, b: &Fe) -> Fe {
+    let m = |x: u64, y: u64| x as u128 * y as u128;
+    // 2^255 is 19, so limbs that go past the top wrap around times 19
+    let b19: Fe = std::array::from_fn(|i| b[i] * 19);
+
+    let r = [
+        m(a[0], b[0]) + m(a[1], b19[4]) + m(a[2], b19[3]) + m(a[3], b19[2]) + m(a[4], b19[1]),
+        m(a[0], b[1]) + m(a[1], b[0]) + m(a[2], b19[4]) + m(a[3], b19[3]) + m(a[4], b19[2]),
+        m(a[0], b[2]) + m(a[1], b[1]) + m(a[2], b[0]) + m(a[3], b19[4]) + m(a[4], b19[3]),
+        m(a[0], b[3]) + m(a[1], b[2]) + m(a[2], b[1]) + m(a[3], b[0]) + m(a[4], b19[4]),
+        m(a[0], b[4]) + m(a[1], b[3]) + m(a[2], b[2]) + m(a[3], b[1]) + m(a[4], b[0]),
+    ];
+
+    let mut out = [0; 5];
+    let mut carry = 0;
+    for i in 0..5 {
+        let limb = r[i] + carry;
+        out[i] = limb as u64 & MASK;
+        carry = limb >> 51;
+    }
+    let low = out[0] as u128 + carry * 19;
+    out[0] = low as u64 & MASK;
+    out[1] += (low >> 51) as u64;
+    out
+}
+
+/// a^(p - 2), which the exponent being public makes constant time
+fn invert(a: &Fe) -> Fe {
+    // p - 2 is 2^255 - 21, all ones except bits 2 and 4
+    let mut out = [1, 0, 0, 0, 0];
+    for bit in (0..255).rev() {
+        out = mul(&out, &out);
+        if bit != 2 && bit != 4 {
+            out = mul(&out, a);
+        }
+    }
+    out
+}
+
+fn cswap(swap: u64, a: &mut Fe, b: &mut Fe) {
+    let mask = 0u64.wrapping_sub(swap);
+    for i in 0..5 {
+        let t = mask & (a[i] ^ b[i]);
+        a[i] ^= t;
+        b[i] ^= t;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> [u8; 32] {
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn x25519_rfc_7748() {
+        // section 5.2
+        assert_eq!(
+            x25519(
+                &unhex("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"),
+                &unhex("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c"),
+            ),
+            unhex("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552"),
+        );
+        assert_eq!(
+            x25519(
+                &unhex("4b66e9d4d1b4673c5ad22691957d6af5c11b6421e0ea01d42ca4169e7918ba0d"),
+                &unhex("e5210f12786811d3f4b7959d0538ae2c31dbe7106fc03c3efc4cd549c715a493"),
+            ),
+            unhex("95cbde9476e8907d7aade45cb4b873f88b595a68799fa152e6f8f7647aac7957"),
+        );
+
+        // section 6.1
+        let alice = unhex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = unhex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let alice_public = x25519(&alice, &BASEPOINT);
+        let bob_public = x25519(&bob, &BASEPOINT);
+        assert_eq!(
+            alice_public,
+            unhex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"),
+        );
+        assert_eq!(
+            bob_public,
+            unhex("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"),
+        );
+        let shared = unhex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(x25519(&alice, &bob_public), shared);
+        assert_eq!(x25519(&bob, &alice_public), shared);
+    }
+}